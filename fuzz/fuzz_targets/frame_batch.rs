@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tapcmio::network::decode_batch;
+
+// Decode the input as a batch of frames from the host
+fuzz_target!(|data: &[u8]| {
+    if let Some(entries) = decode_batch(data) {
+        let count = u16::from_be_bytes([data[4], data[5]]) as usize;
+        assert!(entries.len() <= count);
+        let total: usize = entries.iter().map(|entry| entry.data.len()).sum();
+        assert!(total <= data.len());
+        for entry in &entries {
+            entry.is_intact();
+        }
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tapcmio::unix_tcp_socket::SocketMessage;
+
+// Walk the input as a batch of socket messages from the host, as the socket manager does
+fuzz_target!(|data: &[u8]| {
+    let mut offset = 0;
+    while offset < data.len() {
+        match SocketMessage::deserialize(&data[offset..]) {
+            Ok((message, consumed)) => {
+                assert!(consumed > 0 && consumed <= data.len() - offset);
+                assert!(message.data.len() < consumed);
+                offset += consumed;
+            }
+            Err(_) => break,
+        }
+    }
+});
@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tapcmio::CmioYield;
+
+// Unpack the input as a yield response from the host, followed by the RX buffer size
+fuzz_target!(|data: &[u8]| {
+    let (Some(req), Some(rx_length)) = (data.get(..8), data.get(8..12)) else {
+        return;
+    };
+    let req = u64::from_be_bytes(req.try_into().unwrap());
+    let rx_length = u32::from_be_bytes(rx_length.try_into().unwrap()) as usize;
+
+    let response = CmioYield::unpack(req);
+    assert_eq!(response.pack(), req);
+    if let Ok(length) = response.response_length(rx_length) {
+        assert!(length <= rx_length);
+    }
+});
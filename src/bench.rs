@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+use tracing::info;
+use crate::cmio::{Cmio, CmioError, Exchange, MockCmio};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+
+// Payload sizes measured unless others are given, from an empty poll to a large batch
+pub const DEFAULT_SIZES: &[usize] = &[0, 64, 512, 1500, 4096, 16384, 65536, 262144, 1048576];
+
+// TX buffer of the mock device, that of the usual machine setup
+pub const MOCK_BUFFER_SIZE: usize = 2 * 1024 * 1024;
+
+// Reason code of the benchmark's yields unless another is given, left for the host to
+// answer as it likes
+pub const DEFAULT_BENCH_REASON: u16 = 0x45;
+
+// Latencies and throughput of the yields made with one payload size
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchResult {
+    pub size: usize,
+    pub yields: u64,
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    // Payload bytes sent and received per second
+    pub tx_bytes_per_second: f64,
+    pub rx_bytes_per_second: f64,
+}
+
+/// Measure round trips through the device for every payload size, yielding each for
+/// the given time
+/// 
+/// Sizes beyond the device's TX buffer are skipped.
+pub fn run(cmio: &mut Cmio, sizes: &[usize], duration: Duration, reason: YieldReason) -> Result<Vec<BenchResult>, CmioError> {
+    run_on(cmio, sizes, duration, reason)
+}
+
+/// Measure like run against a mock device looping every yield back, to tell the cost of
+/// the guest side apart from that of the machine
+pub fn run_mock(tx_length: usize, sizes: &[usize], duration: Duration) -> Result<Vec<BenchResult>, CmioError> {
+    run_on(&mut MockCmio::new(tx_length), sizes, duration, YieldReason::Other(DEFAULT_BENCH_REASON))
+}
+
+fn run_on(device: &mut dyn Exchange, sizes: &[usize], duration: Duration, reason: YieldReason) -> Result<Vec<BenchResult>, CmioError> {
+    let mut results = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        if size > device.tx_length() {
+            info!("Skipping {} byte payloads, beyond the {} byte TX buffer", size, device.tx_length());
+            continue;
+        }
+        results.push(measure(device, size, duration, reason)?);
+    }
+    Ok(results)
+}
+
+// Yield payloads of one size for the given time, at least once, timing every round trip
+fn measure(device: &mut dyn Exchange, size: usize, duration: Duration, reason: YieldReason) -> Result<BenchResult, CmioError> {
+    let tx_data: Vec<u8> = (0..size).map(|index| index as u8).collect();
+    let mut rx_data = Vec::with_capacity(size);
+    let mut latencies = Vec::new();
+    let mut rx_bytes = 0u64;
+    let started = Instant::now();
+    while latencies.is_empty() || started.elapsed() < duration {
+        let sent = Instant::now();
+        device.exchange(YieldDevice::Yield, YieldCommand::Manual, reason, &tx_data, &mut rx_data)?;
+        latencies.push(sent.elapsed());
+        rx_bytes += rx_data.len() as u64;
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    Ok(summarize(size, latencies, rx_bytes, elapsed))
+}
+
+// Summarize the latencies of the yields made with one payload size, over elapsed seconds
+fn summarize(size: usize, mut latencies: Vec<Duration>, rx_bytes: u64, elapsed: f64) -> BenchResult {
+    latencies.sort_unstable();
+    let yields = latencies.len() as u64;
+    let percentile = |percent: usize| latencies[(latencies.len() - 1) * percent / 100];
+    BenchResult {
+        size,
+        yields,
+        min: latencies[0],
+        mean: latencies.iter().sum::<Duration>() / yields as u32,
+        p50: percentile(50),
+        p99: percentile(99),
+        max: latencies[latencies.len() - 1],
+        tx_bytes_per_second: (size as u64 * yields) as f64 / elapsed,
+        rx_bytes_per_second: rx_bytes as f64 / elapsed,
+    }
+}
+
+/// Print the results as a table, latencies in microseconds and throughput in MB/s
+pub fn print_table(results: &[BenchResult]) {
+    println!("{:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9} {:>10} {:>10}", "size", "yields", "min us", "mean us", "p50 us", "p99 us", "max us", "TX MB/s", "RX MB/s");
+    let micros = |latency: Duration| latency.as_secs_f64() * 1e6;
+    for result in results {
+        println!("{:>9} {:>9} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>9.1} {:>10.2} {:>10.2}",
+            result.size, result.yields, micros(result.min), micros(result.mean), micros(result.p50), micros(result.p99), micros(result.max),
+            result.tx_bytes_per_second / 1e6, result.rx_bytes_per_second / 1e6);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench() {
+        let latencies = (1..=100).map(Duration::from_micros).collect();
+        let result = summarize(1000, latencies, 50_000, 2.0);
+        assert_eq!((result.yields, result.min, result.max), (100, Duration::from_micros(1), Duration::from_micros(100)));
+        assert_eq!((result.p50, result.p99), (Duration::from_micros(50), Duration::from_micros(99)));
+        assert_eq!(result.mean, Duration::from_nanos(50_500));
+        assert_eq!((result.tx_bytes_per_second, result.rx_bytes_per_second), (50_000.0, 25_000.0));
+
+        // The mock loops every payload back, skipping those beyond its buffer
+        let results = run_mock(4096, &[0, 1500, 8192], Duration::from_millis(5)).unwrap();
+        assert_eq!(results.iter().map(|result| result.size).collect::<Vec<_>>(), vec![0, 1500]);
+        assert!(results[1].yields > 0);
+        assert_eq!(results[1].tx_bytes_per_second, results[1].rx_bytes_per_second);
+    }
+}
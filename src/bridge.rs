@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// How long a learned address is kept without frames from it, as in Linux bridges
+pub const DEFAULT_AGEING_TIME: Duration = Duration::from_secs(300);
+
+// Most addresses learned at once, bounding the table against floods of made-up sources
+const MAX_ENTRIES: usize = 4096;
+
+// Size of the Ethernet destination and source addresses at the start of a frame
+const ADDRESSES_SIZE: usize = 12;
+
+// Port of the bridge a frame came in on or goes out of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Port {
+    // The CMIO-backed TAP interface
+    Tap,
+    // The local TAP interface bridged with it
+    Local,
+    // The host, over CMIO
+    Uplink,
+}
+
+const PORTS: [Port; 3] = [Port::Tap, Port::Local, Port::Uplink];
+
+// Structure learning which port Ethernet addresses are behind, so frames to a known
+// address only go out of that port and all others are flooded
+#[derive(Debug)]
+pub struct ForwardingTable {
+    entries: HashMap<[u8; 6], (Port, Instant)>,
+    ageing_time: Duration,
+}
+
+impl ForwardingTable {
+    pub fn new(ageing_time: Duration) -> Self {
+        Self { entries: HashMap::new(), ageing_time }
+    }
+    
+    /// Learn the source address of an Ethernet frame that came in on a port, and return
+    /// the ports to forward it to
+    ///
+    /// Broadcast, multicast and unknown destinations are flooded to every other port.
+    /// Frames to an address behind the port they came in on aren't forwarded at all, and
+    /// neither are frames too short to carry addresses.
+    pub fn forward(&mut self, frame: &[u8], port: Port, now: Instant) -> Vec<Port> {
+        if frame.len() < ADDRESSES_SIZE {
+            return Vec::new();
+        }
+        let destination: [u8; 6] = frame[0..6].try_into().unwrap();
+        let source: [u8; 6] = frame[6..12].try_into().unwrap();
+        
+        // Only unicast sources are learned
+        if source[0] & 0x01 == 0 {
+            if self.entries.len() >= MAX_ENTRIES && !self.entries.contains_key(&source) {
+                let ageing_time = self.ageing_time;
+                self.entries.retain(|_, (_, seen)| now.duration_since(*seen) < ageing_time);
+            }
+            if self.entries.len() < MAX_ENTRIES || self.entries.contains_key(&source) {
+                self.entries.insert(source, (port, now));
+            }
+        }
+        
+        let known = match self.entries.get(&destination) {
+            Some(&(known, seen)) if destination[0] & 0x01 == 0 && now.duration_since(seen) < self.ageing_time => Some(known),
+            _ => None,
+        };
+        match known {
+            Some(known) if known == port => Vec::new(),
+            Some(known) => vec![known],
+            None => PORTS.iter().copied().filter(|&other| other != port).collect(),
+        }
+    }
+}
+
+impl Default for ForwardingTable {
+    fn default() -> Self {
+        Self::new(DEFAULT_AGEING_TIME)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    const MAC_A: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0a];
+    const MAC_B: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0b];
+    const BROADCAST: [u8; 6] = [0xff; 6];
+
+    fn frame(destination: [u8; 6], source: [u8; 6]) -> Vec<u8> {
+        let mut frame = destination.to_vec();
+        frame.extend_from_slice(&source);
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame
+    }
+
+    #[test]
+    fn test_learning() {
+        let mut table = ForwardingTable::default();
+        let now = Instant::now();
+
+        // Unknown and broadcast destinations are flooded
+        assert_eq!(table.forward(&frame(MAC_B, MAC_A), Port::Local, now), vec![Port::Tap, Port::Uplink]);
+        assert_eq!(table.forward(&frame(BROADCAST, MAC_B), Port::Uplink, now), vec![Port::Tap, Port::Local]);
+
+        // Both addresses are known now
+        assert_eq!(table.forward(&frame(MAC_A, MAC_B), Port::Uplink, now), vec![Port::Local]);
+        assert_eq!(table.forward(&frame(MAC_B, MAC_A), Port::Local, now), vec![Port::Uplink]);
+        assert_eq!(table.forward(&frame(MAC_B, MAC_A), Port::Uplink, now), Vec::<Port>::new());
+
+        // A broadcast source is never learned, and short frames go nowhere
+        table.forward(&frame(MAC_A, BROADCAST), Port::Tap, now);
+        assert_eq!(table.forward(&frame(BROADCAST, MAC_A), Port::Local, now), vec![Port::Tap, Port::Uplink]);
+        assert!(table.forward(&MAC_A, Port::Tap, now).is_empty());
+    }
+
+    #[test]
+    fn test_ageing() {
+        let mut table = ForwardingTable::new(Duration::from_secs(10));
+        let now = Instant::now();
+        table.forward(&frame(BROADCAST, MAC_A), Port::Tap, now);
+
+        assert_eq!(table.forward(&frame(MAC_A, MAC_B), Port::Uplink, now + Duration::from_secs(9)), vec![Port::Tap]);
+        assert_eq!(table.forward(&frame(MAC_A, MAC_B), Port::Uplink, now + Duration::from_secs(10)), vec![Port::Tap, Port::Local]);
+    }
+}
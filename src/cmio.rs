@@ -1,12 +1,33 @@
-use std::os::unix::io::RawFd;
+use std::env;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::ptr;
-use libc::{self, c_void, ioctl, mmap, munmap, MAP_FAILED, MAP_SHARED, PROT_READ, PROT_WRITE};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use libc::{self, c_void, ioctl, mmap, munmap, MAP_FAILED, MAP_LOCKED, MAP_POPULATE, MAP_SHARED, PROT_READ, PROT_WRITE};
 use thiserror::Error;
+use tracing::{info, warn};
+use crate::dump::{self, Direction};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+use crate::status;
 
-const CMIO_DEVICE: &str = "/dev/cmio";
+// Device node opened unless configured otherwise
+pub const DEFAULT_CMIO_DEVICE: &str = "/dev/cmio";
+
+// Environment variable overriding the device node, e.g. with an emulated one for testing
+pub const CMIO_DEVICE_ENV: &str = "TAPCMIO_DEVICE";
 const IOCTL_CMIO_SETUP: libc::c_ulong = 0xd3 << 16 | 0;
 const IOCTL_CMIO_YIELD: libc::c_ulong = 0xd3 << 16 | 1;
 
+// Re-initializations tried after transient yield errors before giving up
+pub const MAX_YIELD_RETRIES: u32 = 3;
+
+// Times an interrupted ioctl is restarted unless configured otherwise
+pub const DEFAULT_IOCTL_RETRIES: u32 = 16;
+
 #[repr(C)]
 pub struct CmioBuffer {
     pub data: u64,
@@ -20,6 +41,7 @@ pub struct CmioSetup {
 }
 
 #[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct CmioYield {
     pub dev: u8,
     pub cmd: u8,
@@ -27,104 +49,436 @@ pub struct CmioYield {
     pub data: u32,
 }
 
+impl CmioYield {
+    /// The yield request as passed to the driver: the device in bits 56-63, the command
+    /// in bits 48-55, the reason code in bits 32-47 and the data in bits 0-31
+    pub const fn pack(&self) -> u64 {
+        ((self.dev as u64) << 56)
+            | ((self.cmd as u64) << 48)
+            | ((self.reason as u64) << 32)
+            | (self.data as u64)
+    }
+    
+    /// The yield of a request packed as by pack
+    pub const fn unpack(req: u64) -> Self {
+        Self {
+            dev: (req >> 56) as u8,
+            cmd: (req >> 48) as u8,
+            reason: (req >> 32) as u16,
+            data: req as u32,
+        }
+    }
+    
+    /// The length of the response in the RX buffer, which the host sets in the data
+    /// 
+    /// A length beyond the RX buffer of rx_length bytes fails with BufferTooLarge rather
+    /// than have the buffer read past its end.
+    pub fn response_length(&self, rx_length: usize) -> Result<usize, CmioError> {
+        let length = self.data as usize;
+        if length > rx_length {
+            return Err(CmioError::BufferTooLarge(length, rx_length));
+        }
+        Ok(length)
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum CmioError {
-    #[error("Failed to open CMIO device: {0}")]
-    OpenError(#[from] std::io::Error),
-    #[error("Failed to setup CMIO: {0}")]
+    #[error("Failed to open CMIO device {0}: {1}")]
+    OpenError(String, #[source] std::io::Error),
+    #[error("Failed to set up CMIO buffers: {}", errno_message(*.0))]
     SetupError(i32),
-    #[error("Failed to map memory: {0}")]
-    MapError(i32),
+    #[error("Failed to map the CMIO {0} buffer: {}", errno_message(*.1))]
+    MapFailed(&'static str, i32),
+    #[error("CMIO yield failed: {}", errno_message(*.0))]
+    YieldFailed(i32),
     #[error("Buffer too large: {0} bytes (max: {1})")]
     BufferTooLarge(usize, usize),
+    #[error("Failed to {op}: {source}")]
+    IoError { op: String, #[source] source: std::io::Error },
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Too many connections, the limit is {0}")]
+    TooManyConnections(usize),
+    #[error("Socket ID {0} is already in use")]
+    SocketIdInUse(u32),
+    #[error("No socket with ID {0}")]
+    UnknownSocket(u32),
+    #[error("TLS with {0} failed: {1}")]
+    TlsError(String, String),
+    #[error("The {0} thread stopped")]
+    ThreadStopped(&'static str),
+}
+
+// Description of an errno, with the number for codes the OS doesn't know
+fn errno_message(errno: i32) -> std::io::Error {
+    std::io::Error::from_raw_os_error(errno)
+}
+
+impl CmioError {
+    /// Error of an I/O operation, described as what was being done when it failed
+    pub fn io(op: impl Into<String>, source: std::io::Error) -> Self {
+        CmioError::IoError { op: op.into(), source }
+    }
+    
+    /// The errno behind the error, or None for malformed data and failures that carry
+    /// none
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            CmioError::OpenError(_, source) | CmioError::IoError { source, .. } => source.raw_os_error(),
+            CmioError::SetupError(errno) | CmioError::MapFailed(_, errno) | CmioError::YieldFailed(errno) => Some(*errno),
+            CmioError::BufferTooLarge(_, _) => Some(libc::EMSGSIZE),
+            CmioError::ProtocolError(_) => None,
+            CmioError::InvalidArgument(_) => Some(libc::EINVAL),
+            CmioError::TooManyConnections(_) => Some(libc::EMFILE),
+            CmioError::SocketIdInUse(_) => Some(libc::EEXIST),
+            CmioError::UnknownSocket(_) => Some(libc::ENOENT),
+            CmioError::TlsError(_, _) => Some(libc::EPROTO),
+            CmioError::ThreadStopped(_) => Some(libc::EPIPE),
+        }
+    }
+    
+    /// Whether re-initializing the device and trying again may get past the error
+    /// 
+    /// Interrupted or would-block ioctls are transient, as are the errors left by a
+    /// machine snapshot or rollback invalidating the open device.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, CmioError::SetupError(errno) | CmioError::YieldFailed(errno)
+            if matches!(*errno, libc::EINTR | libc::EAGAIN | libc::EIO | libc::EBADF | libc::ENODEV | libc::ENXIO))
+    }
+}
+
+// How ioctls failing before the host saw them are restarted rather than surfaced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    // Restarts of one ioctl before its error is returned
+    pub max_retries: u32,
+    // Whether EAGAIN is restarted too, besides EINTR from a signal
+    pub retry_eagain: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self { max_retries: DEFAULT_IOCTL_RETRIES, retry_eagain: false }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether an ioctl failing with errno is restarted
+    pub fn retries(&self, errno: i32) -> bool {
+        errno == libc::EINTR || (self.retry_eagain && errno == libc::EAGAIN)
+    }
+    
+    /// Make a call returning an errno on failure, restarting it as long as the policy
+    /// allows
+    fn run(&self, mut call: impl FnMut() -> Result<(), i32>) -> Result<(), i32> {
+        let mut retries = 0;
+        loop {
+            match call() {
+                Err(errno) if self.retries(errno) && retries < self.max_retries => {
+                    retries += 1;
+                    if errno == libc::EAGAIN {
+                        std::thread::yield_now();
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+// Errno of a failed ioctl
+fn ioctl_result(ret: libc::c_int) -> Result<(), i32> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error().raw_os_error().unwrap_or(-1))
+    } else {
+        Ok(())
+    }
+}
+
+// Wait up to the timeout, or indefinitely with None, for the fd to have data to read,
+// restarting a wait a signal interrupted with the time that is left
+fn poll_readable(fd: RawFd, timeout: Option<Duration>) -> Result<bool, i32> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let timeout_ms = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                left.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+        let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+        if ret < 0 {
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(-1);
+            if errno == libc::EINTR {
+                continue;
+            }
+            return Err(errno);
+        }
+        if pollfd.revents & libc::POLLNVAL != 0 {
+            return Err(libc::EBADF);
+        }
+        return Ok(ret > 0);
+    }
+}
+
+// What the open device offers, for negotiating the protocol with the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmioCapabilities {
+    // Largest buffer a yield can send
+    pub tx_length: usize,
+    // Largest buffer the host can answer with
+    pub rx_length: usize,
+    // Path of the device node
+    pub device: PathBuf,
+    // Major and minor number of the device node, telling the driver behind it
+    pub device_number: Option<(u32, u32)>,
+}
+
+// Path of the device node from the environment, or the default one
+pub fn default_device() -> PathBuf {
+    env::var_os(CMIO_DEVICE_ENV).map_or_else(|| PathBuf::from(DEFAULT_CMIO_DEVICE), PathBuf::from)
+}
+
+// Counters of the yields made through a device since it was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CmioStats {
+    pub yields: u64,
+    // Bytes sent in the TX buffer and received in the RX buffer
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    // Failed yields, including those retried after a re-initialization
+    pub errors: u64,
+    // Re-initializations of the device after transient errors
+    pub reinits: u64,
+}
+
+// Flags of the device's file descriptor and buffer mappings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct MapOptions {
+    // Map the RX buffer read-write rather than read-only
+    rx_writable: bool,
+    // Fault the buffers in with MAP_POPULATE when mapping them
+    populate: bool,
+    // Lock the buffers in memory with MAP_LOCKED
+    locked: bool,
+    // Open the device with O_CLOEXEC, so it isn't inherited across exec
+    cloexec: bool,
+}
+
+// Options for opening the device, created by Cmio::builder
+//
+// Unless set otherwise, the device named by TAPCMIO_DEVICE or /dev/cmio is opened with
+// the default retry policy and without O_CLOEXEC, and its buffers are mapped without
+// MAP_POPULATE or MAP_LOCKED, the RX buffer read-only, as Cmio::new does.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CmioBuilder {
+    device: Option<PathBuf>,
+    retry: RetryPolicy,
+    options: MapOptions,
+}
+
+impl CmioBuilder {
+    /// Open the device node at the given path
+    pub fn device(mut self, path: impl Into<PathBuf>) -> Self {
+        self.device = Some(path.into());
+        self
+    }
+    
+    /// Restart interrupted ioctls as the policy says
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+    
+    /// Map the RX buffer writable as well as readable
+    pub fn map_rx_writable(mut self, writable: bool) -> Self {
+        self.options.rx_writable = writable;
+        self
+    }
+    
+    /// Fault the buffers in when mapping them, so the first yields don't page fault
+    pub fn populate(mut self, populate: bool) -> Self {
+        self.options.populate = populate;
+        self
+    }
+    
+    /// Lock the buffers in memory, which needs a large enough RLIMIT_MEMLOCK
+    pub fn locked(mut self, locked: bool) -> Self {
+        self.options.locked = locked;
+        self
+    }
+    
+    /// Close the device in programs started with exec
+    pub fn cloexec(mut self, cloexec: bool) -> Self {
+        self.options.cloexec = cloexec;
+        self
+    }
+    
+    /// Open the device and map its buffers
+    pub fn build(self) -> Result<Cmio, CmioError> {
+        let path = self.device.unwrap_or_else(default_device);
+        Cmio::open_mapped(&path, self.retry, self.options)
+    }
 }
 
 pub struct Cmio {
+    path: PathBuf,
+    retry: RetryPolicy,
+    options: MapOptions,
     fd: RawFd,
     tx_buffer: *mut c_void,
     rx_buffer: *mut c_void,
     tx_length: usize,
     rx_length: usize,
+    stats: CmioStats,
+    stats_interval: Option<Duration>,
+    last_report: Instant,
 }
 
 impl Cmio {
+    /// Open the device node named by TAPCMIO_DEVICE, or /dev/cmio
     pub fn new() -> Result<Self, CmioError> {
+        Self::open(default_device())
+    }
+    
+    /// Open the CMIO device at the given path and map its buffers
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, CmioError> {
+        Self::open_with(path, RetryPolicy::default())
+    }
+    
+    /// Open the CMIO device at the given path, restarting interrupted ioctls as the
+    /// policy says
+    pub fn open_with(path: impl AsRef<Path>, retry: RetryPolicy) -> Result<Self, CmioError> {
+        Self::open_mapped(path.as_ref(), retry, MapOptions::default())
+    }
+    
+    /// Set up the options to open the device with
+    pub fn builder() -> CmioBuilder {
+        CmioBuilder::default()
+    }
+    
+    fn open_mapped(path: &Path, retry: RetryPolicy, options: MapOptions) -> Result<Self, CmioError> {
+        let open_error = |e| CmioError::OpenError(path.display().to_string(), e);
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| open_error(std::io::Error::from_raw_os_error(libc::EINVAL)))?;
         let fd = unsafe {
             libc::open(
-                CMIO_DEVICE.as_ptr() as *const libc::c_char,
-                libc::O_RDWR,
+                c_path.as_ptr(),
+                if options.cloexec { libc::O_RDWR | libc::O_CLOEXEC } else { libc::O_RDWR },
                 0,
             )
         };
 
         if fd < 0 {
-            return Err(CmioError::OpenError(std::io::Error::last_os_error()));
+            return Err(open_error(std::io::Error::last_os_error()));
         }
+        // Closes the device on the error returns below, until the Cmio takes it over
+        let owned = unsafe { OwnedFd::from_raw_fd(fd) };
 
         let mut setup = CmioSetup {
             tx: CmioBuffer { data: 0, length: 0 },
             rx: CmioBuffer { data: 0, length: 0 },
         };
 
-        if unsafe { ioctl(fd, IOCTL_CMIO_SETUP, &mut setup) } < 0 {
-            return Err(CmioError::SetupError(std::io::Error::last_os_error().raw_os_error().unwrap()));
+        if let Err(errno) = retry.run(|| ioctl_result(unsafe { ioctl(fd, IOCTL_CMIO_SETUP, &mut setup) })) {
+            return Err(CmioError::SetupError(errno));
         }
 
+        let mut map_flags = MAP_SHARED;
+        if options.populate {
+            map_flags |= MAP_POPULATE;
+        }
+        if options.locked {
+            map_flags |= MAP_LOCKED;
+        }
+        let rx_protection = if options.rx_writable { PROT_READ | PROT_WRITE } else { PROT_READ };
+
         let tx_buffer = unsafe {
             mmap(
                 setup.tx.data as *mut c_void,
                 setup.tx.length as usize,
                 PROT_READ | PROT_WRITE,
-                MAP_SHARED,
+                map_flags,
                 fd,
                 0,
             )
         };
 
         if tx_buffer == MAP_FAILED {
-            return Err(CmioError::MapError(std::io::Error::last_os_error().raw_os_error().unwrap()));
+            return Err(CmioError::MapFailed("TX", std::io::Error::last_os_error().raw_os_error().unwrap_or(-1)));
         }
 
         let rx_buffer = unsafe {
             mmap(
                 setup.rx.data as *mut c_void,
                 setup.rx.length as usize,
-                PROT_READ,
-                MAP_SHARED,
+                rx_protection,
+                map_flags,
                 fd,
                 0,
             )
         };
 
         if rx_buffer == MAP_FAILED {
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(-1);
             unsafe { munmap(tx_buffer, setup.tx.length as usize) };
-            return Err(CmioError::MapError(std::io::Error::last_os_error().raw_os_error().unwrap()));
+            return Err(CmioError::MapFailed("RX", errno));
         }
+        status::device_opened();
 
         Ok(Self {
-            fd,
+            path: path.to_path_buf(),
+            retry,
+            options,
+            fd: owned.into_raw_fd(),
             tx_buffer,
             rx_buffer,
             tx_length: setup.tx.length as usize,
             rx_length: setup.rx.length as usize,
+            stats: CmioStats::default(),
+            stats_interval: None,
+            last_report: Instant::now(),
         })
     }
 
     pub fn yield_(&mut self, yield_data: &mut CmioYield) -> Result<(), CmioError> {
-        let packed = ((yield_data.dev as u64) << 56)
-            | ((yield_data.cmd as u64) << 48)
-            | ((yield_data.reason as u64) << 32)
-            | (yield_data.data as u64);
+        let packed = yield_data.pack();
+
+        #[cfg(feature = "trace-cmio")]
+        let _span = tracing::trace_span!("cmio_yield", dev = yield_data.dev, cmd = yield_data.cmd, reason = yield_data.reason, tx_length = yield_data.data).entered();
+        #[cfg(feature = "trace-cmio")]
+        let started = Instant::now();
 
+        // The request is in and out, so every attempt starts from the packed one
         let mut req = packed;
-        if unsafe { ioctl(self.fd, IOCTL_CMIO_YIELD, &mut req) } < 0 {
-            return Err(CmioError::SetupError(std::io::Error::last_os_error().raw_os_error().unwrap()));
+        let fd = self.fd;
+        let result = self.retry.run(|| {
+            req = packed;
+            ioctl_result(unsafe { ioctl(fd, IOCTL_CMIO_YIELD, &mut req) })
+        });
+
+        #[cfg(feature = "trace-cmio")]
+        match result {
+            Ok(()) => {
+                let response = CmioYield::unpack(req);
+                tracing::trace!(reason = response.reason, rx_length = response.data, latency_us = started.elapsed().as_micros() as u64, "yield returned");
+            }
+            Err(errno) => tracing::warn!(errno, latency_us = started.elapsed().as_micros() as u64, "yield failed"),
+        }
+        match result {
+            Ok(()) => self.stats.yields += 1,
+            Err(_) => self.stats.errors += 1,
         }
+        status::record_yield(result.is_ok());
+        self.report_stats();
+        result.map_err(CmioError::YieldFailed)?;
 
-        yield_data.dev = (req >> 56) as u8;
-        yield_data.cmd = (req >> 48) as u8;
-        yield_data.reason = (req >> 32) as u16;
-        yield_data.data = req as u32;
+        *yield_data = CmioYield::unpack(req);
 
         Ok(())
     }
@@ -133,22 +487,41 @@ impl Cmio {
     /// 
     /// # Arguments
     /// 
-    /// * `dev` - Device ID
-    /// * `cmd` - Command ID
-    /// * `reason` - Reason code
+    /// * `dev` - HTIF device
+    /// * `cmd` - Yield command
+    /// * `reason` - Reason code telling the host what the buffer carries
     /// * `tx_data` - Data to send in the TX buffer
     /// 
     /// # Returns
     /// 
-    /// * `Ok((Vec<u8>, u16))` - A tuple containing the data received in the RX buffer and the reason code
+    /// * `Ok((Vec<u8>, u16))` - A tuple containing the data received in the RX buffer and the raw reason code of the response, which may carry flags
     /// * `Err(CmioError)` - If an error occurs
     pub fn yield_with_buffer(
         &mut self,
-        dev: u8,
-        cmd: u8,
-        reason: u16,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
         tx_data: &[u8],
     ) -> Result<(Vec<u8>, u16), CmioError> {
+        let mut rx_data = Vec::new();
+        let reason = self.yield_with_buffer_into(dev, cmd, reason, tx_data, &mut rx_data)?;
+        Ok((rx_data, reason))
+    }
+    
+    /// Yield with a buffer like yield_with_buffer, writing the response into `rx_data`
+    /// instead of a new vector
+    /// 
+    /// The vector is cleared first and keeps its capacity, so a loop passing the same one
+    /// every time stops allocating once it has grown to the largest response. Returns
+    /// the raw reason code of the response.
+    pub fn yield_with_buffer_into(
+        &mut self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+        rx_data: &mut Vec<u8>,
+    ) -> Result<u16, CmioError> {
         // Check if the buffer is too large
         if tx_data.len() > self.tx_length {
             return Err(CmioError::BufferTooLarge(tx_data.len(), self.tx_length));
@@ -165,48 +538,744 @@ impl Cmio {
 
         // Create yield data with the length of the data
         let mut yield_data = CmioYield {
-            dev,
-            cmd,
-            reason,
+            dev: dev as u8,
+            cmd: cmd as u8,
+            reason: reason.code(),
             data: tx_data.len() as u32,
         };
 
         // Perform the yield
+        dump::record(Direction::Tx, yield_data.dev, yield_data.cmd, yield_data.reason, tx_data);
         self.yield_(&mut yield_data)?;
 
-        // Get the length of the response data
-        let rx_length = yield_data.data as usize;
-        
-        // Check if the response is too large
-        if rx_length > self.rx_length {
-            return Err(CmioError::BufferTooLarge(rx_length, self.rx_length));
-        }
+        // Get the length of the response data, which must fit the RX buffer
+        let rx_length = match yield_data.response_length(self.rx_length) {
+            Ok(rx_length) => rx_length,
+            Err(e) => {
+                self.stats.errors += 1;
+                return Err(e);
+            }
+        };
+        self.stats.tx_bytes += tx_data.len() as u64;
+        self.stats.rx_bytes += rx_length as u64;
+        status::record_bytes(tx_data.len(), rx_length);
 
         // Copy data from RX buffer
-        let mut rx_data = vec![0u8; rx_length];
+        rx_data.clear();
+        rx_data.extend_from_slice(unsafe { std::slice::from_raw_parts(self.rx_buffer as *const u8, rx_length) });
+        dump::record(Direction::Rx, yield_data.dev, yield_data.cmd, yield_data.reason, rx_data);
+
+        Ok(yield_data.reason)
+    }
+
+    /// Yield with a buffer like yield_with_buffer, re-initializing the device and
+    /// retrying up to MAX_YIELD_RETRIES times after transient errors
+    /// 
+    /// A retried yield may reach the host twice if the first attempt failed after the
+    /// host took it, so the host should tolerate duplicate batches.
+    pub fn yield_with_retry(
+        &mut self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+    ) -> Result<(Vec<u8>, u16), CmioError> {
+        let mut rx_data = Vec::new();
+        let reason = self.yield_with_retry_into(dev, cmd, reason, tx_data, &mut rx_data)?;
+        Ok((rx_data, reason))
+    }
+    
+    /// Yield with a buffer like yield_with_retry, writing the response into `rx_data` as
+    /// yield_with_buffer_into does
+    pub fn yield_with_retry_into(
+        &mut self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+        rx_data: &mut Vec<u8>,
+    ) -> Result<u16, CmioError> {
+        let mut attempts = 0;
+        loop {
+            let error = match self.yield_with_buffer_into(dev, cmd, reason, tx_data, rx_data) {
+                Err(e) if e.is_transient() && attempts < MAX_YIELD_RETRIES => e,
+                result => return result,
+            };
+            attempts += 1;
+            warn!(attempt = attempts, max_attempts = MAX_YIELD_RETRIES, error = %error, "CMIO yield failed, re-initializing");
+            
+            match self.reinit() {
+                Err(e) if e.is_transient() => continue,
+                Err(e) => return Err(e),
+                Ok(()) => {}
+            }
+        }
+    }
+    
+    /// Close and re-open the device, mapping its buffers again
+    /// 
+    /// The buffer sizes are those of the new setup. If re-opening fails, the device stays
+    /// closed and yields fail until a later re-initialization succeeds. The statistics
+    /// carry over.
+    pub fn reinit(&mut self) -> Result<(), CmioError> {
+        self.release();
+        self.stats.reinits += 1;
+        let reopened = Self::open_mapped(&self.path, self.retry, self.options)?;
+        let (stats, stats_interval, last_report) = (self.stats, self.stats_interval, self.last_report);
+        *self = reopened;
+        self.stats = stats;
+        self.stats_interval = stats_interval;
+        self.last_report = last_report;
+        Ok(())
+    }
+    
+    /// Yield the data to the yield device with a manual yield, stopping the machine until
+    /// the host responds, and return the response and its raw reason code
+    /// 
+    /// The yield is made once and any error returned to the caller: a manual yield that
+    /// reached the host finishes the current request, so repeating it would accept or
+    /// reject the next one as well. Use yield_with_retry for yields the host can answer
+    /// twice, such as queries.
+    pub fn yield_manual(&mut self, reason: YieldReason, tx_data: &[u8]) -> Result<(Vec<u8>, u16), CmioError> {
+        self.yield_with_buffer(YieldDevice::Yield, YieldCommand::Manual, reason, tx_data)
+    }
+    
+    /// Yield the data to the yield device with an automatic yield, which the machine
+    /// continues from without waiting for a response, such as a rollup output
+    /// 
+    /// The yield is made once and any error returned to the caller: the host proves
+    /// outputs, so one repeated after a failure that reached the host would be a second
+    /// notice, voucher or report.
+    pub fn yield_automatic(&mut self, reason: YieldReason, tx_data: &[u8]) -> Result<(), CmioError> {
+        self.yield_with_buffer(YieldDevice::Yield, YieldCommand::Automatic, reason, tx_data)?;
+        Ok(())
+    }
+    
+    /// Yield counters since the device was opened
+    pub fn stats(&self) -> CmioStats {
+        self.stats
+    }
+    
+    /// Log the yield counters at the given interval, or stop logging them with None
+    pub fn set_stats_interval(&mut self, interval: Option<Duration>) {
+        self.stats_interval = interval;
+        self.last_report = Instant::now();
+    }
+    
+    /// Log the yield counters if the reporting interval has passed
+    fn report_stats(&mut self) {
+        let Some(interval) = self.stats_interval else {
+            return;
+        };
+        if self.last_report.elapsed() < interval {
+            return;
+        }
+        self.last_report = Instant::now();
+        
+        let stats = &self.stats;
+        info!("CMIO {}: {} yields, TX {} bytes, RX {} bytes, {} errors, {} re-initializations",
+            self.path.display(), stats.yields, stats.tx_bytes, stats.rx_bytes, stats.errors, stats.reinits);
+    }
+    
+    /// Restart interrupted ioctls as the policy says from now on
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+    
+    /// Path of the device node
+    pub fn device(&self) -> &Path {
+        &self.path
+    }
+    
+    /// Get the maximum size of the TX buffer
+    pub fn get_tx_length(&self) -> usize {
+        self.tx_length
+    }
+    
+    /// Get the maximum size of the RX buffer
+    pub fn get_rx_length(&self) -> usize {
+        self.rx_length
+    }
+    
+    /// Wait up to the timeout, or indefinitely with None, for the device to be readable,
+    /// returning whether it became so
+    /// 
+    /// This lets an event loop wait on the device together with its TAP and socket fds
+    /// rather than yield speculatively with nothing to send. Fails with EBADF while the
+    /// device is closed after a failed re-initialization.
+    pub fn wait_readable(&self, timeout: Option<Duration>) -> Result<bool, CmioError> {
+        if self.fd < 0 {
+            return Err(CmioError::io("poll the CMIO device", errno_message(libc::EBADF)));
+        }
+        poll_readable(self.fd, timeout).map_err(|errno| CmioError::io("poll the CMIO device", errno_message(errno)))
+    }
+    
+    /// The mapped TX buffer, to build a message in place before a yield_ whose data is
+    /// its length
+    /// 
+    /// The slice is empty while the device is closed after a failed re-initialization.
+    pub fn tx_slice_mut(&mut self) -> &mut [u8] {
+        if self.tx_buffer.is_null() {
+            return &mut [];
+        }
+        unsafe { std::slice::from_raw_parts_mut(self.tx_buffer as *mut u8, self.tx_length) }
+    }
+    
+    /// The first `len` bytes of the mapped RX buffer, as left by the host's last response
+    /// 
+    /// Fails with BufferTooLarge if `len` exceeds the RX buffer. The slice borrows the
+    /// device, so it can't outlive the next yield overwriting it.
+    pub fn rx_slice(&self, len: usize) -> Result<&[u8], CmioError> {
+        if len > self.rx_length {
+            return Err(CmioError::BufferTooLarge(len, self.rx_length));
+        }
+        if len == 0 {
+            return Ok(&[]);
+        }
+        Ok(unsafe { std::slice::from_raw_parts(self.rx_buffer as *const u8, len) })
+    }
+    
+    /// Split the device into a half that yields and a half that takes the responses, for
+    /// a producer thread staging the next batch while a consumer parses the last response
+    pub fn split(self) -> (CmioTx, CmioRx) {
+        let tx_length = self.tx_length;
+        split_device(Box::new(self), tx_length)
+    }
+    
+    /// Move the device to a thread of its own, returning a handle any number of threads
+    /// can share to yield through it
+    pub fn into_handle(self) -> Result<CmioHandle, CmioError> {
+        let tx_length = self.tx_length;
+        spawn_handle(Box::new(self), tx_length)
+    }
+    
+    /// Buffer sizes and device information of the open device
+    pub fn capabilities(&self) -> CmioCapabilities {
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        let device_number = (self.fd >= 0 && unsafe { libc::fstat(self.fd, &mut stat) } == 0)
+            .then(|| (libc::major(stat.st_rdev), libc::minor(stat.st_rdev)));
+        CmioCapabilities {
+            tx_length: self.tx_length,
+            rx_length: self.rx_length,
+            device: self.path.clone(),
+            device_number,
+        }
+    }
+    
+    /// Unmap the buffers and close the device, leaving nothing to release again
+    fn release(&mut self) {
+        if self.fd < 0 {
+            return;
+        }
         unsafe {
-            ptr::copy_nonoverlapping(
-                self.rx_buffer as *const u8,
-                rx_data.as_mut_ptr(),
-                rx_length,
-            );
+            munmap(self.tx_buffer, self.tx_length);
+            munmap(self.rx_buffer, self.rx_length);
+            libc::close(self.fd);
         }
+        status::device_closed();
+        self.fd = -1;
+        self.tx_buffer = ptr::null_mut();
+        self.rx_buffer = ptr::null_mut();
+        self.tx_length = 0;
+        self.rx_length = 0;
+    }
+}
+
+// The buffers are mappings of the process and the device is only used through &mut self,
+// so a Cmio can be moved to whichever thread does the yielding
+unsafe impl Send for Cmio {}
+
+// The device's fd, to register with poll, epoll or mio next to the other fds of a loop,
+// -1 while it is closed
+impl AsRawFd for Cmio {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
 
-        Ok((rx_data, yield_data.reason))
+impl Drop for Cmio {
+    fn drop(&mut self) {
+        self.release();
     }
+} 
 
+// Stand-in for the CMIO device that loops every yield back, answering it with the
+// buffer and reason it was given, so the protocol can be exercised without a machine
+pub struct MockCmio {
+    tx_length: usize,
+    stats: CmioStats,
+}
+
+impl MockCmio {
+    pub fn new(tx_length: usize) -> Self {
+        Self { tx_length, stats: CmioStats::default() }
+    }
+    
+    /// Yield like Cmio::yield_with_retry, receiving back the data sent
+    pub fn yield_with_retry(
+        &mut self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+    ) -> Result<(Vec<u8>, u16), CmioError> {
+        let mut rx_data = Vec::new();
+        let reason = self.yield_with_retry_into(dev, cmd, reason, tx_data, &mut rx_data)?;
+        Ok((rx_data, reason))
+    }
+    
+    /// Yield like Cmio::yield_with_retry_into, receiving back the data sent
+    pub fn yield_with_retry_into(
+        &mut self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+        rx_data: &mut Vec<u8>,
+    ) -> Result<u16, CmioError> {
+        if tx_data.len() > self.tx_length {
+            self.stats.errors += 1;
+            return Err(CmioError::BufferTooLarge(tx_data.len(), self.tx_length));
+        }
+        dump::record(Direction::Tx, dev as u8, cmd as u8, reason.code(), tx_data);
+        self.stats.yields += 1;
+        self.stats.tx_bytes += tx_data.len() as u64;
+        self.stats.rx_bytes += tx_data.len() as u64;
+        rx_data.clear();
+        rx_data.extend_from_slice(tx_data);
+        dump::record(Direction::Rx, dev as u8, cmd as u8, reason.code(), rx_data);
+        Ok(reason.code())
+    }
+    
     /// Get the maximum size of the TX buffer
     pub fn get_tx_length(&self) -> usize {
         self.tx_length
     }
+    
+    /// Get the maximum size of the RX buffer, the same as the TX buffer
+    pub fn get_rx_length(&self) -> usize {
+        self.tx_length
+    }
+    
+    /// Number of yields made so far
+    pub fn yields(&self) -> u64 {
+        self.stats.yields
+    }
+    
+    /// Yield counters like Cmio::stats
+    pub fn stats(&self) -> CmioStats {
+        self.stats
+    }
+    
+    /// Split the device into halves like Cmio::split
+    pub fn split(self) -> (CmioTx, CmioRx) {
+        let tx_length = self.tx_length;
+        split_device(Box::new(self), tx_length)
+    }
+    
+    /// Move the device to a thread of its own like Cmio::into_handle
+    pub fn into_handle(self) -> Result<CmioHandle, CmioError> {
+        let tx_length = self.tx_length;
+        spawn_handle(Box::new(self), tx_length)
+    }
 }
 
-impl Drop for Cmio {
+// A device that can be yielded through, the real one or the mock
+pub(crate) trait Exchange: Send {
+    fn exchange(&mut self, dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError>;
+    fn tx_length(&self) -> usize;
+    
+    /// Exchange a yield like exchange, but without retrying it after transient errors,
+    /// for yields the host must not see twice
+    fn exchange_once(&mut self, dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        self.exchange(dev, cmd, reason, tx_data, rx_data)
+    }
+}
+
+impl Exchange for Cmio {
+    fn exchange(&mut self, dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        self.yield_with_retry_into(dev, cmd, reason, tx_data, rx_data)
+    }
+    
+    fn exchange_once(&mut self, dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        self.yield_with_buffer_into(dev, cmd, reason, tx_data, rx_data)
+    }
+    
+    fn tx_length(&self) -> usize {
+        self.tx_length
+    }
+}
+
+impl Exchange for MockCmio {
+    fn exchange(&mut self, dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        self.yield_with_retry_into(dev, cmd, reason, tx_data, rx_data)
+    }
+    
+    fn tx_length(&self) -> usize {
+        self.tx_length
+    }
+}
+
+// State shared by the halves of a split device
+struct SplitState {
+    device: Box<dyn Exchange>,
+    // Response of the last yield until the RX half takes it, and its reason
+    response: Option<(Vec<u8>, u16)>,
+    // Buffer handed back by the RX half to receive the next response into
+    spare: Vec<u8>,
+    tx_closed: bool,
+    rx_closed: bool,
+}
+
+// Structure coordinating the halves, which wait on the condition for each other
+struct Split {
+    state: Mutex<SplitState>,
+    changed: Condvar,
+    tx_length: usize,
+}
+
+impl Split {
+    fn lock(&self) -> MutexGuard<'_, SplitState> {
+        self.state.lock().unwrap()
+    }
+}
+
+// Share a device between a new pair of halves
+pub(crate) fn split_device(device: Box<dyn Exchange>, tx_length: usize) -> (CmioTx, CmioRx) {
+    let split = Arc::new(Split {
+        state: Mutex::new(SplitState { device, response: None, spare: Vec::new(), tx_closed: false, rx_closed: false }),
+        changed: Condvar::new(),
+        tx_length,
+    });
+    (CmioTx { split: Arc::clone(&split) }, CmioRx { split })
+}
+
+// Half of a split device making the yields
+//
+// A yield overwrites the RX buffer, so it waits until the RX half has taken the previous
+// response. The response is copied out of the buffer, leaving the RX half free to parse
+// it while the next batch is staged and yielded.
+pub struct CmioTx {
+    split: Arc<Split>,
+}
+
+impl CmioTx {
+    /// Yield the data like Cmio::yield_with_retry, once the RX half has taken the
+    /// previous response
+    /// 
+    /// Fails with ThreadStopped if the RX half was dropped with a response pending.
+    pub fn send(&mut self, dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8]) -> Result<(), CmioError> {
+        let mut state = self.split.lock();
+        while state.response.is_some() {
+            if state.rx_closed {
+                return Err(CmioError::ThreadStopped("CMIO receiving"));
+            }
+            state = self.split.changed.wait(state).unwrap();
+        }
+        
+        let mut rx_data = std::mem::take(&mut state.spare);
+        let reason = state.device.exchange(dev, cmd, reason, tx_data, &mut rx_data)?;
+        state.response = Some((rx_data, reason));
+        self.split.changed.notify_all();
+        Ok(())
+    }
+    
+    /// Get the maximum size of the TX buffer
+    pub fn get_tx_length(&self) -> usize {
+        self.split.tx_length
+    }
+}
+
+impl Drop for CmioTx {
     fn drop(&mut self) {
+        self.split.lock().tx_closed = true;
+        self.split.changed.notify_all();
+    }
+}
+
+// Half of a split device taking the responses of the yields made by the TX half
+pub struct CmioRx {
+    split: Arc<Split>,
+}
+
+impl CmioRx {
+    /// Wait for the response of the next yield, swapping it into `rx_data` and returning
+    /// its raw reason code
+    /// 
+    /// The vector given in is kept to receive a later response, so swapping the same
+    /// two buffers back and forth doesn't allocate. Fails with ThreadStopped once the
+    /// TX half was dropped and no response is left.
+    pub fn recv(&mut self, rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        let mut state = self.split.lock();
+        loop {
+            if let Some((mut response, reason)) = state.response.take() {
+                std::mem::swap(rx_data, &mut response);
+                state.spare = response;
+                self.split.changed.notify_all();
+                return Ok(reason);
+            }
+            if state.tx_closed {
+                return Err(CmioError::ThreadStopped("CMIO sending"));
+            }
+            state = self.split.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for CmioRx {
+    fn drop(&mut self) {
+        self.split.lock().rx_closed = true;
+        self.split.changed.notify_all();
+    }
+}
+
+// Yield queued for the device thread of a handle, tagged with its reason code
+//
+// The buffer carries the data to send and comes back holding the response, so the
+// caller's buffer and the device thread's spare one are swapped rather than allocated.
+struct YieldRequest {
+    dev: YieldDevice,
+    cmd: YieldCommand,
+    reason: YieldReason,
+    buffer: Vec<u8>,
+    reply: mpsc::SyncSender<(Vec<u8>, Result<u16, CmioError>)>,
+}
+
+// Start the device thread of a handle, which yields the queued requests in order
+pub(crate) fn spawn_handle(mut device: Box<dyn Exchange>, tx_length: usize) -> Result<CmioHandle, CmioError> {
+    let (requests, queue) = mpsc::channel::<YieldRequest>();
+    let spawned = thread::Builder::new().name("cmio".to_string()).spawn(move || {
+        let mut spare = Vec::new();
+        for mut request in queue {
+            let result = device.exchange(request.dev, request.cmd, request.reason, &request.buffer, &mut spare);
+            std::mem::swap(&mut request.buffer, &mut spare);
+            let _ = request.reply.send((request.buffer, result));
+        }
+    });
+    spawned.map_err(|e| CmioError::io("start the CMIO thread", e))?;
+    Ok(CmioHandle { requests, tx_length })
+}
+
+// Shareable handle to a device owned by a thread of its own
+//
+// Yields from any number of threads are queued and made one at a time, and each
+// response goes back to the thread that asked for the yield. The device thread ends
+// once the last handle is dropped, closing the device.
+#[derive(Clone)]
+pub struct CmioHandle {
+    requests: mpsc::Sender<YieldRequest>,
+    tx_length: usize,
+}
+
+impl CmioHandle {
+    /// Yield with a buffer like Cmio::yield_with_retry, once the yields queued before
+    /// are done
+    pub fn yield_with_retry(
+        &self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+    ) -> Result<(Vec<u8>, u16), CmioError> {
+        let mut rx_data = Vec::new();
+        let reason = self.yield_with_retry_into(dev, cmd, reason, tx_data, &mut rx_data)?;
+        Ok((rx_data, reason))
+    }
+    
+    /// Yield with a buffer like Cmio::yield_with_retry_into, once the yields queued
+    /// before are done
+    /// 
+    /// Fails with ThreadStopped if the device thread is gone.
+    pub fn yield_with_retry_into(
+        &self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+        rx_data: &mut Vec<u8>,
+    ) -> Result<u16, CmioError> {
+        let mut buffer = std::mem::take(rx_data);
+        buffer.clear();
+        buffer.extend_from_slice(tx_data);
+        
+        let (reply, response) = mpsc::sync_channel(1);
+        self.requests.send(YieldRequest { dev, cmd, reason, buffer, reply })
+            .map_err(|_| CmioError::ThreadStopped("CMIO"))?;
+        let (buffer, result) = response.recv().map_err(|_| CmioError::ThreadStopped("CMIO"))?;
+        *rx_data = buffer;
+        if result.is_err() {
+            rx_data.clear();
+        }
+        result
+    }
+    
+    /// Get the maximum size of the TX buffer
+    pub fn get_tx_length(&self) -> usize {
+        self.tx_length
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_yield_packing() {
+        let request = CmioYield { dev: 0x02, cmd: 0x01, reason: 0x0042, data: 1500 };
+        assert_eq!(request.pack(), 0x0201_0042_0000_05dc);
+        assert_eq!(CmioYield::unpack(0x0201_0042_0000_05dc), request);
+
+        // Every device and command value, with reasons and data at their edges, comes back
+        // unchanged without spilling into the neighbouring fields
+        for dev in 0..=u8::MAX {
+            for cmd in 0..=u8::MAX {
+                for (reason, data) in [(0, 0), (u16::MAX, u32::MAX), (0x8043, 1), (1, 0x8000_0000)] {
+                    let request = CmioYield { dev, cmd, reason, data };
+                    assert_eq!(CmioYield::unpack(request.pack()), request);
+                }
+            }
+        }
+
+        // Every bit of a packed request belongs to exactly one field
+        for bit in 0..64 {
+            assert_eq!(CmioYield::unpack(1 << bit).pack(), 1 << bit);
+        }
+
+        // The response length the host sets is only trusted within the RX buffer
+        assert_eq!(request.response_length(1500).unwrap(), 1500);
+        assert!(matches!(request.response_length(1499), Err(CmioError::BufferTooLarge(1500, 1499))));
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(CmioError::YieldFailed(libc::EINTR).is_transient());
+        assert!(CmioError::SetupError(libc::EBADF).is_transient());
+        assert!(!CmioError::YieldFailed(libc::EINVAL).is_transient());
+        assert!(!CmioError::BufferTooLarge(2, 1).is_transient());
+        assert!(!CmioError::io("read", std::io::Error::from_raw_os_error(libc::EINTR)).is_transient());
+    }
+
+    #[test]
+    fn test_error_context() {
+        let error = CmioError::io("write frame to tap0", std::io::Error::from_raw_os_error(libc::EIO));
+        assert_eq!(error.errno(), Some(libc::EIO));
+        assert!(error.to_string().starts_with("Failed to write frame to tap0: "));
+        assert_eq!(CmioError::ProtocolError("truncated batch".to_string()).errno(), None);
+        assert_eq!(CmioError::SocketIdInUse(7).errno(), Some(libc::EEXIST));
+        assert!(CmioError::MapFailed("RX", libc::ENOMEM).to_string().starts_with("Failed to map the CMIO RX buffer"));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy { max_retries: 2, retry_eagain: false };
+        let mut calls = 0;
+        assert_eq!(policy.run(|| { calls += 1; if calls < 3 { Err(libc::EINTR) } else { Ok(()) } }), Ok(()));
+        assert_eq!(calls, 3);
+
+        // Retries run out, and EAGAIN is only retried when asked for
+        calls = 0;
+        assert_eq!(policy.run(|| { calls += 1; Err(libc::EINTR) }), Err(libc::EINTR));
+        assert_eq!(calls, 3);
+        calls = 0;
+        assert_eq!(policy.run(|| { calls += 1; Err(libc::EAGAIN) }), Err(libc::EAGAIN));
+        assert_eq!(calls, 1);
+        assert!(RetryPolicy { retry_eagain: true, ..policy }.retries(libc::EAGAIN));
+    }
+
+    #[test]
+    fn test_poll_readable() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [read_fd, write_fd] = fds;
+
+        // Nothing to read until the other end writes
+        let started = Instant::now();
+        assert_eq!(poll_readable(read_fd, Some(Duration::from_millis(20))), Ok(false));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(unsafe { libc::write(write_fd, b"x".as_ptr() as *const c_void, 1) }, 1);
+        assert_eq!(poll_readable(read_fd, None), Ok(true));
+        assert_eq!(poll_readable(read_fd, Some(Duration::ZERO)), Ok(true));
+
         unsafe {
-            munmap(self.tx_buffer, self.tx_length);
-            munmap(self.rx_buffer, self.rx_length);
-            libc::close(self.fd);
+            libc::close(read_fd);
+            libc::close(write_fd);
         }
+        assert_eq!(poll_readable(read_fd, Some(Duration::ZERO)), Err(libc::EBADF));
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_mock_loopback() {
+        let mut cmio = MockCmio::new(4);
+        assert_eq!(cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::TapRxTx, &[1, 2, 3]).unwrap(), (vec![1, 2, 3], 0x42));
+        assert!(matches!(cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::TapRxTx, &[0; 5]), Err(CmioError::BufferTooLarge(5, 4))));
+        assert_eq!(cmio.yields(), 1);
+
+        // The response buffer is reused rather than replaced
+        let mut rx_data = Vec::with_capacity(16);
+        let pointer = rx_data.as_ptr();
+        for tx_data in [&[1, 2, 3][..], &[4]] {
+            assert_eq!(cmio.yield_with_retry_into(YieldDevice::Yield, YieldCommand::Manual, YieldReason::Control, tx_data, &mut rx_data).unwrap(), 0x44);
+            assert_eq!(rx_data, tx_data);
+        }
+        assert_eq!(rx_data.as_ptr(), pointer);
+        assert_eq!(cmio.stats(), CmioStats { yields: 3, tx_bytes: 7, rx_bytes: 7, errors: 1, reinits: 0 });
+    }
+
+    #[test]
+    fn test_split() {
+        let (mut tx, mut rx) = MockCmio::new(64).split();
+        let consumer = std::thread::spawn(move || {
+            let mut received = Vec::new();
+            let mut rx_data = Vec::new();
+            while let Ok(reason) = rx.recv(&mut rx_data) {
+                assert_eq!(reason, 0x42);
+                received.push(rx_data.clone());
+            }
+            received
+        });
+        for index in 0..10u8 {
+            tx.send(YieldDevice::Yield, YieldCommand::Manual, YieldReason::TapRxTx, &[index; 3]).unwrap();
+        }
+        drop(tx);
+
+        // Every response arrives in order, the last one after the TX half is gone
+        let received = consumer.join().unwrap();
+        assert_eq!(received, (0..10u8).map(|index| vec![index; 3]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_handle() {
+        let handle = MockCmio::new(64).into_handle().unwrap();
+        let callers: Vec<_> = (0..4u8).map(|caller| {
+            let handle = handle.clone();
+            std::thread::spawn(move || {
+                let mut rx_data = Vec::new();
+                for round in 0..20u8 {
+                    let tx_data = [caller, round];
+                    assert_eq!(handle.yield_with_retry_into(YieldDevice::Yield, YieldCommand::Manual, YieldReason::Other(caller as u16), &tx_data, &mut rx_data).unwrap(), caller as u16);
+                    assert_eq!(rx_data, tx_data);
+                }
+            })
+        }).collect();
+        for caller in callers {
+            caller.join().unwrap();
+        }
+        assert!(matches!(handle.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::TapRxTx, &[0; 65]), Err(CmioError::BufferTooLarge(65, 64))));
+    }
+
+    #[test]
+    fn test_open_error_names_device() {
+        let error = Cmio::open("/nonexistent/cmio").err().unwrap();
+        assert!(matches!(error, CmioError::OpenError(..)));
+        assert!(error.to_string().contains("/nonexistent/cmio"));
+    }
+
+    #[test]
+    fn test_builder() {
+        let builder = Cmio::builder().device("/nonexistent/cmio").map_rx_writable(true).populate(true).locked(true).cloexec(true);
+        assert_eq!(builder.options, MapOptions { rx_writable: true, populate: true, locked: true, cloexec: true });
+        assert_eq!(Cmio::builder().options, MapOptions::default());
+        let error = builder.build().err().unwrap();
+        assert!(error.to_string().contains("/nonexistent/cmio"));
+    }
+}
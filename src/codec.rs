@@ -0,0 +1,100 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::cmio::{Cmio, CmioError};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+
+// Request sent to the host in a CBOR envelope, naming the method to call
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request<T> {
+    // Number of the request, echoed in its response
+    pub id: u32,
+    pub method: String,
+    pub params: T,
+}
+
+// Response of the host to a request, with its result or an error message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response<T> {
+    pub id: u32,
+    pub result: Result<T, String>,
+}
+
+// Encode a value as CBOR, failing with BufferTooLarge if it exceeds max_len bytes
+pub fn encode<T: Serialize>(value: &T, max_len: usize) -> Result<Vec<u8>, CmioError> {
+    let mut buffer = Vec::new();
+    ciborium::into_writer(value, &mut buffer)
+        .map_err(|e| CmioError::InvalidArgument(format!("can't encode CBOR: {}", e)))?;
+    if buffer.len() > max_len {
+        return Err(CmioError::BufferTooLarge(buffer.len(), max_len));
+    }
+    Ok(buffer)
+}
+
+// Decode a CBOR value, failing with ProtocolError for malformed data or data of
+// another shape
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, CmioError> {
+    ciborium::from_reader(data).map_err(|e| CmioError::ProtocolError(format!("invalid CBOR: {}", e)))
+}
+
+// Structure calling methods on the host with CBOR envelopes yielded on a reason code
+//
+// Each call yields a request with the next ID and expects the response to that request
+// in return, a self-describing alternative to a binary protocol of its own for a new
+// reason code.
+pub struct CborClient {
+    reason: YieldReason,
+    next_id: u32,
+    // Response of the last call, reused so calls don't allocate for it
+    rx_buffer: Vec<u8>,
+}
+
+impl CborClient {
+    pub fn new(reason: YieldReason) -> Self {
+        Self { reason, next_id: 1, rx_buffer: Vec::new() }
+    }
+    
+    /// Call a method on the host, yielding the request with yield_with_buffer
+    /// 
+    /// Fails with ProtocolError for a response that isn't the CBOR envelope of this
+    /// request, and for an error returned by the host, with its message.
+    pub fn call<P: Serialize, R: DeserializeOwned>(&mut self, cmio: &mut Cmio, method: &str, params: P) -> Result<R, CmioError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let request = encode(&Request { id, method: method.to_string(), params }, cmio.get_tx_length())?;
+        cmio.yield_with_buffer_into(YieldDevice::Yield, YieldCommand::Manual, self.reason, &request, &mut self.rx_buffer)?;
+        Self::result(id, method, decode(&self.rx_buffer)?)
+    }
+    
+    /// The result of a response to the request with the given ID
+    fn result<R>(id: u32, method: &str, response: Response<R>) -> Result<R, CmioError> {
+        if response.id != id {
+            return Err(CmioError::ProtocolError(format!("response {} to {} request {}", response.id, method, id)));
+        }
+        response.result.map_err(|message| CmioError::ProtocolError(format!("{} failed on the host: {}", method, message)))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelopes() {
+        // The host decodes the request as sent
+        let request = Request { id: 7, method: "lookup".to_string(), params: ("example.org".to_string(), 443u16) };
+        let data = encode(&request, 64).unwrap();
+        assert_eq!(decode::<Request<(String, u16)>>(&data).unwrap(), request);
+        assert!(matches!(encode(&request, 8), Err(CmioError::BufferTooLarge(_, 8))));
+
+        // Its response gives the result, or fails with the host's message
+        let response: Response<Vec<u8>> = decode(&encode(&Response { id: 7, result: Ok(vec![1u8, 2]) }, 64).unwrap()).unwrap();
+        assert_eq!(CborClient::result(7, "lookup", response).unwrap(), vec![1u8, 2]);
+        let response: Response<Vec<u8>> = Response { id: 7, result: Err("not found".to_string()) };
+        assert!(CborClient::result(7, "lookup", response).unwrap_err().to_string().contains("not found"));
+        assert!(CborClient::result(8, "lookup", Response { id: 7, result: Ok(()) }).is_err());
+
+        // Data of another shape is a protocol error
+        assert!(matches!(decode::<Response<String>>(&data), Err(CmioError::ProtocolError(_))));
+        assert!(matches!(decode::<Request<()>>(&[0xff]), Err(CmioError::ProtocolError(_))));
+    }
+}
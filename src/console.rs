@@ -0,0 +1,348 @@
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use tracing::{info, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+
+// Reason code the console's input and output are exchanged with the host on
+pub const CONSOLE_REASON: YieldReason = YieldReason::Other(0x49);
+
+// Program attached to the console unless another is given
+pub const DEFAULT_CONSOLE_PROGRAM: &str = "/bin/sh";
+
+// Messages from the host
+const CONSOLE_INPUT: u8 = 0x01;
+const CONSOLE_RESIZE: u8 = 0x02;
+
+// Messages to the host
+const CONSOLE_OUTPUT: u8 = 0x81;
+const CONSOLE_EXIT: u8 = 0x83;
+
+// How the program ended, the first byte of an exit message followed by the code (i32,
+// network byte order)
+const EXIT_CODE: u8 = 0x00;
+const EXIT_SIGNAL: u8 = 0x01;
+
+// Size of a message without its payload: 1 (type) + 4 (payload length)
+const MESSAGE_HEADER_SIZE: usize = 5;
+
+// Maximum number of output bytes read from the terminal per poll
+const READ_CHUNK: usize = 16 * 1024;
+
+// Terminal size before the host sends one
+const DEFAULT_ROWS: u16 = 24;
+const DEFAULT_COLUMNS: u16 = 80;
+
+// Serialize a message to the host
+fn encode_message(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MESSAGE_HEADER_SIZE + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+// Payload of an exit message
+fn exit_payload(kind: u8, code: i32) -> Vec<u8> {
+    let mut payload = vec![kind];
+    payload.extend_from_slice(&code.to_be_bytes());
+    payload
+}
+
+// Open a pseudo-terminal, returning its non-blocking master side and its slave side
+fn open_pty() -> io::Result<(File, File)> {
+    let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC | libc::O_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let master = unsafe { File::from_raw_fd(fd) };
+    if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut name = [0 as libc::c_char; 64];
+    let result = unsafe { libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    let slave = OpenOptions::new().read(true).write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(name.to_string_lossy().as_ref())?;
+    Ok((master, slave))
+}
+
+// Set the size of the terminal, which signals SIGWINCH to the program
+fn set_size(master: &File, rows: u16, columns: u16) -> io::Result<()> {
+    let size = libc::winsize { ws_row: rows, ws_col: columns, ws_xpixel: 0, ws_ypixel: 0 };
+    if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// The program attached to the terminal
+struct Session {
+    master: File,
+    child: Child,
+    // Set once the terminal is closed on the program's side
+    hung_up: bool,
+}
+
+// Structure forwarding an interactive console over CMIO, as a subsystem of the
+// multiplexer on CONSOLE_REASON, for hosts without the HTIF console device
+//
+// A program, by default a shell, is attached to a pseudo-terminal as its controlling
+// terminal. Every message is a type and a payload length (u32 BE), followed by the
+// payload. The host sends keystrokes with CONSOLE_INPUT and the terminal size, rows and
+// columns (u16 BE each), with CONSOLE_RESIZE; the terminal's output is sent back with
+// CONSOLE_OUTPUT. When the program ends, CONSOLE_EXIT carries how, like an exec exit
+// message, and the next input starts it again.
+pub struct PtyConsole {
+    program: Vec<String>,
+    session: Option<Session>,
+    // Whether to start the program when none is running; cleared when it exits until input
+    // arrives
+    start: bool,
+    rows: u16,
+    columns: u16,
+    messages: VecDeque<Vec<u8>>,
+    max_message_size: usize,
+}
+
+impl PtyConsole {
+    /// Attach a program and its arguments to the console, starting it on the first yield
+    pub fn new(program: Vec<String>, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        if program.is_empty() {
+            return Err(CmioError::InvalidArgument("no console program given".to_string()));
+        }
+        Ok(Self {
+            program,
+            session: None,
+            start: true,
+            rows: DEFAULT_ROWS,
+            columns: DEFAULT_COLUMNS,
+            messages: VecDeque::new(),
+            max_message_size: cmio_max_buffer_size,
+        })
+    }
+    
+    /// Whether the program is running
+    pub fn running(&self) -> bool {
+        self.session.is_some()
+    }
+    
+    /// Start the program on a new terminal of the current size
+    fn spawn(&mut self) -> io::Result<()> {
+        let (master, slave) = open_pty()?;
+        set_size(&master, self.rows, self.columns)?;
+        let mut command = Command::new(&self.program[0]);
+        command.args(&self.program[1..])
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave))
+            .env("TERM", "xterm");
+        
+        // Make the terminal the controlling one of a new session, so job control and
+        // Ctrl+C work as on a console
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let child = command.spawn()?;
+        info!("Console started {} (pid {})", self.program.join(" "), child.id());
+        self.session = Some(Session { master, child, hung_up: false });
+        Ok(())
+    }
+    
+    /// Start the program if it should run and doesn't, reporting a failure as an exit
+    fn ensure_running(&mut self) {
+        if self.session.is_some() || !self.start {
+            return;
+        }
+        if let Err(e) = self.spawn() {
+            warn!("Failed to start console program {}: {}", self.program[0], e);
+            self.start = false;
+            self.messages.push_back(encode_message(CONSOLE_EXIT, &exit_payload(EXIT_CODE, -1)));
+        }
+    }
+    
+    /// Queue the terminal's output, and the exit of the program once it hung up
+    fn collect_output(&mut self) {
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        let chunk = READ_CHUNK.min(self.max_message_size.saturating_sub(MESSAGE_HEADER_SIZE)).max(1);
+        let mut data = vec![0u8; chunk];
+        if !session.hung_up {
+            match session.master.read(&mut data) {
+                Ok(0) => session.hung_up = true,
+                Ok(n) => self.messages.push_back(encode_message(CONSOLE_OUTPUT, &data[..n])),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => {}
+                // Reads fail with EIO once the program's side is closed
+                Err(_) => session.hung_up = true,
+            }
+        }
+        if session.hung_up {
+            match session.child.try_wait() {
+                Ok(Some(status)) => {
+                    info!("Console program exited: {}", status);
+                    let payload = match (status.code(), status.signal()) {
+                        (Some(code), _) => exit_payload(EXIT_CODE, code),
+                        (None, Some(signal)) => exit_payload(EXIT_SIGNAL, signal),
+                        (None, None) => exit_payload(EXIT_CODE, -1),
+                    };
+                    self.messages.push_back(encode_message(CONSOLE_EXIT, &payload));
+                    self.session = None;
+                    self.start = false;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Failed to wait for the console program: {}", e);
+                    self.session = None;
+                    self.start = false;
+                }
+            }
+        }
+    }
+    
+    /// Type input into the terminal, waiting for it to take all of it
+    fn write_input(&mut self, data: &[u8]) {
+        self.start = true;
+        self.ensure_running();
+        let Some(session) = &mut self.session else {
+            return;
+        };
+        let mut written = 0;
+        while written < data.len() {
+            match session.master.write(&data[written..]) {
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Failed to write console input: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Subsystem for PtyConsole {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        self.ensure_running();
+        if self.messages.is_empty() {
+            self.collect_output();
+        }
+        while let Some(message) = self.messages.front() {
+            if buffer.len() + message.len() > max_len {
+                break;
+            }
+            buffer.extend_from_slice(message);
+            self.messages.pop_front();
+        }
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            // The rest of a batch can't be framed past a truncated message
+            let Some(header) = data.get(offset..offset + MESSAGE_HEADER_SIZE) else {
+                warn!("Dropping {} bytes of a truncated console message at offset {}", data.len() - offset, offset);
+                break;
+            };
+            let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+            let Some(payload) = data.get(offset + MESSAGE_HEADER_SIZE..offset + MESSAGE_HEADER_SIZE + length) else {
+                warn!("Dropping a console message of {} bytes truncated at offset {}", length, offset);
+                break;
+            };
+            match header[0] {
+                CONSOLE_INPUT => self.write_input(payload),
+                CONSOLE_RESIZE if payload.len() >= 4 => {
+                    self.rows = u16::from_be_bytes([payload[0], payload[1]]);
+                    self.columns = u16::from_be_bytes([payload[2], payload[3]]);
+                    if let Some(session) = &self.session {
+                        if let Err(e) = set_size(&session.master, self.rows, self.columns) {
+                            warn!("Failed to resize the console: {}", e);
+                        }
+                    }
+                }
+                kind => warn!("Invalid console message type {:#04x} of {} bytes", kind, length),
+            }
+            offset += MESSAGE_HEADER_SIZE + length;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PtyConsole {
+    fn drop(&mut self) {
+        if let Some(session) = &mut self.session {
+            let _ = session.child.kill();
+            let _ = session.child.wait();
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_console() {
+        let program = vec!["sh".to_string(), "-c".to_string(), "stty size; read line; echo got $line; exit 5".to_string()];
+        let mut console = PtyConsole::new(program, 4096).unwrap();
+        let mut batch = encode_message(CONSOLE_RESIZE, &[0, 30, 0, 100]);
+        batch.extend(encode_message(CONSOLE_INPUT, b"hello\n"));
+        console.handle_rx(&batch, CONSOLE_REASON.code()).unwrap();
+        assert!(console.running());
+
+        // Output arrives as the program writes it, then how it ended
+        let mut output = Vec::new();
+        let mut exit = None;
+        let started = Instant::now();
+        while exit.is_none() {
+            assert!(started.elapsed() < Duration::from_secs(10), "console program didn't exit");
+            let mut buffer = Vec::new();
+            console.poll_tx(&mut buffer, 4096).unwrap();
+            let mut rest = buffer.as_slice();
+            while !rest.is_empty() {
+                let length = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+                let payload = &rest[MESSAGE_HEADER_SIZE..MESSAGE_HEADER_SIZE + length];
+                match rest[0] {
+                    CONSOLE_OUTPUT => output.extend_from_slice(payload),
+                    CONSOLE_EXIT => exit = Some(payload.to_vec()),
+                    kind => panic!("unexpected message type {:#04x}", kind),
+                }
+                rest = &rest[MESSAGE_HEADER_SIZE + length..];
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("30 100"), "{}", output);
+        assert!(output.contains("got hello"), "{}", output);
+        assert_eq!(exit.unwrap(), exit_payload(EXIT_CODE, 5));
+
+        // The program waits for input before starting again
+        assert!(!console.running());
+        console.poll_tx(&mut Vec::new(), 4096).unwrap();
+        assert!(!console.running());
+        
+        // Input cut short is dropped rather than starting it
+        console.handle_rx(&batch[..12], CONSOLE_REASON.code()).unwrap();
+        assert!(!console.running());
+    }
+}
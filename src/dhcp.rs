@@ -0,0 +1,248 @@
+use std::ffi::OsString;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use nix::sys::socket::{setsockopt, sockopt};
+
+// Ports and fixed fields of BOOTP/DHCP messages (RFC 2131)
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// Ask the server to broadcast replies, since the interface has no address to receive unicast on
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Size of the fixed part of a message, before the magic cookie and options
+const FIXED_SIZE: usize = 236;
+
+// Option codes (RFC 2132)
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+// DHCP message types
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+// How long to wait for each reply, and how often to send a request before giving up
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const ATTEMPTS: usize = 5;
+
+// Prefix length assumed when the server sends no subnet mask
+const DEFAULT_PREFIX_LEN: u8 = 24;
+
+// Structure describing the configuration handed out by a DHCP server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    // Lease duration in seconds
+    pub lease_time: Option<u32>,
+    pub server: Ipv4Addr,
+}
+
+// Build a DISCOVER, or a REQUEST for an offered address from the server that offered it
+fn build_message(msg_type: u8, xid: u32, mac: &[u8; 6], requested: Option<(Ipv4Addr, Ipv4Addr)>) -> Vec<u8> {
+    let mut message = vec![0u8; FIXED_SIZE];
+    message[0] = BOOTREQUEST;
+    message[1] = HTYPE_ETHERNET;
+    message[2] = mac.len() as u8;
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(mac); // Client hardware address
+    message.extend_from_slice(&MAGIC_COOKIE);
+    
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, msg_type]);
+    if let Some((address, server)) = requested {
+        message.extend_from_slice(&[OPTION_REQUESTED_IP, 4]);
+        message.extend_from_slice(&address.octets());
+        message.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+        message.extend_from_slice(&server.octets());
+    }
+    message.extend_from_slice(&[OPTION_PARAMETER_LIST, 4, OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS, OPTION_LEASE_TIME]);
+    message.push(OPTION_END);
+    message
+}
+
+// Parse a server reply to our transaction, returning its message type and the lease it describes
+fn parse_reply(data: &[u8], xid: u32) -> Option<(u8, DhcpLease)> {
+    if data.len() < FIXED_SIZE + MAGIC_COOKIE.len() || data[0] != BOOTREPLY {
+        return None;
+    }
+    if data[4..8] != xid.to_be_bytes() || data[FIXED_SIZE..FIXED_SIZE + 4] != MAGIC_COOKIE {
+        return None;
+    }
+    
+    let mut msg_type = None;
+    let mut lease = DhcpLease {
+        address: Ipv4Addr::new(data[16], data[17], data[18], data[19]), // yiaddr
+        prefix_len: DEFAULT_PREFIX_LEN,
+        gateway: None,
+        dns: Vec::new(),
+        lease_time: None,
+        server: Ipv4Addr::new(data[20], data[21], data[22], data[23]), // siaddr, unless the server ID option says otherwise
+    };
+    
+    // Walk the options as code, length and value
+    let mut offset = FIXED_SIZE + MAGIC_COOKIE.len();
+    while offset < data.len() {
+        let code = data[offset];
+        if code == OPTION_END {
+            break;
+        }
+        if code == OPTION_PAD {
+            offset += 1;
+            continue;
+        }
+        
+        let len = *data.get(offset + 1)? as usize;
+        let value = data.get(offset + 2..offset + 2 + len)?;
+        let addresses = value.chunks_exact(4).map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]));
+        match code {
+            OPTION_MESSAGE_TYPE if len == 1 => msg_type = Some(value[0]),
+            OPTION_SUBNET_MASK if len == 4 => lease.prefix_len = u32::from_be_bytes([value[0], value[1], value[2], value[3]]).count_ones() as u8,
+            OPTION_ROUTER => lease.gateway = addresses.clone().next(),
+            OPTION_DNS => lease.dns = addresses.collect(),
+            OPTION_LEASE_TIME if len == 4 => lease.lease_time = Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]])),
+            OPTION_SERVER_ID if len == 4 => lease.server = Ipv4Addr::new(value[0], value[1], value[2], value[3]),
+            _ => {}
+        }
+        offset += 2 + len;
+    }
+    
+    Some((msg_type?, lease))
+}
+
+// Broadcast a message and wait for a reply of one of the expected types, retrying on timeout
+fn exchange(socket: &UdpSocket, message: &[u8], xid: u32, expected: &[u8]) -> io::Result<(u8, DhcpLease)> {
+    let server = SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_SERVER_PORT);
+    let mut buffer = [0u8; 1500];
+    
+    for _ in 0..ATTEMPTS {
+        socket.send_to(message, server)?;
+        
+        // Skip unrelated traffic until the timeout expires
+        loop {
+            match socket.recv(&mut buffer) {
+                Ok(n) => {
+                    if let Some((msg_type, lease)) = parse_reply(&buffer[..n], xid) {
+                        if expected.contains(&msg_type) {
+                            return Ok((msg_type, lease));
+                        }
+                    }
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    
+    Err(io::Error::from_raw_os_error(libc::ETIMEDOUT))
+}
+
+/// Obtain a lease on the interface with a DISCOVER/OFFER/REQUEST/ACK exchange
+///
+/// The interface must be up, and its traffic must reach the host, which means the
+/// network interface loop has to be running while this waits for replies.
+///
+/// # Arguments
+///
+/// * `interface` - Name of the interface to send on
+/// * `mac` - Hardware address of the interface, identifying the client to the server
+pub fn acquire(interface: &str, mac: &[u8; 6]) -> io::Result<DhcpLease> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DHCP_CLIENT_PORT))?;
+    socket.set_broadcast(true)?;
+    socket.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    setsockopt(socket.as_raw_fd(), sockopt::BindToDevice, &OsString::from(interface))
+        .map_err(|e| io::Error::from_raw_os_error(e as i32))?;
+    
+    // Any transaction ID works, it only has to differ between clients
+    let xid = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.subsec_nanos()).unwrap_or(0) ^ std::process::id();
+    
+    let (_, offer) = exchange(&socket, &build_message(DHCPDISCOVER, xid, mac, None), xid, &[DHCPOFFER])?;
+    let request = build_message(DHCPREQUEST, xid, mac, Some((offer.address, offer.server)));
+    match exchange(&socket, &request, xid, &[DHCPACK, DHCPNAK])? {
+        (DHCPACK, lease) => Ok(lease),
+        _ => Err(io::Error::from_raw_os_error(libc::ECONNREFUSED)), // The server withdrew its offer
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+
+    // Turn a request into the server's reply with the given type and options
+    fn reply(request: &[u8], msg_type: u8, options: &[u8]) -> Vec<u8> {
+        let mut reply = request[..FIXED_SIZE].to_vec();
+        reply[0] = BOOTREPLY;
+        reply[16..20].copy_from_slice(&[10, 0, 2, 15]); // yiaddr
+        reply.extend_from_slice(&MAGIC_COOKIE);
+        reply.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, msg_type]);
+        reply.extend_from_slice(options);
+        reply.push(OPTION_END);
+        reply
+    }
+
+    #[test]
+    fn test_build_message() {
+        let discover = build_message(DHCPDISCOVER, 0x12345678, &MAC, None);
+        assert_eq!(discover[0], BOOTREQUEST);
+        assert_eq!(&discover[4..8], &[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(&discover[28..34], &MAC);
+        assert_eq!(&discover[FIXED_SIZE..FIXED_SIZE + 4], &MAGIC_COOKIE);
+        assert_eq!(&discover[FIXED_SIZE + 4..FIXED_SIZE + 7], &[OPTION_MESSAGE_TYPE, 1, DHCPDISCOVER]);
+
+        let request = build_message(DHCPREQUEST, 1, &MAC, Some((Ipv4Addr::new(10, 0, 2, 15), Ipv4Addr::new(10, 0, 2, 2))));
+        let options = &request[FIXED_SIZE + 7..];
+        assert_eq!(&options[..6], &[OPTION_REQUESTED_IP, 4, 10, 0, 2, 15]);
+        assert_eq!(&options[6..12], &[OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+        assert_eq!(request.last(), Some(&OPTION_END));
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let request = build_message(DHCPDISCOVER, 7, &MAC, None);
+        let offer = reply(&request, DHCPOFFER, &[
+            OPTION_PAD,
+            OPTION_SUBNET_MASK, 4, 255, 255, 255, 0,
+            OPTION_ROUTER, 4, 10, 0, 2, 2,
+            OPTION_DNS, 8, 10, 0, 2, 3, 1, 1, 1, 1,
+            OPTION_LEASE_TIME, 4, 0, 0, 0x0E, 0x10,
+            OPTION_SERVER_ID, 4, 10, 0, 2, 2,
+        ]);
+
+        let (msg_type, lease) = parse_reply(&offer, 7).unwrap();
+        assert_eq!(msg_type, DHCPOFFER);
+        assert_eq!(lease, DhcpLease {
+            address: Ipv4Addr::new(10, 0, 2, 15),
+            prefix_len: 24,
+            gateway: Some(Ipv4Addr::new(10, 0, 2, 2)),
+            dns: vec![Ipv4Addr::new(10, 0, 2, 3), Ipv4Addr::new(1, 1, 1, 1)],
+            lease_time: Some(3600),
+            server: Ipv4Addr::new(10, 0, 2, 2),
+        });
+
+        // Replies to other transactions, requests and truncated options are ignored
+        assert!(parse_reply(&offer, 8).is_none());
+        assert!(parse_reply(&request, 7).is_none());
+        let mut truncated = reply(&request, DHCPOFFER, &[OPTION_ROUTER, 4, 10]);
+        truncated.pop();
+        assert!(parse_reply(&truncated, 7).is_none());
+    }
+}
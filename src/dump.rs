@@ -0,0 +1,114 @@
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::recording;
+use crate::shutdown;
+
+// Bytes shown on each line of a hexdump
+const BYTES_PER_LINE: usize = 16;
+
+// Set once a dump is enabled, so yields don't take the lock while it isn't
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Where the buffers of every yield in the process are dumped
+static TARGET: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+// Direction of a dumped buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    // Sent to the host
+    Tx,
+    // Received from the host
+    Rx,
+}
+
+/// Dump the TX and RX buffers of every yield in the process from now on, to stderr
+pub fn to_stderr() {
+    enable(Box::new(io::stderr()));
+}
+
+/// Dump like to_stderr to a file, appending to it if it exists
+pub fn to_file(path: impl AsRef<Path>) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    enable(Box::new(file));
+    Ok(())
+}
+
+fn enable(target: Box<dyn Write + Send>) {
+    *TARGET.lock().unwrap() = Some(target);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether yields are being dumped
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Dump a buffer sent or received on a reason code, with a timestamp, if a dump is
+/// enabled, and append it to the recording if one is started
+/// 
+/// Every yield passes its buffers here, so a non-empty one also restarts the idle exit
+/// timeout. The dump is best effort: a target that fails to write doesn't fail the yield.
+pub(crate) fn record(direction: Direction, dev: u8, cmd: u8, reason: u16, data: &[u8]) {
+    if !data.is_empty() {
+        shutdown::note_activity();
+    }
+    recording::append(direction, dev, cmd, reason, data);
+    if !enabled() {
+        return;
+    }
+    let entry = format_entry(unix_micros(), direction, dev, cmd, reason, data);
+    if let Some(target) = TARGET.lock().unwrap().as_mut() {
+        let _ = target.write_all(entry.as_bytes()).and_then(|()| target.flush());
+    }
+}
+
+// Microseconds since the epoch
+fn unix_micros() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_micros()).unwrap_or(0)
+}
+
+// Format a header line with the timestamp in seconds, the direction, device, command,
+// reason code and length, followed by the hexdump of the data
+fn format_entry(micros: u128, direction: Direction, dev: u8, cmd: u8, reason: u16, data: &[u8]) -> String {
+    let direction = match direction {
+        Direction::Tx => "TX",
+        Direction::Rx => "RX",
+    };
+    let mut entry = format!("{}.{:06} {} dev {:#04x} cmd {:#04x} reason {:#06x} {} bytes\n",
+        micros / 1_000_000, micros % 1_000_000, direction, dev, cmd, reason, data.len());
+    for (line, chunk) in data.chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(entry, "  {:08x} ", line * BYTES_PER_LINE);
+        for index in 0..BYTES_PER_LINE {
+            if index == BYTES_PER_LINE / 2 {
+                entry.push(' ');
+            }
+            match chunk.get(index) {
+                Some(byte) => { let _ = write!(entry, " {:02x}", byte); }
+                None => entry.push_str("   "),
+            }
+        }
+        entry.push_str("  |");
+        entry.extend(chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }));
+        entry.push_str("|\n");
+    }
+    entry
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_entry() {
+        let entry = format_entry(1_700_000_000_000_042, Direction::Tx, 0x02, 0x01, 0x42, b"Hello, TAP CMIO!\x00\x01");
+        assert_eq!(entry, "1700000000.000042 TX dev 0x02 cmd 0x01 reason 0x0042 18 bytes\n\
+            \x20 00000000  48 65 6c 6c 6f 2c 20 54  41 50 20 43 4d 49 4f 21  |Hello, TAP CMIO!|\n\
+            \x20 00000010  00 01                                             |..|\n");
+        assert_eq!(format_entry(1_000_000, Direction::Rx, 0x02, 0x01, 0x8043, &[]), "1.000000 RX dev 0x02 cmd 0x01 reason 0x8043 0 bytes\n");
+    }
+}
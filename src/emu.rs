@@ -0,0 +1,294 @@
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use crate::cmio::{self, CmioError, CmioHandle, CmioRx, CmioStats, CmioTx, Exchange};
+use crate::dump::{self, Direction};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+use crate::status;
+
+// Size of a yield's header on the stream: device, command, reason code and data length
+const YIELD_HEADER_SIZE: usize = 8;
+
+// Size of a response's header on the stream: reason code and data length
+const RESPONSE_HEADER_SIZE: usize = 6;
+
+// Environment variable naming the socket of an emulated host for unix mode
+pub const EMU_SOCKET_ENV: &str = "TAPCMIO_EMU_SOCKET";
+
+// Largest buffer either side accepts unless configured otherwise, that of the usual
+// machine setup
+pub const DEFAULT_EMU_BUFFER_SIZE: usize = 2 * 1024 * 1024;
+
+// A yield as received by the emulated host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmuYield {
+    pub dev: u8,
+    pub cmd: u8,
+    pub reason: u16,
+    pub data: Vec<u8>,
+}
+
+// Stand-in for the CMIO device talking to a fake host driver over a Unix stream, such
+// as one end of a socketpair or a socket the driver listens on
+//
+// Every yield is written to the stream as the device (u8), the command (u8), the reason
+// code (u16 BE) and the data length (u32 BE), followed by the data. The host answers
+// each with the reason code (u16 BE) and the data length (u32 BE), followed by the
+// data, and the yield returns once the answer is read. Both sides refuse data larger
+// than their buffer, as the machine does.
+pub struct EmuCmio {
+    stream: UnixStream,
+    tx_length: usize,
+    rx_length: usize,
+    stats: CmioStats,
+}
+
+impl EmuCmio {
+    /// Connect to a fake host driver listening on a Unix socket
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, CmioError> {
+        let path = path.as_ref();
+        let stream = UnixStream::connect(path)
+            .map_err(|e| CmioError::io(format!("connect to the emulated host at {}", path.display()), e))?;
+        Ok(Self::from_stream(stream, DEFAULT_EMU_BUFFER_SIZE, DEFAULT_EMU_BUFFER_SIZE))
+    }
+    
+    /// Create a device and the emulated host at the other end of a socketpair
+    pub fn pair(tx_length: usize, rx_length: usize) -> Result<(Self, EmuHost), CmioError> {
+        let (device, host) = UnixStream::pair()
+            .map_err(|e| CmioError::io("create the emulation socketpair", e))?;
+        Ok((Self::from_stream(device, tx_length, rx_length), EmuHost::from_stream(host, tx_length, rx_length)))
+    }
+    
+    /// Use a connected stream, with the given buffer sizes
+    pub fn from_stream(stream: UnixStream, tx_length: usize, rx_length: usize) -> Self {
+        status::device_opened();
+        Self { stream, tx_length, rx_length, stats: CmioStats::default() }
+    }
+    
+    /// Yield like Cmio::yield_with_retry, through the emulated host
+    pub fn yield_with_retry(
+        &mut self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+    ) -> Result<(Vec<u8>, u16), CmioError> {
+        let mut rx_data = Vec::new();
+        let reason = self.yield_with_retry_into(dev, cmd, reason, tx_data, &mut rx_data)?;
+        Ok((rx_data, reason))
+    }
+    
+    /// Yield like Cmio::yield_with_retry_into, through the emulated host
+    /// 
+    /// A stream error fails the yield; there is no device to re-initialize, so nothing
+    /// is retried.
+    pub fn yield_with_retry_into(
+        &mut self,
+        dev: YieldDevice,
+        cmd: YieldCommand,
+        reason: YieldReason,
+        tx_data: &[u8],
+        rx_data: &mut Vec<u8>,
+    ) -> Result<u16, CmioError> {
+        if tx_data.len() > self.tx_length {
+            self.stats.errors += 1;
+            return Err(CmioError::BufferTooLarge(tx_data.len(), self.tx_length));
+        }
+        dump::record(Direction::Tx, dev as u8, cmd as u8, reason.code(), tx_data);
+        let result = self.exchange_stream(dev as u8, cmd as u8, reason.code(), tx_data, rx_data);
+        match &result {
+            Ok(response) => {
+                dump::record(Direction::Rx, dev as u8, cmd as u8, *response, rx_data);
+                self.stats.yields += 1;
+                self.stats.tx_bytes += tx_data.len() as u64;
+                self.stats.rx_bytes += rx_data.len() as u64;
+                status::record_bytes(tx_data.len(), rx_data.len());
+            }
+            Err(_) => self.stats.errors += 1,
+        }
+        status::record_yield(result.is_ok());
+        result
+    }
+    
+    /// Yield like Cmio::yield_manual, through the emulated host, which never retries
+    pub fn yield_manual(&mut self, reason: YieldReason, tx_data: &[u8]) -> Result<(Vec<u8>, u16), CmioError> {
+        self.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, reason, tx_data)
+    }
+    
+    /// Yield like Cmio::yield_automatic, through the emulated host, which never retries
+    pub fn yield_automatic(&mut self, reason: YieldReason, tx_data: &[u8]) -> Result<(), CmioError> {
+        self.yield_with_retry(YieldDevice::Yield, YieldCommand::Automatic, reason, tx_data)?;
+        Ok(())
+    }
+    
+    /// Write a yield to the stream and read the answer into rx_data
+    fn exchange_stream(&mut self, dev: u8, cmd: u8, reason: u16, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        let mut header = [0u8; YIELD_HEADER_SIZE];
+        header[0] = dev;
+        header[1] = cmd;
+        header[2..4].copy_from_slice(&reason.to_be_bytes());
+        header[4..8].copy_from_slice(&(tx_data.len() as u32).to_be_bytes());
+        self.stream.write_all(&header)
+            .and_then(|()| self.stream.write_all(tx_data))
+            .map_err(|e| CmioError::io("send a yield to the emulated host", e))?;
+        
+        let mut header = [0u8; RESPONSE_HEADER_SIZE];
+        self.stream.read_exact(&mut header)
+            .map_err(|e| CmioError::io("receive a response from the emulated host", e))?;
+        let reason = u16::from_be_bytes([header[0], header[1]]);
+        let length = u32::from_be_bytes([header[2], header[3], header[4], header[5]]) as usize;
+        if length > self.rx_length {
+            return Err(CmioError::BufferTooLarge(length, self.rx_length));
+        }
+        rx_data.clear();
+        rx_data.resize(length, 0);
+        self.stream.read_exact(rx_data)
+            .map_err(|e| CmioError::io("receive a response from the emulated host", e))?;
+        Ok(reason)
+    }
+    
+    /// Get the maximum size of the TX buffer
+    pub fn get_tx_length(&self) -> usize {
+        self.tx_length
+    }
+    
+    /// Get the maximum size of the RX buffer
+    pub fn get_rx_length(&self) -> usize {
+        self.rx_length
+    }
+    
+    /// Yield counters like Cmio::stats
+    pub fn stats(&self) -> CmioStats {
+        self.stats
+    }
+    
+    /// Split the device into halves like Cmio::split
+    pub fn split(self) -> (CmioTx, CmioRx) {
+        let tx_length = self.tx_length;
+        cmio::split_device(Box::new(self), tx_length)
+    }
+    
+    /// Move the device to a thread of its own like Cmio::into_handle
+    pub fn into_handle(self) -> Result<CmioHandle, CmioError> {
+        let tx_length = self.tx_length;
+        cmio::spawn_handle(Box::new(self), tx_length)
+    }
+}
+
+impl Drop for EmuCmio {
+    fn drop(&mut self) {
+        status::device_closed();
+    }
+}
+
+impl Exchange for EmuCmio {
+    fn exchange(&mut self, dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        self.yield_with_retry_into(dev, cmd, reason, tx_data, rx_data)
+    }
+    
+    fn tx_length(&self) -> usize {
+        self.tx_length
+    }
+}
+
+// The fake host driver's end of the stream, receiving yields and answering them
+pub struct EmuHost {
+    stream: UnixStream,
+    tx_length: usize,
+    rx_length: usize,
+}
+
+impl EmuHost {
+    /// Use a connected stream, such as one accepted from a device connecting, with the
+    /// buffer sizes of the device
+    pub fn from_stream(stream: UnixStream, tx_length: usize, rx_length: usize) -> Self {
+        Self { stream, tx_length, rx_length }
+    }
+    
+    /// Get the maximum size of the device's RX buffer, which responses must fit
+    pub fn get_rx_length(&self) -> usize {
+        self.rx_length
+    }
+    
+    /// Wait for the next yield of the device
+    /// 
+    /// Fails with UnexpectedEof once the device is closed, and with InvalidData for a
+    /// yield larger than the TX buffer.
+    pub fn recv(&mut self) -> io::Result<EmuYield> {
+        let mut header = [0u8; YIELD_HEADER_SIZE];
+        self.stream.read_exact(&mut header)?;
+        let length = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if length > self.tx_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("yield of {} bytes exceeds the {} byte TX buffer", length, self.tx_length)));
+        }
+        let mut data = vec![0u8; length];
+        self.stream.read_exact(&mut data)?;
+        Ok(EmuYield {
+            dev: header[0],
+            cmd: header[1],
+            reason: u16::from_be_bytes([header[2], header[3]]),
+            data,
+        })
+    }
+    
+    /// Answer the last yield with data and a reason code
+    pub fn respond(&mut self, reason: u16, data: &[u8]) -> io::Result<()> {
+        if data.len() > self.rx_length {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("response of {} bytes exceeds the {} byte RX buffer", data.len(), self.rx_length)));
+        }
+        let mut header = [0u8; RESPONSE_HEADER_SIZE];
+        header[0..2].copy_from_slice(&reason.to_be_bytes());
+        header[2..6].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        self.stream.write_all(&header)?;
+        self.stream.write_all(data)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emulated_yield() {
+        let (mut cmio, mut host) = EmuCmio::pair(16, 8).unwrap();
+        let driver = std::thread::spawn(move || {
+            let request = host.recv().unwrap();
+            assert_eq!((request.dev, request.cmd, request.reason), (0x02, 0x01, 0x43));
+            let mut answer = request.data.clone();
+            answer.reverse();
+            host.respond(0x8043, &answer).unwrap();
+
+            // An answer larger than the RX buffer is refused before it is sent
+            host.recv().unwrap();
+            assert!(host.respond(0x43, &[0; 9]).is_err());
+            host.respond(0x43, &[]).unwrap();
+        });
+
+        let (rx_data, reason) = cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::UnixSocket, &[1, 2, 3]).unwrap();
+        assert_eq!((rx_data, reason), (vec![3, 2, 1], 0x8043));
+        assert!(matches!(cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::UnixSocket, &[0; 17]), Err(CmioError::BufferTooLarge(17, 16))));
+        assert_eq!(cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::UnixSocket, &[]).unwrap(), (Vec::new(), 0x43));
+        driver.join().unwrap();
+        assert_eq!(cmio.stats(), CmioStats { yields: 2, tx_bytes: 3, rx_bytes: 3, errors: 1, reinits: 0 });
+
+        // The device fails once the host is gone
+        assert!(matches!(cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::UnixSocket, &[]), Err(CmioError::IoError { .. })));
+    }
+
+    #[test]
+    fn test_yield_commands() {
+        let (mut cmio, mut host) = EmuCmio::pair(16, 16).unwrap();
+        let driver = std::thread::spawn(move || {
+            let output = host.recv().unwrap();
+            assert_eq!((output.dev, output.cmd, output.reason, output.data), (0x02, 0x00, 2, b"notice".to_vec()));
+            host.respond(0, &[]).unwrap();
+            let finish = host.recv().unwrap();
+            assert_eq!((finish.dev, finish.cmd, finish.reason), (0x02, 0x01, 2));
+            host.respond(1, b"query").unwrap();
+        });
+
+        cmio.yield_automatic(YieldReason::TX_OUTPUT, b"notice").unwrap();
+        assert_eq!(cmio.yield_manual(YieldReason::RX_REJECTED, &[]).unwrap(), (b"query".to_vec(), 1));
+        driver.join().unwrap();
+    }
+}
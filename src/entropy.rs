@@ -0,0 +1,176 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+
+// Reason code random bytes are requested from the host on
+pub const ENTROPY_REASON: YieldReason = YieldReason::Other(0x4B);
+
+// Device the host's random bytes are fed into
+pub const DEFAULT_RANDOM_DEVICE: &str = "/dev/random";
+
+// Bytes requested at a time unless another amount is given, enough to seed the pool
+pub const DEFAULT_ENTROPY_BYTES: usize = 64;
+
+// How often more bytes are requested after the first ones unless another interval is given
+pub const DEFAULT_ENTROPY_INTERVAL: Duration = Duration::from_secs(300);
+
+// Largest request, keeping answers small
+const MAX_ENTROPY_BYTES: usize = 4096;
+
+// Request of the guest, with the number of bytes wanted (u32 BE)
+const ENTROPY_REQUEST: u8 = 0x01;
+
+// Answer of the host, followed by the random bytes
+const ENTROPY_RESPONSE: u8 = 0x81;
+
+// Random device ioctl adding bytes to the pool and crediting their entropy
+// (_IOW('R', 0x03, int[2]))
+const RNDADDENTROPY: libc::c_ulong = 0x4008_5203;
+
+// Add bytes to the kernel pool, crediting them as fully random
+//
+// The ioctl takes a struct rand_pool_info: the entropy in bits and the size in bytes
+// (i32 each), followed by the bytes.
+fn add_entropy(device: &File, data: &[u8]) -> io::Result<()> {
+    let mut info = vec![0i32; 2 + data.len().div_ceil(4)];
+    info[0] = (data.len() * 8) as i32;
+    info[1] = data.len() as i32;
+    let bytes = unsafe { std::slice::from_raw_parts_mut(info[2..].as_mut_ptr() as *mut u8, data.len()) };
+    bytes.copy_from_slice(data);
+    if unsafe { libc::ioctl(device.as_raw_fd(), RNDADDENTROPY, info.as_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Structure feeding random bytes from the host into the guest's entropy pool, as a
+// subsystem of the multiplexer on ENTROPY_REASON, so minimal guests don't stall at boot
+// waiting for randomness
+//
+// The guest asks for bytes with ENTROPY_REQUEST right away and then every interval, and
+// the host answers with ENTROPY_RESPONSE followed by them. They are added to the pool with
+// the RNDADDENTROPY ioctl, which credits them and needs CAP_SYS_ADMIN, or otherwise
+// written to the device, which mixes them in without crediting them.
+pub struct EntropyFeed {
+    device: PathBuf,
+    bytes: usize,
+    interval: Duration,
+    last_request: Option<Instant>,
+    // Bytes fed into the pool so far
+    fed: u64,
+    // Set once crediting failed, after which bytes are only written
+    uncredited: bool,
+}
+
+impl EntropyFeed {
+    /// Request the given number of bytes every interval, feeding them into /dev/random
+    pub fn new(bytes: usize, interval: Duration) -> Self {
+        Self::with_device(DEFAULT_RANDOM_DEVICE, bytes, interval)
+    }
+    
+    /// Feed the bytes into another random device
+    pub fn with_device(device: impl AsRef<Path>, bytes: usize, interval: Duration) -> Self {
+        Self {
+            device: device.as_ref().to_path_buf(),
+            bytes: bytes.clamp(1, MAX_ENTROPY_BYTES),
+            interval,
+            last_request: None,
+            fed: 0,
+            uncredited: false,
+        }
+    }
+    
+    /// Number of bytes fed into the pool so far
+    pub fn fed(&self) -> u64 {
+        self.fed
+    }
+    
+    /// Add the host's bytes to the pool, crediting them if allowed
+    fn feed(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut device = OpenOptions::new().write(true).open(&self.device)?;
+        if !self.uncredited {
+            match add_entropy(&device, data) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    warn!("Failed to credit entropy to {}, mixing it in uncredited: {}", self.device.display(), e);
+                    self.uncredited = true;
+                }
+            }
+        }
+        device.write_all(data)
+    }
+}
+
+impl Subsystem for EntropyFeed {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, _max_len: usize) -> Result<(), CmioError> {
+        if self.last_request.is_some_and(|sent| sent.elapsed() < self.interval) {
+            return Ok(());
+        }
+        buffer.push(ENTROPY_REQUEST);
+        buffer.extend_from_slice(&(self.bytes as u32).to_be_bytes());
+        self.last_request = Some(Instant::now());
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        let Some((&kind, random)) = data.split_first() else {
+            return Ok(());
+        };
+        if kind != ENTROPY_RESPONSE {
+            warn!("Ignoring entropy message of invalid type {:#04x}", kind);
+            return Ok(());
+        }
+        if random.is_empty() {
+            debug!("Host had no random bytes to give");
+            return Ok(());
+        }
+        match self.feed(random) {
+            Ok(()) => {
+                if self.fed == 0 {
+                    info!("Seeded {} with {} bytes from the host", self.device.display(), random.len());
+                }
+                self.fed += random.len() as u64;
+            }
+            Err(e) => warn!("Failed to feed {} random bytes into {}: {}", random.len(), self.device.display(), e),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_entropy_feed() {
+        // A regular file doesn't take the ioctl, so the bytes are written to it
+        let path = std::env::temp_dir().join(format!("tapcmio-entropy-{}", std::process::id()));
+        fs::write(&path, b"").unwrap();
+        let mut feed = EntropyFeed::with_device(&path, 32, Duration::from_secs(60));
+        let mut buffer = Vec::new();
+        feed.poll_tx(&mut buffer, 4096).unwrap();
+        assert_eq!(buffer, [ENTROPY_REQUEST, 0, 0, 0, 32]);
+        let mut later = Vec::new();
+        feed.poll_tx(&mut later, 4096).unwrap();
+        assert!(later.is_empty());
+
+        let mut response = vec![ENTROPY_RESPONSE];
+        response.extend((0..32).map(|byte| byte * 7));
+        feed.handle_rx(&response, ENTROPY_REASON.code()).unwrap();
+        assert_eq!(feed.fed(), 32);
+        assert_eq!(fs::read(&path).unwrap(), &response[1..]);
+        
+        // Messages of other types are ignored
+        feed.handle_rx(&[0x02, 1, 2], ENTROPY_REASON.code()).unwrap();
+        assert_eq!(feed.fed(), 32);
+        assert_eq!(fs::read(&path).unwrap(), &response[1..]);
+        fs::remove_file(&path).unwrap();
+    }
+}
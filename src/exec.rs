@@ -0,0 +1,434 @@
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, ChildStderr, ChildStdout, Command, ExitStatus, Stdio};
+use std::thread;
+use tracing::{debug, info, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+
+// Reason code the command requests and their output are exchanged with the host on
+pub const EXEC_REASON: YieldReason = YieldReason::Other(0x48);
+
+// Default maximum number of commands running at once
+pub const DEFAULT_MAX_PROCESSES: usize = 16;
+
+// Messages from the host
+const EXEC_SPAWN: u8 = 0x01;
+const EXEC_KILL: u8 = 0x02;
+
+// Messages to the host
+const EXEC_STDOUT: u8 = 0x81;
+const EXEC_STDERR: u8 = 0x82;
+const EXEC_EXIT: u8 = 0x83;
+
+// How a command ended, the first byte of an exit message followed by the code (i32, network
+// byte order)
+const EXIT_CODE: u8 = 0x00; // Exited with the code
+const EXIT_SIGNAL: u8 = 0x01; // Killed by the signal
+const EXIT_SPAWN_FAILED: u8 = 0x02; // Never started, with the errno
+
+// Size of a message without its payload: 1 (type) + 4 (command ID) + 4 (payload length)
+const MESSAGE_HEADER_SIZE: usize = 9;
+
+// Maximum number of output bytes read from a pipe per poll
+const READ_CHUNK: usize = 16 * 1024;
+
+// Serialize a message to the host
+fn encode_message(kind: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(MESSAGE_HEADER_SIZE + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(payload);
+    message
+}
+
+// Payload of an exit message
+fn exit_payload(kind: u8, code: i32) -> Vec<u8> {
+    let mut payload = vec![kind];
+    payload.extend_from_slice(&code.to_be_bytes());
+    payload
+}
+
+// Exit message payload for the status of a command that ran
+fn exit_status_payload(status: ExitStatus) -> Vec<u8> {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => exit_payload(EXIT_CODE, code),
+        (None, Some(signal)) => exit_payload(EXIT_SIGNAL, signal),
+        (None, None) => exit_payload(EXIT_CODE, -1),
+    }
+}
+
+// Make reads of a pipe return WouldBlock instead of waiting for output
+fn set_nonblocking(pipe: &impl AsRawFd) -> io::Result<()> {
+    let fd = pipe.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Read what a pipe has without waiting, closing it at EOF
+//
+// Returns the bytes read, at most max_len.
+fn read_pipe<R: Read>(pipe: &mut Option<R>, max_len: usize) -> Vec<u8> {
+    let Some(reader) = pipe else {
+        return Vec::new();
+    };
+    let mut data = vec![0u8; max_len];
+    loop {
+        match reader.read(&mut data) {
+            Ok(0) => {
+                *pipe = None;
+                return Vec::new();
+            }
+            Ok(n) => {
+                data.truncate(n);
+                return data;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Vec::new(),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                debug!("Closing a command's output pipe after a read error: {}", e);
+                *pipe = None;
+                return Vec::new();
+            }
+        }
+    }
+}
+
+// A spawn request of the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SpawnRequest {
+    argv: Vec<Vec<u8>>,
+    // Variables set on top of the daemon's environment, as NAME=VALUE
+    env: Vec<Vec<u8>>,
+    stdin: Vec<u8>,
+}
+
+impl SpawnRequest {
+    /// Parse the payload of a spawn message: the argument count (u16 BE) and arguments,
+    /// the variable count and variables, each a length (u16 BE) and bytes, then the
+    /// standard input
+    fn decode(payload: &[u8]) -> Result<Self, CmioError> {
+        let mut offset = 0;
+        let mut strings = || -> Result<Vec<Vec<u8>>, CmioError> {
+            let count = read_u16(payload, &mut offset)?;
+            (0..count).map(|_| {
+                let length = read_u16(payload, &mut offset)? as usize;
+                let string = payload.get(offset..offset + length)
+                    .ok_or_else(|| CmioError::ProtocolError(format!("spawn message truncated at offset {}", offset)))?;
+                offset += length;
+                Ok(string.to_vec())
+            }).collect()
+        };
+        let argv = strings()?;
+        let env = strings()?;
+        if argv.is_empty() {
+            return Err(CmioError::ProtocolError("spawn message without a command".to_string()));
+        }
+        Ok(Self { argv, env, stdin: payload[offset..].to_vec() })
+    }
+    
+    /// Serialize the payload as the host sends it
+    #[cfg(all(test, not(target_arch = "riscv64")))]
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        for strings in [&self.argv, &self.env] {
+            payload.extend_from_slice(&(strings.len() as u16).to_be_bytes());
+            for string in strings {
+                payload.extend_from_slice(&(string.len() as u16).to_be_bytes());
+                payload.extend_from_slice(string);
+            }
+        }
+        payload.extend_from_slice(&self.stdin);
+        payload
+    }
+}
+
+// Read a u16 BE at the offset, advancing it
+fn read_u16(data: &[u8], offset: &mut usize) -> Result<u16, CmioError> {
+    let bytes = data.get(*offset..*offset + 2)
+        .ok_or_else(|| CmioError::ProtocolError(format!("spawn message truncated at offset {}", offset)))?;
+    *offset += 2;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// A command started by the host
+struct Process {
+    child: Child,
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+}
+
+// Structure running commands for the host, as a subsystem of the multiplexer on
+// EXEC_REASON, which makes the daemon a lightweight guest agent
+//
+// Every message is a type, the command ID chosen by the host (u32 BE) and a payload length
+// (u32 BE), followed by the payload. The host spawns a command with EXEC_SPAWN, carrying
+// its arguments, extra environment variables and standard input, which is written to the
+// command and then closed; EXEC_KILL sends it SIGKILL. The command's output is streamed
+// back as EXEC_STDOUT and EXEC_STDERR messages while it runs, followed by a single
+// EXEC_EXIT once both pipes are closed and it has exited.
+pub struct CommandRunner {
+    processes: HashMap<u32, Process>,
+    // Messages waiting for room in a yield
+    messages: VecDeque<Vec<u8>>,
+    max_message_size: usize,
+    max_processes: usize,
+}
+
+impl CommandRunner {
+    /// Run commands for the host, sizing output messages to fit the CMIO buffer
+    pub fn new(cmio_max_buffer_size: usize) -> Self {
+        Self {
+            processes: HashMap::new(),
+            messages: VecDeque::new(),
+            max_message_size: cmio_max_buffer_size,
+            max_processes: DEFAULT_MAX_PROCESSES,
+        }
+    }
+    
+    /// Set the maximum number of commands running at once, beyond which spawns fail with
+    /// EAGAIN
+    pub fn set_max_processes(&mut self, max_processes: usize) {
+        self.max_processes = max_processes;
+    }
+    
+    /// Number of commands running
+    pub fn running(&self) -> usize {
+        self.processes.len()
+    }
+    
+    /// Start a command, queueing an exit message if it can't be
+    fn spawn(&mut self, id: u32, request: SpawnRequest) {
+        if self.processes.contains_key(&id) {
+            warn!("Command {} is already running, refusing to spawn another with its ID", id);
+            self.messages.push_back(encode_message(EXEC_EXIT, id, &exit_payload(EXIT_SPAWN_FAILED, libc::EEXIST)));
+            return;
+        }
+        if self.processes.len() >= self.max_processes {
+            warn!("Refusing to spawn command {}, {} are running", id, self.processes.len());
+            self.messages.push_back(encode_message(EXEC_EXIT, id, &exit_payload(EXIT_SPAWN_FAILED, libc::EAGAIN)));
+            return;
+        }
+        let mut command = Command::new(OsStr::from_bytes(&request.argv[0]));
+        command.args(request.argv[1..].iter().map(|arg| OsStr::from_bytes(arg)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        for variable in &request.env {
+            match variable.iter().position(|&byte| byte == b'=') {
+                Some(split) => { command.env(OsStr::from_bytes(&variable[..split]), OsStr::from_bytes(&variable[split + 1..])); }
+                None => warn!("Ignoring environment variable {} of command {} without a value", String::from_utf8_lossy(variable), id),
+            }
+        }
+        let process = command.spawn().and_then(|mut child| {
+            let stdout = child.stdout.take();
+            let stderr = child.stderr.take();
+            if let Some(pipe) = &stdout {
+                set_nonblocking(pipe)?;
+            }
+            if let Some(pipe) = &stderr {
+                set_nonblocking(pipe)?;
+            }
+            
+            // Feed the input from a thread of its own, so a command that reads it slowly
+            // doesn't hold up the loop, closing it once written
+            if let Some(mut stdin) = child.stdin.take() {
+                if !request.stdin.is_empty() {
+                    let input = request.stdin;
+                    thread::spawn(move || stdin.write_all(&input));
+                }
+            }
+            Ok(Process { child, stdout, stderr })
+        });
+        match process {
+            Ok(process) => {
+                info!("Spawned command {}: {}", id, String::from_utf8_lossy(&request.argv[0]));
+                self.processes.insert(id, process);
+            }
+            Err(e) => {
+                warn!("Failed to spawn command {}: {}", id, e);
+                self.messages.push_back(encode_message(EXEC_EXIT, id, &exit_payload(EXIT_SPAWN_FAILED, e.raw_os_error().unwrap_or(0))));
+            }
+        }
+    }
+    
+    /// Queue the output of every command, and the exit of those that are done
+    fn collect_output(&mut self) {
+        let chunk = READ_CHUNK.min(self.max_message_size.saturating_sub(MESSAGE_HEADER_SIZE)).max(1);
+        let mut finished = Vec::new();
+        for (&id, process) in self.processes.iter_mut() {
+            let stdout = read_pipe(&mut process.stdout, chunk);
+            if !stdout.is_empty() {
+                self.messages.push_back(encode_message(EXEC_STDOUT, id, &stdout));
+            }
+            let stderr = read_pipe(&mut process.stderr, chunk);
+            if !stderr.is_empty() {
+                self.messages.push_back(encode_message(EXEC_STDERR, id, &stderr));
+            }
+            if process.stdout.is_none() && process.stderr.is_none() {
+                match process.child.try_wait() {
+                    Ok(Some(status)) => {
+                        info!("Command {} finished: {}", id, status);
+                        self.messages.push_back(encode_message(EXEC_EXIT, id, &exit_status_payload(status)));
+                        finished.push(id);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("Failed to wait for command {}: {}", id, e);
+                        self.messages.push_back(encode_message(EXEC_EXIT, id, &exit_payload(EXIT_CODE, -1)));
+                        finished.push(id);
+                    }
+                }
+            }
+        }
+        for id in finished {
+            self.processes.remove(&id);
+        }
+    }
+}
+
+impl Subsystem for CommandRunner {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        // Read more output only once the earlier is sent, so a chatty command can't pile
+        // up messages faster than the host takes them
+        if self.messages.is_empty() {
+            self.collect_output();
+        }
+        while let Some(message) = self.messages.front() {
+            if buffer.len() + message.len() > max_len {
+                break;
+            }
+            buffer.extend_from_slice(message);
+            self.messages.pop_front();
+        }
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            // The rest of a batch can't be framed past a truncated message
+            let Some(header) = data.get(offset..offset + MESSAGE_HEADER_SIZE) else {
+                warn!("Dropping {} bytes of a truncated exec message at offset {}", data.len() - offset, offset);
+                break;
+            };
+            let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+            let length = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+            let Some(payload) = data.get(offset + MESSAGE_HEADER_SIZE..offset + MESSAGE_HEADER_SIZE + length) else {
+                warn!("Dropping exec message for command {} truncated at offset {}", id, offset);
+                break;
+            };
+            match header[0] {
+                EXEC_SPAWN => match SpawnRequest::decode(payload) {
+                    Ok(request) => self.spawn(id, request),
+                    Err(e) => {
+                        warn!("Refusing to spawn command {}: {}", id, e);
+                        self.messages.push_back(encode_message(EXEC_EXIT, id, &exit_payload(EXIT_SPAWN_FAILED, libc::EINVAL)));
+                    }
+                },
+                EXEC_KILL => match self.processes.get_mut(&id) {
+                    Some(process) => {
+                        let _ = process.child.kill();
+                    }
+                    None => debug!("Kill for command {}, which isn't running", id),
+                },
+                kind => warn!("Unknown exec message type {:#04x} for command {}", kind, id),
+            }
+            offset += MESSAGE_HEADER_SIZE + length;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CommandRunner {
+    fn drop(&mut self) {
+        // Don't leave commands running after the daemon
+        for process in self.processes.values_mut() {
+            let _ = process.child.kill();
+            let _ = process.child.wait();
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn spawn_message(id: u32, argv: &[&str], env: &[&str], stdin: &[u8]) -> Vec<u8> {
+        let request = SpawnRequest {
+            argv: argv.iter().map(|arg| arg.as_bytes().to_vec()).collect(),
+            env: env.iter().map(|variable| variable.as_bytes().to_vec()).collect(),
+            stdin: stdin.to_vec(),
+        };
+        encode_message(EXEC_SPAWN, id, &request.encode())
+    }
+
+    // Poll until every command is done, gathering (type, id) -> payload in order
+    fn run(runner: &mut CommandRunner) -> Vec<(u8, u32, Vec<u8>)> {
+        let mut messages = Vec::new();
+        let started = Instant::now();
+        loop {
+            let mut buffer = Vec::new();
+            runner.poll_tx(&mut buffer, 4096).unwrap();
+            let mut rest = buffer.as_slice();
+            while !rest.is_empty() {
+                let length = u32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]) as usize;
+                let id = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]);
+                messages.push((rest[0], id, rest[MESSAGE_HEADER_SIZE..MESSAGE_HEADER_SIZE + length].to_vec()));
+                rest = &rest[MESSAGE_HEADER_SIZE + length..];
+            }
+            if runner.running() == 0 && buffer.is_empty() {
+                return messages;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "commands didn't finish");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    // Concatenated payloads of one type for one command
+    fn output(messages: &[(u8, u32, Vec<u8>)], kind: u8, id: u32) -> Vec<u8> {
+        messages.iter().filter(|message| message.0 == kind && message.1 == id).flat_map(|message| message.2.clone()).collect()
+    }
+
+    #[test]
+    fn test_command_runner() {
+        let mut runner = CommandRunner::new(4096);
+        let mut batch = spawn_message(1, &["sh", "-c", "echo $GREETING; echo oops >&2; exit 3"], &["GREETING=hello"], &[]);
+        batch.extend(spawn_message(2, &["cat"], &[], b"fed through stdin"));
+        batch.extend(spawn_message(3, &["/nonexistent/command"], &[], &[]));
+        runner.handle_rx(&batch, EXEC_REASON.code()).unwrap();
+        let messages = run(&mut runner);
+
+        assert_eq!(output(&messages, EXEC_STDOUT, 1), b"hello\n");
+        assert_eq!(output(&messages, EXEC_STDERR, 1), b"oops\n");
+        assert_eq!(output(&messages, EXEC_EXIT, 1), exit_payload(EXIT_CODE, 3));
+        assert_eq!(output(&messages, EXEC_STDOUT, 2), b"fed through stdin");
+        assert_eq!(output(&messages, EXEC_EXIT, 2), exit_payload(EXIT_CODE, 0));
+        assert_eq!(output(&messages, EXEC_EXIT, 3), exit_payload(EXIT_SPAWN_FAILED, libc::ENOENT));
+
+        // The exit comes after all of the output
+        let exit = messages.iter().position(|message| message.0 == EXEC_EXIT && message.1 == 1).unwrap();
+        assert!(messages[exit..].iter().all(|message| message.1 != 1 || message.0 == EXEC_EXIT));
+
+        // A killed command reports the signal
+        runner.handle_rx(&spawn_message(4, &["sleep", "30"], &[], &[]), EXEC_REASON.code()).unwrap();
+        runner.handle_rx(&encode_message(EXEC_KILL, 4, &[]), EXEC_REASON.code()).unwrap();
+        assert_eq!(output(&run(&mut runner), EXEC_EXIT, 4), exit_payload(EXIT_SIGNAL, libc::SIGKILL));
+        
+        // A malformed spawn fails with EINVAL, and a truncated message is dropped
+        let mut malformed = encode_message(EXEC_SPAWN, 5, &[0, 0, 0, 0]);
+        malformed.extend_from_slice(&batch[..12]);
+        runner.handle_rx(&malformed, EXEC_REASON.code()).unwrap();
+        assert_eq!(run(&mut runner), vec![(EXEC_EXIT, 5, exit_payload(EXIT_SPAWN_FAILED, libc::EINVAL))]);
+    }
+}
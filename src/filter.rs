@@ -0,0 +1,302 @@
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::ipv6::{self, ETHERTYPE_IPV6};
+
+// EtherTypes recognised while parsing frames
+pub(crate) const ETHERTYPE_IPV4: u16 = 0x0800;
+pub(crate) const ETHERTYPE_VLAN: u16 = 0x8100;
+
+// Size of the Ethernet header without VLAN tag
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+// Action taken on a frame matching a rule
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilterAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+impl FromStr for FilterAction {
+    type Err = FilterParseError;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(FilterAction::Allow),
+            "deny" => Ok(FilterAction::Deny),
+            _ => Err(FilterParseError(format!("unknown filter action {}", s))),
+        }
+    }
+}
+
+// Error returned for a malformed filter rule
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid filter rule: {0}")]
+pub struct FilterParseError(String);
+
+// Structure describing a filter rule, matching frames on all fields that are set
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FilterRule {
+    pub action: FilterAction,
+    // EtherType, following any VLAN tag
+    pub ethertype: Option<u16>,
+    pub source: Option<[u8; 6]>,
+    pub destination: Option<[u8; 6]>,
+    // VLAN ID of an 802.1Q tagged frame
+    pub vlan: Option<u16>,
+    // IP protocol number, following any IPv6 extension headers
+    pub protocol: Option<u8>,
+}
+
+// Parse a number in decimal or with a 0x prefix in hexadecimal
+fn parse_number(value: &str) -> Result<u16, FilterParseError> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| FilterParseError(format!("invalid number {}", value)))
+}
+
+// Parse a MAC address written as six colon separated hex bytes
+fn parse_mac(value: &str) -> Result<[u8; 6], FilterParseError> {
+    let error = || FilterParseError(format!("invalid MAC address {}", value));
+    let mut mac = [0u8; 6];
+    let mut parts = value.split(':');
+    for byte in mac.iter_mut() {
+        *byte = u8::from_str_radix(parts.next().ok_or_else(error)?, 16).map_err(|_| error())?;
+    }
+    if parts.next().is_some() {
+        return Err(error());
+    }
+    Ok(mac)
+}
+
+impl FromStr for FilterRule {
+    type Err = FilterParseError;
+    
+    /// Parse a rule written as an action followed by comma separated conditions,
+    /// e.g. `allow,ethertype=0x0806`, `deny,src=02:00:00:00:00:01,vlan=10` or `deny,proto=58`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let mut rule = FilterRule {
+            action: parts.next().unwrap_or_default().parse()?,
+            ..Default::default()
+        };
+        
+        for condition in parts {
+            let (key, value) = condition.split_once('=')
+                .ok_or_else(|| FilterParseError(format!("filter condition {} is not key=value", condition)))?;
+            match key {
+                "ethertype" => rule.ethertype = Some(parse_number(value)?),
+                "src" => rule.source = Some(parse_mac(value)?),
+                "dst" => rule.destination = Some(parse_mac(value)?),
+                "vlan" => rule.vlan = Some(parse_number(value)?),
+                "proto" => rule.protocol = Some(u8::try_from(parse_number(value)?)
+                    .map_err(|_| FilterParseError(format!("invalid protocol {}", value)))?),
+                _ => return Err(FilterParseError(format!("unknown filter condition {}", key))),
+            }
+        }
+        Ok(rule)
+    }
+}
+
+// Header fields of a frame that rules match on
+#[derive(Debug, Default, PartialEq, Eq)]
+struct FrameHeader {
+    ethertype: Option<u16>,
+    source: Option<[u8; 6]>,
+    destination: Option<[u8; 6]>,
+    vlan: Option<u16>,
+    protocol: Option<u8>,
+}
+
+// Read the protocol number of an IPv4 or IPv6 packet
+fn ip_protocol(ethertype: Option<u16>, packet: &[u8]) -> Option<u8> {
+    match ethertype? {
+        ETHERTYPE_IPV4 => packet.get(9).copied(),
+        ETHERTYPE_IPV6 => ipv6::parse_header(packet).map(|header| header.next_header),
+        _ => None,
+    }
+}
+
+impl FrameHeader {
+    /// Parse the header of an Ethernet frame, including an 802.1Q tag
+    fn ethernet(frame: &[u8]) -> Self {
+        if frame.len() < ETHERNET_HEADER_SIZE {
+            return Self::default();
+        }
+        let mut header = Self {
+            ethertype: Some(u16::from_be_bytes([frame[12], frame[13]])),
+            destination: frame[0..6].try_into().ok(),
+            source: frame[6..12].try_into().ok(),
+            vlan: None,
+            protocol: None,
+        };
+        let mut payload = &frame[ETHERNET_HEADER_SIZE..];
+        if header.ethertype == Some(ETHERTYPE_VLAN) && frame.len() >= ETHERNET_HEADER_SIZE + 4 {
+            header.vlan = Some(u16::from_be_bytes([frame[14], frame[15]]) & 0x0FFF);
+            header.ethertype = Some(u16::from_be_bytes([frame[16], frame[17]]));
+            payload = &frame[ETHERNET_HEADER_SIZE + 4..];
+        }
+        header.protocol = ip_protocol(header.ethertype, payload);
+        header
+    }
+    
+    /// Derive the EtherType of a raw IP packet from its version
+    fn ip(packet: &[u8]) -> Self {
+        let ethertype = match packet.first().map(|byte| byte >> 4) {
+            Some(4) => Some(ETHERTYPE_IPV4),
+            Some(6) => Some(ETHERTYPE_IPV6),
+            _ => None,
+        };
+        Self { ethertype, protocol: ip_protocol(ethertype, packet), ..Default::default() }
+    }
+}
+
+impl FilterRule {
+    /// Check whether a frame matches all conditions of the rule
+    fn matches(&self, header: &FrameHeader) -> bool {
+        (self.ethertype.is_none() || self.ethertype == header.ethertype)
+            && (self.source.is_none() || self.source == header.source)
+            && (self.destination.is_none() || self.destination == header.destination)
+            && (self.vlan.is_none() || self.vlan == header.vlan)
+            && (self.protocol.is_none() || self.protocol == header.protocol)
+    }
+}
+
+// Structure holding an ordered list of rules, where the first matching rule decides
+// a frame's fate and the default action applies to frames matching none
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameFilter {
+    pub rules: Vec<FilterRule>,
+    pub default_action: FilterAction,
+}
+
+impl FrameFilter {
+    /// Check whether an Ethernet frame passes the filter
+    pub fn allows_ethernet(&self, frame: &[u8]) -> bool {
+        self.is_empty() || self.allows(&FrameHeader::ethernet(frame))
+    }
+    
+    /// Check whether a raw IP packet passes the filter
+    /// 
+    /// IP packets have no MAC addresses or VLAN tag, so rules with those conditions
+    /// never match them; their EtherType is derived from the IP version.
+    pub fn allows_ip(&self, packet: &[u8]) -> bool {
+        self.is_empty() || self.allows(&FrameHeader::ip(packet))
+    }
+    
+    /// Whether the filter lets every frame through without looking at it
+    fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.default_action == FilterAction::Allow
+    }
+    
+    /// Apply the first matching rule, or the default action, to a frame
+    fn allows(&self, header: &FrameHeader) -> bool {
+        let action = self.rules.iter()
+            .find(|rule| rule.matches(header))
+            .map_or(self.default_action, |rule| rule.action);
+        action == FilterAction::Allow
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    const MAC_A: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0a];
+    const MAC_B: [u8; 6] = [0x02, 0, 0, 0, 0, 0x0b];
+
+    // Build an Ethernet frame from MAC_A to MAC_B, optionally tagged with a VLAN
+    fn frame(ethertype: u16, vlan: Option<u16>) -> Vec<u8> {
+        let mut frame = MAC_B.to_vec();
+        frame.extend_from_slice(&MAC_A);
+        if let Some(vlan) = vlan {
+            frame.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+            frame.extend_from_slice(&vlan.to_be_bytes());
+        }
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(&[0; 20]);
+        frame
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        assert_eq!("deny,ethertype=0x86dd,src=02:00:00:00:00:0a,dst=02:00:00:00:00:0b,vlan=10".parse(), Ok(FilterRule {
+            action: FilterAction::Deny,
+            ethertype: Some(ETHERTYPE_IPV6),
+            source: Some(MAC_A),
+            destination: Some(MAC_B),
+            vlan: Some(10),
+            protocol: None,
+        }));
+        assert_eq!("deny,proto=58".parse(), Ok(FilterRule { action: FilterAction::Deny, protocol: Some(58), ..Default::default() }));
+        assert_eq!("allow".parse(), Ok(FilterRule::default()));
+
+        assert!("drop".parse::<FilterRule>().is_err());
+        assert!("allow,ethertype".parse::<FilterRule>().is_err());
+        assert!("allow,src=02:00:00:00:00".parse::<FilterRule>().is_err());
+        assert!("allow,src=02:00:00:00:00:0a:0b".parse::<FilterRule>().is_err());
+        assert!("allow,port=80".parse::<FilterRule>().is_err());
+        assert!("allow,proto=256".parse::<FilterRule>().is_err());
+    }
+
+    #[test]
+    fn test_filter_ipv4_and_arp_only() {
+        let filter = FrameFilter {
+            rules: vec!["allow,ethertype=0x0800".parse().unwrap(), "allow,ethertype=0x0806".parse().unwrap()],
+            default_action: FilterAction::Deny,
+        };
+
+        assert!(filter.allows_ethernet(&frame(ETHERTYPE_IPV4, None)));
+        assert!(filter.allows_ethernet(&frame(0x0806, None)));
+        assert!(filter.allows_ethernet(&frame(ETHERTYPE_IPV4, Some(10))));
+        assert!(!filter.allows_ethernet(&frame(ETHERTYPE_IPV6, None)));
+        assert!(!filter.allows_ethernet(&[0; 4]));
+
+        assert!(filter.allows_ip(&[0x45, 0]));
+        assert!(!filter.allows_ip(&[0x60, 0]));
+    }
+
+    #[test]
+    fn test_filter_first_match_wins() {
+        let filter = FrameFilter {
+            rules: vec!["deny,src=02:00:00:00:00:0a,vlan=10".parse().unwrap(), "allow,vlan=10".parse().unwrap()],
+            default_action: FilterAction::Deny,
+        };
+
+        assert!(!filter.allows_ethernet(&frame(ETHERTYPE_IPV4, Some(10))));
+        assert!(!filter.allows_ethernet(&frame(ETHERTYPE_IPV4, Some(11))));
+        assert!(!filter.allows_ethernet(&frame(ETHERTYPE_IPV4, None)));
+
+        let mut other_source = frame(ETHERTYPE_IPV4, Some(10));
+        other_source[6..12].copy_from_slice(&MAC_B);
+        assert!(filter.allows_ethernet(&other_source));
+
+        assert!(FrameFilter::default().allows_ethernet(&[]));
+    }
+
+    #[test]
+    fn test_filter_protocol() {
+        let filter = FrameFilter {
+            rules: vec!["deny,proto=58".parse().unwrap()],
+            default_action: FilterAction::Allow,
+        };
+
+        // ICMPv6 behind a VLAN tag, and TCP, in IPv6 packets
+        let mut icmpv6 = frame(ETHERTYPE_IPV6, Some(10));
+        icmpv6.truncate(18);
+        icmpv6.extend_from_slice(&[0x60, 0, 0, 0, 0, 0, ipv6::IPPROTO_ICMPV6, 255]);
+        icmpv6.extend_from_slice(&[0; 32]);
+        assert!(!filter.allows_ethernet(&icmpv6));
+        icmpv6[18 + 6] = 6;
+        assert!(filter.allows_ethernet(&icmpv6));
+
+        let mut packet = vec![0x45, 0, 0, 20, 0, 0, 0, 0, 64, 58];
+        packet.extend_from_slice(&[0; 10]);
+        assert!(!filter.allows_ip(&packet));
+        packet[9] = 1;
+        assert!(filter.allows_ip(&packet));
+    }
+}
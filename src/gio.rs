@@ -0,0 +1,111 @@
+use crate::cmio::{Cmio, CmioError, Exchange};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+
+// GIO domain of keccak-256 preimages, the hashes of the rollups' Merkle trees
+pub const KECCAK256_DOMAIN: u16 = 0x2A;
+
+// GIO domain of SHA-256 preimages
+pub const SHA256_DOMAIN: u16 = 0x2B;
+
+// Response code of a GIO request the host answered
+pub const GIO_RESPONSE_OK: u16 = 0;
+
+// Hash function a preimage is looked up by, each with a dehashing GIO domain of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashType {
+    Keccak256,
+    Sha256,
+}
+
+impl HashType {
+    /// The GIO domain requests for preimages of this hash are yielded on
+    pub const fn domain(self) -> u16 {
+        match self {
+            HashType::Keccak256 => KECCAK256_DOMAIN,
+            HashType::Sha256 => SHA256_DOMAIN,
+        }
+    }
+    
+    /// Size of a hash in bytes
+    pub const fn hash_len(self) -> usize {
+        match self {
+            HashType::Keccak256 | HashType::Sha256 => 32,
+        }
+    }
+}
+
+/// Make a generic I/O request, yielding the ID on the domain as the reason code, and
+/// return the host's response code and data
+/// 
+/// This is the Cartesi GIO convention: a manual yield whose reason is the domain and
+/// whose TX buffer holds the request ID, answered with the response code as the reason.
+pub fn gio_request(cmio: &mut Cmio, domain: u16, id: &[u8]) -> Result<(u16, Vec<u8>), CmioError> {
+    request_on(cmio, domain, id)
+}
+
+/// Fetch the preimage of a hash from the host through the dehashing GIO domain of its
+/// hash function
+/// 
+/// Fails with InvalidArgument for a hash of the wrong size and with ProtocolError when the
+/// host answers with another response code, such as for a hash it doesn't know. The
+/// preimage is returned as the host gave it, for the caller to check against the hash.
+pub fn fetch_preimage(cmio: &mut Cmio, hash_type: HashType, hash: &[u8]) -> Result<Vec<u8>, CmioError> {
+    fetch_preimage_on(cmio, hash_type, hash)
+}
+
+fn request_on(device: &mut dyn Exchange, domain: u16, id: &[u8]) -> Result<(u16, Vec<u8>), CmioError> {
+    let mut data = Vec::new();
+    let code = device.exchange(YieldDevice::Yield, YieldCommand::Manual, YieldReason::Other(domain), id, &mut data)?;
+    Ok((code, data))
+}
+
+fn fetch_preimage_on(device: &mut dyn Exchange, hash_type: HashType, hash: &[u8]) -> Result<Vec<u8>, CmioError> {
+    if hash.len() != hash_type.hash_len() {
+        return Err(CmioError::InvalidArgument(format!("{:?} hash of {} bytes, expected {}", hash_type, hash.len(), hash_type.hash_len())));
+    }
+    let (code, preimage) = request_on(device, hash_type.domain(), hash)?;
+    if code != GIO_RESPONSE_OK {
+        return Err(CmioError::ProtocolError(format!("host answered the {:?} preimage request with code {}", hash_type, code)));
+    }
+    Ok(preimage)
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // Host knowing some preimages, answering unknown hashes with code 1
+    struct Host {
+        preimages: HashMap<(u16, Vec<u8>), Vec<u8>>,
+    }
+
+    impl Exchange for Host {
+        fn exchange(&mut self, _dev: YieldDevice, _cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+            rx_data.clear();
+            match self.preimages.get(&(reason.code(), tx_data.to_vec())) {
+                Some(preimage) => {
+                    rx_data.extend_from_slice(preimage);
+                    Ok(GIO_RESPONSE_OK)
+                }
+                None => Ok(1),
+            }
+        }
+
+        fn tx_length(&self) -> usize {
+            4096
+        }
+    }
+
+    #[test]
+    fn test_fetch_preimage() {
+        let hash = [0xab; 32];
+        let mut host = Host { preimages: HashMap::from([((KECCAK256_DOMAIN, hash.to_vec()), b"content".to_vec())]) };
+        assert_eq!(fetch_preimage_on(&mut host, HashType::Keccak256, &hash).unwrap(), b"content");
+
+        // The domain tells the hash functions apart
+        assert!(matches!(fetch_preimage_on(&mut host, HashType::Sha256, &hash), Err(CmioError::ProtocolError(_))));
+        assert!(matches!(fetch_preimage_on(&mut host, HashType::Keccak256, &hash[..20]), Err(CmioError::InvalidArgument(_))));
+        assert_eq!(request_on(&mut host, 0x10, b"id").unwrap(), (1, Vec::new()));
+    }
+}
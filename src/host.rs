@@ -0,0 +1,564 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::time::Duration;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use tun_tap::{Iface, Mode};
+use tracing::{debug, info, warn};
+use crate::cmio::CmioError;
+use crate::emu::{EmuHost, EmuYield, DEFAULT_EMU_BUFFER_SIZE};
+use crate::network::{decode_frames, encode_frames, NetworkStats, Reassembly, FRAME_FLAG_L3};
+use crate::pool::BufferPool;
+use crate::protocol::{YieldCommand, YieldReason};
+use crate::shutdown;
+use crate::unix_tcp_socket::{
+    SocketMessage, SocketStatus, MSG_TYPE_TCP_CLOSE, MSG_TYPE_TCP_CONNECT, MSG_TYPE_TCP_CONNECT_HOST, MSG_TYPE_TCP_RECEIVE,
+    MSG_TYPE_TCP_SEND, MSG_TYPE_UNIX_CLOSE, MSG_TYPE_UNIX_CONNECT, MSG_TYPE_UNIX_RECEIVE, MSG_TYPE_UNIX_SEND,
+    RX_FLAG_CONTINUED, STATUS_SIZE,
+};
+
+// Name of the host's TAP interface unless configured otherwise
+pub const DEFAULT_HOST_TAP_NAME: &str = "tapcmio-host0";
+
+// Unix socket the driver waits for an emulated guest on unless configured otherwise
+pub const DEFAULT_HOST_SOCKET: &str = "/run/tapcmio-host.sock";
+
+// How long the driver waits for frames on the TAP interface before answering a yield
+// that brought none, so an idle guest isn't answered with empty batches in a busy loop
+pub const DEFAULT_HOST_WAIT: Duration = Duration::from_millis(10);
+
+// Largest frame the length field of a batch entry can carry
+const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+
+// Batch buffers kept for reuse, few as each is as large as the guest's RX buffer
+const MAX_POOLED_BATCHES: usize = 4;
+
+// Poll token of the TAP interface
+const TAP_TOKEN: Token = Token(0);
+
+// The machine's CMIO device as the host sees it: the guest's yields, each resumed by a
+// response
+//
+// Implemented by the emulated host of the emu feature; an adapter of the emulator's cmio
+// hooks implements it by running the machine until it yields and sending the response
+// before running it again.
+pub trait MachineLink {
+    /// Wait for the next yield of the guest, failing with UnexpectedEof once it's gone
+    fn recv(&mut self) -> io::Result<EmuYield>;
+    
+    /// Resume the guest with a reason code and data for its RX buffer
+    fn respond(&mut self, reason: u16, data: &[u8]) -> io::Result<()>;
+    
+    /// Size of the guest's RX buffer, which each response must fit
+    fn rx_length(&self) -> usize;
+}
+
+impl MachineLink for EmuHost {
+    fn recv(&mut self) -> io::Result<EmuYield> {
+        EmuHost::recv(self)
+    }
+    
+    fn respond(&mut self, reason: u16, data: &[u8]) -> io::Result<()> {
+        EmuHost::respond(self, reason, data)
+    }
+    
+    fn rx_length(&self) -> usize {
+        self.get_rx_length()
+    }
+}
+
+/// Listen on a Unix socket for a guest yielding through an emulated device, and return
+/// the host's end once one connects
+/// 
+/// A stale socket file left at the path is replaced. Both buffers have the usual size
+/// of the machine, as the device assumes.
+pub fn accept_emulated(path: &Path) -> Result<EmuHost, CmioError> {
+    if path.exists() {
+        fs::remove_file(path)
+            .map_err(|e| CmioError::io(format!("remove the stale socket {}", path.display()), e))?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| CmioError::io(format!("listen on {}", path.display()), e))?;
+    info!("Waiting for the guest on {}", path.display());
+    let (stream, _) = listener.accept()
+        .map_err(|e| CmioError::io(format!("accept the guest on {}", path.display()), e))?;
+    Ok(EmuHost::from_stream(stream, DEFAULT_EMU_BUFFER_SIZE, DEFAULT_EMU_BUFFER_SIZE))
+}
+
+// Structure implementing the host's half of the batch protocol: frames yielded by the
+// guest are decoded and reassembled, and frames for the guest are encoded into batches
+// that each fit its RX buffer, one per response
+pub struct HostBatches {
+    reassembly: Option<Reassembly>,
+    next_frame_id: u16,
+    pool: BufferPool,
+    // Batches waiting for the guest's next yields
+    pending: VecDeque<Vec<u8>>,
+    max_batch_size: usize,
+    stats: NetworkStats,
+}
+
+impl HostBatches {
+    pub fn new(max_batch_size: usize) -> Self {
+        Self {
+            reassembly: None,
+            next_frame_id: 0,
+            pool: BufferPool::new(max_batch_size, MAX_POOLED_BATCHES),
+            pending: VecDeque::new(),
+            max_batch_size,
+            stats: NetworkStats::default(),
+        }
+    }
+    
+    /// Decode a batch yielded by the guest into the Ethernet frames it completes
+    /// 
+    /// Batches the guest's side rejects are dropped here too, and so are IP packets of
+    /// a guest in TUN mode, which a TAP interface can't carry.
+    pub fn receive(&mut self, batch: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        for (flags, frame) in decode_frames(batch, &mut self.reassembly, MAX_FRAME_SIZE, &mut self.stats) {
+            if flags & FRAME_FLAG_L3 != 0 {
+                self.stats.rx_dropped += 1;
+                continue;
+            }
+            self.stats.rx_frames += 1;
+            self.stats.rx_bytes += frame.len() as u64;
+            frames.push(frame.into_owned());
+        }
+        frames
+    }
+    
+    /// Queue frames for the guest, in batches fitting its RX buffer and fragmented when
+    /// a frame alone doesn't fit
+    pub fn queue(&mut self, frames: &[Vec<u8>]) {
+        let batches = encode_frames(frames, 0, false, self.max_batch_size, &mut self.next_frame_id, &mut self.pool, &mut self.stats);
+        self.pending.extend(batches);
+    }
+    
+    /// Whether batches are waiting for the guest
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+    
+    /// Take the next batch for the guest, empty if there is none
+    pub fn next_batch(&mut self) -> Vec<u8> {
+        self.pending.pop_front().unwrap_or_default()
+    }
+    
+    /// Release a batch sent to the guest for reuse
+    pub fn recycle(&mut self, batch: Vec<u8>) {
+        self.pool.give(batch);
+    }
+    
+    /// Traffic statistics, with TX counting frames sent to the guest and RX frames
+    /// received from it
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+}
+
+// Structure bridging a TAP interface of the host with the guest's network mode, by
+// answering its network yields with the frames read from the interface
+pub struct HostDriver {
+    iface: Iface,
+    poll: Poll,
+    link: Box<dyn MachineLink>,
+    batches: HostBatches,
+    // Buffer frames are read from the TAP interface into
+    buffer: Vec<u8>,
+    wait: Duration,
+}
+
+impl HostDriver {
+    /// Create the host's TAP interface and drive the guest at the other end of the link
+    /// 
+    /// The interface is left down and unconfigured, for the host to bridge or address
+    /// like any other.
+    pub fn new(tap_name: &str, link: Box<dyn MachineLink>) -> Result<Self, CmioError> {
+        let iface = Iface::new(tap_name, Mode::Tap)
+            .map_err(|e| CmioError::io(format!("create interface {}", tap_name), e))?;
+        iface.set_non_blocking()
+            .map_err(|e| CmioError::io(format!("make {} non-blocking", iface.name()), e))?;
+        let poll = Poll::new()
+            .map_err(|e| CmioError::io("create the interface poll", e))?;
+        poll.register(&EventedFd(&iface.as_raw_fd()), TAP_TOKEN, Ready::readable(), PollOpt::level())
+            .map_err(|e| CmioError::io(format!("watch {}", iface.name()), e))?;
+        info!("Created host interface {}", iface.name());
+        
+        let batches = HostBatches::new(link.rx_length());
+        Ok(Self { iface, poll, link, batches, buffer: vec![0; MAX_FRAME_SIZE], wait: DEFAULT_HOST_WAIT })
+    }
+    
+    /// Set how long a yield bringing no frames waits for frames on the TAP interface
+    pub fn set_wait(&mut self, wait: Duration) {
+        self.wait = wait;
+    }
+    
+    /// Traffic statistics, with TX counting frames sent to the guest and RX frames
+    /// written to the TAP interface
+    pub fn stats(&self) -> NetworkStats {
+        self.batches.stats()
+    }
+    
+    /// Answer the guest's yields until it goes away or shutdown is requested
+    pub fn run_loop(&mut self) -> Result<(), CmioError> {
+        let mut events = Events::with_capacity(1);
+        while !shutdown::requested() {
+            let request = match self.link.recv() {
+                Ok(request) => request,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    info!("The guest closed the CMIO link");
+                    break;
+                }
+                Err(e) => return Err(CmioError::io("receive a yield from the guest", e)),
+            };
+            
+            let (reason, batch) = self.answer(&request, &mut events)?;
+            self.link.respond(reason, &batch)
+                .map_err(|e| CmioError::io("respond to the guest", e))?;
+            self.batches.recycle(batch);
+        }
+        
+        let stats = self.stats();
+        info!("Host driver stopped: {} frames to the guest, {} from it", stats.tx_frames, stats.rx_frames);
+        Ok(())
+    }
+    
+    /// Write the frames of a network yield to the TAP interface and return the response
+    /// carrying the next batch for the guest
+    /// 
+    /// Yields of other reasons, which belong to other subsystems, are resumed with an
+    /// empty response of their own reason.
+    fn answer(&mut self, request: &EmuYield, events: &mut Events) -> Result<(u16, Vec<u8>), CmioError> {
+        if request.cmd != YieldCommand::Manual as u8 || YieldReason::from_code(request.reason) != YieldReason::TapRxTx {
+            debug!("Resuming yield with reason {:#06x} without data", request.reason);
+            return Ok((request.reason, Vec::new()));
+        }
+        
+        let frames = self.batches.receive(&request.data);
+        for frame in &frames {
+            // A down interface refuses frames, which are then lost as on a cable
+            if let Err(e) = self.iface.send(frame) {
+                warn!("Failed to write {} bytes to {}: {}", frame.len(), self.iface.name(), e);
+                self.batches.stats.rx_errors += 1;
+            }
+        }
+        
+        if !self.batches.has_pending() {
+            let wait = if frames.is_empty() { self.wait } else { Duration::ZERO };
+            self.read_frames(wait, events)?;
+        }
+        Ok((YieldReason::TapRxTx.code(), self.batches.next_batch()))
+    }
+    
+    /// Wait up to the timeout for the TAP interface to become readable, then queue the
+    /// frames waiting on it for the guest
+    fn read_frames(&mut self, timeout: Duration, events: &mut Events) -> Result<(), CmioError> {
+        self.poll.poll(events, Some(timeout))
+            .map_err(|e| CmioError::io(format!("wait for {}", self.iface.name()), e))?;
+        
+        let mut frames = Vec::new();
+        loop {
+            match self.iface.recv(&mut self.buffer) {
+                Ok(0) => break,
+                Ok(n) => frames.push(self.buffer[..n].to_vec()),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(CmioError::io(format!("read a frame from {}", self.iface.name()), e)),
+            }
+        }
+        self.batches.queue(&frames);
+        Ok(())
+    }
+}
+
+// Kind of connection a socket ID names in the guest's socket manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Unix,
+    Tcp,
+}
+
+// Operation a message of the socket manager answers, by its message type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketOp {
+    Connect(Transport),
+    Send(Transport),
+    Receive(Transport),
+    Close(Transport),
+    // Any of the other message types, which the client doesn't build
+    Other(u8),
+}
+
+impl SocketOp {
+    fn from_msg_type(msg_type: u8) -> Self {
+        match msg_type {
+            MSG_TYPE_UNIX_CONNECT => SocketOp::Connect(Transport::Unix),
+            MSG_TYPE_TCP_CONNECT | MSG_TYPE_TCP_CONNECT_HOST => SocketOp::Connect(Transport::Tcp),
+            MSG_TYPE_UNIX_SEND => SocketOp::Send(Transport::Unix),
+            MSG_TYPE_TCP_SEND => SocketOp::Send(Transport::Tcp),
+            MSG_TYPE_UNIX_RECEIVE => SocketOp::Receive(Transport::Unix),
+            MSG_TYPE_TCP_RECEIVE => SocketOp::Receive(Transport::Tcp),
+            MSG_TYPE_UNIX_CLOSE => SocketOp::Close(Transport::Unix),
+            MSG_TYPE_TCP_CLOSE => SocketOp::Close(Transport::Tcp),
+            other => SocketOp::Other(other),
+        }
+    }
+}
+
+// Message of the guest's socket manager: the response to a request, or one it sent on its
+// own, such as a close after an idle timeout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocketResponse {
+    pub op: SocketOp,
+    pub socket_id: u32,
+    pub status: SocketStatus,
+    // Raw errno of the failure, 0 on success
+    pub errno: i32,
+    // Address a TCP connect by hostname reached, None for other messages
+    pub addr: Option<SocketAddr>,
+    // Received data, or whatever else follows the status, such as the bytes still queued
+    // after a send
+    pub payload: Vec<u8>,
+}
+
+// Structure building batches of socket proxy requests for the guest (reason code 0x43)
+// and parsing the batches it yields back, so host integrations speak the native message
+// format without reimplementing it
+//
+// Batches are cut to the guest's RX buffer with the continuation flag of the response
+// reason, and chunked responses are joined before they are returned.
+#[derive(Debug, Default)]
+pub struct SocketClient {
+    requests: Vec<u8>,
+    // Data of chunked responses until their last chunk, by message type and socket ID
+    partial: HashMap<(u8, u32), Vec<u8>>,
+}
+
+impl SocketClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    
+    /// Queue a connect to a Unix socket path of the guest, a leading NUL byte selecting
+    /// the abstract namespace
+    /// 
+    /// Fails with InvalidArgument if the path is longer than 255 bytes.
+    pub fn connect_unix(&mut self, socket_id: u32, path: &[u8]) -> Result<(), CmioError> {
+        self.push(SocketMessage::new(MSG_TYPE_UNIX_CONNECT, socket_id, path.to_vec(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, Vec::new()))
+    }
+    
+    /// Queue a TCP connect to an address
+    pub fn connect_tcp(&mut self, socket_id: u32, addr: SocketAddr) {
+        self.queue(SocketMessage::new(MSG_TYPE_TCP_CONNECT, socket_id, Vec::new(), addr.ip(), addr.port(), Vec::new()));
+    }
+    
+    /// Queue a TCP connect to a hostname the guest resolves, whose response carries the
+    /// address it reached
+    /// 
+    /// Fails with InvalidArgument if the hostname is longer than 255 bytes.
+    pub fn connect_host(&mut self, socket_id: u32, host: &str, port: u16) -> Result<(), CmioError> {
+        self.push(SocketMessage::new(MSG_TYPE_TCP_CONNECT_HOST, socket_id, host.as_bytes().to_vec(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), port, Vec::new()))
+    }
+    
+    /// Queue data to send on a connection
+    pub fn send(&mut self, transport: Transport, socket_id: u32, data: &[u8]) {
+        let msg_type = match transport {
+            Transport::Unix => MSG_TYPE_UNIX_SEND,
+            Transport::Tcp => MSG_TYPE_TCP_SEND,
+        };
+        self.queue(SocketMessage::new(msg_type, socket_id, Vec::new(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, data.to_vec()));
+    }
+    
+    /// Queue a read of up to max_len bytes from a connection, 0 for the guest's default
+    pub fn receive(&mut self, transport: Transport, socket_id: u32, max_len: u32) {
+        let msg_type = match transport {
+            Transport::Unix => MSG_TYPE_UNIX_RECEIVE,
+            Transport::Tcp => MSG_TYPE_TCP_RECEIVE,
+        };
+        self.queue(SocketMessage::new(msg_type, socket_id, Vec::new(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, max_len.to_be_bytes().to_vec()));
+    }
+    
+    /// Queue the close of a connection
+    pub fn close(&mut self, transport: Transport, socket_id: u32) {
+        let msg_type = match transport {
+            Transport::Unix => MSG_TYPE_UNIX_CLOSE,
+            Transport::Tcp => MSG_TYPE_TCP_CLOSE,
+        };
+        self.queue(SocketMessage::new(msg_type, socket_id, Vec::new(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, Vec::new()));
+    }
+    
+    /// Queue a message of any type, such as one of a handler registered in the guest
+    /// 
+    /// Fails with InvalidArgument if its path or hostname is longer than 255 bytes.
+    pub fn push(&mut self, message: SocketMessage) -> Result<(), CmioError> {
+        self.requests.extend_from_slice(&message.serialize()?);
+        Ok(())
+    }
+    
+    // Queue a message without a path or hostname, which always serializes
+    fn queue(&mut self, message: SocketMessage) {
+        self.requests.extend_from_slice(&message.serialize().expect("message without a name serializes"));
+    }
+    
+    /// Whether requests are waiting to be sent
+    pub fn has_requests(&self) -> bool {
+        !self.requests.is_empty()
+    }
+    
+    /// Take the queued requests that fit an RX buffer of max_len bytes, with the reason
+    /// code of the response carrying them
+    /// 
+    /// When requests are left over, the reason has the continuation flag set and the
+    /// guest waits for the rest in the next response before handling the batch.
+    pub fn take_batch(&mut self, max_len: usize) -> (u16, Vec<u8>) {
+        let reason = YieldReason::UnixSocket.code();
+        if self.requests.len() <= max_len {
+            return (reason, std::mem::take(&mut self.requests));
+        }
+        let rest = self.requests.split_off(max_len);
+        (reason | RX_FLAG_CONTINUED, std::mem::replace(&mut self.requests, rest))
+    }
+    
+    /// Parse a batch yielded by the guest's socket manager into its messages
+    /// 
+    /// Chunks of a message are kept until its last one arrives, in this batch or a later
+    /// one. Fails with ProtocolError for a malformed message or one without a status.
+    pub fn parse_batch(&mut self, batch: &[u8]) -> Result<Vec<SocketResponse>, CmioError> {
+        let mut responses = Vec::new();
+        let mut offset = 0;
+        while offset < batch.len() {
+            let (message, consumed) = SocketMessage::deserialize(&batch[offset..])?;
+            offset += consumed;
+            
+            let key = (message.msg_type, message.socket_id);
+            if message.more {
+                self.partial.entry(key).or_default().extend_from_slice(&message.data);
+                continue;
+            }
+            let data = match self.partial.remove(&key) {
+                Some(mut data) => {
+                    data.extend_from_slice(&message.data);
+                    data
+                }
+                None => message.data,
+            };
+            if data.len() < STATUS_SIZE {
+                return Err(CmioError::ProtocolError(format!("message type {:#04x} for socket {} has no status", message.msg_type, message.socket_id)));
+            }
+            
+            let addr = match message.msg_type {
+                MSG_TYPE_TCP_CONNECT_HOST => Some(SocketAddr::new(message.ip_addr, message.port)),
+                _ => None,
+            };
+            responses.push(SocketResponse {
+                op: SocketOp::from_msg_type(message.msg_type),
+                socket_id: message.socket_id,
+                status: SocketStatus::from_code(data[0]),
+                errno: i32::from_be_bytes([data[1], data[2], data[3], data[4]]),
+                addr,
+                payload: data[STATUS_SIZE..].to_vec(),
+            });
+        }
+        Ok(responses)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use crate::multiplexer::Subsystem;
+    use crate::network::PACKET_INFO_SIZE;
+    use crate::unix_tcp_socket::SocketManager;
+    use std::io::{Read, Write};
+
+    // Ethernet frame with packet info, as read from a TAP interface
+    fn frame(size: usize, fill: u8) -> Vec<u8> {
+        let mut frame = vec![0, 0, 0x08, 0x00];
+        frame.resize(PACKET_INFO_SIZE + size, fill);
+        frame
+    }
+
+    #[test]
+    fn test_batches_both_ways() {
+        let mut guest = HostBatches::new(512);
+        let mut host = HostBatches::new(512);
+
+        // Frames of the guest, one of them in fragments, arrive whole
+        let frames = vec![frame(60, 1), frame(1400, 2), frame(100, 3)];
+        guest.queue(&frames);
+        let mut received = Vec::new();
+        while guest.has_pending() {
+            received.extend(host.receive(&guest.next_batch()));
+        }
+        assert_eq!(received, frames);
+        assert_eq!((host.stats().rx_frames, guest.stats().tx_fragments), (3, 3));
+
+        // Batches for the guest fit its buffer, and there are none left after them
+        host.queue(&frames);
+        while host.has_pending() {
+            assert!(host.next_batch().len() <= 512);
+        }
+        assert!(host.next_batch().is_empty());
+        assert!(host.receive(&[]).is_empty());
+        assert_eq!(host.stats().rx_bad_batches, 1);
+    }
+
+    #[test]
+    fn test_socket_client() {
+        let mut manager = SocketManager::for_multiplexer(4096).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = SocketClient::new();
+        client.connect_tcp(5, listener.local_addr().unwrap());
+        client.send(Transport::Tcp, 5, b"ping");
+
+        // A batch cut short waits for the rest before it is handled
+        let exchange = |client: &mut SocketClient, manager: &mut SocketManager| {
+            while client.has_requests() {
+                let (reason, data) = client.take_batch(16);
+                manager.handle_rx(&data, reason).unwrap();
+                assert_eq!(reason & RX_FLAG_CONTINUED == 0, !client.has_requests());
+            }
+            let mut batch = Vec::new();
+            manager.poll_tx(&mut batch, 4096).unwrap();
+            client.parse_batch(&batch).unwrap()
+        };
+        let responses = exchange(&mut client, &mut manager);
+        assert_eq!(responses.iter().map(|r| (r.op, r.socket_id, r.status)).collect::<Vec<_>>(), vec![
+            (SocketOp::Connect(Transport::Tcp), 5, SocketStatus::Success),
+            (SocketOp::Send(Transport::Tcp), 5, SocketStatus::Success),
+        ]);
+
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut received = [0u8; 4];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+        peer.write_all(b"pong").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        client.receive(Transport::Tcp, 5, 0);
+        client.close(Transport::Tcp, 5);
+        let responses = exchange(&mut client, &mut manager);
+        assert_eq!(responses[0].payload, b"pong");
+        assert_eq!((responses[1].op, responses[1].status), (SocketOp::Close(Transport::Tcp), SocketStatus::Success));
+    }
+
+    #[test]
+    fn test_parse_chunked_response() {
+        let mut message = SocketMessage::new(MSG_TYPE_UNIX_RECEIVE, 9, Vec::new(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, vec![0, 0, 0, 0, 0, b'a']);
+        message.more = true;
+        let mut last = message.clone();
+        last.more = false;
+        last.data = b"bc".to_vec();
+
+        let mut client = SocketClient::new();
+        assert!(client.parse_batch(&message.serialize().unwrap()).unwrap().is_empty());
+        let responses = client.parse_batch(&last.serialize().unwrap()).unwrap();
+        assert_eq!((responses[0].op, responses[0].status), (SocketOp::Receive(Transport::Unix), SocketStatus::Success));
+        assert_eq!(responses[0].payload, b"abc");
+        assert!(client.parse_batch(&[MSG_TYPE_UNIX_CLOSE, 0, 0, 0, 1, 0, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tracing::{debug, info, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+
+// Reason code HTTP requests are handed to the host on, and answered on
+pub const HTTP_REQUEST_REASON: YieldReason = YieldReason::Other(0x4C);
+
+// Address guest programs reach the proxy on, e.g. with http_proxy=http://127.0.0.1:3128
+pub const DEFAULT_HTTP_REQUEST_LISTEN: &str = "127.0.0.1:3128";
+
+// Request of the guest for the host to make
+const HTTP_REQUEST: u8 = 0x01;
+
+// Answer of the host with the response it got
+const HTTP_RESPONSE: u8 = 0x81;
+
+// Upper bound on the size of a request line and headers from a guest program
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+// Size of the buffer requests are read from guest programs with
+const READ_CHUNK: usize = 16 * 1024;
+
+// Headers that only concern one connection, which are not passed on
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection", "keep-alive", "proxy-authorization", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade",
+];
+
+// An HTTP request made by a guest program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    // Absolute URL, such as http://example.com/path
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// The response the host got for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// Append bytes preceded by their length (u16 BE)
+fn put_short(message: &mut Vec<u8>, bytes: &[u8]) {
+    message.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    message.extend_from_slice(bytes);
+}
+
+// Append headers: their count (u16 BE), then the name and value of each
+fn put_headers(message: &mut Vec<u8>, headers: &[(String, String)]) {
+    message.extend_from_slice(&(headers.len() as u16).to_be_bytes());
+    for (name, value) in headers {
+        put_short(message, name.as_bytes());
+        put_short(message, value.as_bytes());
+    }
+}
+
+// Reader of the fields of a message
+struct FieldReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], CmioError> {
+        let bytes = self.data.get(self.offset..self.offset + length)
+            .ok_or_else(|| CmioError::ProtocolError(format!("HTTP message truncated at offset {}", self.offset)))?;
+        self.offset += length;
+        Ok(bytes)
+    }
+    
+    fn u16(&mut self) -> Result<u16, CmioError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    
+    fn u32(&mut self) -> Result<u32, CmioError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+    
+    fn short(&mut self) -> Result<String, CmioError> {
+        let length = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+    
+    fn headers(&mut self) -> Result<Vec<(String, String)>, CmioError> {
+        (0..self.u16()?).map(|_| Ok((self.short()?, self.short()?))).collect()
+    }
+}
+
+impl HttpRequest {
+    /// Serialize the request for the host: the message type, request ID (u32 BE), method
+    /// and URL, headers, then the body preceded by its length (u32 BE)
+    pub fn encode(&self, id: u32) -> Vec<u8> {
+        let mut message = vec![HTTP_REQUEST];
+        message.extend_from_slice(&id.to_be_bytes());
+        put_short(&mut message, self.method.as_bytes());
+        put_short(&mut message, self.url.as_bytes());
+        put_headers(&mut message, &self.headers);
+        message.extend_from_slice(&(self.body.len() as u32).to_be_bytes());
+        message.extend_from_slice(&self.body);
+        message
+    }
+    
+    /// Parse a request as the host receives it, returning the request ID and its size too
+    #[cfg(all(test, not(target_arch = "riscv64")))]
+    fn decode(data: &[u8]) -> Result<(u32, Self, usize), CmioError> {
+        let mut reader = FieldReader { data, offset: 1 };
+        let id = reader.u32()?;
+        let method = reader.short()?;
+        let url = reader.short()?;
+        let headers = reader.headers()?;
+        let length = reader.u32()? as usize;
+        let body = reader.bytes(length)?.to_vec();
+        Ok((id, Self { method, url, headers, body }, reader.offset))
+    }
+}
+
+impl HttpResponse {
+    /// Parse a response from the host: the message type, request ID (u32 BE), status
+    /// (u16 BE), headers, then the body preceded by its length (u32 BE), returning the
+    /// request ID and its size too
+    fn decode(data: &[u8]) -> Result<(u32, Self, usize), CmioError> {
+        if data[0] != HTTP_RESPONSE {
+            return Err(CmioError::ProtocolError(format!("invalid HTTP message type {:#04x}", data[0])));
+        }
+        let mut reader = FieldReader { data, offset: 1 };
+        let id = reader.u32()?;
+        let status = reader.u16()?;
+        let headers = reader.headers()?;
+        let length = reader.u32()? as usize;
+        let body = reader.bytes(length)?.to_vec();
+        Ok((id, Self { status, headers, body }, reader.offset))
+    }
+    
+    /// Serialize the response as the host sends it
+    #[cfg(all(test, not(target_arch = "riscv64")))]
+    fn encode(&self, id: u32) -> Vec<u8> {
+        let mut message = vec![HTTP_RESPONSE];
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&self.status.to_be_bytes());
+        put_headers(&mut message, &self.headers);
+        message.extend_from_slice(&(self.body.len() as u32).to_be_bytes());
+        message.extend_from_slice(&self.body);
+        message
+    }
+    
+    /// Write the response as HTTP/1.1 for the guest program, closing the connection after
+    /// the body
+    fn to_http(&self) -> Vec<u8> {
+        let mut http = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status)).into_bytes();
+        for (name, value) in &self.headers {
+            let lower = name.to_ascii_lowercase();
+            if lower != "content-length" && !HOP_BY_HOP_HEADERS.contains(&lower.as_str()) {
+                http.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+            }
+        }
+        http.extend_from_slice(format!("Content-Length: {}\r\nConnection: close\r\n\r\n", self.body.len()).as_bytes());
+        http.extend_from_slice(&self.body);
+        http
+    }
+    
+    /// A response the proxy makes up itself, for requests it can't pass on
+    fn error(status: u16) -> Self {
+        Self { status, headers: Vec::new(), body: format!("{} {}\n", status, reason_phrase(status)).into_bytes() }
+    }
+}
+
+// Reason phrase of the common status codes
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "",
+    }
+}
+
+// Parse the request a guest program sent once it is complete, up to max_size bytes
+//
+// Returns None while more is needed, or the status to answer with if the request can't
+// be passed on: CONNECT tunnels, chunked bodies and requests that don't fit a yield.
+fn parse_request(data: &[u8], max_size: usize) -> Result<Option<HttpRequest>, u16> {
+    let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") else {
+        return if data.len() > MAX_HEADER_SIZE { Err(400) } else { Ok(None) };
+    };
+    let head = std::str::from_utf8(&data[..end]).map_err(|_| 400u16)?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(version)) = (request_line.next(), request_line.next(), request_line.next()) else {
+        return Err(400);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(400);
+    }
+    if method == "CONNECT" {
+        return Err(501);
+    }
+    
+    let mut headers = Vec::new();
+    let mut host = None;
+    let mut content_length = 0;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(400u16)?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "host" => host = Some(value.to_string()),
+            "content-length" => content_length = value.parse::<usize>().map_err(|_| 400u16)?,
+            "transfer-encoding" => return Err(411),
+            lower if HOP_BY_HOP_HEADERS.contains(&lower) => continue,
+            _ => {}
+        }
+        headers.push((name.to_string(), value.to_string()));
+    }
+    
+    // Programs talking to a proxy give absolute URLs, others only a path and the host
+    let url = match (target.starts_with('/'), host) {
+        (false, _) => target.to_string(),
+        (true, Some(host)) => format!("http://{}{}", host, target),
+        (true, None) => return Err(400),
+    };
+    let body_start = end + 4;
+    if data.len() < body_start + content_length {
+        return if body_start + content_length > max_size { Err(413) } else { Ok(None) };
+    }
+    let request = HttpRequest {
+        method: method.to_string(),
+        url,
+        headers,
+        body: data[body_start..body_start + content_length].to_vec(),
+    };
+    Ok(Some(request))
+}
+
+// Write all of the data to a non-blocking stream
+fn write_all(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < data.len() {
+        match stream.write(&data[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// A guest program's connection, carrying one request
+struct Connection {
+    stream: TcpStream,
+    received: Vec<u8>,
+    // Set once the request is handed to the host
+    sent: bool,
+}
+
+// Structure letting the host make HTTP requests for guest programs, as a subsystem of
+// the multiplexer on HTTP_REQUEST_REASON, without a network stack in the guest
+//
+// Guest programs use it as an HTTP proxy on a local TCP address. Each connection carries
+// one request, which is handed to the host in an HTTP_REQUEST message with the method,
+// absolute URL, headers and body; the host answers with an HTTP_RESPONSE carrying the
+// status, headers and body, which is written back before the connection is closed.
+// Requests and responses must fit a single yield.
+pub struct HttpRequestProxy {
+    listener: TcpListener,
+    connections: HashMap<u32, Connection>,
+    next_id: u32,
+    // Requests waiting for room in a yield
+    messages: VecDeque<Vec<u8>>,
+    max_message_size: usize,
+}
+
+impl HttpRequestProxy {
+    /// Listen for guest programs on an address, limiting requests to the CMIO buffer size
+    pub fn bind(address: &str, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| CmioError::io(format!("listen on {}", address), e))?;
+        listener.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make {} non-blocking", address), e))?;
+        Ok(Self {
+            listener,
+            connections: HashMap::new(),
+            next_id: 0,
+            messages: VecDeque::new(),
+            max_message_size: cmio_max_buffer_size,
+        })
+    }
+    
+    /// The address guest programs connect to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+    
+    /// Accept guest programs connecting
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    if stream.set_nonblocking(true).is_err() {
+                        continue;
+                    }
+                    self.next_id = self.next_id.wrapping_add(1);
+                    debug!("HTTP request connection {} from {}", self.next_id, peer);
+                    self.connections.insert(self.next_id, Connection { stream, received: Vec::new(), sent: false });
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept an HTTP request connection: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+    
+    /// Read the requests of the connections, queueing the complete ones for the host and
+    /// answering those that can't be passed on
+    fn read_requests(&mut self) {
+        let mut closed = Vec::new();
+        let mut chunk = [0u8; READ_CHUNK];
+        let max_size = self.max_message_size;
+        for (&id, connection) in self.connections.iter_mut().filter(|(_, connection)| !connection.sent) {
+            let result = loop {
+                match connection.stream.read(&mut chunk) {
+                    Ok(0) => break Err(()),
+                    Ok(n) => connection.received.extend_from_slice(&chunk[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => break Err(()),
+                }
+            };
+            if result.is_err() {
+                closed.push(id);
+                continue;
+            }
+            let parsed = parse_request(&connection.received, max_size).and_then(|request| match request {
+                Some(request) => {
+                    let message = request.encode(id);
+                    if message.len() > max_size { Err(413) } else { Ok(Some((request, message))) }
+                }
+                None => Ok(None),
+            });
+            match parsed {
+                Ok(Some((request, message))) => {
+                    info!("HTTP request {}: {} {}", id, request.method, request.url);
+                    self.messages.push_back(message);
+                    connection.sent = true;
+                }
+                Ok(None) => {}
+                Err(status) => {
+                    debug!("Answering HTTP request {} with {}", id, status);
+                    let _ = write_all(&mut connection.stream, &HttpResponse::error(status).to_http());
+                    closed.push(id);
+                }
+            }
+        }
+        for id in closed {
+            self.connections.remove(&id);
+        }
+    }
+}
+
+impl Subsystem for HttpRequestProxy {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        self.accept();
+        self.read_requests();
+        while let Some(message) = self.messages.front() {
+            if buffer.len() + message.len() > max_len {
+                break;
+            }
+            buffer.extend_from_slice(message);
+            self.messages.pop_front();
+        }
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            // The rest of a batch can't be framed past a malformed response
+            let (id, response, size) = match HttpResponse::decode(&data[offset..]) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    warn!("Dropping {} bytes of HTTP responses: {}", data.len() - offset, e);
+                    break;
+                }
+            };
+            offset += size;
+            let Some(mut connection) = self.connections.remove(&id) else {
+                debug!("Response for HTTP request {}, whose connection is gone", id);
+                continue;
+            };
+            debug!("HTTP request {} answered with {}", id, response.status);
+            if let Err(e) = write_all(&mut connection.stream, &response.to_http()) {
+                warn!("Failed to write the response to HTTP request {}: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Send a request through the proxy, returning what the host gets
+    fn request(proxy: &mut HttpRequestProxy, client: &mut TcpStream, request: &[u8]) -> Vec<u8> {
+        client.write_all(request).unwrap();
+        let mut buffer = Vec::new();
+        for _ in 0..40 {
+            proxy.poll_tx(&mut buffer, 4096).unwrap();
+            if !buffer.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        buffer
+    }
+
+    // Read a response up to the end of the connection
+    fn response(client: &mut TcpStream) -> String {
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_http_request_proxy() {
+        let mut proxy = HttpRequestProxy::bind("127.0.0.1:0", 4096).unwrap();
+        let address = proxy.local_addr().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // The request reaches the host without hop-by-hop headers
+        let message = request(&mut proxy, &mut client,
+            b"POST /api HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\nProxy-Connection: keep-alive\r\n\r\nhello");
+        let (id, sent, size) = HttpRequest::decode(&message).unwrap();
+        assert_eq!(size, message.len());
+        assert_eq!(sent, HttpRequest {
+            method: "POST".to_string(),
+            url: "http://example.com/api".to_string(),
+            headers: vec![("Host".to_string(), "example.com".to_string()), ("Content-Length".to_string(), "5".to_string())],
+            body: b"hello".to_vec(),
+        });
+
+        // The host's response goes back as HTTP/1.1, once one comes through whole
+        let answer = HttpResponse {
+            status: 201,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string()), ("Transfer-Encoding".to_string(), "chunked".to_string())],
+            body: b"done".to_vec(),
+        };
+        proxy.handle_rx(&answer.encode(id)[..8], HTTP_REQUEST_REASON.code()).unwrap();
+        proxy.handle_rx(&[0x7f], HTTP_REQUEST_REASON.code()).unwrap();
+        proxy.handle_rx(&answer.encode(id), HTTP_REQUEST_REASON.code()).unwrap();
+        assert_eq!(response(&mut client),
+            "HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndone");
+
+        // Tunnels and requests without a host are refused right away
+        let mut client = TcpStream::connect(address).unwrap();
+        assert!(request(&mut proxy, &mut client, b"CONNECT example.com:443 HTTP/1.1\r\n\r\n").is_empty());
+        assert!(response(&mut client).starts_with("HTTP/1.1 501 "));
+        let mut client = TcpStream::connect(address).unwrap();
+        assert!(request(&mut proxy, &mut client, b"GET / HTTP/1.1\r\n\r\n").is_empty());
+        assert!(response(&mut client).starts_with("HTTP/1.1 400 "));
+        assert_eq!(parse_request(b"PUT http://example.com/ HTTP/1.1\r\nContent-Length: 9000\r\n\r\n", 4096), Err(413));
+    }
+}
@@ -0,0 +1,117 @@
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+
+// First wait of the exponential backoff, doubled after every further idle round
+const BACKOFF_START: Duration = Duration::from_millis(1);
+
+// How a run loop waits before yielding when there is nothing to send or receive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleStrategy {
+    // Yield again right away, for the lowest latency at the cost of spinning
+    Immediate,
+    // Sleep for a fixed time before every idle yield
+    Sleep(Duration),
+    // Sleep for a time doubling with each idle round in a row, up to a cap
+    Backoff(Duration),
+    // Block until local sockets or interfaces become readable, up to a timeout
+    Poll(Duration),
+}
+
+// Error returned for idle strategies that can't be parsed
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid idle strategy: {0}, expected immediate, sleep:<ms>, backoff:<ms> or poll:<ms>")]
+pub struct ParseIdleStrategyError(String);
+
+impl FromStr for IdleStrategy {
+    type Err = ParseIdleStrategyError;
+    
+    /// Parse a strategy written as `immediate`, `sleep:<ms>`, `backoff:<max ms>` or
+    /// `poll:<timeout ms>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseIdleStrategyError(s.to_string());
+        if s == "immediate" {
+            return Ok(IdleStrategy::Immediate);
+        }
+        let (kind, millis) = s.split_once(':').ok_or_else(error)?;
+        let duration = Duration::from_millis(millis.parse().map_err(|_| error())?);
+        match kind {
+            "sleep" => Ok(IdleStrategy::Sleep(duration)),
+            "backoff" => Ok(IdleStrategy::Backoff(duration)),
+            "poll" => Ok(IdleStrategy::Poll(duration)),
+            _ => Err(error()),
+        }
+    }
+}
+
+// What an idle round should do before yielding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleWait {
+    None,
+    Sleep(Duration),
+    Poll(Duration),
+}
+
+// Structure tracking consecutive idle rounds for a strategy
+#[derive(Debug, Clone)]
+pub struct IdleState {
+    strategy: IdleStrategy,
+    backoff: Duration,
+}
+
+impl IdleState {
+    pub fn new(strategy: IdleStrategy) -> Self {
+        Self { strategy, backoff: BACKOFF_START }
+    }
+    
+    /// The configured strategy
+    pub fn strategy(&self) -> IdleStrategy {
+        self.strategy
+    }
+    
+    /// How to wait in the current idle round, advancing the backoff for the next one
+    pub fn next_wait(&mut self) -> IdleWait {
+        match self.strategy {
+            IdleStrategy::Immediate => IdleWait::None,
+            IdleStrategy::Sleep(duration) => IdleWait::Sleep(duration),
+            IdleStrategy::Poll(timeout) => IdleWait::Poll(timeout),
+            IdleStrategy::Backoff(max) => {
+                let wait = self.backoff.min(max);
+                self.backoff = (self.backoff * 2).min(max);
+                IdleWait::Sleep(wait)
+            }
+        }
+    }
+    
+    /// Start the backoff over after a round that sent or received something
+    pub fn reset(&mut self) {
+        self.backoff = BACKOFF_START;
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("immediate".parse(), Ok(IdleStrategy::Immediate));
+        assert_eq!("sleep:5".parse(), Ok(IdleStrategy::Sleep(Duration::from_millis(5))));
+        assert_eq!("backoff:50".parse(), Ok(IdleStrategy::Backoff(Duration::from_millis(50))));
+        assert_eq!("poll:10".parse(), Ok(IdleStrategy::Poll(Duration::from_millis(10))));
+        assert!("poll".parse::<IdleStrategy>().is_err());
+        assert!("spin:1".parse::<IdleStrategy>().is_err());
+        assert!("sleep:x".parse::<IdleStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut state = IdleState::new(IdleStrategy::Backoff(Duration::from_millis(5)));
+        let waits: Vec<IdleWait> = (0..5).map(|_| state.next_wait()).collect();
+        let expected: Vec<IdleWait> = [1, 2, 4, 5, 5].iter().map(|&ms| IdleWait::Sleep(Duration::from_millis(ms))).collect();
+        assert_eq!(waits, expected);
+
+        state.reset();
+        assert_eq!(state.next_wait(), IdleWait::Sleep(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,221 @@
+use std::net::Ipv6Addr;
+
+// EtherType of IPv6 packets
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+// Size of the fixed IPv6 header and of an Ethernet header without VLAN tag
+const IPV6_HEADER_SIZE: usize = 40;
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+// Next header values of extension headers skipped to reach the upper-layer protocol
+const NEXT_HEADER_HOP_BY_HOP: u8 = 0;
+const NEXT_HEADER_ROUTING: u8 = 43;
+const NEXT_HEADER_DESTINATION_OPTIONS: u8 = 60;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+// ICMPv6 message types and NDP details
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+const NDP_HOP_LIMIT: u8 = 255;
+const NDP_OPTION_TARGET_LINK_ADDRESS: u8 = 2;
+const NA_FLAG_SOLICITED: u8 = 0x40;
+const NA_FLAG_OVERRIDE: u8 = 0x20;
+// Type, code, checksum and reserved bytes before the target address
+const NDP_MESSAGE_SIZE: usize = 4 + 4 + 16;
+
+// Structure describing an IPv6 header, with the extension headers skipped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6Header {
+    // Upper-layer protocol following any extension headers
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub source: Ipv6Addr,
+    pub destination: Ipv6Addr,
+    // Offset of the upper-layer payload in the packet
+    pub payload_offset: usize,
+}
+
+// Read an IPv6 address at offset
+fn address(packet: &[u8], offset: usize) -> Option<Ipv6Addr> {
+    let octets: [u8; 16] = packet.get(offset..offset + 16)?.try_into().ok()?;
+    Some(Ipv6Addr::from(octets))
+}
+
+/// Parse the header of an IPv6 packet, following hop-by-hop, routing and destination
+/// options extension headers to the upper-layer protocol
+pub fn parse_header(packet: &[u8]) -> Option<Ipv6Header> {
+    if packet.len() < IPV6_HEADER_SIZE || packet[0] >> 4 != 6 {
+        return None;
+    }
+    
+    let mut next_header = packet[6];
+    let mut payload_offset = IPV6_HEADER_SIZE;
+    while matches!(next_header, NEXT_HEADER_HOP_BY_HOP | NEXT_HEADER_ROUTING | NEXT_HEADER_DESTINATION_OPTIONS) {
+        // Next header and length in 8-byte units, not counting the first 8 bytes
+        let extension = packet.get(payload_offset..payload_offset + 2)?;
+        next_header = extension[0];
+        payload_offset += (extension[1] as usize + 1) * 8;
+    }
+    
+    Some(Ipv6Header {
+        next_header,
+        hop_limit: packet[7],
+        source: address(packet, 8)?,
+        destination: address(packet, 24)?,
+        payload_offset,
+    })
+}
+
+// Compute the ICMPv6 checksum of a message over the IPv6 pseudo-header
+fn icmpv6_checksum(source: &Ipv6Addr, destination: &Ipv6Addr, message: &[u8]) -> u16 {
+    let mut pseudo_header = Vec::with_capacity(40);
+    pseudo_header.extend_from_slice(&source.octets());
+    pseudo_header.extend_from_slice(&destination.octets());
+    pseudo_header.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    pseudo_header.extend_from_slice(&[0, 0, 0, IPPROTO_ICMPV6]);
+    
+    let mut sum: u32 = 0;
+    for data in [pseudo_header.as_slice(), message] {
+        for chunk in data.chunks(2) {
+            sum += u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)]) as u32;
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Answer a neighbor solicitation for the target address on behalf of its owner
+///
+/// Takes an Ethernet frame and, if it carries a neighbor solicitation for the target,
+/// returns the Ethernet frame of a neighbor advertisement announcing the MAC address as
+/// the target's link-layer address. Any other frame yields None.
+pub fn neighbor_advertisement(frame: &[u8], target: &Ipv6Addr, mac: &[u8; 6]) -> Option<Vec<u8>> {
+    if frame.len() < ETHERNET_HEADER_SIZE || frame[12..14] != ETHERTYPE_IPV6.to_be_bytes() {
+        return None;
+    }
+    let packet = &frame[ETHERNET_HEADER_SIZE..];
+    let header = parse_header(packet)?;
+    let message = packet.get(header.payload_offset..header.payload_offset + NDP_MESSAGE_SIZE)?;
+    if header.next_header != IPPROTO_ICMPV6 || header.hop_limit != NDP_HOP_LIMIT || message[0] != ICMPV6_NEIGHBOR_SOLICITATION {
+        return None;
+    }
+    if address(message, 8)? != *target {
+        return None;
+    }
+    
+    // Solicitations from an unspecified address, part of duplicate address detection,
+    // are answered to all nodes without the solicited flag
+    let (destination, flags) = if header.source.is_unspecified() {
+        (Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1), NA_FLAG_OVERRIDE)
+    } else {
+        (header.source, NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE)
+    };
+    
+    let mut advertisement = vec![ICMPV6_NEIGHBOR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0];
+    advertisement.extend_from_slice(&target.octets());
+    advertisement.extend_from_slice(&[NDP_OPTION_TARGET_LINK_ADDRESS, 1]); // Length in 8-byte units
+    advertisement.extend_from_slice(mac);
+    let checksum = icmpv6_checksum(target, &destination, &advertisement);
+    advertisement[2..4].copy_from_slice(&checksum.to_be_bytes());
+    
+    let mut reply = Vec::with_capacity(ETHERNET_HEADER_SIZE + IPV6_HEADER_SIZE + advertisement.len());
+    reply.extend_from_slice(&frame[6..12]); // Back to the soliciting node
+    reply.extend_from_slice(mac);
+    reply.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+    reply.extend_from_slice(&[0x60, 0, 0, 0]); // Version, traffic class and flow label
+    reply.extend_from_slice(&(advertisement.len() as u16).to_be_bytes());
+    reply.extend_from_slice(&[IPPROTO_ICMPV6, NDP_HOP_LIMIT]);
+    reply.extend_from_slice(&target.octets());
+    reply.extend_from_slice(&destination.octets());
+    reply.extend_from_slice(&advertisement);
+    Some(reply)
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    const GUEST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+    // Build an IPv6 packet with the given next header and payload
+    fn packet(next_header: u8, hop_limit: u8, source: Ipv6Addr, destination: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x60, 0, 0, 0];
+        packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&[next_header, hop_limit]);
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&destination.octets());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    // Build the Ethernet frame of a neighbor solicitation from the host for target
+    fn solicitation(source: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+        let mut message = vec![ICMPV6_NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&target.octets());
+        let destination: Ipv6Addr = "ff02::1:ff00:15".parse().unwrap();
+
+        let mut frame = vec![0x33, 0x33, 0xff, 0, 0, 0x15];
+        frame.extend_from_slice(&HOST_MAC);
+        frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame.extend_from_slice(&packet(IPPROTO_ICMPV6, NDP_HOP_LIMIT, source, destination, &message));
+        frame
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let source: Ipv6Addr = "fd00::1".parse().unwrap();
+        let destination: Ipv6Addr = "fd00::2".parse().unwrap();
+
+        // A hop-by-hop options header in front of a TCP segment
+        let header = parse_header(&packet(NEXT_HEADER_HOP_BY_HOP, 64, source, destination, &[6, 0, 0, 0, 0, 0, 0, 0])).unwrap();
+        assert_eq!(header, Ipv6Header { next_header: 6, hop_limit: 64, source, destination, payload_offset: 48 });
+
+        assert!(parse_header(&packet(NEXT_HEADER_ROUTING, 64, source, destination, &[6])).is_none());
+        assert!(parse_header(&[0x45; 40]).is_none());
+        assert!(parse_header(&[0x60; 39]).is_none());
+    }
+
+    #[test]
+    fn test_icmpv6_checksum() {
+        let source: Ipv6Addr = "fe80::1".parse().unwrap();
+        let destination: Ipv6Addr = "fe80::2".parse().unwrap();
+        let mut message = vec![128, 0, 0, 0, 0, 1, 0, 1, 0xab];
+        let checksum = icmpv6_checksum(&source, &destination, &message);
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+        // Summing a message with its checksum in place gives zero
+        assert_eq!(icmpv6_checksum(&source, &destination, &message), 0);
+    }
+
+    #[test]
+    fn test_neighbor_advertisement() {
+        let host: Ipv6Addr = "fd00::1".parse().unwrap();
+        let guest: Ipv6Addr = "fd00::15".parse().unwrap();
+
+        let reply = neighbor_advertisement(&solicitation(host, guest), &guest, &GUEST_MAC).unwrap();
+        assert_eq!(&reply[..6], &HOST_MAC);
+        assert_eq!(&reply[6..12], &GUEST_MAC);
+
+        let header = parse_header(&reply[ETHERNET_HEADER_SIZE..]).unwrap();
+        assert_eq!((header.source, header.destination, header.hop_limit), (guest, host, NDP_HOP_LIMIT));
+        let message = &reply[ETHERNET_HEADER_SIZE + header.payload_offset..];
+        assert_eq!(&message[..2], &[ICMPV6_NEIGHBOR_ADVERTISEMENT, 0]);
+        assert_eq!(message[4], NA_FLAG_SOLICITED | NA_FLAG_OVERRIDE);
+        assert_eq!(address(message, 8), Some(guest));
+        assert_eq!(&message[24..], &[NDP_OPTION_TARGET_LINK_ADDRESS, 1, 0x02, 0, 0, 0, 0, 0x02]);
+        assert_eq!(icmpv6_checksum(&guest, &host, message), 0);
+
+        // Duplicate address detection is answered to all nodes
+        let reply = neighbor_advertisement(&solicitation(Ipv6Addr::UNSPECIFIED, guest), &guest, &GUEST_MAC).unwrap();
+        assert_eq!(parse_header(&reply[ETHERNET_HEADER_SIZE..]).unwrap().destination, "ff02::1".parse::<Ipv6Addr>().unwrap());
+
+        // Solicitations for other addresses, or not from a neighbor, are left alone
+        assert!(neighbor_advertisement(&solicitation(host, "fd00::16".parse().unwrap()), &guest, &GUEST_MAC).is_none());
+        let mut routed = solicitation(host, guest);
+        routed[ETHERNET_HEADER_SIZE + 7] = 64;
+        assert!(neighbor_advertisement(&routed, &guest, &GUEST_MAC).is_none());
+    }
+}
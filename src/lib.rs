@@ -0,0 +1,44 @@
+pub mod bench;
+pub mod bridge;
+pub mod cmio;
+#[cfg(feature = "cbor-codec")]
+pub mod codec;
+pub mod console;
+pub mod dhcp;
+pub mod dump;
+#[cfg(feature = "emu")]
+pub mod emu;
+pub mod entropy;
+pub mod exec;
+pub mod filter;
+pub mod gio;
+#[cfg(all(feature = "host", not(target_arch = "riscv64")))]
+pub mod host;
+pub mod http_proxy;
+pub mod http_request;
+pub mod idle;
+pub mod ipv6;
+pub mod multiplexer;
+pub mod netlink;
+pub mod network;
+pub mod ninep;
+pub mod pool;
+pub mod protocol;
+pub mod qos;
+pub mod recording;
+pub mod rollup;
+pub mod selftest;
+pub mod shutdown;
+pub mod socks5;
+pub mod socks5_server;
+#[cfg(feature = "user-stack")]
+pub mod stack;
+pub mod status;
+pub mod timesync;
+pub mod transfer;
+pub mod unix_tcp_socket;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub use cmio::{Cmio, CmioBuilder, CmioCapabilities, CmioError, CmioHandle, CmioRx, CmioStats, CmioTx, CmioYield, RetryPolicy};
+pub use protocol::{YieldCommand, YieldDevice, YieldReason};
@@ -1,99 +1,989 @@
-mod cmio;
-mod network;
-mod unix_tcp_socket;
-
 use std::env;
-use cmio::{Cmio, CmioYield};
-use network::NetworkInterface;
-use unix_tcp_socket::SocketManager;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tracing::{error, info};
+use tapcmio::{Cmio, CmioYield};
+use tapcmio::bench::{self, DEFAULT_BENCH_REASON, DEFAULT_SIZES, MOCK_BUFFER_SIZE};
+use tapcmio::cmio::default_device;
+#[cfg(feature = "emu")]
+use tapcmio::emu::{EmuCmio, EMU_SOCKET_ENV};
+#[cfg(all(feature = "host", not(target_arch = "riscv64")))]
+use tapcmio::host::{self, HostDriver, DEFAULT_HOST_SOCKET, DEFAULT_HOST_TAP_NAME, DEFAULT_HOST_WAIT};
+use tapcmio::console::{PtyConsole, CONSOLE_REASON, DEFAULT_CONSOLE_PROGRAM};
+use tapcmio::dump;
+use tapcmio::entropy::{EntropyFeed, DEFAULT_ENTROPY_BYTES, DEFAULT_ENTROPY_INTERVAL, ENTROPY_REASON};
+use tapcmio::exec::{CommandRunner, DEFAULT_MAX_PROCESSES, EXEC_REASON};
+use tapcmio::filter::{FilterAction, FilterRule};
+use tapcmio::http_proxy::HttpProxy;
+use tapcmio::http_request::{HttpRequestProxy, DEFAULT_HTTP_REQUEST_LISTEN, HTTP_REQUEST_REASON};
+use tapcmio::idle::IdleStrategy;
+use tapcmio::multiplexer::{Multiplexer, Subsystem};
+use tapcmio::network::{NetworkInterface, TapConfig, DEFAULT_TAP_NAME, MIN_BATCH_SIZE};
+use tapcmio::ninep::{NinePProxy, DEFAULT_NINEP_SOCKET, NINEP_REASON};
+use tun_tap::Mode;
+use tapcmio::protocol::YieldReason;
+use tapcmio::recording::{self, ReplayCmio};
+use tapcmio::selftest;
+use tapcmio::shutdown;
+use tapcmio::socks5::Socks5Proxy;
+use tapcmio::socks5_server::{PortForward, Socks5Server, DEFAULT_SOCKS5_LISTEN, SOCKS5_SERVER_REASON};
+use tapcmio::status::{self, Status, DEFAULT_STATUS_FILE, STATUS_FILE_ENV};
+use tapcmio::timesync::{ClockAdjustment, TimeSync, DEFAULT_TIME_INTERVAL, TIME_REASON};
+use tapcmio::transfer::{FileTransfer, TRANSFER_REASON};
+#[cfg(feature = "user-stack")]
+use tapcmio::stack::{StackConfig, UserStack, DEFAULT_STACK_SOCKET};
+use tapcmio::unix_tcp_socket::{SocketManager, UpstreamProxy, DEFAULT_MAX_CONNECTIONS};
+#[cfg(feature = "websocket")]
+use tapcmio::websocket::{WebSocketTunnel, WEBSOCKET_REASON};
+
+// Environment variables, listed below the help of every mode
+const ENVIRONMENT_HELP: &str = "Environment:
+  TAPCMIO_DEVICE        CMIO device node opened unless --device is given (default /dev/cmio)
+  TAPCMIO_STATUS_FILE   Status file used unless --status-file is given (default /run/tapcmio.status)
+  TAPCMIO_SOCKS5_PROXY  Route unix mode TCP connections through [user:password@]host:port
+  TAPCMIO_HTTP_PROXY    Tunnel unix mode TCP connections with HTTP CONNECT through [http://][user:password@]host:port
+  TAPCMIO_IDLE          Idle strategy of unix mode and the modes running one subsystem, such as exec or transfer,
+                        as --idle (default immediate, sleep:100 in time and entropy modes)
+  TAPCMIO_STATS         Log unix mode CMIO yield counters at this interval in seconds
+  TAPCMIO_EMU_SOCKET    Run unix mode against a fake host driver listening on this Unix socket (emu feature)";
+
+#[derive(Parser)]
+#[command(name = "tapcmio", version, about = "TAP CMIO Interface", after_help = ENVIRONMENT_HELP, args_override_self = true, arg_required_else_help = true)]
+struct Cli {
+    /// CMIO device node to open (default $TAPCMIO_DEVICE or /dev/cmio)
+    #[arg(long, global = true, value_name = "PATH", alias = "cmio-device")]
+    device: Option<PathBuf>,
+    
+    /// Largest batch sent in one yield, below the device's TX buffer size
+    #[arg(long, global = true, value_name = "BYTES")]
+    max_batch_size: Option<usize>,
+    
+    /// Least severe level of the log messages printed to stderr; trace includes every CMIO
+    /// yield with the trace-cmio feature
+    #[arg(long, global = true, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Print log messages as JSON objects, one per line, for host tooling to parse
+    #[arg(long, global = true)]
+    log_json: bool,
+    
+    /// Read further options of the mode from a file, one or more per line, after those on
+    /// the command line; lines starting with # are ignored
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Hexdump every buffer sent to and received from the host, with its reason code and a
+    /// timestamp, to stderr or appended to a file
+    #[arg(long, global = true, value_name = "FILE", num_args = 0..=1, require_equals = true)]
+    dump_cmio: Option<Option<PathBuf>>,
+
+    /// Shut down cleanly once no data has been sent or received for this long
+    #[arg(long, global = true, value_name = "SECONDS")]
+    idle_exit: Option<u64>,
+
+    /// Record every buffer sent to and received from the host to a file, for replay
+    #[arg(long, global = true, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// File the running daemon keeps its state in, for the status command (default
+    /// $TAPCMIO_STATUS_FILE or /run/tapcmio.status)
+    #[arg(long, global = true, value_name = "PATH")]
+    status_file: Option<PathBuf>,
+    
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run in network mode (TAP interface)
+    Network(NetworkArgs),
+    /// Run a user-space TCP/IP stack, relaying guest programs' connections without TUN/TAP
+    #[cfg(feature = "user-stack")]
+    Stack(StackArgs),
+    /// Have the host make HTTP requests for guest programs using a local HTTP proxy,
+    /// without a network stack
+    HttpRequest {
+        /// Address guest programs reach the proxy on
+        #[arg(long, value_name = "ADDRESS", default_value = DEFAULT_HTTP_REQUEST_LISTEN)]
+        listen: String,
+    },
+    /// Tunnel guest programs' connections to the host through a local SOCKS5 server,
+    /// without a network stack
+    Socks5 {
+        /// Address guest programs reach the server on
+        #[arg(long, value_name = "ADDRESS", default_value = DEFAULT_SOCKS5_LISTEN)]
+        listen: String,
+        /// Also forward a local port to a destination reached by the host, as
+        /// [ADDRESS:]PORT:HOST:HOSTPORT (may be repeated)
+        #[arg(long = "forward", value_name = "FORWARD")]
+        forwards: Vec<PortForward>,
+    },
+    /// Forward local ports to destinations reached by the host, for programs that can't
+    /// use SOCKS5, without a network stack
+    Forward {
+        /// Forwards as [ADDRESS:]PORT:HOST:HOSTPORT, such as 5432:db.internal:5432
+        #[arg(required = true, value_name = "FORWARD")]
+        forwards: Vec<PortForward>,
+    },
+    /// Serve a filesystem of the host to the guest over 9P2000.L, mounted through a Unix
+    /// socket
+    Ninep {
+        /// Unix socket the guest kernel mounts
+        #[arg(long, value_name = "PATH", default_value = DEFAULT_NINEP_SOCKET)]
+        socket: PathBuf,
+    },
+    /// Fetch the host wall clock periodically, optionally setting the guest clock to it
+    Time {
+        /// Fetch the host clock this often
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_TIME_INTERVAL.as_secs())]
+        interval: u64,
+        /// What to do with the guest clock: monitor, step or slew (default monitor)
+        #[arg(long, value_name = "ADJUSTMENT")]
+        adjust: Option<ClockAdjustment>,
+    },
+    /// Let the host get, put, stat and list files under a directory of the guest
+    Transfer {
+        /// Directory the host's paths are relative to
+        #[arg(long, value_name = "PATH", default_value = "/")]
+        root: PathBuf,
+    },
+    /// Forward an interactive console on a pseudo-terminal to the host
+    Console {
+        /// Program and arguments attached to the console
+        #[arg(trailing_var_arg = true, default_value = DEFAULT_CONSOLE_PROGRAM)]
+        command: Vec<String>,
+    },
+    /// Seed the guest's entropy pool with random bytes from the host
+    Entropy {
+        /// Bytes to request at a time, at most 4096
+        #[arg(long, default_value_t = DEFAULT_ENTROPY_BYTES)]
+        bytes: usize,
+        /// Request more bytes this often after the first ones
+        #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_ENTROPY_INTERVAL.as_secs())]
+        interval: u64,
+    },
+    /// Run commands the host asks for, streaming their output and exit code back
+    Exec {
+        /// Maximum number of commands running at once
+        #[arg(long, default_value_t = DEFAULT_MAX_PROCESSES)]
+        max_processes: usize,
+    },
+    /// Open WebSockets for the host and relay their frames, so push protocols can be
+    /// proxied over CMIO
+    #[cfg(feature = "websocket")]
+    Websocket,
+    /// Run on the host instead of the guest, bridging a TAP interface of the host with
+    /// the network mode of a guest yielding through an emulated device
+    #[cfg(all(feature = "host", not(target_arch = "riscv64")))]
+    HostDriver {
+        /// Unix socket the guest's emulated device connects to
+        #[arg(long, value_name = "PATH", default_value = DEFAULT_HOST_SOCKET)]
+        socket: PathBuf,
+        /// Name of the host's TAP interface
+        #[arg(long, value_name = "NAME", default_value = DEFAULT_HOST_TAP_NAME)]
+        tap: String,
+        /// How long a yield without frames waits for frames from the host
+        #[arg(long, value_name = "MILLISECONDS", default_value_t = DEFAULT_HOST_WAIT.as_millis() as u64)]
+        wait: u64,
+    },
+    /// Run in Unix domain socket mode
+    #[command(after_help = ENVIRONMENT_HELP)]
+    Unix {
+        /// Maximum number of open connections and listeners
+        #[arg(default_value_t = DEFAULT_MAX_CONNECTIONS)]
+        max_connections: usize,
+    },
+    /// Run network and unix mode together over one CMIO device
+    #[command(alias = "all", after_help = ENVIRONMENT_HELP)]
+    Both(NetworkArgs),
+    /// Check the CMIO device, its buffers and TAP support, then the network path end to end
+    /// against a mock CMIO device, exiting with 1 if anything fails
+    #[command(alias = "selftest-net")]
+    Selftest {
+        /// Only check the network path, without the machine
+        #[arg(long)]
+        network_only: bool,
+    },
+    /// Measure the latency and throughput of yields with payloads of several sizes
+    Bench {
+        /// Payload sizes in bytes, comma separated (default 0 to 1 MiB)
+        #[arg(long, value_name = "BYTES", value_delimiter = ',')]
+        sizes: Vec<usize>,
+        /// Yield each size for this long
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        duration: u64,
+        /// Reason code of the yields, for the host to answer
+        #[arg(long, value_name = "CODE", default_value_t = DEFAULT_BENCH_REASON)]
+        reason: u16,
+        /// Yield to a mock device looping every payload back instead of the machine
+        #[arg(long)]
+        mock: bool,
+    },
+    /// Feed the responses of a recorded session to the socket proxy, as unix mode would get
+    /// them from the host
+    Replay {
+        /// Recording made with --record
+        file: PathBuf,
+    },
+    /// Report the state of the running daemon from its status file, exiting with 1 if it
+    /// isn't healthy
+    Status {
+        /// Consider the daemon hung if it hasn't updated the file for this long
+        #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+        max_age: u64,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+// Network mode options, turned into a TAP interface configuration
+#[derive(Args)]
+struct NetworkArgs {
+    /// TAP interface name
+    #[arg(long, default_value = DEFAULT_TAP_NAME)]
+    name: String,
+    /// User allowed to open the TAP device
+    #[arg(long, value_name = "UID")]
+    owner: Option<u32>,
+    /// Group allowed to open the TAP device
+    #[arg(long, value_name = "GID")]
+    group: Option<u32>,
+    /// Keep the TAP device after exit
+    #[arg(long)]
+    persistent: bool,
+    /// Exchange IP packets (TUN) instead of Ethernet frames
+    #[arg(long)]
+    tun: bool,
+    /// Set the interface MTU, e.g. 9000 for jumbo frames (default 1500)
+    #[arg(long, value_name = "BYTES")]
+    mtu: Option<u32>,
+    /// Bring the link up and assign an IPv4 or IPv6 address (repeatable)
+    #[arg(long = "address", value_name = "IP/LEN", value_parser = parse_address)]
+    addresses: Vec<(IpAddr, u8)>,
+    /// Bring the link up and install a default route via the gateway
+    #[arg(long, value_name = "IP")]
+    gateway: Option<IpAddr>,
+    /// Obtain an IPv4 address, gateway and DNS servers over DHCP
+    #[arg(long)]
+    dhcp: bool,
+    /// Send a CRC32 with each frame for the host to verify
+    #[arg(long)]
+    crc32: bool,
+    /// Send ARP, ICMP, DNS and DHCP frames to the host ahead of bulk TCP
+    #[arg(long)]
+    qos: bool,
+    /// Set the TAP interface's MAC address, e.g. 02:00:00:00:00:15
+    #[arg(long, value_name = "ADDRESS", value_parser = parse_mac)]
+    mac: Option<[u8; 6]>,
+    /// Let the host's link up and down control messages set the interface's carrier
+    #[arg(long)]
+    link_control: bool,
+    /// Create a second, local TAP interface and bridge it with the first and the host
+    #[arg(long, value_name = "NAME")]
+    bridge: Option<String>,
+    /// Log traffic statistics and CMIO yield counters at this interval
+    #[arg(long, value_name = "SECONDS")]
+    stats: Option<u64>,
+    /// Tag frames sent to the host with this VLAN ID, and only accept frames tagged with it
+    #[arg(long, value_name = "ID")]
+    vlan: Option<u16>,
+    /// Allow or deny frames, e.g. deny,ethertype=0x86dd,src=<mac>,dst=<mac>,vlan=<id>,proto=<n> (repeatable, first match wins)
+    #[arg(long = "filter", value_name = "RULE")]
+    filters: Vec<FilterRule>,
+    /// Action for frames no filter rule matches, allow or deny (default allow)
+    #[arg(long, value_name = "ACTION")]
+    filter_default: Option<FilterAction>,
+    /// Answer the host's IPv6 neighbor solicitations for this address with the TAP interface's MAC
+    #[arg(long, value_name = "IPV6")]
+    ndp_proxy: Option<Ipv6Addr>,
+    /// Read and write the TAP interface on threads of their own, apart from CMIO yields
+    #[arg(long)]
+    pipeline: bool,
+    /// Read at most this many frames from the TAP interface before processing received frames
+    #[arg(long, value_name = "N")]
+    max_read_frames: Option<usize>,
+    /// Read at most this many bytes from the TAP interface before processing received frames
+    #[arg(long, value_name = "N")]
+    max_read_bytes: Option<usize>,
+    /// Drop frames sent to the host beyond this many packets per second
+    #[arg(long, value_name = "N")]
+    egress_pps: Option<u64>,
+    /// Drop frames sent to the host beyond this many bytes per second
+    #[arg(long, value_name = "N")]
+    egress_bytes: Option<u64>,
+    /// Drop frames from the host beyond this many packets per second
+    #[arg(long, value_name = "N")]
+    ingress_pps: Option<u64>,
+    /// Drop frames from the host beyond this many bytes per second
+    #[arg(long, value_name = "N")]
+    ingress_bytes: Option<u64>,
+    /// Wait for outgoing packets this long before an idle yield (default 10)
+    #[arg(long, value_name = "MS", conflicts_with = "idle")]
+    idle_timeout: Option<u64>,
+    /// Wait before idle yields: immediate, sleep:<ms>, backoff:<max ms> or poll:<ms> (default poll:10)
+    #[arg(long, value_name = "STRATEGY")]
+    idle: Option<IdleStrategy>,
+}
+
+// User-space stack mode options
+#[cfg(feature = "user-stack")]
+#[derive(Args)]
+struct StackArgs {
+    /// Ethernet address of the stack (default 02:00:00:00:00:15)
+    #[arg(long, value_name = "ADDRESS", value_parser = parse_mac)]
+    mac: Option<[u8; 6]>,
+    /// Assign an IPv4 or IPv6 address (at most two)
+    #[arg(long = "address", value_name = "IP/LEN", value_parser = parse_address)]
+    addresses: Vec<(IpAddr, u8)>,
+    /// Install a default route via the gateway
+    #[arg(long, value_name = "IP")]
+    gateway: Option<IpAddr>,
+    /// Unix socket guest programs connect to
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_STACK_SOCKET)]
+    socket: String,
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("TAP CMIO Interface");
-    println!("==================");
+    let cli = Cli::parse_from(expand_config(env::args().collect())?);
     
-    // Parse command line arguments
-    let args: Vec<String> = env::args().collect();
-    let mode = if args.len() > 1 {
-        args[1].as_str()
-    } else {
-        "help"
-    };
+    init_logging(cli.log_level, cli.log_json);
+    info!("TAP CMIO Interface {}", env!("CARGO_PKG_VERSION"));
+    
+    match &cli.dump_cmio {
+        Some(Some(path)) => dump::to_file(path).map_err(|e| format!("can't open dump file {}: {}", path.display(), e))?,
+        Some(None) => dump::to_stderr(),
+        None => {}
+    }
     
-    match mode {
-        "network" => run_network_mode()?,
-        "unix" => run_unix_socket_mode()?,
-        "help" | _ => {
-            println!("Usage: {} [mode]", args[0]);
-            println!("Modes:");
-            println!("  network  - Run in network mode (TAP interface)");
-            println!("  unix     - Run in Unix domain socket mode");
-            println!("  help     - Show this help message");
+    if let Some(seconds) = cli.idle_exit {
+        shutdown::exit_when_idle(Duration::from_secs(seconds));
+    }
+    if let Some(path) = &cli.record {
+        recording::start(path).map_err(|e| format!("can't create recording {}: {}", path.display(), e))?;
+    }
+    
+    // Log the error that ended the mode, so it reaches host tooling in the chosen format
+    if let Err(e) = run(&cli) {
+        error!("{}", e);
+        process::exit(1);
+    }
+    
+    Ok(())
+}
+
+fn run(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    match &cli.command {
+        Command::Network(args) => {
+            status::spawn_writer(cli.status_file(), &["network"]);
+            run_network_mode(&args.tap_config(cli))?
+        },
+        #[cfg(feature = "user-stack")]
+        Command::Stack(args) => {
+            status::spawn_writer(cli.status_file(), &["stack"]);
+            run_stack_mode(&args.stack_config(cli))?
+        },
+        Command::HttpRequest { listen } => {
+            status::spawn_writer(cli.status_file(), &["http-request"]);
+            run_http_request_mode(cli, listen)?
+        },
+        Command::Socks5 { listen, forwards } => {
+            status::spawn_writer(cli.status_file(), &["socks5"]);
+            run_socks5_mode(cli, Some(listen), forwards)?
+        },
+        Command::Forward { forwards } => {
+            status::spawn_writer(cli.status_file(), &["forward"]);
+            run_socks5_mode(cli, None, forwards)?
+        },
+        Command::Ninep { socket } => {
+            status::spawn_writer(cli.status_file(), &["ninep"]);
+            run_ninep_mode(cli, socket)?
+        },
+        Command::Time { interval, adjust } => {
+            status::spawn_writer(cli.status_file(), &["time"]);
+            run_time_mode(cli, adjust.unwrap_or(ClockAdjustment::Monitor), Duration::from_secs(*interval))?
+        },
+        Command::Transfer { root } => {
+            status::spawn_writer(cli.status_file(), &["transfer"]);
+            run_transfer_mode(cli, root)?
+        },
+        Command::Console { command } => {
+            status::spawn_writer(cli.status_file(), &["console"]);
+            run_console_mode(cli, command)?
+        },
+        Command::Entropy { bytes, interval } => {
+            status::spawn_writer(cli.status_file(), &["entropy"]);
+            run_entropy_mode(cli, *bytes, Duration::from_secs(*interval))?
+        },
+        Command::Exec { max_processes } => {
+            status::spawn_writer(cli.status_file(), &["exec"]);
+            run_exec_mode(cli, *max_processes)?
+        },
+        #[cfg(feature = "websocket")]
+        Command::Websocket => {
+            status::spawn_writer(cli.status_file(), &["websocket"]);
+            run_websocket_mode(cli)?
+        },
+        #[cfg(all(feature = "host", not(target_arch = "riscv64")))]
+        Command::HostDriver { socket, tap, wait } => run_host_driver(socket, tap, Duration::from_millis(*wait))?,
+        Command::Unix { max_connections } => {
+            status::spawn_writer(cli.status_file(), &["unix"]);
+            run_unix_socket_mode(cli, *max_connections)?
+        },
+        Command::Both(args) => {
+            status::spawn_writer(cli.status_file(), &["network", "unix"]);
+            run_combined_mode(&args.tap_config(cli))?
+        },
+        Command::Selftest { network_only } => {
+            let environment = match network_only {
+                true => Ok(()),
+                false => selftest::run_environment(&cli.device.clone().unwrap_or_else(default_device), cli.max_batch_size),
+            };
+            selftest::run_network()?;
+            environment?
+        },
+        Command::Bench { sizes, duration, reason, mock } => {
+            let sizes = if sizes.is_empty() { DEFAULT_SIZES } else { sizes.as_slice() };
+            run_bench(cli, sizes, Duration::from_millis(*duration), YieldReason::from_code(*reason), *mock)?
+        },
+        Command::Replay { file } => run_replay(cli, file)?,
+        Command::Status { max_age } => report_status(&cli.status_file(), Duration::from_secs(*max_age))?,
+    }
+    
+    Ok(())
+}
+
+// Move the arguments in the files given with --config behind the command line's own,
+// split at whitespace and skipping lines starting with #, so they apply to the mode
+fn expand_config(args: Vec<String>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut expanded = Vec::with_capacity(args.len());
+    let mut from_files = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let path = match arg.strip_prefix("--config=") {
+            Some(path) => path.to_string(),
+            None if arg == "--config" => match args.next() {
+                Some(path) => path,
+                // Leave the missing value for the parser to report
+                None => {
+                    expanded.push(arg);
+                    break;
+                }
+            },
+            None => {
+                expanded.push(arg);
+                continue;
+            }
+        };
+        let contents = fs::read_to_string(&path).map_err(|e| format!("can't read config file {}: {}", path, e))?;
+        from_files.extend(contents.lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(str::split_whitespace)
+            .map(str::to_string));
+    }
+    expanded.append(&mut from_files);
+    Ok(expanded)
+}
+
+// Print log messages from the given level up to stderr, as text or as JSON lines
+fn init_logging(level: LogLevel, json: bool) {
+    let logger = tracing_subscriber::fmt().with_max_level(level.level()).with_writer(std::io::stderr);
+    if json {
+        logger.json().init();
+    } else {
+        logger.init();
+    }
+}
+
+// Parse a MAC address written as six colon separated hex bytes
+fn parse_mac(value: &str) -> Result<[u8; 6], String> {
+    let bytes = value.split(':')
+        .map(|byte| u8::from_str_radix(byte, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|e| format!("invalid MAC address {}: {}", value, e))?;
+    bytes.try_into().map_err(|_| format!("invalid MAC address {}", value))
+}
+
+// Parse an address with its prefix length, written as address/prefix
+fn parse_address(value: &str) -> Result<(IpAddr, u8), String> {
+    let (addr, prefix_len) = value.split_once('/').ok_or("expected address/prefix")?;
+    let addr = addr.parse().map_err(|e| format!("invalid address {}: {}", addr, e))?;
+    let prefix_len = prefix_len.parse().map_err(|e| format!("invalid prefix length {}: {}", prefix_len, e))?;
+    Ok((addr, prefix_len))
+}
+
+impl LogLevel {
+    fn level(self) -> tracing::Level {
+        match self {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
         }
     }
+}
+
+impl Cli {
+    /// The status file given on the command line, in the environment or the default one
+    fn status_file(&self) -> PathBuf {
+        self.status_file.clone()
+            .or_else(|| env::var_os(STATUS_FILE_ENV).map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_STATUS_FILE))
+    }
+}
+
+impl NetworkArgs {
+    /// The TAP interface configuration of the options, with the global ones
+    fn tap_config(&self, cli: &Cli) -> TapConfig {
+        let mut config = TapConfig {
+            name: self.name.clone(),
+            mode: if self.tun { Mode::Tun } else { Mode::Tap },
+            owner: self.owner,
+            group: self.group,
+            persistent: self.persistent,
+            mtu: self.mtu,
+            addresses: self.addresses.clone(),
+            gateway: self.gateway,
+            dhcp: self.dhcp,
+            checksum: self.crc32,
+            qos: self.qos,
+            mac: self.mac,
+            link_control: self.link_control,
+            bridge: self.bridge.clone(),
+            pipeline: self.pipeline,
+            vlan: self.vlan,
+            ndp_proxy: self.ndp_proxy,
+            stats_interval: self.stats.map(Duration::from_secs),
+            cmio_device: cli.device.clone(),
+            max_batch_size: cli.max_batch_size,
+            ..TapConfig::default()
+        };
+        config.read_limit.frames = self.max_read_frames;
+        config.read_limit.bytes = self.max_read_bytes;
+        config.egress_limit.packets_per_second = self.egress_pps;
+        config.egress_limit.bytes_per_second = self.egress_bytes;
+        config.ingress_limit.packets_per_second = self.ingress_pps;
+        config.ingress_limit.bytes_per_second = self.ingress_bytes;
+        config.filter.rules = self.filters.clone();
+        if let Some(action) = self.filter_default {
+            config.filter.default_action = action;
+        }
+        if let Some(timeout) = self.idle_timeout {
+            config.idle = IdleStrategy::Poll(Duration::from_millis(timeout));
+        }
+        if let Some(idle) = self.idle {
+            config.idle = idle;
+        }
+        config
+    }
+}
+
+#[cfg(feature = "user-stack")]
+impl StackArgs {
+    /// The user-space stack configuration of the options, with the global ones
+    fn stack_config(&self, cli: &Cli) -> StackConfig {
+        let mut config = StackConfig {
+            addresses: self.addresses.clone(),
+            gateway: self.gateway,
+            socket_path: self.socket.clone(),
+            cmio_device: cli.device.clone(),
+            ..StackConfig::default()
+        };
+        if let Some(mac) = self.mac {
+            config.mac = mac;
+        }
+        config
+    }
+}
+
+// Open the CMIO device given on the command line, or the default one
+fn open_cmio(device: &Option<PathBuf>) -> Result<Cmio, Box<dyn std::error::Error>> {
+    Ok(match device {
+        Some(path) => Cmio::open(path)?,
+        None => Cmio::new()?,
+    })
+}
+
+// Idle strategy of the modes that only yield for periodic requests, unless TAPCMIO_IDLE
+// says otherwise
+const PERIODIC_IDLE: IdleStrategy = IdleStrategy::Sleep(Duration::from_millis(100));
+
+// Run a subsystem alone on the multiplexer until shutdown, idling as TAPCMIO_IDLE says or
+// as the mode defaults to
+fn run_subsystem(cmio: Cmio, reason: YieldReason, subsystem: Box<dyn Subsystem>, default_idle: IdleStrategy) -> Result<(), Box<dyn std::error::Error>> {
+    let mut multiplexer = Multiplexer::new(cmio);
+    multiplexer.register(reason, subsystem)?;
+    multiplexer.set_idle_strategy(match env::var("TAPCMIO_IDLE") {
+        Ok(spec) => spec.parse()?,
+        Err(_) => default_idle,
+    });
+    
+    info!("Starting multiplexer loop (press Ctrl+C to exit)...");
+    shutdown::install_handlers()?;
+    multiplexer.run_loop()?;
     
     Ok(())
 }
 
-fn run_network_mode() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Running in network mode");
+// Cap the device's TX buffer size at the batch size given on the command line
+fn batch_size(cmio_max_buffer_size: usize, max_batch_size: Option<usize>) -> Result<usize, Box<dyn std::error::Error>> {
+    match max_batch_size {
+        Some(size) if size < MIN_BATCH_SIZE => Err(format!("batch size {} is below {} bytes", size, MIN_BATCH_SIZE).into()),
+        Some(size) => Ok(size.min(cmio_max_buffer_size)),
+        None => Ok(cmio_max_buffer_size),
+    }
+}
+
+// Measure yields through the device or the mock and print the results as a table
+fn run_bench(cli: &Cli, sizes: &[usize], duration: Duration, reason: YieldReason, mock: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let results = if mock {
+        info!("Measuring yields through a mock device");
+        bench::run_mock(cli.max_batch_size.unwrap_or(MOCK_BUFFER_SIZE), sizes, duration)?
+    } else {
+        let mut cmio = open_cmio(&cli.device)?;
+        info!("Measuring yields through {} with reason code {:#06x}", cmio.device().display(), reason.code());
+        bench::run(&mut cmio, sizes, duration, reason)?
+    };
+    bench::print_table(&results);
+    Ok(())
+}
+
+// Replay the responses of a recording through a socket manager until they are used up
+fn run_replay(cli: &Cli, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let tx_length = cli.max_batch_size.unwrap_or(MOCK_BUFFER_SIZE);
+    let cmio = ReplayCmio::new(recording::read(path)?, tx_length);
+    let remaining = cmio.remaining();
+    info!("Replaying {} responses from {}", remaining.load(Ordering::SeqCst), path.display());
     
-    // Example 1: Basic CMIO functionality
-    println!("\nTesting basic CMIO functionality...");
-    let mut cmio = Cmio::new()?;
-    println!("CMIO initialized successfully");
+    let mut socket_manager = SocketManager::for_multiplexer(tx_length)?;
+    configure_socket_manager(&mut socket_manager)?;
+    let mut multiplexer = Multiplexer::replay(cmio);
+    multiplexer.register(YieldReason::UnixSocket, Box::new(socket_manager))?;
+    while remaining.load(Ordering::SeqCst) > 0 {
+        multiplexer.run_once()?;
+    }
+    info!("Replay finished, {} responses for other reason codes dropped", multiplexer.unrouted());
+    Ok(())
+}
+
+// Print the state of the running daemon, failing if it isn't healthy
+fn report_status(path: &Path, max_age: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let status = Status::read(path)?;
+    let now = status::unix_time();
+    println!("Process:     {}", status.pid);
+    println!("Modes:       {}", status.modes.join(", "));
+    println!("CMIO:        {}", if status.cmio_open { "open" } else { "closed" });
+    println!("Connections: {}", status.connections);
+    println!("Yields:      {} ({} errors)", status.yields, status.errors);
+    println!("TX:          {} bytes, {} bytes/s", status.tx_bytes, status.tx_rate);
+    println!("RX:          {} bytes, {} bytes/s", status.rx_bytes, status.rx_rate);
+    println!("Uptime:      {} seconds", now.saturating_sub(status.started));
+    match status.problem(now, max_age) {
+        Some(problem) => Err(format!("unhealthy: {}", problem).into()),
+        None => {
+            println!("Healthy");
+            Ok(())
+        }
+    }
+}
 
+fn run_network_mode(config: &TapConfig) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in network mode");
+    
+    // Example 1: Basic CMIO functionality
+    info!("Testing basic CMIO functionality...");
+    let mut cmio = open_cmio(&config.cmio_device)?;
+    info!("CMIO initialized successfully on {}", cmio.device().display());
+    info!("CMIO capabilities: {:?}", cmio.capabilities());
+    
     let mut yield_data = CmioYield {
         dev: 0,
         cmd: 0,
         reason: 0,
         data: 0,
     };
-
-    println!("Performing basic yield operation...");
+    
+    info!("Performing basic yield operation...");
     cmio.yield_(&mut yield_data)?;
-    println!("Yield completed with: dev={}, cmd={}, reason={}, data={}",
+    info!("Yield completed with: dev={}, cmd={}, reason={}, data={}",
         yield_data.dev, yield_data.cmd, yield_data.reason, yield_data.data);
-
+    
     // Example 2: Using the convenience function with a buffer
-    println!("\nTesting yield with buffer...");
+    info!("Testing yield with buffer...");
     let tx_data = b"Hello, TAP CMIO!";
-    let (rx_data, reason) = cmio.yield_with_buffer(1, 2, 3, tx_data)?;
+    let (rx_data, reason) = cmio.yield_manual(YieldReason::Other(3), tx_data)?;
+    
+    info!("Sent {} bytes: {:?}", tx_data.len(), tx_data);
+    info!("Received {} bytes with reason {}: {:?}", rx_data.len(), reason, rx_data);
     
-    println!("Sent {} bytes: {:?}", tx_data.len(), tx_data);
-    println!("Received {} bytes with reason {}: {:?}", rx_data.len(), reason, rx_data);
-
     // Example 3: Network interface
-    println!("\nInitializing network interface...");
-    let mut network = NetworkInterface::new()?;
-    println!("Network interface initialized successfully");
+    info!("Initializing network interface {}...", config.name);
+    let mut network = NetworkInterface::with_config(config)?;
+    info!("Network interface initialized successfully");
     
     // Run the network interface loop
-    println!("\nStarting network interface loop (press Ctrl+C to exit)...");
+    info!("Starting network interface loop (press Ctrl+C to exit)...");
+    shutdown::install_handlers()?;
     network.run_loop()?;
+    
+    Ok(())
+}
 
+#[cfg(feature = "user-stack")]
+fn run_stack_mode(config: &StackConfig) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in user-space stack mode");
+    
+    let mut stack = UserStack::new(config)?;
+    info!("Stack initialized, guest programs connect to {}", config.socket_path);
+    
+    info!("Starting stack loop (press Ctrl+C to exit)...");
+    shutdown::install_handlers()?;
+    stack.run_loop()?;
+    
     Ok(())
 }
 
-fn run_unix_socket_mode() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Running in Unix domain socket mode");
+// Hand guest programs' HTTP requests to the host until shutdown
+fn run_http_request_mode(cli: &Cli, listen: &str) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in HTTP request mode");
+    
+    let cmio = open_cmio(&cli.device)?;
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    let proxy = HttpRequestProxy::bind(listen, cmio_max_buffer_size)?;
+    info!("Guest programs can use http_proxy=http://{}", listen);
+    
+    run_subsystem(cmio, HTTP_REQUEST_REASON, Box::new(proxy), IdleStrategy::Immediate)
+}
+
+// Tunnel guest programs' SOCKS5 and forwarded connections to the host until shutdown
+fn run_socks5_mode(cli: &Cli, listen: Option<&str>, forwards: &[PortForward]) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in {} mode", if listen.is_some() { "SOCKS5 server" } else { "port forwarding" });
+    
+    let cmio = open_cmio(&cli.device)?;
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    let mut server = match listen {
+        Some(listen) => {
+            info!("Guest programs can use ALL_PROXY=socks5h://{}", listen);
+            Socks5Server::bind(listen, cmio_max_buffer_size)?
+        }
+        None => Socks5Server::new(cmio_max_buffer_size),
+    };
+    for forward in forwards {
+        server.add_forward(forward)?;
+    }
+    
+    run_subsystem(cmio, SOCKS5_SERVER_REASON, Box::new(server), IdleStrategy::Immediate)
+}
+
+// Relay 9P between the guest kernel and the host until shutdown
+fn run_ninep_mode(cli: &Cli, socket: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in 9P mode");
+    
+    let cmio = open_cmio(&cli.device)?;
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    let proxy = NinePProxy::bind(socket, cmio_max_buffer_size)?;
+    info!("Mount with: mount -t 9p -o trans=unix,version=9p2000.L {} /mnt", socket.display());
+    
+    run_subsystem(cmio, NINEP_REASON, Box::new(proxy), IdleStrategy::Immediate)
+}
+
+// Keep fetching the host clock until shutdown
+fn run_time_mode(cli: &Cli, adjustment: ClockAdjustment, interval: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in time synchronization mode, {:?} every {} seconds", adjustment, interval.as_secs());
+    
+    let cmio = open_cmio(&cli.device)?;
+    info!("CMIO initialized successfully on {}", cmio.device().display());
+    
+    run_subsystem(cmio, TIME_REASON, Box::new(TimeSync::new(adjustment, interval)), PERIODIC_IDLE)
+}
+
+// Serve the host's file transfer requests until shutdown
+fn run_transfer_mode(cli: &Cli, root: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in file transfer mode");
+    
+    let cmio = open_cmio(&cli.device)?;
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    let transfer = FileTransfer::new(root, cmio_max_buffer_size)?;
+    info!("Serving files under {}", root.display());
+    
+    run_subsystem(cmio, TRANSFER_REASON, Box::new(transfer), IdleStrategy::Immediate)
+}
+
+// Forward the console until shutdown
+fn run_console_mode(cli: &Cli, program: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in console mode");
+    
+    let cmio = open_cmio(&cli.device)?;
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    let console = PtyConsole::new(program.to_vec(), cmio_max_buffer_size)?;
+    run_subsystem(cmio, CONSOLE_REASON, Box::new(console), IdleStrategy::Immediate)
+}
+
+// Keep feeding the host's random bytes into the pool until shutdown
+fn run_entropy_mode(cli: &Cli, bytes: usize, interval: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in entropy mode, {} bytes every {} seconds", bytes, interval.as_secs());
+    
+    let cmio = open_cmio(&cli.device)?;
+    info!("CMIO initialized successfully on {}", cmio.device().display());
+    
+    run_subsystem(cmio, ENTROPY_REASON, Box::new(EntropyFeed::new(bytes, interval)), PERIODIC_IDLE)
+}
+
+// Run the host's commands until shutdown
+fn run_exec_mode(cli: &Cli, max_processes: usize) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in command execution mode");
+    
+    let cmio = open_cmio(&cli.device)?;
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    let mut runner = CommandRunner::new(cmio_max_buffer_size);
+    runner.set_max_processes(max_processes);
+    
+    run_subsystem(cmio, EXEC_REASON, Box::new(runner), IdleStrategy::Immediate)
+}
+
+// Tunnel the WebSockets the host opens until shutdown
+#[cfg(feature = "websocket")]
+fn run_websocket_mode(cli: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in WebSocket tunnel mode");
+    
+    let cmio = open_cmio(&cli.device)?;
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    run_subsystem(cmio, WEBSOCKET_REASON, Box::new(WebSocketTunnel::new(cmio_max_buffer_size)), IdleStrategy::Immediate)
+}
+
+#[cfg(all(feature = "host", not(target_arch = "riscv64")))]
+fn run_host_driver(socket: &Path, tap: &str, wait: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running as the host driver");
+    
+    let link = host::accept_emulated(socket)?;
+    info!("Guest connected on {}", socket.display());
+    let mut driver = HostDriver::new(tap, Box::new(link))?;
+    driver.set_wait(wait);
+    
+    info!("Starting host driver loop (press Ctrl+C to exit)...");
+    shutdown::install_handlers()?;
+    driver.run_loop()?;
+    
+    Ok(())
+}
+
+fn run_unix_socket_mode(cli: &Cli, max_connections: usize) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in Unix domain socket mode");
+    
+    // Yield to a fake host driver instead of the device if one is given
+    #[cfg(feature = "emu")]
+    if let Ok(path) = env::var(EMU_SOCKET_ENV) {
+        let cmio = EmuCmio::connect(&path)?;
+        info!("Connected to the emulated host at {}", path);
+        let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+        let socket_manager = SocketManager::with_handle(cmio.into_handle()?, cmio_max_buffer_size)?;
+        return run_socket_manager(socket_manager, max_connections);
+    }
     
     // Initialize CMIO
-    println!("\nInitializing CMIO...");
-    let cmio = Cmio::new()?;
-    println!("CMIO initialized successfully");
+    info!("Initializing CMIO...");
+    let mut cmio = open_cmio(&cli.device)?;
+    info!("CMIO initialized successfully on {}", cmio.device().display());
+    if let Ok(seconds) = env::var("TAPCMIO_STATS") {
+        cmio.set_stats_interval(Some(Duration::from_secs(seconds.parse()?)));
+    }
     
     // Get the CMIO max buffer size
-    let cmio_max_buffer_size = cmio.get_tx_length();
-    println!("CMIO max buffer size: {} bytes", cmio_max_buffer_size);
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), cli.max_batch_size)?;
+    info!("CMIO max buffer size: {} bytes (RX {} bytes)", cmio_max_buffer_size, cmio.get_rx_length());
     
     // Initialize socket manager
-    println!("\nInitializing socket manager...");
-    let socket_manager = SocketManager::new(cmio, cmio_max_buffer_size);
-    println!("Socket manager initialized successfully");
+    info!("Initializing socket manager...");
+    let socket_manager = SocketManager::new(cmio, cmio_max_buffer_size)?;
+    run_socket_manager(socket_manager, max_connections)
+}
+
+// Configure the socket manager and run its loop until shutdown
+fn run_socket_manager(mut socket_manager: SocketManager, max_connections: usize) -> Result<(), Box<dyn std::error::Error>> {
+    socket_manager.set_max_connections(max_connections);
+    configure_socket_manager(&mut socket_manager)?;
+    info!("Socket manager initialized successfully (max {} connections)", max_connections);
     
     // Run the socket manager loop
-    println!("\nStarting socket manager loop (press Ctrl+C to exit)...");
+    info!("Starting socket manager loop (press Ctrl+C to exit)...");
+    shutdown::install_handlers()?;
     socket_manager.run_loop()?;
     
     Ok(())
 }
+
+// Apply the unix mode environment variables to a socket manager
+fn configure_socket_manager(manager: &mut SocketManager) -> Result<(), Box<dyn std::error::Error>> {
+    // Route outbound TCP connections through an upstream proxy if one is configured
+    match (env::var("TAPCMIO_SOCKS5_PROXY"), env::var("TAPCMIO_HTTP_PROXY")) {
+        (Ok(_), Ok(_)) => return Err("TAPCMIO_SOCKS5_PROXY and TAPCMIO_HTTP_PROXY are mutually exclusive".into()),
+        (Ok(spec), _) => {
+            manager.set_upstream_proxy(Some(UpstreamProxy::Socks5(Socks5Proxy::parse(&spec)?)));
+            info!("Using SOCKS5 proxy for TCP connections");
+        },
+        (_, Ok(spec)) => {
+            manager.set_upstream_proxy(Some(UpstreamProxy::HttpConnect(HttpProxy::parse(&spec)?)));
+            info!("Using HTTP CONNECT proxy for TCP connections");
+        },
+        _ => {}
+    }
+    if let Ok(spec) = env::var("TAPCMIO_IDLE") {
+        manager.set_idle_strategy(spec.parse()?);
+    }
+    Ok(())
+}
+
+fn run_combined_mode(config: &TapConfig) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Running in combined network and Unix domain socket mode");
+    
+    // Both subsystems share one CMIO device, yielding on their own reason codes
+    let mut cmio = open_cmio(&config.cmio_device)?;
+    cmio.set_stats_interval(config.stats_interval);
+    let cmio_max_buffer_size = batch_size(cmio.get_tx_length(), config.max_batch_size)?;
+    info!("CMIO initialized successfully on {}, max buffer size {} bytes", cmio.device().display(), cmio_max_buffer_size);
+    
+    info!("Initializing network interface {}...", config.name);
+    let network = NetworkInterface::for_multiplexer(config, cmio_max_buffer_size)?;
+    
+    info!("Initializing socket manager...");
+    let mut socket_manager = SocketManager::for_multiplexer(cmio_max_buffer_size)?;
+    configure_socket_manager(&mut socket_manager)?;
+    
+    let mut multiplexer = Multiplexer::new(cmio);
+    multiplexer.set_idle_strategy(config.idle);
+    multiplexer.register(YieldReason::TapRxTx, Box::new(network))?;
+    multiplexer.register(YieldReason::UnixSocket, Box::new(socket_manager))?;
+    
+    info!("Starting multiplexer loop (press Ctrl+C to exit)...");
+    shutdown::install_handlers()?;
+    multiplexer.run_loop()?;
+    
+    Ok(())
+}
\ No newline at end of file
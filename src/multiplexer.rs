@@ -0,0 +1,278 @@
+use tracing::{debug, warn};
+use crate::cmio::{Cmio, CmioError, Exchange};
+use crate::idle::{IdleState, IdleStrategy, IdleWait};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+use crate::shutdown;
+
+// High bit of a response reason, a flag for the subsystem rather than part of its code
+const REASON_FLAGS: u16 = 0x8000;
+
+// Part of the guest sharing the device with others under a reason code of its own, such
+// as network mode on 0x42 and the socket proxy on 0x43
+pub trait Subsystem {
+    /// Fill the empty buffer with the next data to send to the host, at most max_len
+    /// bytes, leaving it empty when nothing is pending
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError>;
+    
+    /// Handle data the host sent on the subsystem's reason code, given with the raw
+    /// response reason, which may carry flags
+    fn handle_rx(&mut self, data: &[u8], reason: u16) -> Result<(), CmioError>;
+}
+
+// Structure running a single yield loop for several subsystems on one device
+//
+// Every round, each subsystem with data pending gets a yield on its reason code, taking
+// turns at going first. When none has anything to send, an empty yield polls the host
+// on the reason code of the next subsystem in turn. Responses go to the subsystem
+// registered for their reason code, whichever subsystem's yield they answered, and the
+// host is polled with empty yields until it has nothing more to send.
+pub struct Multiplexer {
+    device: Box<dyn Exchange>,
+    subsystems: Vec<(YieldReason, Box<dyn Subsystem>)>,
+    // Index of the subsystem going first in the next round
+    next: usize,
+    tx_buffer: Vec<u8>,
+    rx_buffer: Vec<u8>,
+    idle: IdleState,
+    // Responses no subsystem is registered for
+    unrouted: u64,
+    // Responses a subsystem failed to handle
+    rejected: u64,
+}
+
+impl Multiplexer {
+    pub fn new(cmio: Cmio) -> Self {
+        Self::with_device(Box::new(cmio))
+    }
+    
+    /// Create a multiplexer yielding through an emulated device
+    #[cfg(feature = "emu")]
+    pub fn emulated(cmio: crate::emu::EmuCmio) -> Self {
+        Self::with_device(Box::new(cmio))
+    }
+    
+    /// Create a multiplexer answered by the RX buffers of a recording
+    pub fn replay(cmio: crate::recording::ReplayCmio) -> Self {
+        Self::with_device(Box::new(cmio))
+    }
+    
+    pub(crate) fn with_device(device: Box<dyn Exchange>) -> Self {
+        Self {
+            device,
+            subsystems: Vec::new(),
+            next: 0,
+            tx_buffer: Vec::new(),
+            rx_buffer: Vec::new(),
+            idle: IdleState::new(IdleStrategy::Immediate),
+            unrouted: 0,
+            rejected: 0,
+        }
+    }
+    
+    /// Register a subsystem for a reason code
+    /// 
+    /// Fails with InvalidArgument if the code is taken, or is the control reason code,
+    /// which the multiplexer keeps for control messages.
+    pub fn register(&mut self, reason: YieldReason, subsystem: Box<dyn Subsystem>) -> Result<(), CmioError> {
+        if reason == YieldReason::Control || reason.code() & REASON_FLAGS != 0 {
+            return Err(CmioError::InvalidArgument(format!("reason code {:#06x} can't be registered", reason.code())));
+        }
+        if self.subsystems.iter().any(|(registered, _)| *registered == reason) {
+            return Err(CmioError::InvalidArgument(format!("reason code {:#06x} is already registered", reason.code())));
+        }
+        self.subsystems.push((reason, subsystem));
+        Ok(())
+    }
+    
+    /// Wait as the strategy says when a round neither sent nor received anything
+    pub fn set_idle_strategy(&mut self, strategy: IdleStrategy) {
+        self.idle = IdleState::new(strategy);
+    }
+    
+    /// Number of responses dropped because no subsystem is registered for their reason
+    pub fn unrouted(&self) -> u64 {
+        self.unrouted
+    }
+    
+    /// Number of responses dropped because their subsystem failed to handle them
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+    
+    /// Run one round, returning whether anything was sent or received
+    pub fn run_once(&mut self) -> Result<bool, CmioError> {
+        let count = self.subsystems.len();
+        if count == 0 {
+            return Ok(false);
+        }
+        
+        let max_len = self.device.tx_length();
+        let mut activity = false;
+        for turn in 0..count {
+            let index = (self.next + turn) % count;
+            let mut tx_buffer = std::mem::take(&mut self.tx_buffer);
+            tx_buffer.clear();
+            let result = self.subsystems[index].1.poll_tx(&mut tx_buffer, max_len);
+            if result.is_ok() && !tx_buffer.is_empty() {
+                activity = true;
+                let reason = self.subsystems[index].0;
+                let exchanged = self.exchange(reason, &tx_buffer);
+                self.tx_buffer = tx_buffer;
+                exchanged?;
+            } else {
+                self.tx_buffer = tx_buffer;
+                result?;
+            }
+        }
+        
+        // Nothing to send, ask the host whether it has anything
+        if !activity {
+            let reason = self.subsystems[self.next].0;
+            activity = self.exchange(reason, &[])?;
+        }
+        self.next = (self.next + 1) % count;
+        Ok(activity)
+    }
+    
+    /// Run rounds until a shutdown is requested, waiting as the idle strategy says after
+    /// rounds without activity
+    pub fn run_loop(&mut self) -> Result<(), CmioError> {
+        while !shutdown::requested() {
+            if self.run_once()? {
+                self.idle.reset();
+                continue;
+            }
+            match self.idle.next_wait() {
+                IdleWait::None => {}
+                // No descriptors to wait on here, so polling sleeps for its timeout
+                IdleWait::Sleep(duration) | IdleWait::Poll(duration) => std::thread::sleep(duration),
+            }
+        }
+        Ok(())
+    }
+    
+    /// Yield the data on a reason code and dispatch the responses, polling the host with
+    /// empty yields until it sends nothing more; returns whether anything was received
+    fn exchange(&mut self, reason: YieldReason, tx_data: &[u8]) -> Result<bool, CmioError> {
+        let mut received = false;
+        let mut tx_data = tx_data;
+        loop {
+            let mut rx_buffer = std::mem::take(&mut self.rx_buffer);
+            let result = self.device.exchange(YieldDevice::Yield, YieldCommand::Manual, reason, tx_data, &mut rx_buffer);
+            let dispatched = match result {
+                Ok(response) if !rx_buffer.is_empty() => {
+                    self.dispatch(&rx_buffer, response);
+                    Ok(true)
+                }
+                Ok(_) => Ok(false),
+                Err(e) => Err(e),
+            };
+            self.rx_buffer = rx_buffer;
+            if !dispatched? {
+                return Ok(received);
+            }
+            received = true;
+            tx_data = &[];
+        }
+    }
+    
+    /// Hand a response to the subsystem registered for its reason code
+    /// 
+    /// A response the subsystem fails to handle, such as a malformed batch, is dropped
+    /// rather than ending the loop for every subsystem.
+    fn dispatch(&mut self, data: &[u8], reason: u16) {
+        let code = YieldReason::from_code(reason & !REASON_FLAGS);
+        if let Some((_, subsystem)) = self.subsystems.iter_mut().find(|(registered, _)| *registered == code) {
+            if let Err(e) = subsystem.handle_rx(data, reason) {
+                warn!("Dropping {} bytes the subsystem on reason code {:#06x} failed to handle: {}", data.len(), reason, e);
+                self.rejected += 1;
+            }
+            return;
+        }
+        if code == YieldReason::Control {
+            debug!("Ignoring control message {:?}", data);
+        } else {
+            warn!("Dropping {} bytes for unregistered reason code {:#06x}", data.len(), reason);
+            self.unrouted += 1;
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::cmio::MockCmio;
+
+    // Subsystem sending queued messages and recording what comes back
+    struct Recorder {
+        pending: Vec<Vec<u8>>,
+        received: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl Subsystem for Recorder {
+        fn poll_tx(&mut self, buffer: &mut Vec<u8>, _max_len: usize) -> Result<(), CmioError> {
+            if !self.pending.is_empty() {
+                buffer.extend(self.pending.remove(0));
+            }
+            Ok(())
+        }
+
+        fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+            self.received.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut mux = Multiplexer::with_device(Box::new(MockCmio::new(64)));
+        let network = Arc::new(Mutex::new(Vec::new()));
+        let sockets = Arc::new(Mutex::new(Vec::new()));
+        mux.register(YieldReason::TapRxTx, Box::new(Recorder { pending: vec![vec![1], vec![2]], received: Arc::clone(&network) })).unwrap();
+        mux.register(YieldReason::UnixSocket, Box::new(Recorder { pending: vec![vec![3]], received: Arc::clone(&sockets) })).unwrap();
+        assert!(mux.register(YieldReason::TapRxTx, Box::new(Recorder { pending: Vec::new(), received: Arc::default() })).is_err());
+        assert!(mux.register(YieldReason::Control, Box::new(Recorder { pending: Vec::new(), received: Arc::default() })).is_err());
+
+        // The mock answers every yield on its own reason code, so each subsystem gets its echo
+        while mux.run_once().unwrap() {}
+        assert_eq!(*network.lock().unwrap(), vec![vec![1], vec![2]]);
+        assert_eq!(*sockets.lock().unwrap(), vec![vec![3]]);
+
+        // Responses for a code nobody registered are dropped
+        mux.dispatch(&[4], YieldReason::Other(7).code());
+        assert_eq!(mux.unrouted(), 1);
+    }
+
+    // Subsystem refusing everything the host sends it
+    struct Refuser {
+        pending: Vec<Vec<u8>>,
+    }
+
+    impl Subsystem for Refuser {
+        fn poll_tx(&mut self, buffer: &mut Vec<u8>, _max_len: usize) -> Result<(), CmioError> {
+            if !self.pending.is_empty() {
+                buffer.extend(self.pending.remove(0));
+            }
+            Ok(())
+        }
+
+        fn handle_rx(&mut self, _data: &[u8], _reason: u16) -> Result<(), CmioError> {
+            Err(CmioError::ProtocolError("malformed batch".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_handle_rx_error() {
+        let mut mux = Multiplexer::with_device(Box::new(MockCmio::new(64)));
+        let sockets = Arc::new(Mutex::new(Vec::new()));
+        mux.register(YieldReason::TapRxTx, Box::new(Refuser { pending: vec![vec![1], vec![2]] })).unwrap();
+        mux.register(YieldReason::UnixSocket, Box::new(Recorder { pending: vec![vec![3]], received: Arc::clone(&sockets) })).unwrap();
+
+        // The refused batches are counted and dropped, and the rounds go on for everyone
+        assert!(mux.run_once().unwrap());
+        assert!(mux.run_once().unwrap());
+        assert_eq!(mux.rejected(), 2);
+        assert_eq!(*sockets.lock().unwrap(), vec![vec![3]]);
+    }
+}
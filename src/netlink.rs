@@ -0,0 +1,234 @@
+use std::ffi::CString;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+// Route netlink message types
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+const NLMSG_ERROR: u16 = 2;
+
+// Netlink message flags
+const NLM_F_REQUEST: u16 = 0x001;
+const NLM_F_ACK: u16 = 0x004;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+// Attribute types for addresses and routes
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+
+// Route table, origin, scope and type of the default route
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+// Size of the netlink message header
+const NLMSG_HEADER_SIZE: usize = 16;
+
+// Address family of an IP address
+fn family(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+// Raw bytes of an IP address
+fn octets(addr: &IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+// Append a route attribute, padded to 4 bytes
+fn push_attribute(buffer: &mut Vec<u8>, attr_type: u16, data: &[u8]) {
+    buffer.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+    buffer.extend_from_slice(&attr_type.to_ne_bytes());
+    buffer.extend_from_slice(data);
+    buffer.resize(buffer.len().next_multiple_of(4), 0);
+}
+
+// Wrap a payload in a netlink message header
+fn message(msg_type: u16, flags: u16, seq: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(NLMSG_HEADER_SIZE + payload.len());
+    buffer.extend_from_slice(&((NLMSG_HEADER_SIZE + payload.len()) as u32).to_ne_bytes());
+    buffer.extend_from_slice(&msg_type.to_ne_bytes());
+    buffer.extend_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+    buffer.extend_from_slice(&seq.to_ne_bytes());
+    buffer.extend_from_slice(&0u32.to_ne_bytes()); // Port ID, filled in by the kernel
+    buffer.extend_from_slice(payload);
+    buffer
+}
+
+// Payload of RTM_NEWLINK setting IFF_UP on an interface
+fn link_up_payload(index: u32) -> Vec<u8> {
+    let mut payload = vec![libc::AF_UNSPEC as u8, 0];
+    payload.extend_from_slice(&0u16.to_ne_bytes()); // Device type
+    payload.extend_from_slice(&(index as i32).to_ne_bytes());
+    payload.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes()); // Flags
+    payload.extend_from_slice(&(libc::IFF_UP as u32).to_ne_bytes()); // Change mask
+    payload
+}
+
+// Payload of RTM_NEWADDR assigning an address with a prefix length to an interface
+fn address_payload(index: u32, addr: &IpAddr, prefix_len: u8) -> Vec<u8> {
+    let mut payload = vec![family(addr), prefix_len, 0, RT_SCOPE_UNIVERSE];
+    payload.extend_from_slice(&index.to_ne_bytes());
+    push_attribute(&mut payload, IFA_LOCAL, &octets(addr));
+    push_attribute(&mut payload, IFA_ADDRESS, &octets(addr));
+    payload
+}
+
+// Payload of RTM_NEWROUTE adding a default route via a gateway on an interface
+fn default_route_payload(index: u32, gateway: &IpAddr) -> Vec<u8> {
+    let mut payload = vec![
+        family(gateway),
+        0, // Destination prefix length, 0 for the default route
+        0, // Source prefix length
+        0, // TOS
+        RT_TABLE_MAIN,
+        RTPROT_BOOT,
+        RT_SCOPE_UNIVERSE,
+        RTN_UNICAST,
+    ];
+    payload.extend_from_slice(&0u32.to_ne_bytes()); // Flags
+    push_attribute(&mut payload, RTA_GATEWAY, &octets(gateway));
+    push_attribute(&mut payload, RTA_OIF, &index.to_ne_bytes());
+    payload
+}
+
+// Extract the result of an acknowledged request from the kernel's NLMSG_ERROR reply
+fn parse_ack(reply: &[u8]) -> io::Result<()> {
+    if reply.len() < NLMSG_HEADER_SIZE + 4 {
+        return Err(io::Error::from_raw_os_error(libc::EPROTO));
+    }
+    let msg_type = u16::from_ne_bytes([reply[4], reply[5]]);
+    if msg_type != NLMSG_ERROR {
+        return Err(io::Error::from_raw_os_error(libc::EPROTO));
+    }
+    
+    // An error code of 0 is the acknowledgement, anything else a negated errno
+    let error = i32::from_ne_bytes([reply[16], reply[17], reply[18], reply[19]]);
+    if error < 0 {
+        return Err(io::Error::from_raw_os_error(-error));
+    }
+    Ok(())
+}
+
+// Structure wrapping a route netlink socket that sends requests one at a time
+pub struct Netlink {
+    socket: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            socket: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+    
+    /// Bring the interface up
+    pub fn link_up(&mut self, index: u32) -> io::Result<()> {
+        self.request(RTM_NEWLINK, 0, &link_up_payload(index))
+    }
+    
+    /// Assign an IPv4 or IPv6 address with the given prefix length to the interface
+    pub fn add_address(&mut self, index: u32, addr: IpAddr, prefix_len: u8) -> io::Result<()> {
+        self.request(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, &address_payload(index, &addr, prefix_len))
+    }
+    
+    /// Install a default route through the gateway on the interface
+    pub fn add_default_route(&mut self, index: u32, gateway: IpAddr) -> io::Result<()> {
+        self.request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, &default_route_payload(index, &gateway))
+    }
+    
+    // Send a request and wait for the kernel to acknowledge it
+    fn request(&mut self, msg_type: u16, flags: u16, payload: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let request = message(msg_type, flags, self.seq, payload);
+        
+        let sent = unsafe { libc::send(self.socket.as_raw_fd(), request.as_ptr() as *const libc::c_void, request.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        
+        let mut reply = [0u8; 4096];
+        let received = unsafe { libc::recv(self.socket.as_raw_fd(), reply.as_mut_ptr() as *mut libc::c_void, reply.len(), 0) };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        parse_ack(&reply[..received as usize])
+    }
+}
+
+/// Look up the index of a network interface by name
+pub fn interface_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_address_payload() {
+        let payload = address_payload(3, &IpAddr::V4(Ipv4Addr::new(10, 0, 2, 15)), 24);
+
+        assert_eq!(&payload[..4], &[libc::AF_INET as u8, 24, 0, RT_SCOPE_UNIVERSE]);
+        assert_eq!(&payload[4..8], &3u32.to_ne_bytes());
+        // Two 8-byte attributes carrying the address
+        assert_eq!(payload.len(), 8 + 2 * 8);
+        assert_eq!(&payload[10..12], &IFA_LOCAL.to_ne_bytes());
+        assert_eq!(&payload[12..16], &[10, 0, 2, 15]);
+        assert_eq!(&payload[18..20], &IFA_ADDRESS.to_ne_bytes());
+
+        let payload = address_payload(3, &IpAddr::V6(Ipv6Addr::LOCALHOST), 128);
+        assert_eq!(payload[0], libc::AF_INET6 as u8);
+        assert_eq!(payload.len(), 8 + 2 * 20);
+    }
+
+    #[test]
+    fn test_default_route_message() {
+        let request = message(RTM_NEWROUTE, NLM_F_CREATE, 7, &default_route_payload(3, &IpAddr::V4(Ipv4Addr::new(10, 0, 2, 2))));
+
+        assert_eq!(&request[..4], &(request.len() as u32).to_ne_bytes());
+        assert_eq!(&request[4..6], &RTM_NEWROUTE.to_ne_bytes());
+        assert_eq!(&request[6..8], &(NLM_F_CREATE | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        assert_eq!(&request[8..12], &7u32.to_ne_bytes());
+
+        let payload = &request[NLMSG_HEADER_SIZE..];
+        assert_eq!(payload[1], 0); // Default route
+        assert_eq!(payload[4], RT_TABLE_MAIN);
+        assert_eq!(&payload[16..20], &[10, 0, 2, 2]);
+        assert_eq!(&payload[24..28], &3u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn test_parse_ack() {
+        let mut reply = message(NLMSG_ERROR, 0, 1, &0i32.to_ne_bytes());
+        assert!(parse_ack(&reply).is_ok());
+
+        reply.truncate(NLMSG_HEADER_SIZE);
+        reply.extend_from_slice(&(-libc::EEXIST).to_ne_bytes());
+        assert_eq!(parse_ack(&reply).unwrap_err().raw_os_error(), Some(libc::EEXIST));
+
+        assert!(parse_ack(&[0; 8]).is_err());
+    }
+}
@@ -1,258 +1,1753 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs;
 use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
 use libc;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
 use tun_tap::{Iface, Mode};
+use tracing::{debug, info, warn};
+use crate::bridge::{ForwardingTable, Port};
 use crate::cmio::{Cmio, CmioError};
-
-// HTIF yield constants
-const HTIF_DEVICE_YIELD: u8 = 0x02;
-const HTIF_YIELD_CMD_MANUAL: u8 = 0x01;
-const TAP_RXTX_CMD: u16 = 0x42;
+use crate::dhcp::{self, DhcpLease};
+use crate::filter::{FrameFilter, ETHERTYPE_VLAN};
+use crate::idle::{IdleState, IdleStrategy, IdleWait};
+use crate::ipv6::{self, ETHERTYPE_IPV6};
+use crate::multiplexer::Subsystem;
+use crate::netlink::{interface_index, Netlink};
+use crate::pool::{BufferPool, DEFAULT_POOL_SIZE};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+use crate::qos::{self, Priority};
+use crate::shutdown;
 
 // Buffer sizes
-const MAX_PACKET_SIZE: usize = 1500; // Standard MTU size
+const DEFAULT_MTU: u32 = 1500; // Standard MTU size
+const MIN_MTU: u32 = 68; // Smallest MTU IPv4 allows
+pub const MIN_BATCH_SIZE: usize = 256; // Smallest batch size a cap may set
+// Room for the packet info, Ethernet header and a VLAN tag around the MTU-sized payload
+pub(crate) const PACKET_INFO_SIZE: usize = 4;
+const FRAME_OVERHEAD: usize = PACKET_INFO_SIZE + 14 + 4;
+
+// Batch header: magic, version, flags, frame count and two reserved bytes
+const BATCH_MAGIC: u16 = 0x5443; // "TC"
+const BATCH_VERSION: u8 = 2;
+pub(crate) const BATCH_HEADER_SIZE: usize = 2 + 1 + 1 + 2 + 2;
+
+// Frame entry header: length, flags and interface index, followed by the fragment
+// ID and offset for fragments
+pub(crate) const FRAME_HEADER_SIZE: usize = 2 + 1 + 1;
+const FRAGMENT_INFO_SIZE: usize = 2 + 2;
+// Size of the CRC32 of the frame data, following the fragment info when present
+const CHECKSUM_SIZE: usize = 4;
+
+// Frame flags
+pub(crate) const FRAME_FLAG_L3: u8 = 0x01; // IP packet (TUN mode) rather than an Ethernet frame
+const FRAME_FLAG_FRAGMENT: u8 = 0x02; // Fragment of a frame larger than the CMIO buffer
+const FRAME_FLAG_MORE_FRAGMENTS: u8 = 0x04; // More fragments of the frame follow
+const FRAME_FLAG_CHECKSUM: u8 = 0x08; // A CRC32 of the frame data is included
+
+// Offset of the EtherType in an Ethernet frame, where an 802.1Q tag is inserted
+const ETHERTYPE_OFFSET: usize = 12;
+const VLAN_TAG_SIZE: usize = 4;
+// VLAN IDs 0 and 4095 are reserved
+const MAX_VLAN_ID: u16 = 4094;
+
+// Largest MTU whose frames still fit the length field
+const MAX_MTU: u32 = u16::MAX as u32 - FRAME_OVERHEAD as u32;
+
+// TAP device ioctls (_IOW('T', nr, int)), taking their argument by value
+const TUNSETPERSIST: libc::c_ulong = 0x400454cb;
+const TUNSETOWNER: libc::c_ulong = 0x400454cc;
+const TUNSETGROUP: libc::c_ulong = 0x400454ce;
+const TUNSETCARRIER: libc::c_ulong = 0x400454e2;
+
+// Control messages from the host, received on the control reason code
+const CONTROL_LINK_DOWN: u8 = 0x01;
+const CONTROL_LINK_UP: u8 = 0x02;
+
+// Name of the TAP interface unless configured otherwise
+pub const DEFAULT_TAP_NAME: &str = "tapcmio0";
+
+// How long an idle loop waits for the TAP interface to become readable before yielding
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+// Batch buffers kept for reuse, few as each is as large as the CMIO buffer
+const MAX_POOLED_BATCHES: usize = 4;
+
+// Batches of frames and single frames queued between the pipeline threads
+const PIPELINE_DEPTH: usize = 64;
+
+// How often the pipeline's TAP reader checks whether it should stop
+const READER_POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Poll token of the TAP interface
+const TAP_TOKEN: Token = Token(0);
+const BRIDGE_TOKEN: Token = Token(1);
+
+// Structure describing how the TAP interface is created
+#[derive(Debug, Clone)]
+pub struct TapConfig {
+    // Interface name
+    pub name: String,
+    // Mode::Tap exchanges Ethernet frames, Mode::Tun raw IP packets
+    pub mode: Mode,
+    // User allowed to open the device without CAP_NET_ADMIN
+    pub owner: Option<u32>,
+    // Group allowed to open the device without CAP_NET_ADMIN
+    pub group: Option<u32>,
+    // Keep the device after the interface is closed
+    pub persistent: bool,
+    // MTU to set on the interface, which otherwise keeps the standard 1500 bytes
+    pub mtu: Option<u32>,
+    // Addresses with prefix lengths to assign to the interface
+    pub addresses: Vec<(IpAddr, u8)>,
+    // Gateway of a default route through the interface
+    pub gateway: Option<IpAddr>,
+    // Obtain an IPv4 address, gateway and DNS servers with the built-in DHCP client
+    pub dhcp: bool,
+    // How to wait for outgoing packets when idle; polling with a timeout bounds the
+    // latency of incoming ones
+    pub idle: IdleStrategy,
+    // Include a CRC32 with each frame sent to the host
+    pub checksum: bool,
+    // Interval at which run_loop logs the traffic statistics
+    pub stats_interval: Option<Duration>,
+    // Limit on frames read from the TAP interface and sent to the host
+    pub egress_limit: RateLimit,
+    // Cap on frames read from the TAP interfaces per loop iteration, so received frames
+    // get processed between bounded batches under load
+    pub read_limit: ReadLimit,
+    // Limit on frames received from the host and written to the TAP interface
+    pub ingress_limit: RateLimit,
+    // Rules deciding which frames may pass in either direction
+    pub filter: FrameFilter,
+    // VLAN ID tagged onto frames sent to the host and stripped from frames received
+    pub vlan: Option<u16>,
+    // IPv6 address whose neighbor solicitations from the host are answered with the
+    // TAP interface's MAC address
+    pub ndp_proxy: Option<Ipv6Addr>,
+    // Whether frames sent to the host are reordered so control traffic goes first
+    pub qos: bool,
+    // Ethernet address programmed into the TAP interface at startup
+    pub mac: Option<[u8; 6]>,
+    // Whether the host's link up and down control messages set the interface's carrier
+    pub link_control: bool,
+    // Name of a local TAP interface bridged with this one and the host
+    pub bridge: Option<String>,
+    // Read from and write to the TAP interface on threads of their own, so slow TAP
+    // writes don't hold up CMIO yields and the other way round
+    pub pipeline: bool,
+    // CMIO device node, by default the one named by TAPCMIO_DEVICE or /dev/cmio
+    pub cmio_device: Option<PathBuf>,
+    // Largest batch sent in one yield, below the device's TX buffer size
+    pub max_batch_size: Option<usize>,
+}
+
+impl Default for TapConfig {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_TAP_NAME.to_string(),
+            mode: Mode::Tap,
+            owner: None,
+            group: None,
+            persistent: false,
+            mtu: None,
+            addresses: Vec::new(),
+            gateway: None,
+            dhcp: false,
+            idle: IdleStrategy::Poll(DEFAULT_IDLE_TIMEOUT),
+            checksum: false,
+            stats_interval: None,
+            egress_limit: RateLimit::default(),
+            read_limit: ReadLimit::default(),
+            ingress_limit: RateLimit::default(),
+            filter: FrameFilter::default(),
+            vlan: None,
+            ndp_proxy: None,
+            qos: false,
+            mac: None,
+            link_control: false,
+            bridge: None,
+            pipeline: false,
+            cmio_device: None,
+            max_batch_size: None,
+        }
+    }
+}
+
+// Insert an 802.1Q tag with the VLAN ID into the Ethernet frame starting at offset, in
+// place so a frame with room to spare isn't reallocated
+fn insert_vlan_tag(frame: &mut Vec<u8>, offset: usize, vlan: u16) {
+    let tag_offset = offset + ETHERTYPE_OFFSET;
+    if frame.len() < tag_offset + 2 {
+        return;
+    }
+    
+    let mut tag = [0u8; VLAN_TAG_SIZE];
+    tag[..2].copy_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+    tag[2..].copy_from_slice(&vlan.to_be_bytes()); // Priority 0, ID
+    frame.splice(tag_offset..tag_offset, tag);
+}
+
+// Remove the 802.1Q tag from the Ethernet frame starting at offset, returning None
+// unless it's tagged with the VLAN ID
+fn strip_vlan_tag(frame: &[u8], offset: usize, vlan: u16) -> Option<Vec<u8>> {
+    let tag_offset = offset + ETHERTYPE_OFFSET;
+    let tag = frame.get(tag_offset..tag_offset + VLAN_TAG_SIZE)?;
+    if u16::from_be_bytes([tag[0], tag[1]]) != ETHERTYPE_VLAN || u16::from_be_bytes([tag[2], tag[3]]) & 0x0FFF != vlan {
+        return None;
+    }
+    
+    let mut untagged = frame[..tag_offset].to_vec();
+    untagged.extend_from_slice(&frame[tag_offset + VLAN_TAG_SIZE..]);
+    Some(untagged)
+}
+
+// Structure describing a rate limit in packets and bytes per second, either unlimited if None
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub packets_per_second: Option<u64>,
+    pub bytes_per_second: Option<u64>,
+}
+
+// Structure describing how many frames and bytes are read from the TAP interfaces in one
+// loop iteration, either unlimited if None
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadLimit {
+    pub frames: Option<usize>,
+    pub bytes: Option<usize>,
+}
+
+impl ReadLimit {
+    /// Whether the frames and bytes read so far reach the limit
+    fn reached(&self, frames: usize, bytes: usize) -> bool {
+        self.frames.is_some_and(|limit| frames >= limit) || self.bytes.is_some_and(|limit| bytes >= limit)
+    }
+}
+
+// Structure implementing a token bucket refilled at a fixed rate, holding up to a second's worth
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last_refill: now,
+        }
+    }
+    
+    /// Add the tokens accumulated since the last refill, up to the bucket's capacity
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+}
+
+// Structure enforcing a rate limit with a token bucket for packets and one for bytes
+struct RateLimiter {
+    packets: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            packets: limit.packets_per_second.map(|rate| TokenBucket::new(rate, now)),
+            bytes: limit.bytes_per_second.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+    
+    /// Take the tokens for a frame of the given length, returning false if either
+    /// bucket doesn't hold enough, in which case neither is charged
+    fn allow(&mut self, length: usize, now: Instant) -> bool {
+        let mut buckets = [(self.packets.as_mut(), 1.0), (self.bytes.as_mut(), length as f64)];
+        for (bucket, cost) in buckets.iter_mut() {
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                if bucket.tokens < *cost {
+                    return false;
+                }
+            }
+        }
+        for (bucket, cost) in buckets.iter_mut() {
+            if let Some(bucket) = bucket {
+                bucket.tokens -= *cost;
+            }
+        }
+        true
+    }
+}
+
+// Issue a TAP device ioctl whose argument is passed by value
+fn tap_ioctl(iface: &Iface, request: libc::c_ulong, value: libc::c_ulong) -> Result<(), CmioError> {
+    if unsafe { libc::ioctl(iface.as_raw_fd(), request, value) } < 0 {
+        return Err(CmioError::io(format!("configure {} (ioctl {:#x})", iface.name(), request), io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Open a socket for interface ioctls together with a request naming the interface
+fn interface_request(name: &str) -> Result<(OwnedFd, libc::ifreq), CmioError> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(CmioError::InvalidArgument(format!("interface name {} is too long", name)));
+    }
+    
+    // Interface ioctls go through any socket
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(CmioError::io("open a socket for interface ioctls", io::Error::last_os_error()));
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok((socket, request))
+}
+
+// Set the MTU of a network interface by name
+fn set_mtu(name: &str, mtu: u32) -> Result<(), CmioError> {
+    let (socket, mut request) = interface_request(name)?;
+    request.ifr_ifru.ifru_mtu = mtu as libc::c_int;
+    
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFMTU, &request) } < 0 {
+        return Err(CmioError::io(format!("set the MTU of {} to {}", name, mtu), io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Get the Ethernet address of a network interface by name
+fn hardware_address(name: &str) -> Result<[u8; 6], CmioError> {
+    let (socket, mut request) = interface_request(name)?;
+    
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFHWADDR, &mut request) } < 0 {
+        return Err(CmioError::io(format!("get the Ethernet address of {}", name), io::Error::last_os_error()));
+    }
+    let data = unsafe { request.ifr_ifru.ifru_hwaddr.sa_data };
+    let mut mac = [0u8; 6];
+    for (dst, src) in mac.iter_mut().zip(data.iter()) {
+        *dst = *src as u8;
+    }
+    Ok(mac)
+}
+
+// Set the Ethernet address of a network interface by name
+fn set_hardware_address(name: &str, mac: &[u8; 6]) -> Result<(), CmioError> {
+    let (socket, mut request) = interface_request(name)?;
+    unsafe {
+        request.ifr_ifru.ifru_hwaddr.sa_family = libc::ARPHRD_ETHER;
+        for (dst, src) in request.ifr_ifru.ifru_hwaddr.sa_data.iter_mut().zip(mac.iter()) {
+            *dst = *src as libc::c_char;
+        }
+    }
+    
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFHWADDR, &request) } < 0 {
+        return Err(CmioError::io(format!("set the Ethernet address of {}", name), io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Bring the interface up, assign its addresses and install the default route
+fn configure_ip(name: &str, addresses: &[(IpAddr, u8)], gateway: Option<IpAddr>) -> io::Result<()> {
+    let index = interface_index(name)?;
+    let mut netlink = Netlink::new()?;
+    
+    netlink.link_up(index)?;
+    for &(addr, prefix_len) in addresses {
+        netlink.add_address(index, addr, prefix_len)?;
+    }
+    if let Some(gateway) = gateway {
+        netlink.add_default_route(index, gateway)?;
+    }
+    Ok(())
+}
+
+// Obtain a lease on the interface and apply its address, default route and DNS servers
+fn run_dhcp(name: &str, mac: &[u8; 6]) -> io::Result<DhcpLease> {
+    let lease = dhcp::acquire(name, mac)?;
+    configure_ip(name, &[(IpAddr::V4(lease.address), lease.prefix_len)], lease.gateway.map(IpAddr::V4))?;
+    
+    if !lease.dns.is_empty() {
+        let resolv_conf: String = lease.dns.iter().map(|server| format!("nameserver {}\n", server)).collect();
+        fs::write("/etc/resolv.conf", resolv_conf)?;
+    }
+    Ok(lease)
+}
+
+// Structure describing a frame entry of a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameEntry<'a> {
+    pub flags: u8,
+    // Index of the interface the frame belongs to, always 0 for now
+    pub interface: u8,
+    // Frame ID and offset of a fragment
+    pub fragment: Option<(u16, u16)>,
+    // CRC32 of the data
+    pub checksum: Option<u32>,
+    pub data: &'a [u8],
+}
+
+impl<'a> FrameEntry<'a> {
+    pub(crate) fn new(flags: u8, data: &'a [u8]) -> Self {
+        Self { flags, interface: 0, fragment: None, checksum: None, data }
+    }
+    
+    /// Add a CRC32 of the data to the entry
+    fn with_checksum(mut self) -> Self {
+        self.flags |= FRAME_FLAG_CHECKSUM;
+        self.checksum = Some(crc32(self.data));
+        self
+    }
+    
+    /// Check the data against the entry's CRC32, if it has one
+    pub fn is_intact(&self) -> bool {
+        self.checksum.is_none_or(|checksum| checksum == crc32(self.data))
+    }
+    
+    /// Size of the entry in a batch, header included
+    fn size(&self) -> usize {
+        FRAME_HEADER_SIZE
+            + if self.fragment.is_some() { FRAGMENT_INFO_SIZE } else { 0 }
+            + if self.checksum.is_some() { CHECKSUM_SIZE } else { 0 }
+            + self.data.len()
+    }
+}
+
+// Whether an error reading or writing a single frame on the TAP interface leaves the
+// interface usable, so the frame can be dropped and the loop go on
+fn is_recoverable(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EIO | libc::EINVAL | libc::EMSGSIZE | libc::ENOBUFS | libc::ENOMEM))
+}
+
+// Read the frames waiting on a non-blocking TAP interface into buffers from the pool
+// until the read limit is reached, counting recoverable errors
+//
+// The frames and bytes read are added to read, so the limit can span several interfaces.
+fn read_frames(iface: &Iface, buffer: &mut [u8], pool: &mut BufferPool, limit: &ReadLimit, read: &mut (usize, usize), errors: &mut u64) -> Result<Vec<Vec<u8>>, CmioError> {
+    let mut frames = Vec::new();
+    
+    while !limit.reached(read.0, read.1) {
+        match iface.recv(buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                frames.push(pool.copy_of(&buffer[..n]));
+                *read = (read.0 + 1, read.1 + n);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) if is_recoverable(&e) => {
+                // Leave the rest for the next round rather than end the loop
+                warn!("Failed to read from {}: {}", iface.name(), e);
+                *errors += 1;
+                break;
+            }
+            Err(e) => return Err(CmioError::io(format!("read a frame from {}", iface.name()), e)),
+        }
+    }
+    Ok(frames)
+}
+
+// Write a frame to a TAP interface, returning whether it was written; frames failing
+// with a recoverable error are logged, counted and dropped
+fn send_frame(iface: &Iface, frame: &[u8], errors: &mut u64) -> Result<bool, CmioError> {
+    match iface.send(frame) {
+        Ok(_) => Ok(true),
+        Err(e) if is_recoverable(&e) => {
+            warn!("Failed to write {} bytes to {}: {}", frame.len(), iface.name(), e);
+            *errors += 1;
+            Ok(false)
+        }
+        Err(e) => Err(CmioError::io(format!("write a {} byte frame to {}", frame.len(), iface.name()), e)),
+    }
+}
+
+// Compute the CRC32 (IEEE 802.3) of the data
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Encode frame entries into a batch behind the batch header, for the user-space stack;
+// the TAP loop encodes into its pooled buffers instead
+#[cfg(any(test, feature = "user-stack"))]
+pub(crate) fn encode_batch(entries: &[FrameEntry]) -> Vec<u8> {
+    let mut batch = Vec::with_capacity(BATCH_HEADER_SIZE + entries.iter().map(FrameEntry::size).sum::<usize>());
+    encode_batch_into(entries, &mut batch);
+    batch
+}
+
+// Encode frame entries into a batch appended to a buffer, which may be reused
+fn encode_batch_into(entries: &[FrameEntry], batch: &mut Vec<u8>) {
+    batch.reserve(BATCH_HEADER_SIZE + entries.iter().map(FrameEntry::size).sum::<usize>());
+    batch.extend_from_slice(&BATCH_MAGIC.to_be_bytes());
+    batch.push(BATCH_VERSION);
+    batch.push(0); // Batch flags
+    batch.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+    batch.extend_from_slice(&[0, 0]); // Reserved
+    
+    for entry in entries {
+        batch.extend_from_slice(&(entry.data.len() as u16).to_be_bytes());
+        batch.push(entry.flags);
+        batch.push(entry.interface);
+        if let Some((frame_id, offset)) = entry.fragment {
+            batch.extend_from_slice(&frame_id.to_be_bytes());
+            batch.extend_from_slice(&offset.to_be_bytes());
+        }
+        if let Some(checksum) = entry.checksum {
+            batch.extend_from_slice(&checksum.to_be_bytes());
+        }
+        batch.extend_from_slice(entry.data);
+    }
+}
+
+// Decode the frame entries of a batch
+//
+// Returns None for a batch without a valid header, which comes from a host speaking
+// another protocol version. A truncated entry ends the batch, so any input decodes
+// without panicking.
+pub fn decode_batch(data: &[u8]) -> Option<Vec<FrameEntry<'_>>> {
+    if data.len() < BATCH_HEADER_SIZE || data[..2] != BATCH_MAGIC.to_be_bytes() || data[2] != BATCH_VERSION {
+        return None;
+    }
+    let count = u16::from_be_bytes([data[4], data[5]]) as usize;
+    
+    let mut entries = Vec::with_capacity(count);
+    let mut offset = BATCH_HEADER_SIZE;
+    while entries.len() < count && offset + FRAME_HEADER_SIZE <= data.len() {
+        let length = u16::from_be_bytes([data[offset], data[offset + 1]]) as usize;
+        let mut entry = FrameEntry::new(data[offset + 2], &[]);
+        entry.interface = data[offset + 3];
+        offset += FRAME_HEADER_SIZE;
+        
+        if entry.flags & FRAME_FLAG_FRAGMENT != 0 {
+            if offset + FRAGMENT_INFO_SIZE > data.len() {
+                break;
+            }
+            let frame_id = u16::from_be_bytes([data[offset], data[offset + 1]]);
+            let fragment_offset = u16::from_be_bytes([data[offset + 2], data[offset + 3]]);
+            entry.fragment = Some((frame_id, fragment_offset));
+            offset += FRAGMENT_INFO_SIZE;
+        }
+        if entry.flags & FRAME_FLAG_CHECKSUM != 0 {
+            if offset + CHECKSUM_SIZE > data.len() {
+                break;
+            }
+            entry.checksum = Some(u32::from_be_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]));
+            offset += CHECKSUM_SIZE;
+        }
+        
+        match data.get(offset..offset + length) {
+            Some(frame) => entry.data = frame,
+            None => break,
+        }
+        offset += length;
+        entries.push(entry);
+    }
+    Some(entries)
+}
+
+// Split a frame into fragment entries that each fit a batch of at most max_size bytes,
+// leaving room for a checksum
+fn fragment_frame(frame: &[u8], frame_id: u16, flags: u8, max_size: usize) -> Vec<FrameEntry<'_>> {
+    let chunk_size = max_size.saturating_sub(BATCH_HEADER_SIZE + FRAME_HEADER_SIZE + FRAGMENT_INFO_SIZE + CHECKSUM_SIZE).max(1);
+    
+    frame.chunks(chunk_size).enumerate().map(|(index, chunk)| {
+        let offset = index * chunk_size;
+        let more = if offset + chunk.len() < frame.len() { FRAME_FLAG_MORE_FRAGMENTS } else { 0 };
+        let mut entry = FrameEntry::new(flags | FRAME_FLAG_FRAGMENT | more, chunk);
+        entry.fragment = Some((frame_id, offset as u16));
+        entry
+    }).collect()
+}
+
+// Structure collecting the fragments of a frame received from the host
+pub(crate) struct Reassembly {
+    frame_id: u16,
+    flags: u8,
+    data: Vec<u8>,
+}
+
+// Add a fragment entry to the frame being reassembled, returning the frame once complete
+//
+// Fragments arrive in order, so a fragment that doesn't continue the current frame
+// discards it, and only a fragment at offset 0 starts a new one. Frames growing past
+// max_size are dropped.
+fn reassemble(state: &mut Option<Reassembly>, entry: &FrameEntry, max_size: usize) -> Option<Vec<u8>> {
+    let (frame_id, offset) = entry.fragment?;
+    let flags = entry.flags & FRAME_FLAG_L3;
+    let offset = offset as usize;
+    
+    let mut reassembly = match state.take() {
+        Some(reassembly) if reassembly.frame_id == frame_id && reassembly.flags == flags && reassembly.data.len() == offset => reassembly,
+        _ if offset == 0 => Reassembly { frame_id, flags, data: Vec::new() },
+        _ => return None,
+    };
+    
+    reassembly.data.extend_from_slice(entry.data);
+    if reassembly.data.len() > max_size {
+        return None;
+    }
+    if entry.flags & FRAME_FLAG_MORE_FRAGMENTS != 0 {
+        *state = Some(reassembly);
+        return None;
+    }
+    Some(reassembly.data)
+}
+
+// Encode frame entries into a batch in a buffer taken from the pool
+fn pooled_batch(pool: &mut BufferPool, entries: &[FrameEntry]) -> Vec<u8> {
+    let mut batch = pool.take();
+    encode_batch_into(entries, &mut batch);
+    batch
+}
+
+// Encode frames into CMIO-sized batches, counting them in the TX statistics
+//
+// Frames go into a batch until the next one would overflow the buffer. Frames too
+// large for a buffer of their own are sent in fragments, each in a batch of its own,
+// carrying the frame ID, its offset and whether more fragments follow, so the host can
+// reassemble the frame. Batch buffers are taken from the pool.
+pub(crate) fn encode_frames(frames: &[Vec<u8>], flags: u8, checksum: bool, max_size: usize, next_frame_id: &mut u16, pool: &mut BufferPool, stats: &mut NetworkStats) -> Vec<Vec<u8>> {
+    let mut batches = Vec::new();
+    let mut current_batch: Vec<FrameEntry> = Vec::new();
+    let mut current_batch_size = BATCH_HEADER_SIZE;
+    
+    for frame in frames {
+        stats.tx_frames += 1;
+        stats.tx_bytes += frame.len() as u64;
+        
+        // Calculate the size of this frame with its frame header and checksum
+        let frame_size = frame.len() + FRAME_HEADER_SIZE + if checksum { CHECKSUM_SIZE } else { 0 };
+        
+        // Frames too large for a CMIO buffer are sent in fragments of their own
+        if BATCH_HEADER_SIZE + frame_size > max_size {
+            if !current_batch.is_empty() {
+                batches.push(pooled_batch(pool, &current_batch));
+                current_batch.clear();
+                current_batch_size = BATCH_HEADER_SIZE;
+            }
+            let frame_id = *next_frame_id;
+            *next_frame_id = next_frame_id.wrapping_add(1);
+            for fragment in fragment_frame(frame, frame_id, flags, max_size) {
+                stats.tx_fragments += 1;
+                let fragment = if checksum { fragment.with_checksum() } else { fragment };
+                batches.push(pooled_batch(pool, &[fragment]));
+            }
+            continue;
+        }
+        
+        // Start a new batch if adding this frame would exceed the CMIO buffer size
+        if current_batch_size + frame_size > max_size && !current_batch.is_empty() {
+            batches.push(pooled_batch(pool, &current_batch));
+            current_batch.clear();
+            current_batch_size = BATCH_HEADER_SIZE;
+        }
+        
+        let entry = FrameEntry::new(flags, frame);
+        current_batch.push(if checksum { entry.with_checksum() } else { entry });
+        current_batch_size += frame_size;
+    }
+    
+    if !current_batch.is_empty() {
+        batches.push(pooled_batch(pool, &current_batch));
+    }
+    batches
+}
+
+// Decode a batch received from the host into complete frames with their flags
+//
+// Batches with an unknown magic or version are dropped, and so are frames failing their
+// CRC32 check and those cut off by a length prefix running past the end of the batch,
+// counting each in the RX statistics. Fragments are reassembled across batches.
+pub(crate) fn decode_frames<'a>(data: &'a [u8], reassembly: &mut Option<Reassembly>, max_frame_size: usize, stats: &mut NetworkStats) -> Vec<(u8, Cow<'a, [u8]>)> {
+    let Some(entries) = decode_batch(data) else {
+        warn!("Dropping {} bytes of batch with an unsupported header", data.len());
+        stats.rx_bad_batches += 1;
+        return Vec::new();
+    };
+    let count = u16::from_be_bytes([data[4], data[5]]) as usize;
+    if entries.len() < count {
+        warn!("Dropping {} of {} frames of a batch with a bad length prefix", count - entries.len(), count);
+        stats.rx_malformed += (count - entries.len()) as u64;
+    }
+    
+    let mut frames = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if !entry.is_intact() {
+            stats.rx_corrupted += 1;
+            continue;
+        }
+        
+        if entry.fragment.is_some() {
+            // Collect fragments until their frame is complete
+            if let Some(frame) = reassemble(reassembly, &entry, max_frame_size) {
+                frames.push((entry.flags, Cow::Owned(frame)));
+            }
+        } else {
+            frames.push((entry.flags, Cow::Borrowed(entry.data)));
+        }
+    }
+    frames
+}
+
+// Structure connecting the CMIO thread to the TAP reader and writer threads of the
+// pipeline by bounded channels
+struct Pipeline {
+    // Frames read from the TAP interface, and their buffers on the way back for reuse
+    frames: Receiver<Vec<Vec<u8>>>,
+    recycle: SyncSender<Vec<Vec<u8>>>,
+    // Frames from the host to write to the TAP interface
+    writer: SyncSender<Vec<u8>>,
+    // Frames received while waiting idle, sent in the next round
+    waiting: Vec<Vec<u8>>,
+    // Recoverable read and write errors counted by the threads
+    tx_errors: Arc<AtomicU64>,
+    rx_errors: Arc<AtomicU64>,
+    running: Arc<AtomicBool>,
+}
+
+impl Pipeline {
+    /// Start the TAP reader and writer threads
+    fn start(iface: &Arc<Iface>, frame_size: usize, limit: ReadLimit) -> Result<Self, CmioError> {
+        let (frames_sender, frames) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let (recycle, recycled) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let (writer, writer_frames) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let tx_errors = Arc::new(AtomicU64::new(0));
+        let rx_errors = Arc::new(AtomicU64::new(0));
+        let running = Arc::new(AtomicBool::new(true));
+        
+        let reader = TapReader {
+            iface: Arc::clone(iface),
+            frame_size,
+            limit,
+            frames: frames_sender,
+            recycled,
+            errors: Arc::clone(&tx_errors),
+            running: Arc::clone(&running),
+        };
+        let spawned = thread::Builder::new().name("tap-reader".to_string()).spawn(move || {
+            if let Err(e) = reader.run() {
+                warn!("TAP reader stopped: {}", e);
+            }
+        });
+        spawned.map_err(|e| CmioError::io("start the TAP reader thread", e))?;
+        
+        let write_iface = Arc::clone(iface);
+        let write_errors = Arc::clone(&rx_errors);
+        let spawned = thread::Builder::new().name("tap-writer".to_string()).spawn(move || {
+            run_writer(&write_iface, writer_frames, &write_errors);
+        });
+        spawned.map_err(|e| CmioError::io("start the TAP writer thread", e))?;
+        
+        Ok(Self { frames, recycle, writer, waiting: Vec::new(), tx_errors, rx_errors, running })
+    }
+    
+    /// Take the frames the reader has queued so far, failing if it stopped
+    fn take_frames(&mut self) -> Result<Vec<Vec<u8>>, CmioError> {
+        let mut frames = std::mem::take(&mut self.waiting);
+        loop {
+            match self.frames.try_recv() {
+                Ok(batch) => frames.extend(batch),
+                Err(TryRecvError::Empty) => return Ok(frames),
+                Err(TryRecvError::Disconnected) => return Err(CmioError::ThreadStopped("TAP reader")),
+            }
+        }
+    }
+    
+    /// Wait up to the timeout for the reader to queue frames, keeping them for the
+    /// next round
+    fn wait_for_frames(&mut self, timeout: Duration) -> Result<(), CmioError> {
+        match self.frames.recv_timeout(timeout) {
+            Ok(batch) => self.waiting.extend(batch),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Err(CmioError::ThreadStopped("TAP reader")),
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        // The writer stops once its channel closes, the reader at its next poll timeout
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+// Structure reading frames from the TAP interface on the reader thread of the pipeline
+struct TapReader {
+    iface: Arc<Iface>,
+    frame_size: usize,
+    limit: ReadLimit,
+    frames: SyncSender<Vec<Vec<u8>>>,
+    recycled: Receiver<Vec<Vec<u8>>>,
+    errors: Arc<AtomicU64>,
+    running: Arc<AtomicBool>,
+}
+
+impl TapReader {
+    /// Hand frames to the CMIO thread in batches up to the read limit until the pipeline
+    /// stops, blocking while the channel is full so the backlog stays in the kernel
+    fn run(self) -> Result<(), CmioError> {
+        let poll = Poll::new()
+            .map_err(|e| CmioError::io("create the TAP reader poll", e))?;
+        poll.register(&EventedFd(&self.iface.as_raw_fd()), TAP_TOKEN, Ready::readable(), PollOpt::level())
+            .map_err(|e| CmioError::io(format!("watch {}", self.iface.name()), e))?;
+        let mut events = Events::with_capacity(1);
+        let mut buffer = vec![0u8; self.frame_size];
+        let mut pool = BufferPool::new(self.frame_size + VLAN_TAG_SIZE, DEFAULT_POOL_SIZE);
+        let mut errors = 0;
+        
+        while self.running.load(Ordering::SeqCst) {
+            while let Ok(frames) = self.recycled.try_recv() {
+                pool.give_all(frames);
+            }
+            
+            let frames = read_frames(&self.iface, &mut buffer, &mut pool, &self.limit, &mut (0, 0), &mut errors)?;
+            self.errors.store(errors, Ordering::Relaxed);
+            if !frames.is_empty() {
+                if self.frames.send(frames).is_err() {
+                    break;
+                }
+                continue;
+            }
+            
+            match poll.poll(&mut events, Some(READER_POLL_TIMEOUT)) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(CmioError::io(format!("wait for frames from {}", self.iface.name()), e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Write frames from the host to the TAP interface on the writer thread of the pipeline
+// until the channel closes
+fn run_writer(iface: &Iface, frames: Receiver<Vec<u8>>, errors: &AtomicU64) {
+    let mut count = 0;
+    for frame in frames {
+        if let Err(e) = send_frame(iface, &frame, &mut count) {
+            warn!("TAP writer stopped: {}", e);
+            return;
+        }
+        errors.store(count, Ordering::Relaxed);
+    }
+}
+
+// Structure counting the traffic through the network interface
+//
+// TX counts frames read from the TAP interface and sent to the host, RX frames received
+// from the host and written to the TAP interface.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetworkStats {
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    // Fragments sent for frames larger than the CMIO buffer
+    pub tx_fragments: u64,
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    // Frames of the wrong layer or larger than the MTU
+    pub rx_dropped: u64,
+    // Frames failing their CRC32 check
+    pub rx_corrupted: u64,
+    // Batches with an unknown magic or version
+    pub rx_bad_batches: u64,
+    // Frames missing from a batch because of a bad length prefix
+    pub rx_malformed: u64,
+    // Recoverable errors reading from and writing to the TAP interface
+    pub tx_errors: u64,
+    pub rx_errors: u64,
+    // Frames exceeding the egress and ingress rate limits
+    pub tx_rate_limited: u64,
+    pub rx_rate_limited: u64,
+    // Frames rejected by the filter rules
+    pub tx_filtered: u64,
+    pub rx_filtered: u64,
+    // IPv6 frames among those sent and written
+    pub tx_ipv6_frames: u64,
+    pub rx_ipv6_frames: u64,
+    // Neighbor solicitations answered by the NDP proxy
+    pub ndp_proxied: u64,
+    // Frames forwarded between the two TAP interfaces in bridge mode
+    pub bridged: u64,
+    // CMIO yields, with or without data
+    pub yields: u64,
+}
+
+// Check a TAP interface configuration before anything is opened
+fn check_config(config: &TapConfig) -> Result<(), CmioError> {
+    let mtu = config.mtu.unwrap_or(DEFAULT_MTU);
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+        return Err(CmioError::InvalidArgument(format!("MTU {} is outside {}..={}", mtu, MIN_MTU, MAX_MTU)));
+    }
+    let ethernet_only = config.dhcp || config.vlan.is_some() || config.ndp_proxy.is_some() || config.mac.is_some() || config.bridge.is_some();
+    if ethernet_only && config.mode == Mode::Tun {
+        return Err(CmioError::InvalidArgument("DHCP, VLAN tagging, the NDP proxy, a MAC address and a bridge need TAP mode".to_string()));
+    }
+    if let Some(vlan) = config.vlan.filter(|vlan| !(1..=MAX_VLAN_ID).contains(vlan)) {
+        return Err(CmioError::InvalidArgument(format!("VLAN ID {} is outside 1..={}", vlan, MAX_VLAN_ID)));
+    }
+    if config.read_limit.frames == Some(0) || config.read_limit.bytes == Some(0) {
+        return Err(CmioError::InvalidArgument("read limits must be above zero".to_string()));
+    }
+    if config.pipeline && config.bridge.is_some() {
+        return Err(CmioError::InvalidArgument("the pipeline can't be combined with a bridge".to_string()));
+    }
+    if let Some(size) = config.max_batch_size.filter(|&size| size < MIN_BATCH_SIZE) {
+        return Err(CmioError::InvalidArgument(format!("batch size {} is below {} bytes", size, MIN_BATCH_SIZE)));
+    }
+    Ok(())
+}
 
 pub struct NetworkInterface {
-    cmio: Cmio,
-    iface: Iface,
+    // Device yielded to by run_loop, absent when a multiplexer does the yields
+    cmio: Option<Cmio>,
+    iface: Arc<Iface>,
+    mode: Mode,
     read_buffer: Vec<u8>,
+    // Reused buffers of frames read from the TAP interfaces and of batches sent
+    frame_pool: BufferPool,
+    batch_pool: BufferPool,
+    // Response of the last yield, reused so receiving doesn't allocate per yield
+    rx_buffer: Vec<u8>,
+    // Largest frame accepted from the host, derived from the MTU
+    max_frame_size: usize,
+    // Frame being reassembled from the host's fragments
+    reassembly: Option<Reassembly>,
+    // ID of the next frame sent in fragments
+    next_frame_id: u16,
+    // Whether frames sent carry a CRC32
+    checksum: bool,
+    stats: NetworkStats,
+    stats_interval: Option<Duration>,
+    last_report: Instant,
+    egress_limiter: RateLimiter,
+    read_limit: ReadLimit,
+    ingress_limiter: RateLimiter,
+    filter: FrameFilter,
+    vlan: Option<u16>,
+    // Proxied IPv6 address with the MAC address announced for it
+    ndp_proxy: Option<(Ipv6Addr, [u8; 6])>,
+    // Neighbor advertisements waiting to be sent to the host
+    pending_replies: Vec<Vec<u8>>,
+    // Whether frames sent are drained from priority queues
+    qos: bool,
+    // Whether the host controls the carrier, and its current state
+    link_control: bool,
+    carrier: bool,
+    // Local TAP interface and the addresses learned behind each port in bridge mode
+    bridge: Option<(Iface, ForwardingTable)>,
     cmio_max_buffer_size: usize,
+    // Hardware address to run the DHCP client with once the loop starts
+    dhcp_mac: Option<[u8; 6]>,
+    // Poll instance waiting for the TAP interface to become readable
+    poll: Poll,
+    events: Events,
+    idle: IdleState,
+    // Whether run_loop starts the TAP reader and writer threads, and their channels
+    // once started
+    pipelined: bool,
+    pipeline: Option<Pipeline>,
+    // Encoded batches waiting for the multiplexer to poll them
+    tx_batches: VecDeque<Vec<u8>>,
 }
 
 impl NetworkInterface {
     pub fn new() -> Result<Self, CmioError> {
+        Self::with_config(&TapConfig::default())
+    }
+    
+    /// Create the network interface with the given TAP interface name and device settings
+    /// 
+    /// In TUN mode, packets carry the FRAME_FLAG_L3 flag in their frame header. Owner
+    /// and group are applied before the persistent flag, so a persistent device can be
+    /// reopened later by that user or group. The MTU must be between 68 and 65513 bytes.
+    /// If addresses or a gateway are configured, the link is brought up and configured
+    /// over route netlink. DHCP, VLAN tagging, the NDP proxy, a MAC address and a bridge
+    /// need Ethernet frames, so they can't be combined with TUN mode.
+    /// 
+    /// With a bridge, a second, local TAP interface is created and brought up, and frames
+    /// are forwarded between it, the first TAP interface and the host by the addresses
+    /// learned behind each. Existing guest bridges or containers attached to the local
+    /// interface then share the CMIO uplink.
+    pub fn with_config(config: &TapConfig) -> Result<Self, CmioError> {
+        check_config(config)?;
+        
         // Initialize CMIO
-        let cmio = Cmio::new()?;
+        let cmio = match &config.cmio_device {
+            Some(path) => Cmio::open(path)?,
+            None => Cmio::new()?,
+        };
         
-        // Get the CMIO max buffer size from the CMIO instance
-        let cmio_max_buffer_size = cmio.get_tx_length();
+        // Get the CMIO max buffer size from the CMIO instance, unless batches are capped
+        // below it
+        let cmio_max_buffer_size = config.max_batch_size.map_or(cmio.get_tx_length(), |size| size.min(cmio.get_tx_length()));
+        Self::build(config, Some(cmio), cmio_max_buffer_size)
+    }
+    
+    /// Create the network interface without opening a CMIO device, to be registered
+    /// with a multiplexer sharing one with other subsystems
+    /// 
+    /// Batches are sized for buffers of cmio_max_buffer_size bytes. The interface is set
+    /// up as by with_config, but its run_loop fails, as the multiplexer does the yields.
+    pub fn for_multiplexer(config: &TapConfig, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        check_config(config)?;
+        Self::build(config, None, cmio_max_buffer_size)
+    }
+    
+    fn build(config: &TapConfig, mut cmio: Option<Cmio>, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        if let Some(cmio) = &mut cmio {
+            cmio.set_stats_interval(config.stats_interval);
+        }
+        let mtu = config.mtu.unwrap_or(DEFAULT_MTU);
         
-        // Create a TAP interface
-        let iface = Iface::new("tapcmio0", Mode::Tap)
-            .map_err(|e| CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))?;
+        // Create a TAP interface, or a TUN interface without packet info for raw IP packets
+        let iface = match config.mode {
+            Mode::Tap => Iface::new(&config.name, Mode::Tap),
+            Mode::Tun => Iface::without_packet_info(&config.name, Mode::Tun),
+        }.map_err(|e| CmioError::io(format!("create interface {}", config.name), e))?;
         
-        // Set up buffer for reading
-        let read_buffer = vec![0u8; MAX_PACKET_SIZE];
+        // Read without blocking, and wait for readability through poll instead
+        iface.set_non_blocking()
+            .map_err(|e| CmioError::io(format!("make {} non-blocking", iface.name()), e))?;
+        let poll = Poll::new()
+            .map_err(|e| CmioError::io("create the interface poll", e))?;
+        poll.register(&EventedFd(&iface.as_raw_fd()), TAP_TOKEN, Ready::readable(), PollOpt::level())
+            .map_err(|e| CmioError::io(format!("watch {}", iface.name()), e))?;
+        
+        // Apply ownership and persistence to the TAP device
+        if let Some(owner) = config.owner {
+            tap_ioctl(&iface, TUNSETOWNER, owner as libc::c_ulong)?;
+        }
+        if let Some(group) = config.group {
+            tap_ioctl(&iface, TUNSETGROUP, group as libc::c_ulong)?;
+        }
+        if config.persistent {
+            tap_ioctl(&iface, TUNSETPERSIST, 1)?;
+        }
+        if config.mtu.is_some() {
+            set_mtu(iface.name(), mtu)?;
+        }
+        if let Some(mac) = &config.mac {
+            set_hardware_address(iface.name(), mac)?;
+        }
+        
+        // Open the local TAP interface of the bridge, waiting for it alongside the first
+        let bridge = match &config.bridge {
+            Some(name) => {
+                let local = Iface::new(name, Mode::Tap)
+                    .map_err(|e| CmioError::io(format!("create bridge interface {}", name), e))?;
+                local.set_non_blocking()
+                    .map_err(|e| CmioError::io(format!("make {} non-blocking", local.name()), e))?;
+                poll.register(&EventedFd(&local.as_raw_fd()), BRIDGE_TOKEN, Ready::readable(), PollOpt::level())
+                    .map_err(|e| CmioError::io(format!("watch {}", local.name()), e))?;
+                configure_ip(local.name(), &[], None)
+                    .map_err(|e| CmioError::io(format!("bring up {}", local.name()), e))?;
+                Some((local, ForwardingTable::default()))
+            }
+            None => None,
+        };
+        
+        // Configure IP networking on the interface if requested
+        if !config.addresses.is_empty() || config.gateway.is_some() || config.dhcp {
+            configure_ip(iface.name(), &config.addresses, config.gateway)
+                .map_err(|e| CmioError::io(format!("configure IP on {}", iface.name()), e))?;
+        }
+        
+        // Set up buffer for reading, large enough for a full frame at this MTU
+        let max_frame_size = mtu as usize + FRAME_OVERHEAD;
+        let read_buffer = vec![0u8; max_frame_size];
+        
+        let dhcp_mac = if config.dhcp { Some(hardware_address(iface.name())?) } else { None };
+        let ndp_proxy = match config.ndp_proxy {
+            Some(address) => Some((address, hardware_address(iface.name())?)),
+            None => None,
+        };
         
         Ok(Self {
             cmio,
-            iface,
+            iface: Arc::new(iface),
+            mode: config.mode,
             read_buffer,
+            frame_pool: BufferPool::new(max_frame_size + VLAN_TAG_SIZE, DEFAULT_POOL_SIZE),
+            batch_pool: BufferPool::new(cmio_max_buffer_size, MAX_POOLED_BATCHES),
+            rx_buffer: Vec::new(),
+            max_frame_size,
+            reassembly: None,
+            next_frame_id: 0,
+            checksum: config.checksum,
+            stats: NetworkStats::default(),
+            stats_interval: config.stats_interval,
+            last_report: Instant::now(),
+            egress_limiter: RateLimiter::new(&config.egress_limit, Instant::now()),
+            read_limit: config.read_limit,
+            ingress_limiter: RateLimiter::new(&config.ingress_limit, Instant::now()),
+            filter: config.filter.clone(),
+            vlan: config.vlan,
+            ndp_proxy,
+            pending_replies: Vec::new(),
+            qos: config.qos,
+            link_control: config.link_control,
+            carrier: true,
+            bridge,
             cmio_max_buffer_size,
+            dhcp_mac,
+            poll,
+            events: Events::with_capacity(2),
+            idle: IdleState::new(config.idle),
+            pipelined: config.pipeline,
+            pipeline: None,
+            tx_batches: VecDeque::new(),
         })
     }
     
+    /// Traffic statistics since the interface was created
+    pub fn stats(&self) -> NetworkStats {
+        self.stats
+    }
+    
+    /// Whether the interface's carrier is on, as last set by the host with link control
+    pub fn carrier(&self) -> bool {
+        self.carrier
+    }
+    
     /// Run the network interface loop
     /// 
     /// This function implements the main loop for the network interface:
-    /// 1. Read as many frames as possible from the TAP interface
-    /// 2. Batch them into CMIO transmissions with length prefixes
+    /// 1. Read as many frames as possible from the TAP interface, up to the read limit
+    /// 2. Batch them into CMIO transmissions behind a batch header, control traffic first
+    ///    when QoS is enabled
     /// 3. Process received data by injecting frames one at a time into the TAP interface
     /// 4. Try to read more frames from CMIO until we get a zero-length response
-    /// 5. Yield to the scheduler when there's no more data to process
+    /// 5. Wait up to the idle timeout for the TAP interface to become readable, then yield
+    ///    to the scheduler when there's still no data to process
+    /// 6. Once a shutdown was requested, finish the round flushing pending frames, send the
+    ///    link down goodbye and return
+    /// 
+    /// With DHCP enabled, the client runs on a separate thread as its traffic has to
+    /// pass through this loop, and configures the interface once it has a lease.
+    /// 
+    /// In pipeline mode, the loop becomes the CMIO thread: frames are read from the TAP
+    /// interface by a reader thread and written by a writer thread, connected to it by
+    /// bounded channels. Frames from the host are dropped rather than waited on when the
+    /// writer falls behind, and count as written once queued.
     pub fn run_loop(&mut self) -> Result<(), CmioError> {
+        if self.cmio.is_none() {
+            return Err(CmioError::InvalidArgument(format!("{} was created for a multiplexer, which runs the loop", self.iface.name())));
+        }
+        self.start()?;
+        
         loop {
-            // Step 1: Read as many frames as possible from the TAP interface
-            let packets = self.get_packets_to_transmit()?;
+            let stopping = shutdown::requested();
+            self.update_stats();
+            
+            // Step 1: Gather the frames to send to the host
+            let packets = self.outgoing_packets()?;
             
             if !packets.is_empty() {
-                // Step 2: Batch packets into CMIO-sized chunks and send them
-                
-                // Create batches of packets that fit within CMIO buffer size
-                let mut current_batch = Vec::new();
-                let mut current_batch_size = 0;
+                self.idle.reset();
                 
-                for packet in packets {
-                    // Calculate the size of this packet with its length prefix
-                    let packet_size = packet.len() + 2; // 2 bytes for length prefix
-                    
-                    // Check if adding this packet would exceed the CMIO buffer size
-                    if current_batch_size + packet_size > self.cmio_max_buffer_size && !current_batch.is_empty() {
-                        // Send the current batch
-                        self.send_batch(&current_batch)?;
-                        
-                        // Start a new batch
-                        current_batch = Vec::new();
-                        current_batch_size = 0;
-                    }
-                    
-                    // Add the packet to the current batch
-                    current_batch.push(packet);
-                    current_batch_size += packet_size;
-                }
-                
-                // Send any remaining packets in the last batch
-                if !current_batch.is_empty() {
-                    self.send_batch(&current_batch)?;
+                // Step 2: Batch packets into CMIO-sized chunks and send them
+                let flags = self.frame_flags();
+                let batches = encode_frames(&packets, flags, self.checksum, self.cmio_max_buffer_size, &mut self.next_frame_id, &mut self.batch_pool, &mut self.stats);
+                self.release_frames(packets);
+                for batch in batches {
+                    self.transmit(&batch)?;
+                    self.batch_pool.give(batch);
                 }
                 
                 // Step 4: Try to read more frames from CMIO until we get a zero-length response
-                loop {
-                    let (rx_data, _reason) = self.cmio.yield_with_buffer(
-                        HTIF_DEVICE_YIELD,
-                        HTIF_YIELD_CMD_MANUAL,
-                        TAP_RXTX_CMD,
-                        &[],
-                    )?;
-                    
-                    if rx_data.is_empty() {
-                        // No more data to receive, break the inner loop
-                        break;
-                    }
-                    
-                    self.process_received_data(&rx_data)?;
-                }
+                while self.transmit(&[])? {}
             } else {
-                // No data to transmit, check for incoming data
-                let (rx_data, _reason) = self.cmio.yield_with_buffer(
-                    HTIF_DEVICE_YIELD,
-                    HTIF_YIELD_CMD_MANUAL,
-                    TAP_RXTX_CMD,
-                    &[],
-                )?;
-                
-                // Process received data if any
-                if !rx_data.is_empty() {
-                    self.process_received_data(&rx_data)?;
+                // No data to transmit, check for incoming data and process it if any
+                if self.transmit(&[])? {
+                    self.idle.reset();
                     
                     // Try to read more frames from CMIO until we get a zero-length response
-                    loop {
-                        let (rx_data, _reason) = self.cmio.yield_with_buffer(
-                            HTIF_DEVICE_YIELD,
-                            HTIF_YIELD_CMD_MANUAL,
-                            TAP_RXTX_CMD,
-                            &[],
-                        )?;
-                        
-                        if rx_data.is_empty() {
-                            // No more data to receive, break the inner loop
-                            break;
-                        }
-                        
-                        self.process_received_data(&rx_data)?;
-                    }
+                    while self.transmit(&[])? {}
                 } else {
-                    // Step 5: No data to transmit or receive, yield to the scheduler
-                    // Use HTIF yield device with manual yield command and TAP_RXTX_CMD reason
-                    self.cmio.yield_with_buffer(
-                        HTIF_DEVICE_YIELD,
-                        HTIF_YIELD_CMD_MANUAL,
-                        TAP_RXTX_CMD,
-                        &[],
-                    )?;
+                    // Step 5: No data to transmit or receive, wait as the idle strategy
+                    // says instead of spinning
+                    self.wait_idle()?;
+                    
+                    // Yield to the scheduler, using HTIF yield device with manual yield
+                    // command and TAP reason
+                    self.yield_cmio(&[])?;
+                }
+            }
+            
+            // Step 6: The round above sent what was left, so the host can be told the
+            // link is down; the TAP interface and CMIO buffers are released on drop
+            if stopping {
+                info!("Shutting down {}", self.iface.name());
+                if let Some(cmio) = &mut self.cmio {
+                    return shutdown::say_goodbye(cmio, shutdown::GOODBYE_LINK_DOWN);
                 }
             }
         }
     }
     
-    /// Get packets to transmit from the network interface
+    /// Start the DHCP client and the pipeline threads if configured, once
+    fn start(&mut self) -> Result<(), CmioError> {
+        if let Some(mac) = self.dhcp_mac.take() {
+            let name = self.iface.name().to_string();
+            thread::spawn(move || match run_dhcp(&name, &mac) {
+                Ok(lease) => info!("DHCP lease on {}: {}/{} via {:?}, DNS {:?}", name, lease.address, lease.prefix_len, lease.gateway, lease.dns),
+                Err(e) => warn!("DHCP on {} failed: {}", name, e),
+            });
+        }
+        if self.pipelined && self.pipeline.is_none() {
+            self.pipeline = Some(Pipeline::start(&self.iface, self.read_buffer.len(), self.read_limit)?);
+            info!("Started TAP reader and writer threads for {}", self.iface.name());
+        }
+        Ok(())
+    }
+    
+    /// Take the error counters of the pipeline threads and log the statistics if due
+    fn update_stats(&mut self) {
+        if let Some(pipeline) = &self.pipeline {
+            self.stats.tx_errors = pipeline.tx_errors.load(Ordering::Relaxed);
+            self.stats.rx_errors = pipeline.rx_errors.load(Ordering::Relaxed);
+        }
+        self.report_stats();
+    }
+    
+    /// Gather the frames to send to the host, in the order to send them
     /// 
-    /// This function reads multiple packets from the TAP interface and returns them
-    /// as a vector of individual packets.
-    fn get_packets_to_transmit(&mut self) -> Result<Vec<Vec<u8>>, CmioError> {
-        let mut packets = Vec::new();
-        
-        loop {
-            // Try to read a packet using recv
-            match self.iface.recv(&mut self.read_buffer) {
-                Ok(n) => {
-                    if n > 0 {
-                        // We have data to transmit
-                        // Create a new packet buffer and copy the data
-                        let mut packet = vec![0u8; n];
-                        packet.copy_from_slice(&self.read_buffer[..n]);
-                        packets.push(packet);
-                    } else {
-                        // No more data available
-                        break;
-                    }
-                },
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        // No more data available, non-blocking read
-                        break;
-                    } else {
-                        // Some other error occurred
-                        return Err(CmioError::SetupError(e.raw_os_error().unwrap_or(-1)));
-                    }
-                }
+    /// Reads as many frames as the read limit allows from the TAP interface, leaving the
+    /// rest for after the received frames are processed, and drops those rejected by the
+    /// filter or exceeding the egress rate limit. Neighbor advertisements from the NDP
+    /// proxy go out first. With QoS, frames are prioritized, and with a VLAN they are
+    /// tagged.
+    fn outgoing_packets(&mut self) -> Result<Vec<Vec<u8>>, CmioError> {
+        let mut packets = std::mem::take(&mut self.pending_replies);
+        packets.extend(self.get_packets_to_transmit()?);
+        let now = Instant::now();
+        let mut allowed = Vec::with_capacity(packets.len());
+        let mut rejected = Vec::new();
+        for packet in packets {
+            if !self.filter_allows(&packet) {
+                self.stats.tx_filtered += 1;
+                rejected.push(packet);
+                continue;
+            }
+            if !self.egress_limiter.allow(packet.len(), now) {
+                self.stats.tx_rate_limited += 1;
+                rejected.push(packet);
+                continue;
+            }
+            if self.is_ipv6(&packet) {
+                self.stats.tx_ipv6_frames += 1;
             }
+            allowed.push(packet);
         }
+        self.release_frames(rejected);
+        let packets = allowed;
         
-        // Return the packets
+        // Queue ARP, ICMP, DNS and DHCP ahead of bulk TCP so they aren't stuck behind it
+        let packets = if self.qos {
+            qos::prioritize(packets, |packet| self.priority(packet))
+        } else {
+            packets
+        };
+        
+        // Tag frames on their way out to the host's VLAN
+        let mut packets = packets;
+        if let Some(vlan) = self.vlan {
+            for packet in &mut packets {
+                insert_vlan_tag(packet, PACKET_INFO_SIZE, vlan);
+            }
+        }
         Ok(packets)
     }
     
-    /// Send a batch of packets via CMIO
+    /// Log the traffic statistics if the reporting interval has passed
+    fn report_stats(&mut self) {
+        let Some(interval) = self.stats_interval else {
+            return;
+        };
+        if self.last_report.elapsed() < interval {
+            return;
+        }
+        self.last_report = Instant::now();
+        
+        let stats = &self.stats;
+        info!("{}: TX {} frames/{} bytes ({} IPv6, {} fragments, {} filtered, {} rate limited), RX {} frames/{} bytes ({} IPv6, {} filtered, {} rate limited), {} dropped, {} corrupted, {} malformed, {} bad batches, {}/{} TX/RX errors, {} NDP proxied, {} bridged, {} yields",
+            self.iface.name(), stats.tx_frames, stats.tx_bytes, stats.tx_ipv6_frames, stats.tx_fragments, stats.tx_filtered, stats.tx_rate_limited, stats.rx_frames, stats.rx_bytes,
+            stats.rx_ipv6_frames, stats.rx_filtered, stats.rx_rate_limited, stats.rx_dropped, stats.rx_corrupted, stats.rx_malformed, stats.rx_bad_batches, stats.tx_errors, stats.rx_errors, stats.ndp_proxied, stats.bridged, stats.yields);
+    }
+    
+    /// Check a frame against the filter rules, skipping the packet info in front of
+    /// Ethernet frames
+    fn filter_allows(&self, frame: &[u8]) -> bool {
+        match self.mode {
+            Mode::Tap => self.filter.allows_ethernet(frame.get(PACKET_INFO_SIZE..).unwrap_or_default()),
+            Mode::Tun => self.filter.allows_ip(frame),
+        }
+    }
+    
+    /// Classify a frame into its QoS priority class
+    fn priority(&self, frame: &[u8]) -> Priority {
+        match self.mode {
+            Mode::Tap => qos::classify_ethernet(frame.get(PACKET_INFO_SIZE..).unwrap_or_default()),
+            Mode::Tun => qos::classify_ip(frame),
+        }
+    }
+    
+    /// Check whether a frame carries an IPv6 packet
+    fn is_ipv6(&self, frame: &[u8]) -> bool {
+        match self.mode {
+            Mode::Tap => frame.get(PACKET_INFO_SIZE + ETHERTYPE_OFFSET..PACKET_INFO_SIZE + ETHERTYPE_OFFSET + 2)
+                .is_some_and(|ethertype| ethertype == ETHERTYPE_IPV6.to_be_bytes()),
+            Mode::Tun => frame.first().is_some_and(|byte| byte >> 4 == 6),
+        }
+    }
+    
+    /// Wait before an idle yield as the idle strategy says
     /// 
-    /// This function takes a vector of packets, adds length prefixes to each,
-    /// and sends them as a single batch via CMIO.
-    fn send_batch(&mut self, packets: &[Vec<u8>]) -> Result<(), CmioError> {
-        // Create a buffer for the batched data
-        let mut batch_buffer = Vec::new();
+    /// Polling returns as soon as the TAP interface has packets to read, or in pipeline
+    /// mode the reader thread has queued some. The CMIO device can't be polled, so
+    /// incoming packets are only picked up by the yield following the wait; its length
+    /// bounds how long they can be delayed.
+    fn wait_idle(&mut self) -> Result<(), CmioError> {
+        match self.idle.next_wait() {
+            IdleWait::None => Ok(()),
+            IdleWait::Sleep(duration) => {
+                thread::sleep(duration);
+                Ok(())
+            }
+            IdleWait::Poll(timeout) => {
+                if let Some(pipeline) = &mut self.pipeline {
+                    return pipeline.wait_for_frames(timeout);
+                }
+                match self.poll.poll(&mut self.events, Some(timeout)) {
+                    Ok(_) => Ok(()),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                    Err(e) => Err(CmioError::io("wait for frames", e)),
+                }
+            }
+        }
+    }
+    
+    /// Get packets to transmit from the network interface
+    /// 
+    /// This function reads multiple packets from the TAP interface and returns them
+    /// as a vector of individual packets. In bridge mode, frames from both TAP
+    /// interfaces are forwarded between them, and only those for the host are returned.
+    fn get_packets_to_transmit(&mut self) -> Result<Vec<Vec<u8>>, CmioError> {
+        if let Some(pipeline) = &mut self.pipeline {
+            return pipeline.take_frames();
+        }
+        
+        let mut read = (0, 0);
+        let packets = read_frames(&self.iface, &mut self.read_buffer, &mut self.frame_pool, &self.read_limit, &mut read, &mut self.stats.tx_errors)?;
+        let Some((local, table)) = &mut self.bridge else {
+            return Ok(packets);
+        };
         
-        // Add each packet with its length prefix
+        let now = Instant::now();
+        let mut uplink = Vec::new();
         for packet in packets {
-            // Add u16 length prefix (network byte order)
-            let length_bytes = (packet.len() as u16).to_be_bytes();
-            batch_buffer.extend_from_slice(&length_bytes);
-            
-            // Add the packet data
-            batch_buffer.extend_from_slice(packet);
+            let ports = table.forward(packet.get(PACKET_INFO_SIZE..).unwrap_or_default(), Port::Tap, now);
+            if ports.contains(&Port::Local) && send_frame(local, &packet, &mut self.stats.rx_errors)? {
+                self.stats.bridged += 1;
+            }
+            if ports.contains(&Port::Uplink) {
+                uplink.push(packet);
+            } else {
+                self.frame_pool.give(packet);
+            }
         }
-        
-        // Send the batched data via CMIO
-        let (rx_data, _reason) = self.cmio.yield_with_buffer(
-            HTIF_DEVICE_YIELD,
-            HTIF_YIELD_CMD_MANUAL,
-            TAP_RXTX_CMD,
-            &batch_buffer,
+        for packet in read_frames(local, &mut self.read_buffer, &mut self.frame_pool, &self.read_limit, &mut read, &mut self.stats.tx_errors)? {
+            let ports = table.forward(packet.get(PACKET_INFO_SIZE..).unwrap_or_default(), Port::Local, now);
+            if ports.contains(&Port::Tap) && send_frame(&self.iface, &packet, &mut self.stats.rx_errors)? {
+                self.stats.bridged += 1;
+            }
+            if ports.contains(&Port::Uplink) {
+                uplink.push(packet);
+            } else {
+                self.frame_pool.give(packet);
+            }
+        }
+        Ok(uplink)
+    }
+    
+    /// Release the buffers of frames that were sent or dropped for reuse, in pipeline
+    /// mode handing them back to the reader thread
+    fn release_frames(&mut self, frames: Vec<Vec<u8>>) {
+        match &self.pipeline {
+            Some(pipeline) => {
+                let _ = pipeline.recycle.try_send(frames);
+            }
+            None => self.frame_pool.give_all(frames),
+        }
+    }
+    
+    /// Flags of the frames sent, marking IP packets so the host can tell them from
+    /// Ethernet frames
+    fn frame_flags(&self) -> u8 {
+        if self.mode == Mode::Tun { FRAME_FLAG_L3 } else { 0 }
+    }
+    
+    /// Yield a buffer to the host via CMIO, leaving the data received in return in the
+    /// reused RX buffer
+    fn yield_cmio(&mut self, buffer: &[u8]) -> Result<(), CmioError> {
+        self.stats.yields += 1;
+        let cmio = self.cmio.as_mut()
+            .ok_or_else(|| CmioError::InvalidArgument("no CMIO device to yield to".to_string()))?;
+        let reason = cmio.yield_with_retry_into(
+            YieldDevice::Yield,
+            YieldCommand::Manual,
+            YieldReason::TapRxTx,
+            buffer,
+            &mut self.rx_buffer,
         )?;
         
-        // Process received data if any
-        if !rx_data.is_empty() {
-            self.process_received_data(&rx_data)?;
+        // Control messages carry no frames, so the caller sees an empty response
+        if YieldReason::from_code(reason) == YieldReason::Control {
+            let message = std::mem::take(&mut self.rx_buffer);
+            self.handle_control(&message);
+            self.rx_buffer = message;
+            self.rx_buffer.clear();
         }
-        
         Ok(())
     }
     
+    /// Handle a control message from the host
+    /// 
+    /// With link control enabled, link up and down messages switch the interface's carrier
+    /// so the guest network stack sees the host-side link change. Other messages are
+    /// logged and ignored.
+    fn handle_control(&mut self, message: &[u8]) {
+        let carrier = match message.first() {
+            Some(&CONTROL_LINK_UP) if self.link_control => true,
+            Some(&CONTROL_LINK_DOWN) if self.link_control => false,
+            _ => {
+                debug!("Ignoring control message {:?}", message);
+                return;
+            }
+        };
+        match tap_ioctl(&self.iface, TUNSETCARRIER, carrier as libc::c_ulong) {
+            Ok(()) => {
+                self.carrier = carrier;
+                info!("Link on {} is {}", self.iface.name(), if carrier { "up" } else { "down" });
+            }
+            Err(e) => warn!("Failed to set the carrier of {}: {}", self.iface.name(), e),
+        }
+    }
+    
+    /// Send a buffer via CMIO and process any data received in return, returning
+    /// whether there was any
+    fn transmit(&mut self, buffer: &[u8]) -> Result<bool, CmioError> {
+        self.yield_cmio(buffer)?;
+        if self.rx_buffer.is_empty() {
+            return Ok(false);
+        }
+        
+        // The buffer is put back after processing, keeping its capacity for the next yield
+        let rx_data = std::mem::take(&mut self.rx_buffer);
+        let result = self.process_received_data(&rx_data);
+        self.rx_buffer = rx_data;
+        result.map(|()| true)
+    }
+    
     /// Process received data and write it to the network interface
     /// 
-    /// This function processes received data that may contain multiple packets,
-    /// each prefixed with a u16 length, and writes them to the TAP interface.
+    /// This function decodes a batch that may contain multiple frames and writes them
+    /// to the TAP interface. Batches with an unknown magic or version are dropped, as
+    /// are Ethernet frames received in TUN mode, IP packets received in TAP mode and
+    /// packets exceeding the MTU. Frames failing their CRC32 check are counted and
+    /// dropped. Fragments are reassembled before being written. A length prefix running
+    /// past the end of the batch drops the frames from there on.
     fn process_received_data(&mut self, data: &[u8]) -> Result<(), CmioError> {
-        let mut offset = 0;
+        for (flags, frame) in decode_frames(data, &mut self.reassembly, self.max_frame_size, &mut self.stats) {
+            self.write_packet(flags, &frame)?;
+        }
+        Ok(())
+    }
+    
+    /// Write a packet received from the host to the TAP interface
+    /// 
+    /// Packets of the other layer, which the interface can't carry, and packets larger
+    /// than the MTU allows are dropped, as are packets rejected by the filter or exceeding
+    /// the ingress rate limit. With a VLAN configured, its tag is stripped and frames not
+    /// carrying it are dropped. Neighbor solicitations for the NDP proxy's address are
+    /// answered instead of being written. Frames the interface fails to take with a
+    /// recoverable error are logged and dropped.
+    fn write_packet(&mut self, flags: u8, packet_data: &[u8]) -> Result<(), CmioError> {
+        if (flags & FRAME_FLAG_L3 != 0) != (self.mode == Mode::Tun) || packet_data.len() > self.max_frame_size {
+            self.stats.rx_dropped += 1;
+            return Ok(());
+        }
+        let packet_data = match self.vlan {
+            Some(vlan) => match strip_vlan_tag(packet_data, PACKET_INFO_SIZE, vlan) {
+                Some(untagged) => Cow::Owned(untagged),
+                None => {
+                    self.stats.rx_dropped += 1;
+                    return Ok(());
+                }
+            },
+            None => Cow::Borrowed(packet_data),
+        };
+        let packet_data = packet_data.as_ref();
+        if let Some((address, mac)) = &self.ndp_proxy {
+            if let Some(reply) = ipv6::neighbor_advertisement(packet_data.get(PACKET_INFO_SIZE..).unwrap_or_default(), address, mac) {
+                let mut frame = vec![0, 0];
+                frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+                frame.extend_from_slice(&reply);
+                self.pending_replies.push(frame);
+                self.stats.ndp_proxied += 1;
+                return Ok(());
+            }
+        }
+        if !self.filter_allows(packet_data) {
+            self.stats.rx_filtered += 1;
+            return Ok(());
+        }
+        if !self.ingress_limiter.allow(packet_data.len(), Instant::now()) {
+            self.stats.rx_rate_limited += 1;
+            return Ok(());
+        }
         
-        // Process each packet in the batch
-        while offset < data.len() {
-            // Check if we have enough data for the length prefix
-            if offset + 2 > data.len() {
-                break;
+        // Write the packet to the TAP interface using send, or in bridge mode to the TAP
+        // interfaces its destination may be behind
+        let sent = match &mut self.bridge {
+            Some((local, table)) => {
+                let ports = table.forward(packet_data.get(PACKET_INFO_SIZE..).unwrap_or_default(), Port::Uplink, Instant::now());
+                let to_local = ports.contains(&Port::Local) && send_frame(local, packet_data, &mut self.stats.rx_errors)?;
+                let to_tap = ports.contains(&Port::Tap) && send_frame(&self.iface, packet_data, &mut self.stats.rx_errors)?;
+                to_local || to_tap
             }
-            
-            // Read the length prefix (network byte order)
-            let length_bytes = [data[offset], data[offset + 1]];
-            let packet_length = u16::from_be_bytes(length_bytes) as usize;
-            offset += 2;
-            
-            // Check if we have enough data for the packet
-            if offset + packet_length > data.len() {
-                break;
+            None => match &self.pipeline {
+                Some(pipeline) => match pipeline.writer.try_send(packet_data.to_vec()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        self.stats.rx_dropped += 1;
+                        false
+                    }
+                    Err(TrySendError::Disconnected(_)) => return Err(CmioError::ThreadStopped("TAP writer")),
+                },
+                None => send_frame(&self.iface, packet_data, &mut self.stats.rx_errors)?,
+            },
+        };
+        if !sent {
+            return Ok(());
+        }
+        self.stats.rx_frames += 1;
+        self.stats.rx_bytes += packet_data.len() as u64;
+        if self.is_ipv6(packet_data) {
+            self.stats.rx_ipv6_frames += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Subsystem for NetworkInterface {
+    /// Hand out the next batch of frames for the host, reading and encoding frames from
+    /// the TAP interface once the previous batches are sent
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        self.start()?;
+        self.update_stats();
+        if self.tx_batches.is_empty() {
+            let packets = self.outgoing_packets()?;
+            if !packets.is_empty() {
+                let flags = self.frame_flags();
+                let max_len = max_len.min(self.cmio_max_buffer_size);
+                let batches = encode_frames(&packets, flags, self.checksum, max_len, &mut self.next_frame_id, &mut self.batch_pool, &mut self.stats);
+                self.release_frames(packets);
+                self.tx_batches.extend(batches);
             }
-            
-            // Extract the packet data
-            let packet_data = &data[offset..offset + packet_length];
-            
-            // Write the packet to the TAP interface using send
-            self.iface.send(packet_data)
-                .map_err(|e| CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))?;
-            
-            // Move to the next packet
-            offset += packet_length;
         }
-        
+        if let Some(batch) = self.tx_batches.pop_front() {
+            buffer.extend_from_slice(&batch);
+            self.batch_pool.give(batch);
+        }
         Ok(())
     }
+    
+    /// Write the frames of a batch from the host to the TAP interface
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        self.process_received_data(data)
+    }
 }
 
-// No need for a custom Drop implementation as Iface implements Drop 
\ No newline at end of file
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_round_trip() {
+        let mut fragment = FrameEntry::new(FRAME_FLAG_FRAGMENT, &[4, 5]);
+        fragment.fragment = Some((9, 3));
+        let entries = vec![FrameEntry::new(FRAME_FLAG_L3, &[1, 2, 3]), fragment];
+
+        let batch = encode_batch(&entries);
+        assert_eq!(&batch[..BATCH_HEADER_SIZE], &[0x54, 0x43, BATCH_VERSION, 0, 0, 2, 0, 0]);
+        assert_eq!(&batch[BATCH_HEADER_SIZE..BATCH_HEADER_SIZE + FRAME_HEADER_SIZE], &[0, 3, FRAME_FLAG_L3, 0]);
+        assert_eq!(batch.len(), BATCH_HEADER_SIZE + entries.iter().map(FrameEntry::size).sum::<usize>());
+        assert_eq!(decode_batch(&batch), Some(entries));
+
+        // A truncated entry ends the batch
+        assert_eq!(decode_batch(&batch[..batch.len() - 1]).unwrap().len(), 1);
+
+        // Batches without the header are rejected
+        assert_eq!(decode_batch(&[0, 3, 1, 2, 3]), None);
+        let mut other_version = batch.clone();
+        other_version[2] = 1;
+        assert_eq!(decode_batch(&other_version), None);
+    }
+
+    #[test]
+    fn test_recoverable_errors() {
+        assert!(is_recoverable(&io::Error::from_raw_os_error(libc::EIO)));
+        assert!(is_recoverable(&io::Error::from_raw_os_error(libc::EMSGSIZE)));
+        assert!(!is_recoverable(&io::Error::from_raw_os_error(libc::EBADF)));
+        assert!(!is_recoverable(&io::Error::other("no errno")));
+    }
+
+    #[test]
+    fn test_interface_is_send() {
+        // The interface can be moved to a thread of its own to yield from
+        fn assert_send<T: Send>() {}
+        assert_send::<NetworkInterface>();
+    }
+
+    #[test]
+    fn test_read_limit() {
+        assert!(!ReadLimit::default().reached(usize::MAX, usize::MAX));
+        let limit = ReadLimit { frames: Some(2), bytes: Some(3000) };
+        assert!(!limit.reached(1, 1500));
+        assert!(limit.reached(2, 120));
+        assert!(limit.reached(1, 3000));
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let entries = vec![FrameEntry::new(0, &[1, 2, 3]).with_checksum()];
+        let mut batch = encode_batch(&entries);
+        assert_eq!(batch.len(), BATCH_HEADER_SIZE + FRAME_HEADER_SIZE + CHECKSUM_SIZE + 3);
+        assert_eq!(decode_batch(&batch), Some(entries));
+        assert!(decode_batch(&batch).unwrap()[0].is_intact());
+
+        // Corrupting the data fails the check
+        *batch.last_mut().unwrap() ^= 0xFF;
+        assert!(!decode_batch(&batch).unwrap()[0].is_intact());
+        assert!(FrameEntry::new(0, &[1]).is_intact());
+    }
+
+    #[test]
+    fn test_vlan_tag() {
+        // Packet info, destination and source MAC, EtherType and payload
+        let mut frame = vec![0, 0, 0x08, 0x00];
+        frame.extend_from_slice(&[0xaa; 6]);
+        frame.extend_from_slice(&[0xbb; 6]);
+        frame.extend_from_slice(&[0x08, 0x00, 1, 2, 3]);
+
+        let mut tagged = frame.clone();
+        insert_vlan_tag(&mut tagged, PACKET_INFO_SIZE, 10);
+        assert_eq!(tagged.len(), frame.len() + VLAN_TAG_SIZE);
+        assert_eq!(&tagged[16..20], &[0x81, 0x00, 0, 10]);
+        assert_eq!(&tagged[20..], &[0x08, 0x00, 1, 2, 3]);
+
+        assert_eq!(strip_vlan_tag(&tagged, PACKET_INFO_SIZE, 10), Some(frame.clone()));
+        // Frames of other VLANs and untagged frames are rejected
+        assert_eq!(strip_vlan_tag(&tagged, PACKET_INFO_SIZE, 11), None);
+        assert_eq!(strip_vlan_tag(&frame, PACKET_INFO_SIZE, 10), None);
+        assert_eq!(strip_vlan_tag(&frame[..10], PACKET_INFO_SIZE, 10), None);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let limit = RateLimit { packets_per_second: Some(2), bytes_per_second: Some(1000) };
+        let mut limiter = RateLimiter::new(&limit, start);
+
+        assert!(limiter.allow(600, start));
+        // Over the byte limit, which doesn't charge the packet bucket either
+        assert!(!limiter.allow(600, start));
+        assert!(limiter.allow(100, start));
+        // Over the packet limit
+        assert!(!limiter.allow(1, start));
+
+        // Half a second refills one packet and 500 bytes
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.allow(500, later));
+        assert!(!limiter.allow(1, later));
+
+        // Buckets hold at most a second's worth
+        let much_later = later + Duration::from_secs(60);
+        assert!(limiter.allow(1, much_later));
+        assert!(limiter.allow(1, much_later));
+        assert!(!limiter.allow(1, much_later));
+
+        let mut unlimited = RateLimiter::new(&RateLimit::default(), start);
+        assert!((0..1000).all(|_| unlimited.allow(65535, start)));
+    }
+
+    #[test]
+    fn test_fragment_frame() {
+        let frame: Vec<u8> = (0..20).collect();
+        let overhead = BATCH_HEADER_SIZE + FRAME_HEADER_SIZE + FRAGMENT_INFO_SIZE + CHECKSUM_SIZE;
+        let fragments = fragment_frame(&frame, 7, FRAME_FLAG_L3, overhead + 8);
+
+        assert_eq!(fragments.len(), 3);
+        assert_eq!(fragments[0].fragment, Some((7, 0)));
+        assert_eq!(fragments[0].flags, FRAME_FLAG_L3 | FRAME_FLAG_FRAGMENT | FRAME_FLAG_MORE_FRAGMENTS);
+        assert_eq!(fragments[1].fragment, Some((7, 8)));
+        assert_eq!(fragments[2].fragment, Some((7, 16)));
+        assert_eq!(fragments[2].flags, FRAME_FLAG_L3 | FRAME_FLAG_FRAGMENT);
+        assert_eq!(fragments[2].data, &[16, 17, 18, 19]);
+        assert!(fragments.into_iter().all(|fragment| BATCH_HEADER_SIZE + fragment.with_checksum().size() <= overhead + 8));
+    }
+
+    #[test]
+    fn test_reassemble() {
+        // Build a fragment entry of frame_id at offset
+        fn fragment(frame_id: u16, offset: u16, more: bool, data: &[u8]) -> FrameEntry<'_> {
+            let flags = FRAME_FLAG_FRAGMENT | if more { FRAME_FLAG_MORE_FRAGMENTS } else { 0 };
+            let mut entry = FrameEntry::new(flags, data);
+            entry.fragment = Some((frame_id, offset));
+            entry
+        }
+
+        let mut state = None;
+        assert_eq!(reassemble(&mut state, &fragment(1, 0, true, &[1, 2]), 16), None);
+        assert_eq!(reassemble(&mut state, &fragment(1, 2, false, &[3]), 16), Some(vec![1, 2, 3]));
+        assert!(state.is_none());
+
+        // A gap or a different frame discards the partial frame
+        assert_eq!(reassemble(&mut state, &fragment(2, 0, true, &[1, 2]), 16), None);
+        assert_eq!(reassemble(&mut state, &fragment(2, 3, true, &[4]), 16), None);
+        assert!(state.is_none());
+        assert_eq!(reassemble(&mut state, &fragment(3, 0, true, &[1]), 16), None);
+        assert_eq!(reassemble(&mut state, &fragment(4, 1, false, &[2]), 16), None);
+        assert!(state.is_none());
+
+        // Frames larger than the MTU allows are dropped
+        assert_eq!(reassemble(&mut state, &fragment(5, 0, true, &[0; 10]), 16), None);
+        assert_eq!(reassemble(&mut state, &fragment(5, 10, false, &[0; 10]), 16), None);
+        assert!(state.is_none());
+    }
+}
@@ -0,0 +1,325 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use tracing::{info, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+
+// Reason code the 9P messages are exchanged with the host on
+pub const NINEP_REASON: YieldReason = YieldReason::Other(0x46);
+
+// Unix socket the guest kernel mounts through unless another is given
+pub const DEFAULT_NINEP_SOCKET: &str = "/run/tapcmio-9p.sock";
+
+// Protocol version the host is expected to speak
+pub const NINEP_VERSION: &str = "9P2000.L";
+
+// Size of a message's header: size[4] type[1] tag[2]
+const HEADER_SIZE: usize = 7;
+
+// Version negotiation messages, which carry msize[4] and version[s] after the header
+const TVERSION: u8 = 100;
+const RVERSION: u8 = 101;
+
+// Size of the buffer messages are read from the guest kernel with
+const READ_CHUNK: usize = 64 * 1024;
+
+// Length of the complete message at the start of data, None if more data is needed
+//
+// Fails for a size smaller than the header or larger than max_size.
+fn message_length(data: &[u8], max_size: usize) -> Result<Option<usize>, CmioError> {
+    if data.len() < 4 {
+        return Ok(None);
+    }
+    let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    if size < HEADER_SIZE || size > max_size {
+        return Err(CmioError::ProtocolError(format!("9P message of {} bytes, expected {} to {}", size, HEADER_SIZE, max_size)));
+    }
+    Ok((data.len() >= size).then_some(size))
+}
+
+// Lower the msize of a Tversion message to max_size, so the guest kernel never sends a
+// message larger than a yield can carry
+fn clamp_msize(message: &mut [u8], max_size: usize) {
+    if message.len() < HEADER_SIZE + 4 || message[4] != TVERSION {
+        return;
+    }
+    let msize = u32::from_le_bytes([message[7], message[8], message[9], message[10]]) as usize;
+    if msize > max_size {
+        message[7..11].copy_from_slice(&(max_size as u32).to_le_bytes());
+    }
+}
+
+// The version string of an Rversion message, None for other messages
+fn version(message: &[u8]) -> Option<&[u8]> {
+    if message.len() < HEADER_SIZE + 6 || message[4] != RVERSION {
+        return None;
+    }
+    let length = u16::from_le_bytes([message[11], message[12]]) as usize;
+    message.get(HEADER_SIZE + 6..HEADER_SIZE + 6 + length)
+}
+
+// Structure relaying 9P2000.L between the guest kernel and a file server on the host,
+// as a subsystem of the multiplexer on NINEP_REASON
+//
+// The guest mounts through a Unix socket, e.g. `mount -t 9p -o trans=unix,version=9p2000.L
+// /run/tapcmio-9p.sock /mnt`. Complete T-messages read from it are batched to the host
+// back to back, and the host answers with R-messages the same way, which are written
+// back to the kernel; 9P messages carry their own size, so batches need no other
+// framing. The msize offered by the kernel is lowered to what a yield can carry. One
+// mount is served at a time, further connections are closed until it goes away.
+pub struct NinePProxy {
+    listener: UnixListener,
+    socket_path: PathBuf,
+    connection: Option<UnixStream>,
+    // Data read from the kernel, holding at most one partial message after the complete ones
+    pending: Vec<u8>,
+    max_message_size: usize,
+}
+
+impl NinePProxy {
+    /// Listen for the guest kernel on a Unix socket, limiting messages to the size of the
+    /// CMIO buffer
+    pub fn bind(socket_path: impl AsRef<Path>, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        let socket_path = socket_path.as_ref().to_path_buf();
+        
+        // Replace a socket file left behind by an earlier run
+        let _ = fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path)
+            .map_err(|e| CmioError::io(format!("listen on {}", socket_path.display()), e))?;
+        listener.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make {} non-blocking", socket_path.display()), e))?;
+        Ok(Self {
+            listener,
+            socket_path,
+            connection: None,
+            pending: Vec::new(),
+            max_message_size: cmio_max_buffer_size,
+        })
+    }
+    
+    /// Whether the guest kernel is connected
+    pub fn connected(&self) -> bool {
+        self.connection.is_some()
+    }
+    
+    /// Accept the guest kernel connecting, closing connections beyond the first
+    fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) if self.connection.is_none() => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        info!("9P client connected on {}", self.socket_path.display());
+                        self.connection = Some(stream);
+                    }
+                }
+                Ok(_) => warn!("Closing a second 9P client on {}, one mount is served at a time", self.socket_path.display()),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept on {}: {}", self.socket_path.display(), e);
+                    break;
+                }
+            }
+        }
+    }
+    
+    /// Read what the kernel sent, dropping the connection once it is closed
+    fn read_connection(&mut self) {
+        let Some(stream) = &mut self.connection else {
+            return;
+        };
+        let mut chunk = [0u8; READ_CHUNK];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    info!("9P client on {} disconnected", self.socket_path.display());
+                    self.disconnect();
+                    return;
+                }
+                Ok(n) => self.pending.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Failed to read from the 9P client: {}", e);
+                    self.disconnect();
+                    return;
+                }
+            }
+        }
+    }
+    
+    /// Forget the connection and what is left of its messages
+    fn disconnect(&mut self) {
+        self.connection = None;
+        self.pending.clear();
+    }
+    
+    /// Write responses to the kernel, waiting for it to take them all
+    fn write_connection(&mut self, data: &[u8]) -> io::Result<()> {
+        let Some(stream) = &mut self.connection else {
+            return Ok(());
+        };
+        let mut written = 0;
+        while written < data.len() {
+            match stream.write(&data[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::yield_now(),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Subsystem for NinePProxy {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        self.accept();
+        self.read_connection();
+        
+        // Send as many complete messages as fit, the rest waits for the next yield
+        let max_size = self.max_message_size.min(max_len);
+        let mut offset = 0;
+        loop {
+            let length = match message_length(&self.pending[offset..], max_size) {
+                Ok(Some(length)) => length,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Closing the 9P client: {}", e);
+                    self.disconnect();
+                    return Ok(());
+                }
+            };
+            if buffer.len() + length > max_len {
+                break;
+            }
+            let start = buffer.len();
+            buffer.extend_from_slice(&self.pending[offset..offset + length]);
+            clamp_msize(&mut buffer[start..], max_size);
+            offset += length;
+        }
+        self.pending.drain(..offset);
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        // Check the batch is whole messages before any of it reaches the kernel
+        let mut offset = 0;
+        while offset < data.len() {
+            // A client that misses an answer would wait on its tag forever
+            let length = match message_length(&data[offset..], data.len() - offset) {
+                Ok(Some(length)) => length,
+                Ok(None) => {
+                    warn!("Closing the 9P client after a truncated message from the host");
+                    self.disconnect();
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Closing the 9P client: {}", e);
+                    self.disconnect();
+                    return Ok(());
+                }
+            };
+            if let Some(version) = version(&data[offset..offset + length]) {
+                if version != NINEP_VERSION.as_bytes() {
+                    warn!("Host answered with 9P version {}, expected {}", String::from_utf8_lossy(version), NINEP_VERSION);
+                }
+            }
+            offset += length;
+        }
+        if let Err(e) = self.write_connection(data) {
+            warn!("Failed to write to the 9P client: {}", e);
+            self.disconnect();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NinePProxy {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Build a message of the given type and tag around a body
+    fn message(kind: u8, tag: u16, body: &[u8]) -> Vec<u8> {
+        let mut message = ((HEADER_SIZE + body.len()) as u32).to_le_bytes().to_vec();
+        message.push(kind);
+        message.extend_from_slice(&tag.to_le_bytes());
+        message.extend_from_slice(body);
+        message
+    }
+
+    // Body of a version message with an msize and version string
+    fn version_body(msize: u32, version: &str) -> Vec<u8> {
+        let mut body = msize.to_le_bytes().to_vec();
+        body.extend_from_slice(&(version.len() as u16).to_le_bytes());
+        body.extend_from_slice(version.as_bytes());
+        body
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let tversion = message(TVERSION, 0xffff, &version_body(512 * 1024, NINEP_VERSION));
+        assert_eq!(message_length(&tversion, 4096).unwrap(), Some(tversion.len()));
+        assert_eq!(message_length(&tversion[..10], 4096).unwrap(), None);
+        assert!(message_length(&[3, 0, 0, 0], 4096).is_err());
+        assert!(message_length(&tversion, 8).is_err());
+
+        let mut clamped = tversion.clone();
+        clamp_msize(&mut clamped, 8192);
+        assert_eq!(&clamped[7..11], &8192u32.to_le_bytes());
+        let rversion = message(RVERSION, 0xffff, &version_body(8192, NINEP_VERSION));
+        assert_eq!(version(&rversion), Some(NINEP_VERSION.as_bytes()));
+        assert_eq!(version(&tversion), None);
+    }
+
+    #[test]
+    fn test_proxy() {
+        let path = std::env::temp_dir().join(format!("tapcmio-9p-{}.sock", std::process::id()));
+        let mut proxy = NinePProxy::bind(&path, 8192).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // The kernel's Tversion and a partial message reach the host with a lowered msize
+        let tversion = message(TVERSION, 0xffff, &version_body(512 * 1024, NINEP_VERSION));
+        let tattach = message(104, 1, &[0; 20]);
+        client.write_all(&tversion).unwrap();
+        client.write_all(&tattach[..10]).unwrap();
+        let mut batch = Vec::new();
+        for _ in 0..100 {
+            proxy.poll_tx(&mut batch, 8192).unwrap();
+            if !batch.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(proxy.connected());
+        assert_eq!(batch.len(), tversion.len());
+        assert_eq!(&batch[7..11], &8192u32.to_le_bytes());
+
+        // The host's answers are written back as they came
+        let rversion = message(RVERSION, 0xffff, &version_body(8192, NINEP_VERSION));
+        proxy.handle_rx(&rversion, NINEP_REASON.code()).unwrap();
+        let mut received = vec![0u8; rversion.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, rversion);
+        
+        // A truncated answer disconnects the client instead
+        proxy.handle_rx(&rversion[..9], NINEP_REASON.code()).unwrap();
+        assert!(!proxy.connected());
+        assert_eq!(client.read(&mut received).unwrap(), 0);
+
+        drop(proxy);
+        assert!(!path.exists());
+    }
+}
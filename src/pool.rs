@@ -0,0 +1,78 @@
+// Buffers kept for reuse unless configured otherwise, enough for a few full batches
+pub const DEFAULT_POOL_SIZE: usize = 256;
+
+// Structure keeping released buffers for reuse, so frames and batches passing through
+// the loop don't each cost an allocation
+//
+// Buffers keep their capacity while pooled, so after the first rounds they're large
+// enough for any frame or batch and taking one costs no allocation at all.
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Vec<Vec<u8>>,
+    buffer_capacity: usize,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Create a pool handing out buffers of at least buffer_capacity bytes and keeping
+    /// up to max_buffers released ones
+    pub fn new(buffer_capacity: usize, max_buffers: usize) -> Self {
+        Self { free: Vec::with_capacity(max_buffers), buffer_capacity, max_buffers }
+    }
+    
+    /// Take an empty buffer, reusing a released one if there is any
+    pub fn take(&mut self) -> Vec<u8> {
+        self.free.pop().unwrap_or_else(|| Vec::with_capacity(self.buffer_capacity))
+    }
+    
+    /// Take a buffer holding a copy of the data
+    pub fn copy_of(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut buffer = self.take();
+        buffer.extend_from_slice(data);
+        buffer
+    }
+    
+    /// Release a buffer for reuse, dropping it if the pool is full
+    pub fn give(&mut self, mut buffer: Vec<u8>) {
+        if self.free.len() < self.max_buffers && buffer.capacity() > 0 {
+            buffer.clear();
+            self.free.push(buffer);
+        }
+    }
+    
+    /// Release all buffers of a collection
+    pub fn give_all(&mut self, buffers: impl IntoIterator<Item = Vec<u8>>) {
+        for buffer in buffers {
+            self.give(buffer);
+        }
+    }
+    
+    /// Number of buffers ready for reuse
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse() {
+        let mut pool = BufferPool::new(64, 2);
+        let buffer = pool.copy_of(&[1, 2, 3]);
+        let pointer = buffer.as_ptr();
+        pool.give(buffer);
+        assert_eq!(pool.available(), 1);
+
+        // The same allocation comes back, emptied
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), pointer);
+        assert!(buffer.capacity() >= 64);
+
+        // Buffers beyond the limit are dropped
+        pool.give_all([buffer, Vec::with_capacity(8), Vec::with_capacity(8)]);
+        assert_eq!(pool.available(), 2);
+    }
+}
@@ -0,0 +1,89 @@
+// HTIF device a yield is addressed to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum YieldDevice {
+    // The yield device, handing the buffers to the host
+    Yield = 0x02,
+}
+
+// Command of a yield, telling the host whether the machine waits for its response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum YieldCommand {
+    // The machine continues without a response
+    Automatic = 0x00,
+    // The machine stops until the host responds
+    Manual = 0x01,
+}
+
+// Reason code of a yield, telling the host what the TX buffer carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YieldReason {
+    // Batches of frames in network and stack mode
+    TapRxTx,
+    // Batches of socket messages in unix mode
+    UnixSocket,
+    // Control messages, such as the goodbye sent on shutdown
+    Control,
+    // Any other code, for reasons defined by the host
+    Other(u16),
+}
+
+impl YieldReason {
+    // Reasons of the Cartesi rollups' manual yields finishing a request, asking the host
+    // for the next one
+    pub const RX_ACCEPTED: Self = YieldReason::Other(1);
+    pub const RX_REJECTED: Self = YieldReason::Other(2);
+    
+    // Reasons of the Cartesi rollups' automatic yields emitting outputs: vouchers and
+    // notices, then reports, with 1 left to progress reports
+    pub const TX_OUTPUT: Self = YieldReason::Other(2);
+    pub const TX_REPORT: Self = YieldReason::Other(4);
+    
+    // Reason of the Cartesi rollups' manual yield throwing an exception, after which the
+    // host stops the machine
+    pub const TX_EXCEPTION: Self = YieldReason::Other(4);
+    
+    /// The code sent to the host
+    pub const fn code(self) -> u16 {
+        match self {
+            YieldReason::TapRxTx => 0x42,
+            YieldReason::UnixSocket => 0x43,
+            YieldReason::Control => 0x44,
+            YieldReason::Other(code) => code,
+        }
+    }
+    
+    /// The reason of a code received from the host
+    pub const fn from_code(code: u16) -> Self {
+        match code {
+            0x42 => YieldReason::TapRxTx,
+            0x43 => YieldReason::UnixSocket,
+            0x44 => YieldReason::Control,
+            code => YieldReason::Other(code),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reason_codes() {
+        for reason in [YieldReason::TapRxTx, YieldReason::UnixSocket, YieldReason::Control, YieldReason::Other(7)] {
+            assert_eq!(YieldReason::from_code(reason.code()), reason);
+        }
+        assert_eq!(YieldReason::Control.code(), 0x44);
+        assert_eq!(YieldDevice::Yield as u8, 0x02);
+        assert_eq!(YieldCommand::Manual as u8, 0x01);
+        assert_eq!(YieldCommand::Automatic as u8, 0x00);
+
+        // Rollup reasons share codes, told apart by the command they are yielded with
+        assert_eq!(YieldReason::RX_ACCEPTED.code(), 1);
+        assert_eq!(YieldReason::RX_REJECTED.code(), 2);
+        assert_eq!(YieldReason::TX_OUTPUT.code(), 2);
+        assert_eq!(YieldReason::TX_REPORT.code(), 4);
+        assert_eq!(YieldReason::TX_EXCEPTION.code(), 4);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::filter::ETHERTYPE_IPV4;
+use crate::ipv6::{self, ETHERTYPE_IPV6, IPPROTO_ICMPV6};
+
+// EtherType of ARP packets
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+// IP protocol numbers and ports of control traffic
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const DNS_PORT: u16 = 53;
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+// Priority class of an outbound frame, in the order the queues are drained
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // ARP, ICMP, NDP, DNS and DHCP
+    Control,
+    // Everything not classified otherwise, such as UDP
+    Normal,
+    // TCP other than DNS
+    Bulk,
+}
+
+// Read the source and destination ports of a TCP or UDP segment
+fn ports(segment: &[u8]) -> Option<(u16, u16)> {
+    let ports = segment.get(0..4)?;
+    Some((u16::from_be_bytes([ports[0], ports[1]]), u16::from_be_bytes([ports[2], ports[3]])))
+}
+
+// Classify by the upper-layer protocol and its payload
+fn classify_protocol(protocol: u8, payload: &[u8]) -> Priority {
+    let is_port = |port: u16| ports(payload).is_some_and(|(source, destination)| source == port || destination == port);
+    match protocol {
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => Priority::Control,
+        IPPROTO_UDP if is_port(DNS_PORT) || is_port(DHCP_SERVER_PORT) || is_port(DHCP_CLIENT_PORT) => Priority::Control,
+        IPPROTO_TCP if is_port(DNS_PORT) => Priority::Control,
+        IPPROTO_TCP => Priority::Bulk,
+        _ => Priority::Normal,
+    }
+}
+
+/// Classify a raw IPv4 or IPv6 packet
+pub fn classify_ip(packet: &[u8]) -> Priority {
+    match packet.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            let header_size = (packet[0] & 0x0F) as usize * 4;
+            match (packet.get(9), packet.get(header_size..)) {
+                (Some(&protocol), Some(payload)) => classify_protocol(protocol, payload),
+                _ => Priority::Normal,
+            }
+        }
+        Some(6) => match ipv6::parse_header(packet) {
+            Some(header) => classify_protocol(header.next_header, packet.get(header.payload_offset..).unwrap_or_default()),
+            None => Priority::Normal,
+        },
+        _ => Priority::Normal,
+    }
+}
+
+/// Classify an untagged Ethernet frame
+pub fn classify_ethernet(frame: &[u8]) -> Priority {
+    let Some(ethertype) = frame.get(12..14) else {
+        return Priority::Normal;
+    };
+    match u16::from_be_bytes([ethertype[0], ethertype[1]]) {
+        ETHERTYPE_ARP => Priority::Control,
+        ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => classify_ip(&frame[14..]),
+        _ => Priority::Normal,
+    }
+}
+
+/// Reorder frames so that higher priority classes go first
+///
+/// Frames are queued per class and the queues drained in priority order, which keeps
+/// frames of the same class in the order they were read.
+pub fn prioritize<T>(frames: Vec<T>, classify: impl Fn(&T) -> Priority) -> Vec<T> {
+    let mut queues: [Vec<T>; 3] = Default::default();
+    for frame in frames {
+        queues[classify(&frame) as usize].push(frame);
+    }
+    queues.into_iter().flatten().collect()
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    // Build an IPv4 packet carrying a segment between the given ports
+    fn ipv4(protocol: u8, source_port: u16, destination_port: u16) -> Vec<u8> {
+        let mut packet = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, protocol];
+        packet.extend_from_slice(&[0; 10]);
+        packet.extend_from_slice(&source_port.to_be_bytes());
+        packet.extend_from_slice(&destination_port.to_be_bytes());
+        packet.extend_from_slice(&[0; 4]);
+        packet
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify_ip(&ipv4(IPPROTO_ICMP, 0, 0)), Priority::Control);
+        assert_eq!(classify_ip(&ipv4(IPPROTO_UDP, 40000, DNS_PORT)), Priority::Control);
+        assert_eq!(classify_ip(&ipv4(IPPROTO_UDP, DHCP_CLIENT_PORT, DHCP_SERVER_PORT)), Priority::Control);
+        assert_eq!(classify_ip(&ipv4(IPPROTO_TCP, DNS_PORT, 40000)), Priority::Control);
+        assert_eq!(classify_ip(&ipv4(IPPROTO_TCP, 40000, 443)), Priority::Bulk);
+        assert_eq!(classify_ip(&ipv4(IPPROTO_UDP, 40000, 443)), Priority::Normal);
+        assert_eq!(classify_ip(&[]), Priority::Normal);
+
+        let mut ipv6 = vec![0x60, 0, 0, 0, 0, 8, IPPROTO_ICMPV6, 255];
+        ipv6.extend_from_slice(&[0; 32]);
+        assert_eq!(classify_ip(&ipv6), Priority::Control);
+
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+        assert_eq!(classify_ethernet(&frame), Priority::Control);
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ipv4(IPPROTO_TCP, 40000, 22));
+        assert_eq!(classify_ethernet(&frame), Priority::Bulk);
+    }
+
+    #[test]
+    fn test_prioritize() {
+        let frames = vec![(Priority::Bulk, 1), (Priority::Normal, 2), (Priority::Control, 3), (Priority::Bulk, 4), (Priority::Control, 5)];
+        let order: Vec<u32> = prioritize(frames, |frame| frame.0).into_iter().map(|frame| frame.1).collect();
+        assert_eq!(order, vec![3, 5, 2, 1, 4]);
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::cmio::{CmioError, Exchange};
+use crate::dump::Direction;
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+
+// Start of every recording, with the version of its format
+const MAGIC: &[u8; 8] = b"CMIOREC\x01";
+
+// Size of an entry's header: direction, device, command, reason code and data length
+const ENTRY_HEADER_SIZE: usize = 9;
+
+// Set once a recording is started, so yields don't take the lock while there is none
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// File the buffers of every yield in the process are recorded to
+static TARGET: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+
+// A buffer of a recorded yield
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub direction: Direction,
+    pub dev: u8,
+    pub cmd: u8,
+    pub reason: u16,
+    pub data: Vec<u8>,
+}
+
+/// Record the TX and RX buffers of every yield in the process from now on, replacing
+/// the file
+/// 
+/// The file starts with a magic number, followed by an entry per buffer: the direction
+/// (0 for TX, 1 for RX), the device, the command, the reason code (u16 BE) and the data
+/// length (u32 BE), then the data. Entries are flushed as they are written, so a
+/// recording cut short by a crash is still readable up to it.
+pub fn start(path: impl AsRef<Path>) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(MAGIC)?;
+    file.flush()?;
+    *TARGET.lock().unwrap() = Some(file);
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Append a buffer to the recording if one is started
+/// 
+/// Recording is best effort: a file that fails to write doesn't fail the yield.
+pub(crate) fn append(direction: Direction, dev: u8, cmd: u8, reason: u16, data: &[u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(file) = TARGET.lock().unwrap().as_mut() {
+        let _ = write_entry(file, direction, dev, cmd, reason, data).and_then(|()| file.flush());
+    }
+}
+
+fn write_entry(writer: &mut impl Write, direction: Direction, dev: u8, cmd: u8, reason: u16, data: &[u8]) -> io::Result<()> {
+    let mut header = [0u8; ENTRY_HEADER_SIZE];
+    header[0] = match direction {
+        Direction::Tx => 0,
+        Direction::Rx => 1,
+    };
+    header[1] = dev;
+    header[2] = cmd;
+    header[3..5].copy_from_slice(&reason.to_be_bytes());
+    header[5..9].copy_from_slice(&(data.len() as u32).to_be_bytes());
+    writer.write_all(&header)?;
+    writer.write_all(data)
+}
+
+/// Read the entries of a recording
+/// 
+/// An entry cut short at the end, as left by a crash, ends the recording.
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<Entry>, CmioError> {
+    let path = path.as_ref();
+    let data = fs::read(path).map_err(|e| CmioError::io(format!("read recording {}", path.display()), e))?;
+    parse(&data)
+}
+
+// Parse the entries of a recording's contents
+fn parse(data: &[u8]) -> Result<Vec<Entry>, CmioError> {
+    let mut rest = data.strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| CmioError::ProtocolError("not a CMIO recording".to_string()))?;
+    let mut entries = Vec::new();
+    while rest.len() >= ENTRY_HEADER_SIZE {
+        let direction = match rest[0] {
+            0 => Direction::Tx,
+            1 => Direction::Rx,
+            other => return Err(CmioError::ProtocolError(format!("invalid direction {} in recording", other))),
+        };
+        let length = u32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]) as usize;
+        let Some(data) = rest.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + length) else {
+            break;
+        };
+        entries.push(Entry {
+            direction,
+            dev: rest[1],
+            cmd: rest[2],
+            reason: u16::from_be_bytes([rest[3], rest[4]]),
+            data: data.to_vec(),
+        });
+        rest = &rest[ENTRY_HEADER_SIZE + length..];
+    }
+    Ok(entries)
+}
+
+// Stand-in for the CMIO device answering yields with the RX buffers of a recording, in
+// order, so the protocol handlers see what the host sent in the field
+//
+// What the guest sends is not compared against the recording, as it depends on the local
+// sockets and interfaces. Once the recording is used up, yields get empty responses.
+pub struct ReplayCmio {
+    responses: VecDeque<(u16, Vec<u8>)>,
+    tx_length: usize,
+    remaining: Arc<AtomicUsize>,
+}
+
+impl ReplayCmio {
+    /// Replay the RX buffers of the entries, accepting yields of up to tx_length bytes
+    pub fn new(entries: Vec<Entry>, tx_length: usize) -> Self {
+        let responses: VecDeque<(u16, Vec<u8>)> = entries.into_iter()
+            .filter(|entry| entry.direction == Direction::Rx)
+            .map(|entry| (entry.reason, entry.data))
+            .collect();
+        let remaining = Arc::new(AtomicUsize::new(responses.len()));
+        Self { responses, tx_length, remaining }
+    }
+    
+    /// Counter of the responses not replayed yet, which can be watched after the device
+    /// is handed to a multiplexer
+    pub fn remaining(&self) -> Arc<AtomicUsize> {
+        self.remaining.clone()
+    }
+}
+
+impl Exchange for ReplayCmio {
+    fn exchange(&mut self, _dev: YieldDevice, _cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+        if tx_data.len() > self.tx_length {
+            return Err(CmioError::BufferTooLarge(tx_data.len(), self.tx_length));
+        }
+        rx_data.clear();
+        match self.responses.pop_front() {
+            Some((response, data)) => {
+                self.remaining.store(self.responses.len(), Ordering::SeqCst);
+                rx_data.extend_from_slice(&data);
+                Ok(response)
+            }
+            None => Ok(reason.code()),
+        }
+    }
+    
+    fn tx_length(&self) -> usize {
+        self.tx_length
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use crate::multiplexer::{Multiplexer, Subsystem};
+
+    // Subsystem recording what the replay hands it
+    struct Collector(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Subsystem for Collector {
+        fn poll_tx(&mut self, _buffer: &mut Vec<u8>, _max_len: usize) -> Result<(), CmioError> {
+            Ok(())
+        }
+
+        fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+            self.0.lock().unwrap().push(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut data = MAGIC.to_vec();
+        write_entry(&mut data, Direction::Tx, 2, 1, 0x43, b"request").unwrap();
+        write_entry(&mut data, Direction::Rx, 2, 1, 0x43, b"first").unwrap();
+        write_entry(&mut data, Direction::Rx, 2, 1, 0x8043, b"second").unwrap();
+        let entries = parse(&data).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0], Entry { direction: Direction::Tx, dev: 2, cmd: 1, reason: 0x43, data: b"request".to_vec() });
+
+        // An entry cut short ends the recording, other data isn't one
+        assert_eq!(parse(&data[..data.len() - 1]).unwrap().len(), 2);
+        assert!(parse(b"garbage").is_err());
+
+        // The RX buffers reach the subsystem in order, then the host has nothing more
+        let replay = ReplayCmio::new(entries, 4096);
+        let remaining = replay.remaining();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let mut multiplexer = Multiplexer::replay(replay);
+        multiplexer.register(YieldReason::UnixSocket, Box::new(Collector(received.clone()))).unwrap();
+        while remaining.load(Ordering::SeqCst) > 0 {
+            multiplexer.run_once().unwrap();
+        }
+        assert_eq!(*received.lock().unwrap(), vec![b"first".to_vec(), b"second".to_vec()]);
+        assert!(!multiplexer.run_once().unwrap());
+    }
+}
@@ -0,0 +1,436 @@
+use tracing::{debug, warn};
+use crate::cmio::{Cmio, CmioError, Exchange};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+use crate::shutdown;
+
+// Reasons of the manual yields finishing a request, asking the host for the next one
+pub const ROLLUP_ACCEPTED: u16 = YieldReason::RX_ACCEPTED.code();
+pub const ROLLUP_REJECTED: u16 = YieldReason::RX_REJECTED.code();
+
+// Reason of the manual yield throwing an exception, after which the host stops the machine
+pub const ROLLUP_EXCEPTION: u16 = YieldReason::TX_EXCEPTION.code();
+
+// Reasons of the automatic yields emitting outputs: vouchers and notices, then reports
+pub const ROLLUP_OUTPUT: u16 = YieldReason::TX_OUTPUT.code();
+pub const ROLLUP_REPORT: u16 = YieldReason::TX_REPORT.code();
+
+// Reasons of the host's responses, telling the kind of the next request
+pub const ROLLUP_ADVANCE_STATE: u16 = 0;
+pub const ROLLUP_INSPECT_STATE: u16 = 1;
+
+// ABI selectors of EvmAdvance(uint256,address,address,uint256,uint256,uint256,uint256,bytes),
+// Notice(bytes) and Voucher(address,uint256,bytes)
+const EVM_ADVANCE_SELECTOR: [u8; 4] = [0x41, 0x5b, 0xf3, 0x63];
+const NOTICE_SELECTOR: [u8; 4] = [0xc2, 0x58, 0xd6, 0xe5];
+const VOUCHER_SELECTOR: [u8; 4] = [0x23, 0x7a, 0x81, 0x6f];
+
+// Size of an ABI word
+const WORD: usize = 32;
+
+// An advance-state request: an input added to the application's inbox
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvanceInput {
+    pub chain_id: u64,
+    pub app_contract: [u8; 20],
+    pub msg_sender: [u8; 20],
+    pub block_number: u64,
+    pub block_timestamp: u64,
+    pub prev_randao: [u8; 32],
+    // Position of the input in the inbox
+    pub index: u64,
+    pub payload: Vec<u8>,
+}
+
+impl AdvanceInput {
+    /// Decode the EvmAdvance call the host sends an input as
+    pub fn decode(data: &[u8]) -> Result<Self, CmioError> {
+        if data.get(..4) != Some(&EVM_ADVANCE_SELECTOR[..]) {
+            return Err(CmioError::ProtocolError("advance request isn't an EvmAdvance call".to_string()));
+        }
+        let args = &data[4..];
+        let word = |index: usize| -> Result<&[u8], CmioError> {
+            args.get(index * WORD..(index + 1) * WORD)
+                .ok_or_else(|| CmioError::ProtocolError(format!("EvmAdvance call truncated at word {}", index)))
+        };
+        let uint_value = |bytes: &[u8], name: &str| -> Result<u64, CmioError> {
+            if bytes[..24].iter().any(|&byte| byte != 0) {
+                return Err(CmioError::ProtocolError(format!("EvmAdvance {} exceeds 64 bits", name)));
+            }
+            Ok(u64::from_be_bytes(bytes[24..].try_into().unwrap()))
+        };
+        let uint = |index: usize| -> Result<u64, CmioError> { uint_value(word(index)?, &format!("word {}", index)) };
+        let address = |index: usize| -> Result<[u8; 20], CmioError> { Ok(word(index)?[12..].try_into().unwrap()) };
+        
+        // The offset and length come from the host, so their sums are checked rather than
+        // left to overflow
+        let out_of_range = || CmioError::ProtocolError("EvmAdvance payload offset out of range".to_string());
+        let offset = usize::try_from(uint(7)?).map_err(|_| out_of_range())?;
+        let start = offset.checked_add(WORD).ok_or_else(out_of_range)?;
+        let length = uint_value(args.get(offset..start).ok_or_else(out_of_range)?, "payload length")?;
+        let payload = usize::try_from(length).ok()
+            .and_then(|length| start.checked_add(length))
+            .and_then(|end| args.get(start..end))
+            .ok_or_else(|| CmioError::ProtocolError("EvmAdvance payload truncated".to_string()))?;
+        Ok(Self {
+            chain_id: uint(0)?,
+            app_contract: address(1)?,
+            msg_sender: address(2)?,
+            block_number: uint(3)?,
+            block_timestamp: uint(4)?,
+            prev_randao: word(5)?.try_into().unwrap(),
+            index: uint(6)?,
+            payload: payload.to_vec(),
+        })
+    }
+    
+    /// Encode the input as the host sends it
+    #[cfg(all(test, not(target_arch = "riscv64")))]
+    fn encode(&self) -> Vec<u8> {
+        let mut data = EVM_ADVANCE_SELECTOR.to_vec();
+        data.extend_from_slice(&uint_word(self.chain_id));
+        data.extend_from_slice(&address_word(&self.app_contract));
+        data.extend_from_slice(&address_word(&self.msg_sender));
+        data.extend_from_slice(&uint_word(self.block_number));
+        data.extend_from_slice(&uint_word(self.block_timestamp));
+        data.extend_from_slice(&self.prev_randao);
+        data.extend_from_slice(&uint_word(self.index));
+        data.extend_from_slice(&uint_word(8 * WORD as u64));
+        put_bytes(&mut data, &self.payload);
+        data
+    }
+}
+
+// An unsigned integer as an ABI word
+fn uint_word(value: u64) -> [u8; WORD] {
+    let mut word = [0; WORD];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+// An address as an ABI word
+fn address_word(address: &[u8; 20]) -> [u8; WORD] {
+    let mut word = [0; WORD];
+    word[12..].copy_from_slice(address);
+    word
+}
+
+// Append the tail of a bytes argument: its length, then the bytes padded to a whole word
+fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+    data.extend_from_slice(&uint_word(bytes.len() as u64));
+    data.extend_from_slice(bytes);
+    data.resize(data.len() + (WORD - bytes.len() % WORD) % WORD, 0);
+}
+
+// A request of the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Advance(AdvanceInput),
+    // Inspect-state query, with its payload
+    Inspect(Vec<u8>),
+}
+
+// Emitter of reports, the only outputs of an inspect-state request
+pub struct Reports<'a> {
+    device: &'a mut dyn Exchange,
+    rx_data: &'a mut Vec<u8>,
+}
+
+impl Reports<'_> {
+    /// Emit a report, diagnostic data the host keeps without proof
+    pub fn report(&mut self, payload: &[u8]) -> Result<(), CmioError> {
+        self.emit(YieldReason::TX_REPORT, payload)
+    }
+    
+    // Outputs are yielded once, since the host would prove a repeated one as another
+    fn emit(&mut self, reason: YieldReason, data: &[u8]) -> Result<(), CmioError> {
+        self.device.exchange_once(YieldDevice::Yield, YieldCommand::Automatic, reason, data, self.rx_data)?;
+        Ok(())
+    }
+}
+
+// Emitter of the outputs of an advance-state request: vouchers, notices and reports
+pub struct Outputs<'a> {
+    reports: Reports<'a>,
+}
+
+impl Outputs<'_> {
+    /// Emit a notice, data the host proves the application produced
+    pub fn notice(&mut self, payload: &[u8]) -> Result<(), CmioError> {
+        let mut data = NOTICE_SELECTOR.to_vec();
+        data.extend_from_slice(&uint_word(WORD as u64));
+        put_bytes(&mut data, payload);
+        self.reports.emit(YieldReason::TX_OUTPUT, &data)
+    }
+    
+    /// Emit a voucher, a call of the destination contract with the value (uint256 BE)
+    /// that can be executed once proven
+    pub fn voucher(&mut self, destination: &[u8; 20], value: &[u8; 32], payload: &[u8]) -> Result<(), CmioError> {
+        let mut data = VOUCHER_SELECTOR.to_vec();
+        data.extend_from_slice(&address_word(destination));
+        data.extend_from_slice(value);
+        data.extend_from_slice(&uint_word(3 * WORD as u64));
+        put_bytes(&mut data, payload);
+        self.reports.emit(YieldReason::TX_OUTPUT, &data)
+    }
+    
+    /// Emit a report like during inspect
+    pub fn report(&mut self, payload: &[u8]) -> Result<(), CmioError> {
+        self.reports.report(payload)
+    }
+}
+
+// Handler of advance-state requests, returning whether the input is accepted
+pub type AdvanceHandler = Box<dyn FnMut(&AdvanceInput, &mut Outputs) -> Result<bool, CmioError>>;
+
+// Handler of inspect-state requests with the query payload, returning whether it is
+// accepted; it only gets to emit reports, as an inspect can't change the state
+pub type InspectHandler = Box<dyn FnMut(&[u8], &mut Reports) -> Result<bool, CmioError>>;
+
+// Structure running a Cartesi rollups application over the CMIO device
+//
+// Each request is finished with a manual yield, accepted or rejected, whose response is
+// the next request: an advance-state input as an EvmAdvance call, or an inspect-state
+// query. The handler registered for its kind runs and emits outputs with automatic
+// yields. Inputs are rejected while no advance handler is registered, and queries
+// accepted without reports while no inspect handler is. Applications can also finish
+// requests themselves with accept_input and reject_input, and signal fatal errors with
+// throw_exception.
+pub struct Rollup {
+    device: Box<dyn Exchange>,
+    advance: Option<AdvanceHandler>,
+    inspect: Option<InspectHandler>,
+    // Whether the last request was accepted, told to the host when asking for the next
+    accepted: bool,
+    rx_data: Vec<u8>,
+}
+
+impl Rollup {
+    pub fn new(cmio: Cmio) -> Self {
+        Self::with_device(Box::new(cmio))
+    }
+    
+    fn with_device(device: Box<dyn Exchange>) -> Self {
+        Self { device, advance: None, inspect: None, accepted: true, rx_data: Vec::new() }
+    }
+    
+    /// Handle advance-state requests with the given handler
+    pub fn on_advance(&mut self, handler: impl FnMut(&AdvanceInput, &mut Outputs) -> Result<bool, CmioError> + 'static) {
+        self.advance = Some(Box::new(handler));
+    }
+    
+    /// Handle inspect-state requests with the given handler, which can only emit reports
+    pub fn on_inspect(&mut self, handler: impl FnMut(&[u8], &mut Reports) -> Result<bool, CmioError> + 'static) {
+        self.inspect = Some(Box::new(handler));
+    }
+    
+    /// Handle requests until shutdown is requested
+    pub fn run(&mut self) -> Result<(), CmioError> {
+        while !shutdown::requested() {
+            self.step()?;
+        }
+        Ok(())
+    }
+    
+    /// Finish the last request, then wait for the next one and handle it
+    /// 
+    /// A handler failing throws an exception with its error as the payload before the
+    /// error is returned.
+    pub fn step(&mut self) -> Result<(), CmioError> {
+        let request = self.finish(if self.accepted { YieldReason::RX_ACCEPTED } else { YieldReason::RX_REJECTED })?;
+        let mut reports = Reports { device: self.device.as_mut(), rx_data: &mut self.rx_data };
+        let result = match &request {
+            Request::Advance(input) => {
+                debug!(index = input.index, length = input.payload.len(), "Advance-state request");
+                match self.advance.as_mut() {
+                    Some(handler) => handler(input, &mut Outputs { reports }),
+                    None => Ok(false),
+                }
+            }
+            Request::Inspect(query) => {
+                debug!(length = query.len(), "Inspect-state request");
+                match self.inspect.as_mut() {
+                    Some(handler) => handler(query, &mut reports),
+                    None => Ok(true),
+                }
+            }
+        };
+        match result {
+            Ok(accepted) => {
+                self.accepted = accepted;
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Rollup request handler failed: {}", e);
+                self.throw_exception(e.to_string().as_bytes())?;
+                Err(e)
+            }
+        }
+    }
+    
+    /// Accept the current input, keeping the state it led to, and wait for the next
+    /// request, to drive the application without handlers
+    pub fn accept_input(&mut self) -> Result<Request, CmioError> {
+        self.finish(YieldReason::RX_ACCEPTED)
+    }
+    
+    /// Reject the current input, having the host revert the state and discard the outputs
+    /// emitted for it, and wait for the next request
+    pub fn reject_input(&mut self) -> Result<Request, CmioError> {
+        self.finish(YieldReason::RX_REJECTED)
+    }
+    
+    /// Signal a fatal error of the application, with a payload describing it
+    /// 
+    /// The host treats the machine as failed, so no request follows. The exception is
+    /// yielded once and a failure returned, since a retry could reach the host twice.
+    pub fn throw_exception(&mut self, payload: &[u8]) -> Result<(), CmioError> {
+        self.device.exchange_once(YieldDevice::Yield, YieldCommand::Manual, YieldReason::TX_EXCEPTION, payload, &mut self.rx_data)?;
+        Ok(())
+    }
+    
+    /// Finish the current request with the given reason and decode the next one
+    /// 
+    /// The yield isn't retried: had the host taken it before failing, a retry would
+    /// finish the next request too, skipping its input.
+    fn finish(&mut self, reason: YieldReason) -> Result<Request, CmioError> {
+        let mut request = Vec::new();
+        let kind = self.device.exchange_once(YieldDevice::Yield, YieldCommand::Manual, reason, &[], &mut request)?;
+        match kind {
+            ROLLUP_ADVANCE_STATE => Ok(Request::Advance(AdvanceInput::decode(&request)?)),
+            ROLLUP_INSPECT_STATE => Ok(Request::Inspect(request)),
+            kind => Err(CmioError::ProtocolError(format!("invalid rollup request kind {}", kind))),
+        }
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    // Yields made, with their command, reason and data
+    type Yields = Arc<Mutex<Vec<(YieldCommand, u16, Vec<u8>)>>>;
+
+    // Host answering the manual yields with scripted requests, recording every yield
+    struct Host {
+        requests: VecDeque<(u16, Vec<u8>)>,
+        yields: Yields,
+    }
+
+    impl Exchange for Host {
+        // No rollup yield may be retried, the host would take a repeated one as the next
+        fn exchange(&mut self, _dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, _tx_data: &[u8], _rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+            panic!("{:?} yield with reason {} that may be retried", cmd, reason.code());
+        }
+
+        fn exchange_once(&mut self, _dev: YieldDevice, cmd: YieldCommand, reason: YieldReason, tx_data: &[u8], rx_data: &mut Vec<u8>) -> Result<u16, CmioError> {
+            self.yields.lock().unwrap().push((cmd, reason.code(), tx_data.to_vec()));
+            rx_data.clear();
+            if cmd == YieldCommand::Automatic || reason.code() == ROLLUP_EXCEPTION {
+                return Ok(reason.code());
+            }
+            let (kind, request) = self.requests.pop_front().ok_or(CmioError::YieldFailed(libc::EIO))?;
+            rx_data.extend_from_slice(&request);
+            Ok(kind)
+        }
+
+        fn tx_length(&self) -> usize {
+            4096
+        }
+    }
+
+    #[test]
+    fn test_rollup() {
+        let input = AdvanceInput {
+            chain_id: 31337,
+            app_contract: [0xaa; 20],
+            msg_sender: [0xbb; 20],
+            block_number: 12,
+            block_timestamp: 1_700_000_000,
+            prev_randao: [7; 32],
+            index: 3,
+            payload: b"hello rollup, a payload over one word".to_vec(),
+        };
+        assert_eq!(AdvanceInput::decode(&input.encode()).unwrap(), input);
+        assert!(AdvanceInput::decode(&input.encode()[..100]).is_err());
+
+        // Offsets and lengths that would overflow, and lengths beyond 64 bits, are refused
+        let (offset, length) = (4 + 7 * WORD, 4 + 8 * WORD);
+        let mut call = input.encode();
+        call[offset + 24..offset + WORD].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(AdvanceInput::decode(&call), Err(CmioError::ProtocolError(_))));
+        let mut call = input.encode();
+        call[length + 24..length + WORD].copy_from_slice(&(u64::MAX - 16).to_be_bytes());
+        assert!(matches!(AdvanceInput::decode(&call), Err(CmioError::ProtocolError(_))));
+        call[length] = 1;
+        assert!(matches!(AdvanceInput::decode(&call), Err(CmioError::ProtocolError(e)) if e.contains("payload length")));
+
+        let yields = Arc::new(Mutex::new(Vec::new()));
+        let requests = VecDeque::from([
+            (ROLLUP_ADVANCE_STATE, input.encode()),
+            (ROLLUP_INSPECT_STATE, b"query".to_vec()),
+            (ROLLUP_ADVANCE_STATE, input.encode()),
+        ]);
+        let mut rollup = Rollup::with_device(Box::new(Host { requests, yields: yields.clone() }));
+        rollup.on_advance(|input, outputs| {
+            outputs.notice(&input.payload[..5])?;
+            outputs.voucher(&[0xcc; 20], &[0; 32], b"call")?;
+            Ok(input.index != 3)
+        });
+        rollup.on_inspect(|query, reports| {
+            reports.report(query)?;
+            Ok(true)
+        });
+        for _ in 0..3 {
+            rollup.step().unwrap();
+        }
+        assert!(matches!(rollup.step(), Err(CmioError::YieldFailed(libc::EIO))));
+
+        // The advance emits a notice and a voucher and is rejected, the inspect a report,
+        // with the codes Cartesi gives outputs and reports
+        let yields = yields.lock().unwrap();
+        let mut notice = NOTICE_SELECTOR.to_vec();
+        notice.extend_from_slice(&uint_word(32));
+        notice.extend_from_slice(&uint_word(5));
+        notice.extend_from_slice(b"hello");
+        notice.resize(4 + 3 * WORD, 0);
+        assert_eq!(yields[0], (YieldCommand::Manual, ROLLUP_ACCEPTED, Vec::new()));
+        assert_eq!(yields[1], (YieldCommand::Automatic, 2, notice));
+        assert_eq!((yields[2].0, yields[2].1, yields[2].2.len()), (YieldCommand::Automatic, 2, 4 + 5 * WORD));
+        assert_eq!(yields[3], (YieldCommand::Manual, ROLLUP_REJECTED, Vec::new()));
+        assert_eq!(yields[4], (YieldCommand::Automatic, 4, b"query".to_vec()));
+        assert_eq!(yields[5], (YieldCommand::Manual, ROLLUP_ACCEPTED, Vec::new()));
+    }
+
+    #[test]
+    fn test_reject_and_exception() {
+        let input = AdvanceInput {
+            chain_id: 1,
+            app_contract: [0; 20],
+            msg_sender: [0; 20],
+            block_number: 1,
+            block_timestamp: 1,
+            prev_randao: [0; 32],
+            index: 0,
+            payload: Vec::new(),
+        };
+        let yields = Arc::new(Mutex::new(Vec::new()));
+        let requests = VecDeque::from([
+            (ROLLUP_INSPECT_STATE, b"query".to_vec()),
+            (ROLLUP_ADVANCE_STATE, input.encode()),
+            (ROLLUP_ADVANCE_STATE, input.encode()),
+        ]);
+        let mut rollup = Rollup::with_device(Box::new(Host { requests, yields: yields.clone() }));
+        assert_eq!(rollup.reject_input().unwrap(), Request::Inspect(b"query".to_vec()));
+        assert_eq!(rollup.accept_input().unwrap(), Request::Advance(input));
+
+        // A failing handler throws an exception carrying its error
+        rollup.on_advance(|_, _| Err(CmioError::ProtocolError("bad input".to_string())));
+        assert!(matches!(rollup.step(), Err(CmioError::ProtocolError(_))));
+        let yields = yields.lock().unwrap();
+        assert_eq!(yields[0], (YieldCommand::Manual, ROLLUP_REJECTED, Vec::new()));
+        assert_eq!(yields[1], (YieldCommand::Manual, ROLLUP_ACCEPTED, Vec::new()));
+        assert_eq!(yields[3], (YieldCommand::Manual, ROLLUP_EXCEPTION, b"Protocol error: bad input".to_vec()));
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use thiserror::Error;
+use tracing::{error, info};
+use tun_tap::{Iface, Mode};
+use crate::cmio::{Cmio, CmioError, MockCmio};
+use crate::pool::BufferPool;
+use crate::network::{decode_frames, encode_frames, NetworkStats, FRAME_FLAG_L3, MIN_BATCH_SIZE, PACKET_INFO_SIZE};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+
+// Size of the mock CMIO buffer, small enough for jumbo frames to be fragmented
+const BUFFER_SIZE: usize = 4096;
+
+// Largest frame accepted back, that of a 9000 byte MTU with its Ethernet header
+const MAX_FRAME_SIZE: usize = PACKET_INFO_SIZE + 18 + 9000;
+
+// EtherType of IPv4 in the packet info in front of Ethernet frames
+const PACKET_INFO_IPV4: [u8; PACKET_INFO_SIZE] = [0, 0, 0x08, 0x00];
+
+// Device node TUN and TAP interfaces are created through
+const TUN_DEVICE: &str = "/dev/net/tun";
+
+// Name template of the TAP interface created to check for TAP support, numbered by the
+// kernel
+const CHECK_TAP_NAME: &str = "tapcmiochk%d";
+
+// Error returned when the network path self-test fails
+#[derive(Error, Debug)]
+pub enum SelftestError {
+    #[error("CMIO error: {0}")]
+    Cmio(#[from] CmioError),
+    #[error("Self-test case {0} failed: {1}")]
+    Failed(&'static str, String),
+    #[error("{0} of the environment checks failed")]
+    Environment(usize),
+}
+
+// Outcome of an environment check, with what was found or why it failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<String, String>,
+}
+
+// Build a frame of the given size behind the packet info, filled with a pattern that
+// differs between frames so reordering is noticed
+fn ethernet_frame(size: usize, seed: u8) -> Vec<u8> {
+    let mut frame = PACKET_INFO_IPV4.to_vec();
+    frame.extend((0..size).map(|index| (index as u8).wrapping_mul(31).wrapping_add(seed)));
+    frame
+}
+
+// Build a raw IPv4 packet of the given size as read from a TUN interface
+fn ip_packet(size: usize, seed: u8) -> Vec<u8> {
+    let mut packet = vec![0x45; size.max(1)];
+    packet.iter_mut().skip(1).enumerate().for_each(|(index, byte)| *byte = (index as u8) ^ seed);
+    packet
+}
+
+// Send frames through the batch encoder and the mock device, decoding the batches
+// looped back into frames with their flags
+fn round_trip(cmio: &mut MockCmio, frames: &[Vec<u8>], flags: u8, checksum: bool, stats: &mut NetworkStats) -> Result<Vec<(u8, Vec<u8>)>, CmioError> {
+    let mut next_frame_id = 0;
+    let mut reassembly = None;
+    let mut received = Vec::new();
+    
+    let mut pool = BufferPool::new(cmio.get_tx_length(), 1);
+    for batch in encode_frames(frames, flags, checksum, cmio.get_tx_length(), &mut next_frame_id, &mut pool, stats) {
+        let (rx_data, _reason) = cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::TapRxTx, &batch)?;
+        for (flags, frame) in decode_frames(&rx_data, &mut reassembly, MAX_FRAME_SIZE, stats) {
+            received.push((flags, frame.into_owned()));
+        }
+    }
+    Ok(received)
+}
+
+// Run one case, checking that every frame came back intact, in order and with its flags
+fn run_case(cmio: &mut MockCmio, name: &'static str, frames: &[Vec<u8>], flags: u8, checksum: bool) -> Result<(), SelftestError> {
+    let mut stats = NetworkStats::default();
+    let received = round_trip(cmio, frames, flags, checksum, &mut stats)?;
+    let fail = |reason: String| Err(SelftestError::Failed(name, reason));
+    
+    if stats.rx_corrupted + stats.rx_malformed + stats.rx_bad_batches > 0 {
+        return fail(format!("{} corrupted, {} malformed, {} bad batches", stats.rx_corrupted, stats.rx_malformed, stats.rx_bad_batches));
+    }
+    if received.len() != frames.len() {
+        return fail(format!("sent {} frames, received {}", frames.len(), received.len()));
+    }
+    for (index, (sent, (received_flags, received))) in frames.iter().zip(&received).enumerate() {
+        if received_flags & FRAME_FLAG_L3 != flags {
+            return fail(format!("frame {} came back with flags {:#04x}", index, received_flags));
+        }
+        if sent != received {
+            return fail(format!("frame {} of {} bytes came back as {} different bytes", index, sent.len(), received.len()));
+        }
+    }
+    info!("{}: {} frames, {} fragments intact", name, frames.len(), stats.tx_fragments);
+    Ok(())
+}
+
+/// Check the network path end to end without a machine
+///
+/// Crafted frames go through the batch encoder, are looped back by a mock CMIO device
+/// and come back through the decoder used for frames from the host, which must return
+/// them intact and in order. Covers batching, fragmentation of jumbo frames, checksums,
+/// IP packets and detection of a corrupted frame.
+pub fn run_network() -> Result<(), SelftestError> {
+    let mut cmio = MockCmio::new(BUFFER_SIZE);
+    
+    let mixed: Vec<Vec<u8>> = (0..64).map(|index| ethernet_frame(60 + index * 23, index as u8)).collect();
+    run_case(&mut cmio, "single frame", &[ethernet_frame(60, 0)], 0, false)?;
+    run_case(&mut cmio, "batches", &mixed, 0, false)?;
+    run_case(&mut cmio, "checksums", &mixed, 0, true)?;
+    run_case(&mut cmio, "fragments", &[ethernet_frame(9000, 1), ethernet_frame(60, 2), ethernet_frame(BUFFER_SIZE, 3)], 0, true)?;
+    run_case(&mut cmio, "ip packets", &[ip_packet(20, 0), ip_packet(1500, 1), ip_packet(9000, 2)], FRAME_FLAG_L3, false)?;
+    
+    // A flipped bit must fail the frame's checksum rather than reach the interface
+    let mut stats = NetworkStats::default();
+    let mut batches = encode_frames(&[ethernet_frame(60, 4)], 0, true, BUFFER_SIZE, &mut 0, &mut BufferPool::new(BUFFER_SIZE, 1), &mut stats);
+    if let Some(byte) = batches[0].last_mut() {
+        *byte ^= 0x01;
+    }
+    let (rx_data, _reason) = cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::TapRxTx, &batches[0])?;
+    let received = decode_frames(&rx_data, &mut None, MAX_FRAME_SIZE, &mut stats);
+    if !received.is_empty() || stats.rx_corrupted != 1 {
+        return Err(SelftestError::Failed("corruption", format!("{} frames passed, {} detected", received.len(), stats.rx_corrupted)));
+    }
+    info!("corruption: detected");
+    
+    info!("Network self-test passed ({} yields)", cmio.yields());
+    Ok(())
+}
+
+/// Check that the machine is set up for the daemon, returning the outcome of each check
+/// 
+/// The CMIO device must be a character device whose setup ioctl succeeds, with buffers
+/// of at least MIN_BATCH_SIZE bytes and a batch size in between, and /dev/net/tun must
+/// allow creating a TAP interface. Checks depending on a failed one are left out.
+pub fn check_environment(device: &Path, max_batch_size: Option<usize>) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut check = |name, result| {
+        let passed = Result::is_ok(&result);
+        checks.push(Check { name, result });
+        passed
+    };
+    
+    let node = fs::metadata(device)
+        .map_err(|e| format!("{}: {}", device.display(), e))
+        .and_then(|metadata| match metadata.file_type().is_char_device() {
+            true => Ok(format!("{} is a character device", device.display())),
+            false => Err(format!("{} is not a character device, is the CMIO driver loaded?", device.display())),
+        });
+    if check("cmio device", node) {
+        match Cmio::open(device) {
+            Ok(cmio) => {
+                check("cmio setup", Ok(format!("{:?}", cmio.capabilities())));
+                check("buffer sizes", check_buffer_sizes(cmio.get_tx_length(), cmio.get_rx_length(), max_batch_size));
+            }
+            Err(e) => {
+                check("cmio setup", Err(e.to_string()));
+            }
+        }
+    }
+    
+    let tun = OpenOptions::new().read(true).write(true).open(TUN_DEVICE)
+        .map(|_| format!("{} opens", TUN_DEVICE))
+        .map_err(|e| format!("{}: {}, is the tun module loaded?", TUN_DEVICE, e));
+    if check("tun device", tun) {
+        let tap = Iface::new(CHECK_TAP_NAME, Mode::Tap)
+            .map(|iface| format!("created {}", iface.name()))
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::EPERM) => format!("creating a TAP interface needs CAP_NET_ADMIN: {}", e),
+                _ => format!("can't create a TAP interface: {}", e),
+            });
+        check("tap interface", tap);
+    }
+    checks
+}
+
+// Check that both buffers hold a batch of the minimum size, and that the batch size
+// given, if any, fits between that and the TX buffer
+fn check_buffer_sizes(tx_length: usize, rx_length: usize, max_batch_size: Option<usize>) -> Result<String, String> {
+    if tx_length < MIN_BATCH_SIZE || rx_length < MIN_BATCH_SIZE {
+        return Err(format!("TX {} bytes, RX {} bytes, below the {} byte minimum", tx_length, rx_length, MIN_BATCH_SIZE));
+    }
+    match max_batch_size {
+        Some(size) if size < MIN_BATCH_SIZE => Err(format!("batch size {} is below the {} byte minimum", size, MIN_BATCH_SIZE)),
+        Some(size) if size > tx_length => Err(format!("batch size {} exceeds the {} byte TX buffer", size, tx_length)),
+        _ => Ok(format!("TX {} bytes, RX {} bytes", tx_length, rx_length)),
+    }
+}
+
+/// Run the environment checks, logging each, and fail if any did
+pub fn run_environment(device: &Path, max_batch_size: Option<usize>) -> Result<(), SelftestError> {
+    let checks = check_environment(device, max_batch_size);
+    let mut failed = 0;
+    for check in &checks {
+        match &check.result {
+            Ok(found) => info!("{}: ok, {}", check.name, found),
+            Err(problem) => {
+                error!("{}: FAILED, {}", check.name, problem);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(SelftestError::Environment(failed));
+    }
+    info!("Environment checks passed");
+    Ok(())
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network_selftest() {
+        run_network().unwrap();
+    }
+
+    #[test]
+    fn test_environment_checks() {
+        assert!(check_buffer_sizes(2 << 20, 2 << 20, None).is_ok());
+        assert!(check_buffer_sizes(2 << 20, 2 << 20, Some(65536)).is_ok());
+        assert!(check_buffer_sizes(128, 2 << 20, None).unwrap_err().contains("minimum"));
+        assert!(check_buffer_sizes(4096, 4096, Some(8192)).unwrap_err().contains("exceeds"));
+        assert!(check_buffer_sizes(4096, 4096, Some(64)).is_err());
+
+        // A missing device fails without the checks depending on it
+        let checks = check_environment(Path::new("/nonexistent/cmio"), None);
+        assert_eq!(checks[0].name, "cmio device");
+        assert!(checks[0].result.as_ref().unwrap_err().contains("/nonexistent/cmio"));
+        assert!(checks.iter().all(|check| check.name != "cmio setup"));
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use tracing::info;
+use crate::cmio::{Cmio, CmioError, CmioHandle};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+
+// Goodbye messages telling the host which mode went away
+pub const GOODBYE_LINK_DOWN: u8 = 0x01; // Network mode, the TAP interface no longer bridges
+pub const GOODBYE_SOCKETS_CLOSED: u8 = 0x02; // Unix mode, all sockets were closed
+
+// Set by the signal handler once SIGTERM or SIGINT arrives
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Time without data exchanged after which a shutdown is requested, in milliseconds,
+// zero while exiting on idle isn't enabled
+static IDLE_EXIT_MS: AtomicU64 = AtomicU64::new(0);
+
+// Milliseconds since IDLE_START of the last yield exchanging data
+static LAST_ACTIVITY_MS: AtomicU64 = AtomicU64::new(0);
+static IDLE_START: OnceLock<Instant> = OnceLock::new();
+
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Install handlers for SIGTERM and SIGINT that request a shutdown
+///
+/// The run loops check for the request between iterations, so they can finish sending
+/// what they have and clean up instead of the process being killed midway. Without
+/// SA_RESTART, a blocking poll returns early to notice the request.
+pub fn install_handlers() -> Result<(), CmioError> {
+    let action = SigAction::new(SigHandler::Handler(handle_signal), SaFlags::empty(), SigSet::empty());
+    for signal in [Signal::SIGTERM, Signal::SIGINT] {
+        unsafe { sigaction(signal, &action) }
+            .map_err(|e| CmioError::io(format!("install the {} handler", signal), std::io::Error::from(e)))?;
+    }
+    Ok(())
+}
+
+/// Whether a shutdown was requested by a signal, or by no data being exchanged for
+/// longer than the idle exit timeout
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst) || idle_expired()
+}
+
+/// Request a shutdown once no yield has sent or received any data for the timeout, so
+/// one-shot computations end instead of yielding forever
+pub fn exit_when_idle(timeout: Duration) {
+    IDLE_START.get_or_init(Instant::now);
+    note_activity();
+    IDLE_EXIT_MS.store(timeout.as_millis().max(1) as u64, Ordering::SeqCst);
+}
+
+/// Restart the idle exit timeout, called for every yield exchanging data
+pub(crate) fn note_activity() {
+    if let Some(start) = IDLE_START.get() {
+        LAST_ACTIVITY_MS.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+// Whether the idle exit timeout has passed, requesting the shutdown the first time
+fn idle_expired() -> bool {
+    let timeout = IDLE_EXIT_MS.load(Ordering::Relaxed);
+    let Some(start) = IDLE_START.get().filter(|_| timeout > 0) else {
+        return false;
+    };
+    let idle = (start.elapsed().as_millis() as u64).saturating_sub(LAST_ACTIVITY_MS.load(Ordering::Relaxed));
+    if idle <= timeout {
+        return false;
+    }
+    if !REQUESTED.swap(true, Ordering::SeqCst) {
+        info!("No data exchanged for {} ms, shutting down", idle);
+    }
+    true
+}
+
+/// Send the goodbye message to the host on the control reason code
+pub fn say_goodbye(cmio: &mut Cmio, message: u8) -> Result<(), CmioError> {
+    cmio.yield_with_buffer(YieldDevice::Yield, YieldCommand::Manual, YieldReason::Control, &[message])?;
+    Ok(())
+}
+
+/// Send the goodbye message like say_goodbye, through a handle shared with other users
+/// of the device
+pub fn say_goodbye_through(cmio: &CmioHandle, message: u8) -> Result<(), CmioError> {
+    cmio.yield_with_retry(YieldDevice::Yield, YieldCommand::Manual, YieldReason::Control, &[message])?;
+    Ok(())
+}
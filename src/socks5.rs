@@ -0,0 +1,302 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// SOCKS protocol version
+pub(crate) const SOCKS_VERSION: u8 = 0x05;
+
+// Authentication methods (RFC 1928) and the username/password sub-negotiation version (RFC 1929)
+pub(crate) const AUTH_METHOD_NONE: u8 = 0x00;
+const AUTH_METHOD_USERNAME_PASSWORD: u8 = 0x02;
+pub(crate) const AUTH_METHOD_NO_ACCEPTABLE: u8 = 0xFF;
+const AUTH_USERNAME_PASSWORD_VERSION: u8 = 0x01;
+
+// CONNECT command and address types
+pub(crate) const CMD_CONNECT: u8 = 0x01;
+pub(crate) const ATYP_IPV4: u8 = 0x01;
+pub(crate) const ATYP_DOMAIN: u8 = 0x03;
+pub(crate) const ATYP_IPV6: u8 = 0x04;
+
+// Destination of a proxied connection
+#[derive(Debug, Clone)]
+pub enum Socks5Target {
+    Addr(SocketAddr),
+    // Hostname resolved by the proxy, and port
+    Host(String, u16),
+}
+
+// Structure describing a SOCKS5 proxy reachable from the guest
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    // Proxy address as host:port
+    address: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Proxy {
+    pub fn new(address: String, credentials: Option<(String, String)>) -> Self {
+        Self { address, credentials }
+    }
+    
+    /// Parse a proxy specification of the form `[username:password@]host:port`
+    pub fn parse(spec: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "expected [username:password@]host:port");
+        
+        let (credentials, address) = match spec.rsplit_once('@') {
+            Some((credentials, address)) => {
+                let (username, password) = credentials.split_once(':').ok_or_else(invalid)?;
+                (Some((username.to_string(), password.to_string())), address)
+            },
+            None => (None, spec),
+        };
+        
+        // The port is required
+        let (_, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+        port.parse::<u16>().map_err(|_| invalid())?;
+        
+        Ok(Self::new(address.to_string(), credentials))
+    }
+    
+    /// Connect to the target through the proxy
+    ///
+    /// The timeout, if any, bounds the connection to the proxy and each step of the
+    /// handshake. The returned stream is ready to carry the proxied connection.
+    pub fn connect(&self, target: &Socks5Target, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.address.to_socket_addrs()? {
+            let result = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match result {
+                Ok(mut stream) => {
+                    stream.set_read_timeout(timeout)?;
+                    stream.set_write_timeout(timeout)?;
+                    handshake(&mut stream, target, self.credentials.as_ref())?;
+                    stream.set_read_timeout(None)?;
+                    stream.set_write_timeout(None)?;
+                    return Ok(stream);
+                },
+                Err(e) => last_error = Some(e),
+            }
+        }
+        
+        Err(last_error.unwrap_or_else(|| io::Error::from_raw_os_error(libc::EHOSTUNREACH)))
+    }
+}
+
+// Map a SOCKS5 reply code to the errno a direct connection would have failed with
+fn reply_errno(reply: u8) -> i32 {
+    match reply {
+        0x02 => libc::EACCES,       // Connection not allowed by ruleset
+        0x03 => libc::ENETUNREACH,  // Network unreachable
+        0x04 => libc::EHOSTUNREACH, // Host unreachable
+        0x05 => libc::ECONNREFUSED, // Connection refused
+        0x06 => libc::ETIMEDOUT,    // TTL expired
+        0x07 | 0x08 => libc::EPROTO, // Command or address type not supported
+        _ => libc::EIO,             // General failure
+    }
+}
+
+/// Perform the SOCKS5 method negotiation, authentication and CONNECT request
+///
+/// # Arguments
+///
+/// * `stream` - Connection to the proxy
+/// * `target` - Destination to connect to
+/// * `credentials` - Username and password, offered in addition to no authentication
+pub fn handshake<S: Read + Write>(stream: &mut S, target: &Socks5Target, credentials: Option<&(String, String)>) -> io::Result<()> {
+    let protocol_error = || io::Error::from_raw_os_error(libc::EPROTO);
+    
+    // Step 1: Offer the authentication methods we support
+    let mut greeting = vec![SOCKS_VERSION];
+    if credentials.is_some() {
+        greeting.extend_from_slice(&[2, AUTH_METHOD_NONE, AUTH_METHOD_USERNAME_PASSWORD]);
+    } else {
+        greeting.extend_from_slice(&[1, AUTH_METHOD_NONE]);
+    }
+    stream.write_all(&greeting)?;
+    
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(protocol_error());
+    }
+    
+    // Step 2: Authenticate with the method chosen by the proxy
+    match (choice[1], credentials) {
+        (AUTH_METHOD_NONE, _) => {},
+        (AUTH_METHOD_USERNAME_PASSWORD, Some((username, password))) => {
+            if username.len() > u8::MAX as usize || password.len() > u8::MAX as usize {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            
+            let mut request = vec![AUTH_USERNAME_PASSWORD_VERSION, username.len() as u8];
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request)?;
+            
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status)?;
+            if status[1] != 0 {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+        },
+        (AUTH_METHOD_NO_ACCEPTABLE, _) => return Err(io::Error::from_raw_os_error(libc::EACCES)),
+        _ => return Err(protocol_error()),
+    }
+    
+    // Step 3: Ask the proxy to connect to the target
+    let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0x00];
+    let port = match target {
+        Socks5Target::Addr(addr) => {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    request.push(ATYP_IPV4);
+                    request.extend_from_slice(&ip.octets());
+                },
+                IpAddr::V6(ip) => {
+                    request.push(ATYP_IPV6);
+                    request.extend_from_slice(&ip.octets());
+                },
+            }
+            addr.port()
+        },
+        Socks5Target::Host(host, port) => {
+            if host.len() > u8::MAX as usize {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+            *port
+        },
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+    
+    // Step 4: Read the reply, skipping the bound address
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(protocol_error());
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::from_raw_os_error(reply_errno(reply[1])));
+    }
+    
+    let addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        },
+        _ => return Err(protocol_error()),
+    };
+    let mut bound = vec![0u8; addr_len + 2]; // address + 2 (port)
+    stream.read_exact(&mut bound)?;
+    
+    Ok(())
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // In-memory proxy replaying canned replies and recording what was sent
+    struct MockProxy {
+        replies: Cursor<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl Read for MockProxy {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for MockProxy {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn mock(replies: &[u8]) -> MockProxy {
+        MockProxy {
+            replies: Cursor::new(replies.to_vec()),
+            sent: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_handshake_without_auth() {
+        let mut proxy = mock(&[5, 0, 5, 0, 0, 1, 10, 0, 0, 1, 0x1F, 0x90]);
+        let target = Socks5Target::Addr("192.0.2.1:443".parse().unwrap());
+
+        handshake(&mut proxy, &target, None).unwrap();
+
+        assert_eq!(
+            proxy.sent,
+            vec![5, 1, 0, 5, 1, 0, ATYP_IPV4, 192, 0, 2, 1, 0x01, 0xBB]
+        );
+    }
+
+    #[test]
+    fn test_handshake_with_auth_and_hostname() {
+        let mut proxy = mock(&[5, 2, 1, 0, 5, 0, 0, 3, 3, b'a', b'b', b'c', 0, 80]);
+        let target = Socks5Target::Host("example.com".to_string(), 80);
+        let credentials = ("user".to_string(), "pw".to_string());
+
+        handshake(&mut proxy, &target, Some(&credentials)).unwrap();
+
+        let mut expected = vec![5, 2, 0, 2];
+        expected.extend_from_slice(&[1, 4, b'u', b's', b'e', b'r', 2, b'p', b'w']);
+        expected.extend_from_slice(&[5, 1, 0, ATYP_DOMAIN, 11]);
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&80u16.to_be_bytes());
+        assert_eq!(proxy.sent, expected);
+    }
+
+    #[test]
+    fn test_handshake_errors() {
+        let target = Socks5Target::Addr("192.0.2.1:443".parse().unwrap());
+
+        // Connection refused by the target
+        let mut proxy = mock(&[5, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let error = handshake(&mut proxy, &target, None).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::ECONNREFUSED));
+
+        // Rejected credentials
+        let credentials = ("user".to_string(), "wrong".to_string());
+        let mut proxy = mock(&[5, 2, 1, 1]);
+        let error = handshake(&mut proxy, &target, Some(&credentials)).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EACCES));
+
+        // Not a SOCKS5 proxy
+        let mut proxy = mock(&[4, 0]);
+        let error = handshake(&mut proxy, &target, None).unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EPROTO));
+    }
+
+    #[test]
+    fn test_parse_proxy() {
+        let proxy = Socks5Proxy::parse("user:p@ss@proxy.local:1080").unwrap();
+        assert_eq!(proxy.address, "proxy.local:1080");
+        assert_eq!(proxy.credentials, Some(("user".to_string(), "p@ss".to_string())));
+
+        let proxy = Socks5Proxy::parse("127.0.0.1:9050").unwrap();
+        assert_eq!(proxy.address, "127.0.0.1:9050");
+        assert!(proxy.credentials.is_none());
+
+        assert!(Socks5Proxy::parse("proxy.local").is_err());
+        assert!(Socks5Proxy::parse("proxy.local:http").is_err());
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::{debug, info, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+use crate::socks5::{ATYP_DOMAIN, ATYP_IPV4, ATYP_IPV6, AUTH_METHOD_NONE, AUTH_METHOD_NO_ACCEPTABLE, CMD_CONNECT, SOCKS_VERSION};
+
+// Reason code tunneled connections are exchanged with the host on
+pub const SOCKS5_SERVER_REASON: YieldReason = YieldReason::Other(0x4E);
+
+// Address guest programs reach the server on, e.g. with ALL_PROXY=socks5h://127.0.0.1:1080
+pub const DEFAULT_SOCKS5_LISTEN: &str = "127.0.0.1:1080";
+
+// Request of the guest for the host to connect, with the destination as in SOCKS5: the
+// address type, the address, then the port (u16 BE)
+const SOCKS_CONNECT: u8 = 0x01;
+
+// Data a guest program sent on its connection
+const SOCKS_DATA: u8 = 0x02;
+
+// Notice of the guest that the program is done sending, or gone
+const SOCKS_CLOSE: u8 = 0x03;
+
+// Answer of the host to a connect request, with a SOCKS5 reply code
+const SOCKS_CONNECTED: u8 = 0x81;
+
+// Data the destination sent, for the guest program
+const SOCKS_RECEIVED: u8 = 0x82;
+
+// Notice of the host that the destination closed the connection
+const SOCKS_CLOSED: u8 = 0x83;
+
+// SOCKS5 reply codes the server makes up itself
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+// Size of the type, connection ID and length preceding each payload
+const HEADER_SIZE: usize = 9;
+
+// Size of the buffer connections are read with
+const READ_CHUNK: usize = 16 * 1024;
+
+// Append a message: the type, connection ID (u32 BE), payload length (u32 BE), then the payload
+fn put_message(messages: &mut VecDeque<Vec<u8>>, kind: u8, id: u32, payload: &[u8]) {
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(payload);
+    messages.push_back(message);
+}
+
+// A SOCKS5 reply to a CONNECT request, with an unspecified bound address
+fn reply(code: u8) -> [u8; 10] {
+    [SOCKS_VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+// Size of a complete CONNECT request at the start of the data, None while more is needed
+//
+// The request is the version, command, a reserved byte and the destination: the address
+// type, the address (4 or 16 bytes, or a length and a hostname) and the port.
+fn request_size(data: &[u8]) -> Option<usize> {
+    let address_size = match *data.get(3)? {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => 1 + *data.get(4)? as usize,
+        // Unknown types are answered as soon as they are seen
+        _ => 0,
+    };
+    let size = 4 + address_size + 2;
+    (data.len() >= size).then_some(size)
+}
+
+// A static port forward: a local address whose connections are tunneled to a fixed
+// destination, resolved by the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortForward {
+    pub listen: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+// Error returned for port forwards that can't be parsed
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid port forward: {0}, expected [ADDRESS:]PORT:HOST:HOSTPORT")]
+pub struct ParsePortForwardError(String);
+
+impl FromStr for PortForward {
+    type Err = ParsePortForwardError;
+    
+    // Parse a forward like ssh -L, with the listening address defaulting to 127.0.0.1 and
+    // IPv6 addresses in brackets
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParsePortForwardError(s.to_string());
+        let (rest, port) = s.rsplit_once(':').ok_or_else(error)?;
+        let port = port.parse().map_err(|_| error())?;
+        let (listen, host) = match rest.strip_suffix(']') {
+            Some(rest) => rest.rsplit_once(":[").ok_or_else(error)?,
+            None => rest.rsplit_once(':').ok_or_else(error)?,
+        };
+        let listen = match listen.parse::<u16>() {
+            Ok(local_port) => SocketAddr::from(([127, 0, 0, 1], local_port)),
+            Err(_) => listen.replace(['[', ']'], "").rsplit_once(':')
+                .and_then(|(address, local_port)| Some(SocketAddr::new(address.parse().ok()?, local_port.parse().ok()?)))
+                .ok_or_else(error)?,
+        };
+        if host.is_empty() || host.len() > 255 {
+            return Err(error());
+        }
+        Ok(Self { listen, host: host.to_string(), port })
+    }
+}
+
+impl PortForward {
+    /// The destination as in a SOCKS5 request: the address type, address and port
+    fn destination(&self) -> Vec<u8> {
+        let mut destination = match self.host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(address)) => [&[ATYP_IPV4][..], &address.octets()].concat(),
+            Ok(IpAddr::V6(address)) => [&[ATYP_IPV6][..], &address.octets()].concat(),
+            Err(_) => [&[ATYP_DOMAIN, self.host.len() as u8][..], self.host.as_bytes()].concat(),
+        };
+        destination.extend_from_slice(&self.port.to_be_bytes());
+        destination
+    }
+}
+
+// Listen on an address without blocking
+fn listen<A: std::net::ToSocketAddrs + std::fmt::Display>(address: A) -> Result<TcpListener, CmioError> {
+    let listener = TcpListener::bind(&address)
+        .map_err(|e| CmioError::io(format!("listen on {}", address), e))?;
+    listener.set_nonblocking(true)
+        .map_err(|e| CmioError::io(format!("make {} non-blocking", address), e))?;
+    Ok(listener)
+}
+
+// Stage of a guest program's connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    // Waiting for the method negotiation
+    Greeting,
+    // Waiting for the CONNECT request
+    Request,
+    // Waiting for the host to connect
+    Connecting,
+    // Relaying data
+    Open,
+}
+
+// A guest program's connection
+struct Connection {
+    stream: TcpStream,
+    stage: Stage,
+    // Set for connections of a port forward, which get no SOCKS5 reply
+    forwarded: bool,
+    // Data read but not handled yet: the handshake, or data sent before the host connected
+    received: Vec<u8>,
+    // Data of the destination not written to the program yet
+    pending: Vec<u8>,
+    // Set once the program is done sending
+    eof: bool,
+    // Set once the destination closed, to close the connection once pending is written
+    closing: bool,
+}
+
+impl Connection {
+    /// Write as much of the pending data as the program takes
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.pending.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+    
+    /// Write a handshake answer, small enough to go out right away
+    fn answer(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        let _ = self.flush();
+    }
+}
+
+// Structure serving SOCKS5 to guest programs on a local TCP address and tunneling their
+// connections to the host, as a subsystem of the multiplexer on SOCKS5_SERVER_REASON,
+// for outbound connectivity without TAP or a kernel network stack
+//
+// Programs negotiate no authentication and send a CONNECT request, which is handed to
+// the host in a SOCKS_CONNECT message carrying the destination. The host connects and
+// answers with SOCKS_CONNECTED and a SOCKS5 reply code, which the program gets as the
+// reply, after which data flows in SOCKS_DATA and SOCKS_RECEIVED messages. SOCKS_CLOSE
+// and SOCKS_CLOSED tell the other side that one side is done sending; the connection
+// is dropped once the host closed it. Every message is a type, the connection ID (u32 BE)
+// and the length of the payload (u32 BE), followed by the payload.
+//
+// Static port forwards listen on addresses of their own, for programs that can't be
+// taught SOCKS. Their connections skip the handshake: the connect request with the
+// forward's destination goes to the host right away, and data is relayed once it
+// answered, with the reply dropped.
+pub struct Socks5Server {
+    // SOCKS5 listener, absent when only forwarding
+    listener: Option<TcpListener>,
+    // Listeners of the port forwards, with the destination of each as in a SOCKS5 request
+    forwards: Vec<(TcpListener, Vec<u8>)>,
+    connections: HashMap<u32, Connection>,
+    next_id: u32,
+    messages: VecDeque<Vec<u8>>,
+    max_message_size: usize,
+}
+
+impl Socks5Server {
+    /// Tunnel connections without a SOCKS5 listener, splitting their data to fit the CMIO
+    /// buffer size, for port forwards only
+    pub fn new(cmio_max_buffer_size: usize) -> Self {
+        Self {
+            listener: None,
+            forwards: Vec::new(),
+            connections: HashMap::new(),
+            next_id: 0,
+            messages: VecDeque::new(),
+            max_message_size: cmio_max_buffer_size,
+        }
+    }
+    
+    /// Listen for guest programs speaking SOCKS5 on an address
+    pub fn bind(address: &str, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        let mut server = Self::new(cmio_max_buffer_size);
+        server.listener = Some(listen(address)?);
+        Ok(server)
+    }
+    
+    /// Forward the connections made to a local address to the forward's destination,
+    /// returning the address listened on
+    pub fn add_forward(&mut self, forward: &PortForward) -> Result<SocketAddr, CmioError> {
+        let listener = listen(forward.listen)?;
+        let address = listener.local_addr()
+            .map_err(|e| CmioError::io(format!("get the address of {}", forward.listen), e))?;
+        info!("Forwarding {} to {}:{} on the host", address, forward.host, forward.port);
+        self.forwards.push((listener, forward.destination()));
+        Ok(address)
+    }
+    
+    /// The address guest programs connect to for SOCKS5
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Some(listener) => listener.local_addr(),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no SOCKS5 listener")),
+        }
+    }
+    
+    /// Number of connections of guest programs
+    pub fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+    
+    /// Accept guest programs connecting, to the SOCKS5 listener and the port forwards
+    fn accept(&mut self) {
+        let listeners = self.listener.iter().map(|listener| (listener, None))
+            .chain(self.forwards.iter().map(|(listener, destination)| (listener, Some(destination))));
+        for (listener, destination) in listeners {
+            loop {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        if stream.set_nonblocking(true).is_err() {
+                            continue;
+                        }
+                        self.next_id = self.next_id.wrapping_add(1);
+                        debug!("SOCKS5 connection {} from {}", self.next_id, peer);
+                        // Forwarded connections ask the host to connect right away
+                        let stage = match destination {
+                            Some(destination) => {
+                                put_message(&mut self.messages, SOCKS_CONNECT, self.next_id, destination);
+                                Stage::Connecting
+                            }
+                            None => Stage::Greeting,
+                        };
+                        self.connections.insert(self.next_id, Connection {
+                            stream,
+                            stage,
+                            forwarded: destination.is_some(),
+                            received: Vec::new(),
+                            pending: Vec::new(),
+                            eof: false,
+                            closing: false,
+                        });
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        warn!("Failed to accept a SOCKS5 connection: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    
+    /// Read from the connections, advancing their handshakes and queueing their data for
+    /// the host, and drop those that are over
+    fn read_connections(&mut self) {
+        let mut dropped = Vec::new();
+        let mut chunk = [0u8; READ_CHUNK];
+        let chunk_size = self.max_message_size.saturating_sub(HEADER_SIZE).clamp(1, READ_CHUNK);
+        for (&id, connection) in self.connections.iter_mut() {
+            if connection.flush().is_err() || (connection.closing && connection.pending.is_empty()) {
+                dropped.push(id);
+                continue;
+            }
+            if connection.eof || connection.closing || connection.stage == Stage::Connecting {
+                continue;
+            }
+            loop {
+                match connection.stream.read(&mut chunk[..chunk_size]) {
+                    Ok(0) => {
+                        connection.eof = true;
+                        break;
+                    }
+                    Ok(n) if connection.stage == Stage::Open => put_message(&mut self.messages, SOCKS_DATA, id, &chunk[..n]),
+                    Ok(n) => connection.received.extend_from_slice(&chunk[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => {
+                        connection.eof = true;
+                        connection.closing = true;
+                        break;
+                    }
+                }
+            }
+            if !Self::handshake(id, connection, &mut self.messages) {
+                dropped.push(id);
+                continue;
+            }
+            if connection.eof {
+                if connection.stage == Stage::Open {
+                    put_message(&mut self.messages, SOCKS_CLOSE, id, &[]);
+                } else {
+                    dropped.push(id);
+                }
+            }
+        }
+        for id in dropped {
+            if let Some(connection) = self.connections.remove(&id) {
+                if connection.stage == Stage::Open && !connection.eof {
+                    put_message(&mut self.messages, SOCKS_CLOSE, id, &[]);
+                }
+            }
+        }
+    }
+    
+    /// Advance the handshake of a connection with what it sent, returning false if it is
+    /// to be dropped
+    fn handshake(id: u32, connection: &mut Connection, messages: &mut VecDeque<Vec<u8>>) -> bool {
+        let data = &connection.received;
+        match connection.stage {
+            Stage::Greeting => {
+                let Some(&count) = data.get(1) else {
+                    return true;
+                };
+                if data[0] != SOCKS_VERSION {
+                    return false;
+                }
+                if data.len() < 2 + count as usize {
+                    return true;
+                }
+                if !data[2..2 + count as usize].contains(&AUTH_METHOD_NONE) {
+                    connection.answer(&[SOCKS_VERSION, AUTH_METHOD_NO_ACCEPTABLE]);
+                    return false;
+                }
+                connection.received.drain(..2 + count as usize);
+                connection.answer(&[SOCKS_VERSION, AUTH_METHOD_NONE]);
+                connection.stage = Stage::Request;
+                Self::handshake(id, connection, messages)
+            }
+            Stage::Request => {
+                let Some(size) = request_size(data) else {
+                    return true;
+                };
+                if data[0] != SOCKS_VERSION {
+                    return false;
+                }
+                let code = match (data[1], data[3]) {
+                    (CMD_CONNECT, ATYP_IPV4 | ATYP_IPV6 | ATYP_DOMAIN) => REPLY_SUCCEEDED,
+                    (CMD_CONNECT, _) => REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+                    _ => REPLY_COMMAND_NOT_SUPPORTED,
+                };
+                if code != REPLY_SUCCEEDED {
+                    debug!("Refusing SOCKS5 request {} with {:#04x}", id, code);
+                    connection.answer(&reply(code));
+                    return false;
+                }
+                put_message(messages, SOCKS_CONNECT, id, &data[3..size]);
+                connection.received.drain(..size);
+                connection.stage = Stage::Connecting;
+                true
+            }
+            Stage::Connecting | Stage::Open => true,
+        }
+    }
+    
+    /// Handle the host's answer to a connect request
+    fn connected(&mut self, id: u32, code: u8) {
+        let Some(connection) = self.connections.get_mut(&id).filter(|connection| connection.stage == Stage::Connecting) else {
+            debug!("Connect answer for SOCKS5 connection {}, which isn't connecting", id);
+            return;
+        };
+        if !connection.forwarded {
+            connection.answer(&reply(code));
+        }
+        if code != REPLY_SUCCEEDED {
+            debug!("Host failed to connect SOCKS5 connection {}: {:#04x}", id, code);
+            connection.closing = true;
+            return;
+        }
+        info!("SOCKS5 connection {} open", id);
+        connection.stage = Stage::Open;
+        let early = std::mem::take(&mut connection.received);
+        let eof = connection.eof;
+        if !early.is_empty() {
+            put_message(&mut self.messages, SOCKS_DATA, id, &early);
+        }
+        if eof {
+            put_message(&mut self.messages, SOCKS_CLOSE, id, &[]);
+        }
+    }
+}
+
+impl Subsystem for Socks5Server {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        self.accept();
+        // Only read more once the host has taken what is queued
+        if self.messages.is_empty() {
+            self.read_connections();
+        }
+        while let Some(message) = self.messages.front() {
+            if buffer.len() + message.len() > max_len {
+                break;
+            }
+            buffer.extend_from_slice(message);
+            self.messages.pop_front();
+        }
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            // The rest of a batch can't be framed past a truncated message
+            let Some(header) = data.get(offset..offset + HEADER_SIZE) else {
+                warn!("Dropping {} bytes of a truncated SOCKS5 message at offset {}", data.len() - offset, offset);
+                break;
+            };
+            let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+            let length = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+            let Some(payload) = data.get(offset + HEADER_SIZE..offset + HEADER_SIZE + length) else {
+                warn!("Dropping SOCKS5 connection {} message with a payload truncated at offset {}", id, offset);
+                break;
+            };
+            match header[0] {
+                SOCKS_CONNECTED => self.connected(id, payload.first().copied().unwrap_or(REPLY_SUCCEEDED)),
+                SOCKS_RECEIVED => match self.connections.get_mut(&id) {
+                    Some(connection) => {
+                        connection.pending.extend_from_slice(payload);
+                        let _ = connection.flush();
+                    }
+                    None => debug!("Dropping data for SOCKS5 connection {}, which is gone", id),
+                },
+                SOCKS_CLOSED => {
+                    if let Some(connection) = self.connections.get_mut(&id) {
+                        let _ = connection.flush();
+                        connection.closing = true;
+                        if connection.pending.is_empty() {
+                            let _ = connection.stream.shutdown(Shutdown::Both);
+                            self.connections.remove(&id);
+                        }
+                    }
+                }
+                kind => warn!("Ignoring SOCKS5 connection {} message of invalid type {:#04x}", id, kind),
+            }
+            offset += HEADER_SIZE + length;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn message(kind: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut messages = VecDeque::new();
+        put_message(&mut messages, kind, id, payload);
+        messages.pop_front().unwrap()
+    }
+
+    // Poll until the server has something for the host
+    fn poll(server: &mut Socks5Server) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for _ in 0..200 {
+            server.poll_tx(&mut buffer, 4096).unwrap();
+            if !buffer.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        buffer
+    }
+
+    // Read exactly as many bytes as expected
+    fn read(client: &mut TcpStream, length: usize) -> Vec<u8> {
+        let mut data = vec![0; length];
+        client.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_socks5_server() {
+        let mut server = Socks5Server::bind("127.0.0.1:0", 4096).unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // The greeting is answered by the guest, the request handed to the host
+        client.write_all(&[SOCKS_VERSION, 1, AUTH_METHOD_NONE]).unwrap();
+        client.write_all(&[SOCKS_VERSION, CMD_CONNECT, 0, ATYP_DOMAIN, 11]).unwrap();
+        client.write_all(b"example.com\x00\x50").unwrap();
+        let connect = poll(&mut server);
+        assert_eq!(read(&mut client, 2), [SOCKS_VERSION, AUTH_METHOD_NONE]);
+        assert_eq!(connect, message(SOCKS_CONNECT, 1, b"\x03\x0bexample.com\x00\x50"));
+
+        // Once the host connected, data flows both ways
+        server.handle_rx(&message(SOCKS_CONNECTED, 1, &[REPLY_SUCCEEDED]), SOCKS5_SERVER_REASON.code()).unwrap();
+        assert_eq!(read(&mut client, 10), reply(REPLY_SUCCEEDED));
+        client.write_all(b"ping").unwrap();
+        assert_eq!(poll(&mut server), message(SOCKS_DATA, 1, b"ping"));
+        let mut answer = message(SOCKS_RECEIVED, 1, b"pong");
+        answer.extend(message(SOCKS_CLOSED, 1, &[]));
+        
+        // Messages of unknown type are skipped and truncated ones dropped
+        let mut malformed = message(0x7f, 1, b"x");
+        malformed.extend_from_slice(&answer[..HEADER_SIZE + 3]);
+        server.handle_rx(&malformed, SOCKS5_SERVER_REASON.code()).unwrap();
+        server.handle_rx(&answer, SOCKS5_SERVER_REASON.code()).unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"pong");
+        assert_eq!(server.connection_count(), 0);
+    }
+
+    #[test]
+    fn test_port_forward() {
+        assert_eq!("5432:db.internal:5432".parse(), Ok(PortForward {
+            listen: "127.0.0.1:5432".parse().unwrap(),
+            host: "db.internal".to_string(),
+            port: 5432,
+        }));
+        let forward: PortForward = "[::1]:8080:[fd00::2]:80".parse().unwrap();
+        assert_eq!((forward.listen, forward.host.as_str()), ("[::1]:8080".parse().unwrap(), "fd00::2"));
+        assert_eq!(forward.destination()[0], ATYP_IPV6);
+        assert!("db.internal:5432".parse::<PortForward>().is_err());
+
+        // Connections go to the destination without a handshake or a reply
+        let mut server = Socks5Server::new(4096);
+        let address = server.add_forward(&"127.0.0.1:0:db.internal:5432".parse().unwrap()).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(poll(&mut server), message(SOCKS_CONNECT, 1, b"\x03\x0bdb.internal\x15\x38"));
+        client.write_all(b"query").unwrap();
+        server.handle_rx(&message(SOCKS_CONNECTED, 1, &[REPLY_SUCCEEDED]), SOCKS5_SERVER_REASON.code()).unwrap();
+        assert_eq!(poll(&mut server), message(SOCKS_DATA, 1, b"query"));
+        server.handle_rx(&message(SOCKS_RECEIVED, 1, b"rows"), SOCKS5_SERVER_REASON.code()).unwrap();
+        assert_eq!(read(&mut client, 4), b"rows");
+    }
+
+    #[test]
+    fn test_unsupported_command() {
+        let mut server = Socks5Server::bind("127.0.0.1:0", 4096).unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // BIND is refused without involving the host
+        client.write_all(&[SOCKS_VERSION, 1, AUTH_METHOD_NONE, SOCKS_VERSION, 0x02, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80]).unwrap();
+        for _ in 0..200 {
+            let mut buffer = Vec::new();
+            server.poll_tx(&mut buffer, 4096).unwrap();
+            assert!(buffer.is_empty());
+            if server.connection_count() == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut answer = Vec::new();
+        client.read_to_end(&mut answer).unwrap();
+        assert_eq!(answer[..2], [SOCKS_VERSION, AUTH_METHOD_NONE]);
+        assert_eq!(answer[2..], reply(REPLY_COMMAND_NOT_SUPPORTED));
+    }
+}
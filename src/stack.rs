@@ -0,0 +1,566 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp};
+use smoltcp::time::Instant;
+use smoltcp::wire::{EthernetAddress, HardwareAddress, IpCidr, IpEndpoint};
+use tracing::warn;
+use crate::cmio::{Cmio, CmioError};
+use crate::network::{decode_batch, encode_batch, FrameEntry, BATCH_HEADER_SIZE, FRAME_FLAG_L3, FRAME_HEADER_SIZE, PACKET_INFO_SIZE};
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+use crate::shutdown;
+
+// Path of the Unix socket guest programs connect to by default
+pub const DEFAULT_STACK_SOCKET: &str = "/run/tapcmio-stack.sock";
+
+// MTU of the stack's Ethernet interface, and the Ethernet header on top of it
+const STACK_MTU: usize = 1500;
+const ETHERNET_HEADER_SIZE: usize = 14;
+
+// Buffer sizes of the stack's sockets
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_PACKET_COUNT: usize = 32;
+
+// Data buffered in either direction of a relay before it stops taking more
+const MAX_RELAY_BUFFER: usize = 64 * 1024;
+
+// Longest request line a guest program may send
+const MAX_REQUEST_SIZE: usize = 256;
+
+// First local port of the stack's sockets, from the dynamic range
+const FIRST_LOCAL_PORT: u16 = 49152;
+
+// Longest sleep when no frames were exchanged
+const IDLE_TIMEOUT: Duration = Duration::from_millis(10);
+
+// Configuration of the user-space network stack
+#[derive(Debug, Clone)]
+pub struct StackConfig {
+    // Ethernet address of the stack's interface
+    pub mac: [u8; 6],
+    // Addresses with prefix length, at most two
+    pub addresses: Vec<(IpAddr, u8)>,
+    pub gateway: Option<IpAddr>,
+    // Path of the Unix socket guest programs connect to
+    pub socket_path: String,
+    // CMIO device node, by default the one named by TAPCMIO_DEVICE or /dev/cmio
+    pub cmio_device: Option<PathBuf>,
+}
+
+impl Default for StackConfig {
+    fn default() -> Self {
+        Self {
+            mac: [0x02, 0, 0, 0, 0, 0x15],
+            addresses: Vec::new(),
+            gateway: None,
+            socket_path: DEFAULT_STACK_SOCKET.to_string(),
+            cmio_device: None,
+        }
+    }
+}
+
+// Protocol of a relayed connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+// Parse a guest program's request line, e.g. `tcp 10.0.2.2:80` or `udp [fd00::2]:53`
+fn parse_request(line: &str) -> Option<(Protocol, SocketAddr)> {
+    let (protocol, address) = line.trim_end().split_once(' ')?;
+    let protocol = match protocol {
+        "tcp" => Protocol::Tcp,
+        "udp" => Protocol::Udp,
+        _ => return None,
+    };
+    Some((protocol, address.parse().ok()?))
+}
+
+// Take a datagram, written as its length (u16 BE) followed by the data, off the front
+// of a buffer once it's complete
+fn take_datagram(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let length = u16::from_be_bytes([*buffer.first()?, *buffer.get(1)?]) as usize;
+    let datagram = buffer.get(2..2 + length)?.to_vec();
+    buffer.drain(..2 + length);
+    Some(datagram)
+}
+
+// Device handing Ethernet frames between the stack and the CMIO batches
+struct FrameQueue {
+    // Frames received from the host
+    rx: VecDeque<Vec<u8>>,
+    // Frames for the host
+    tx: Vec<Vec<u8>>,
+}
+
+struct RxToken(Vec<u8>);
+
+struct TxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        self.0.push(frame);
+        result
+    }
+}
+
+impl Device for FrameQueue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+    
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.rx.pop_front()?;
+        Some((RxToken(frame), TxToken(&mut self.tx)))
+    }
+    
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken(&mut self.tx))
+    }
+    
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = STACK_MTU + ETHERNET_HEADER_SIZE;
+        capabilities
+    }
+}
+
+// Structure relaying a guest program's Unix stream through a socket of the stack
+//
+// The guest program sends a request line naming the protocol and remote address, and
+// is answered with `ok` once the socket is usable or `error <reason>` before the stream
+// is closed. TCP data then flows as is; UDP datagrams are written as their length (u16
+// BE) followed by the data, in both directions.
+struct Relay {
+    stream: UnixStream,
+    // Socket of the stack, once the request line was read
+    socket: Option<(Protocol, SocketHandle, SocketAddr)>,
+    // Data read from the guest program that the socket didn't take yet
+    from_guest: Vec<u8>,
+    // Data for the guest program that its stream didn't take yet
+    to_guest: Vec<u8>,
+    connected: bool,
+    guest_closed: bool,
+    remote_closed: bool,
+}
+
+impl Relay {
+    fn new(stream: UnixStream) -> Self {
+        Self {
+            stream,
+            socket: None,
+            from_guest: Vec::new(),
+            to_guest: Vec::new(),
+            connected: false,
+            guest_closed: false,
+            remote_closed: false,
+        }
+    }
+    
+    /// Move data between the guest program and the socket, returning whether the relay
+    /// is still alive
+    fn step(&mut self, iface: &mut Interface, sockets: &mut SocketSet<'static>, next_port: &mut u16) -> bool {
+        if !self.read_guest() {
+            return false;
+        }
+        
+        let (protocol, handle, remote) = match self.socket {
+            Some(socket) => socket,
+            None => match self.open_socket(iface, sockets, next_port) {
+                Some(socket) => socket,
+                None => return self.write_guest() && !self.guest_closed,
+            },
+        };
+        
+        let alive = match protocol {
+            Protocol::Tcp => self.relay_tcp(sockets.get_mut::<tcp::Socket>(handle)),
+            Protocol::Udp => self.relay_udp(sockets.get_mut::<udp::Socket>(handle), remote),
+        };
+        let written = self.write_guest();
+        if !alive || !written {
+            sockets.remove(handle);
+            return false;
+        }
+        true
+    }
+    
+    /// Read what the guest program sent, returning false if its stream failed
+    fn read_guest(&mut self) -> bool {
+        let mut buffer = [0u8; 16 * 1024];
+        while !self.guest_closed && self.from_guest.len() < MAX_RELAY_BUFFER {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.guest_closed = true,
+                Ok(n) => self.from_guest.extend_from_slice(&buffer[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+    
+    /// Write what is buffered for the guest program, returning false if its stream failed
+    fn write_guest(&mut self) -> bool {
+        while !self.to_guest.is_empty() {
+            match self.stream.write(&self.to_guest) {
+                Ok(n) => {
+                    self.to_guest.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+    
+    /// Open the socket named by the request line once it's complete
+    ///
+    /// Malformed requests are answered with an error and mark the guest program's side
+    /// closed, so the relay ends once the answer is written.
+    fn open_socket(&mut self, iface: &mut Interface, sockets: &mut SocketSet<'static>, next_port: &mut u16) -> Option<(Protocol, SocketHandle, SocketAddr)> {
+        let Some(end) = self.from_guest.iter().position(|&byte| byte == b'\n') else {
+            if self.from_guest.len() > MAX_REQUEST_SIZE || self.guest_closed {
+                self.fail("invalid request");
+            }
+            return None;
+        };
+        let request = String::from_utf8_lossy(&self.from_guest[..end]).into_owned();
+        self.from_guest.drain(..=end);
+        let Some((protocol, remote)) = parse_request(&request) else {
+            self.fail("invalid request");
+            return None;
+        };
+        
+        let local_port = *next_port;
+        *next_port = next_port.checked_add(1).unwrap_or(FIRST_LOCAL_PORT);
+        let handle = match protocol {
+            Protocol::Tcp => {
+                let mut socket = tcp::Socket::new(
+                    tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                    tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+                );
+                if socket.connect(iface.context(), remote, local_port).is_err() {
+                    self.fail("invalid address");
+                    return None;
+                }
+                sockets.add(socket)
+            }
+            Protocol::Udp => {
+                let mut socket = udp::Socket::new(
+                    udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKET_COUNT], vec![0; UDP_BUFFER_SIZE]),
+                    udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKET_COUNT], vec![0; UDP_BUFFER_SIZE]),
+                );
+                if socket.bind(local_port).is_err() {
+                    self.fail("no local port");
+                    return None;
+                }
+                // Datagrams can be sent right away
+                self.to_guest.extend_from_slice(b"ok\n");
+                self.connected = true;
+                sockets.add(socket)
+            }
+        };
+        self.socket = Some((protocol, handle, remote));
+        self.socket
+    }
+    
+    /// Answer the guest program with an error and stop reading from it
+    fn fail(&mut self, reason: &str) {
+        self.to_guest.extend_from_slice(format!("error {}\n", reason).as_bytes());
+        self.from_guest.clear();
+        self.guest_closed = true;
+    }
+    
+    /// Relay a TCP connection, returning false once it's over
+    fn relay_tcp(&mut self, socket: &mut tcp::Socket) -> bool {
+        if !self.connected {
+            if socket.may_send() {
+                self.to_guest.extend_from_slice(b"ok\n");
+                self.connected = true;
+            } else if !socket.is_active() {
+                self.fail("connection failed");
+                return false;
+            } else {
+                return true;
+            }
+        }
+        
+        if let Ok(n) = socket.send_slice(&self.from_guest) {
+            self.from_guest.drain(..n);
+        }
+        if self.guest_closed && self.from_guest.is_empty() {
+            socket.close();
+        }
+        
+        while socket.can_recv() && self.to_guest.len() < MAX_RELAY_BUFFER {
+            let received = socket.recv(|data| {
+                let n = data.len().min(MAX_RELAY_BUFFER - self.to_guest.len());
+                self.to_guest.extend_from_slice(&data[..n]);
+                (n, n)
+            });
+            if received.unwrap_or(0) == 0 {
+                break;
+            }
+        }
+        
+        // Pass the end of the remote's stream on once everything before it was written
+        if !socket.may_recv() && !self.remote_closed && self.to_guest.is_empty() {
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.remote_closed = true;
+        }
+        socket.is_active() || !self.to_guest.is_empty()
+    }
+    
+    /// Relay UDP datagrams, returning false once the guest program is gone
+    fn relay_udp(&mut self, socket: &mut udp::Socket, remote: SocketAddr) -> bool {
+        // Datagrams that don't fit the socket's buffer are dropped, as on a network
+        while let Some(datagram) = take_datagram(&mut self.from_guest) {
+            let _ = socket.send_slice(&datagram, IpEndpoint::from(remote));
+        }
+        while let Ok((data, _meta)) = socket.recv() {
+            if self.to_guest.len() + 2 + data.len() <= MAX_RELAY_BUFFER {
+                self.to_guest.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                self.to_guest.extend_from_slice(data);
+            }
+        }
+        !self.guest_closed
+    }
+}
+
+// Structure running a user-space TCP/IP stack on the frames exchanged with the host
+//
+// For guests without TUN/TAP support in the kernel, Ethernet and IP are terminated in
+// the stack, and guest programs reach the network through a Unix socket instead.
+pub struct UserStack {
+    cmio: Cmio,
+    cmio_max_buffer_size: usize,
+    device: FrameQueue,
+    iface: Interface,
+    sockets: SocketSet<'static>,
+    listener: UnixListener,
+    socket_path: String,
+    relays: Vec<Relay>,
+    // Local port of the next socket opened for a guest program
+    next_port: u16,
+}
+
+impl UserStack {
+    /// Create the stack with its interface's addresses and default route, listening for
+    /// guest programs on the configured Unix socket
+    ///
+    /// The interface takes at most two addresses; more, or a gateway that doesn't fit the
+    /// route table, fail with EINVAL.
+    pub fn new(config: &StackConfig) -> Result<Self, CmioError> {
+        let cmio = match &config.cmio_device {
+            Some(path) => Cmio::open(path)?,
+            None => Cmio::new()?,
+        };
+        let cmio_max_buffer_size = cmio.get_tx_length();
+        
+        let mut device = FrameQueue { rx: VecDeque::new(), tx: Vec::new() };
+        let mut iface_config = Config::new(HardwareAddress::Ethernet(EthernetAddress(config.mac)));
+        iface_config.random_seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64;
+        let mut iface = Interface::new(iface_config, &mut device, Instant::now());
+        
+        let mut addresses_fit = true;
+        iface.update_ip_addrs(|addrs| {
+            for &(addr, prefix_len) in &config.addresses {
+                addresses_fit &= addrs.push(IpCidr::new(addr.into(), prefix_len)).is_ok();
+            }
+        });
+        let gateway_fits = match config.gateway {
+            Some(IpAddr::V4(gateway)) => iface.routes_mut().add_default_ipv4_route(gateway).is_ok(),
+            Some(IpAddr::V6(gateway)) => iface.routes_mut().add_default_ipv6_route(gateway).is_ok(),
+            None => true,
+        };
+        if !addresses_fit {
+            return Err(CmioError::InvalidArgument("the stack takes at most two addresses".to_string()));
+        }
+        if !gateway_fits {
+            return Err(CmioError::InvalidArgument("no route can be added through the gateway".to_string()));
+        }
+        
+        // Replace a socket file left behind by an earlier run
+        let _ = fs::remove_file(&config.socket_path);
+        let listener = UnixListener::bind(&config.socket_path)
+            .map_err(|e| CmioError::io(format!("listen on {}", config.socket_path), e))?;
+        listener.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make {} non-blocking", config.socket_path), e))?;
+        
+        Ok(Self {
+            cmio,
+            cmio_max_buffer_size,
+            device,
+            iface,
+            sockets: SocketSet::new(Vec::new()),
+            listener,
+            socket_path: config.socket_path.clone(),
+            relays: Vec::new(),
+            next_port: FIRST_LOCAL_PORT,
+        })
+    }
+    
+    /// Run the stack loop
+    ///
+    /// Every iteration accepts new guest programs, relays their data through the stack's
+    /// sockets, polls the interface and exchanges the resulting frames with the host in
+    /// batches like network mode does. When no frames went either way, the loop sleeps
+    /// until the stack's next timer, at most 10 ms. A shutdown request closes all relays
+    /// and ends the loop after the link down goodbye.
+    pub fn run_loop(&mut self) -> Result<(), CmioError> {
+        loop {
+            if shutdown::requested() {
+                self.relays.clear();
+                let _ = fs::remove_file(&self.socket_path);
+                return shutdown::say_goodbye(&mut self.cmio, shutdown::GOODBYE_LINK_DOWN);
+            }
+            
+            self.accept_guests();
+            self.relays.retain_mut(|relay| relay.step(&mut self.iface, &mut self.sockets, &mut self.next_port));
+            self.iface.poll(Instant::now(), &mut self.device, &mut self.sockets);
+            
+            if !self.exchange_frames()? {
+                let delay = self.iface.poll_delay(Instant::now(), &self.sockets)
+                    .map_or(IDLE_TIMEOUT, |delay| Duration::from_micros(delay.total_micros()));
+                thread::sleep(delay.min(IDLE_TIMEOUT));
+            }
+        }
+    }
+    
+    /// Accept guest programs connecting to the Unix socket
+    fn accept_guests(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        self.relays.push(Relay::new(stream));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept on {}: {}", self.socket_path, e);
+                    break;
+                }
+            }
+        }
+    }
+    
+    /// Send the stack's frames to the host and queue the frames received in return,
+    /// returning whether any frames went either way
+    ///
+    /// Frames carry packet info like TAP frames of network mode, so the host handles
+    /// both modes alike. IP packets and fragments aren't accepted by the stack.
+    fn exchange_frames(&mut self) -> Result<bool, CmioError> {
+        let frames: Vec<Vec<u8>> = self.device.tx.drain(..).map(|frame| {
+            let mut packet = vec![0, 0];
+            packet.extend_from_slice(frame.get(12..14).unwrap_or(&[0, 0]));
+            packet.extend_from_slice(&frame);
+            packet
+        }).collect();
+        let mut activity = !frames.is_empty();
+        
+        // Batch the frames up to the CMIO buffer size, with an empty yield if there are none
+        let mut batches = Vec::new();
+        let mut entries = Vec::new();
+        let mut batch_size = BATCH_HEADER_SIZE;
+        for frame in &frames {
+            if batch_size + FRAME_HEADER_SIZE + frame.len() > self.cmio_max_buffer_size && !entries.is_empty() {
+                batches.push(encode_batch(&entries));
+                entries.clear();
+                batch_size = BATCH_HEADER_SIZE;
+            }
+            entries.push(FrameEntry::new(0, frame));
+            batch_size += FRAME_HEADER_SIZE + frame.len();
+        }
+        batches.push(if entries.is_empty() { Vec::new() } else { encode_batch(&entries) });
+        
+        for batch in batches {
+            let mut rx_data = self.yield_cmio(&batch)?;
+            while !rx_data.is_empty() {
+                activity = true;
+                self.receive_frames(&rx_data);
+                rx_data = self.yield_cmio(&[])?;
+            }
+        }
+        Ok(activity)
+    }
+    
+    /// Queue the Ethernet frames of a batch received from the host for the stack
+    fn receive_frames(&mut self, data: &[u8]) {
+        let Some(entries) = decode_batch(data) else {
+            warn!("Dropping {} bytes of batch with an unsupported header", data.len());
+            return;
+        };
+        for entry in entries {
+            if entry.flags & FRAME_FLAG_L3 != 0 || entry.fragment.is_some() || !entry.is_intact() {
+                continue;
+            }
+            if let Some(frame) = entry.data.get(PACKET_INFO_SIZE..) {
+                self.device.rx.push_back(frame.to_vec());
+            }
+        }
+    }
+    
+    /// Yield a buffer to the host, ignoring control messages
+    fn yield_cmio(&mut self, buffer: &[u8]) -> Result<Vec<u8>, CmioError> {
+        let (rx_data, reason) = self.cmio.yield_with_retry(
+            YieldDevice::Yield,
+            YieldCommand::Manual,
+            YieldReason::TapRxTx,
+            buffer,
+        )?;
+        if YieldReason::from_code(reason) == YieldReason::Control {
+            return Ok(Vec::new());
+        }
+        Ok(rx_data)
+    }
+}
+
+impl Drop for UserStack {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(parse_request("tcp 10.0.2.2:80\n"), Some((Protocol::Tcp, "10.0.2.2:80".parse().unwrap())));
+        assert_eq!(parse_request("udp [fd00::2]:53"), Some((Protocol::Udp, "[fd00::2]:53".parse().unwrap())));
+        assert_eq!(parse_request("sctp 10.0.2.2:80"), None);
+        assert_eq!(parse_request("tcp example.com:80"), None);
+        assert_eq!(parse_request("tcp"), None);
+    }
+
+    #[test]
+    fn test_take_datagram() {
+        let mut buffer = vec![0, 2, 0xaa, 0xbb, 0, 3, 0xcc];
+        assert_eq!(take_datagram(&mut buffer), Some(vec![0xaa, 0xbb]));
+        assert_eq!(take_datagram(&mut buffer), None);
+        assert_eq!(buffer, vec![0, 3, 0xcc]);
+
+        buffer.extend_from_slice(&[0xdd, 0xee, 0, 0]);
+        assert_eq!(take_datagram(&mut buffer), Some(vec![0xcc, 0xdd, 0xee]));
+        assert_eq!(take_datagram(&mut buffer), Some(vec![]));
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::warn;
+use crate::shutdown;
+
+// Environment variable naming the status file instead of the default one
+pub const STATUS_FILE_ENV: &str = "TAPCMIO_STATUS_FILE";
+
+// Status file written by a running daemon and read by the status command
+pub const DEFAULT_STATUS_FILE: &str = "/run/tapcmio.status";
+
+// How often a running daemon rewrites its status file
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Counters of the process, updated by the devices and the socket manager wherever they
+// are, for the status file
+static OPEN_DEVICES: AtomicUsize = AtomicUsize::new(0);
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static YIELDS: AtomicU64 = AtomicU64::new(0);
+static TX_BYTES: AtomicU64 = AtomicU64::new(0);
+static RX_BYTES: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn device_opened() {
+    OPEN_DEVICES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn device_closed() {
+    OPEN_DEVICES.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_yield(succeeded: bool) {
+    let counter = if succeeded { &YIELDS } else { &ERRORS };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_bytes(tx_bytes: usize, rx_bytes: usize) {
+    TX_BYTES.fetch_add(tx_bytes as u64, Ordering::Relaxed);
+    RX_BYTES.fetch_add(rx_bytes as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn set_connections(count: usize) {
+    CONNECTIONS.store(count, Ordering::Relaxed);
+}
+
+// Error returned when a status file can't be read or isn't one
+#[derive(Error, Debug)]
+pub enum StatusError {
+    #[error("Failed to read status file {0}: {1}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("Invalid status file line: {0}")]
+    Invalid(String),
+}
+
+// State of a running daemon as written to its status file
+//
+// The file holds one key=value pair per line, so shell scripts can read it too.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    pub pid: u32,
+    // Modes the daemon runs, such as network and unix
+    pub modes: Vec<String>,
+    pub cmio_open: bool,
+    // Open connections of the socket proxy
+    pub connections: usize,
+    pub yields: u64,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+    pub errors: u64,
+    // Bytes per second sent and received over the last interval
+    pub tx_rate: u64,
+    pub rx_rate: u64,
+    // Seconds since the epoch when the daemon started and last wrote the file
+    pub started: u64,
+    pub updated: u64,
+}
+
+impl Status {
+    /// The current state of this process, running the given modes
+    pub fn current(modes: &[&str], started: u64) -> Self {
+        Self {
+            pid: std::process::id(),
+            modes: modes.iter().map(|mode| mode.to_string()).collect(),
+            cmio_open: OPEN_DEVICES.load(Ordering::Relaxed) > 0,
+            connections: CONNECTIONS.load(Ordering::Relaxed),
+            yields: YIELDS.load(Ordering::Relaxed),
+            tx_bytes: TX_BYTES.load(Ordering::Relaxed),
+            rx_bytes: RX_BYTES.load(Ordering::Relaxed),
+            errors: ERRORS.load(Ordering::Relaxed),
+            tx_rate: 0,
+            rx_rate: 0,
+            started,
+            updated: unix_time(),
+        }
+    }
+    
+    /// Read the status file of a daemon
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, StatusError> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(|e| StatusError::Read(path.to_path_buf(), e))?
+            .parse()
+    }
+    
+    /// Replace the status file, through a temporary file so readers never see half of it
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, self.to_string())?;
+        fs::rename(&temporary, path)
+    }
+    
+    /// Why the daemon isn't healthy at the given time, or None if it is
+    /// 
+    /// It is unhealthy if its process is gone, it hasn't written the file for longer than
+    /// max_age, or it has no CMIO device open.
+    pub fn problem(&self, now: u64, max_age: Duration) -> Option<String> {
+        if unsafe { libc::kill(self.pid as libc::pid_t, 0) } != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH) {
+            return Some(format!("process {} is not running", self.pid));
+        }
+        let age = now.saturating_sub(self.updated);
+        if age > max_age.as_secs() {
+            return Some(format!("status is {} seconds old", age));
+        }
+        if !self.cmio_open {
+            return Some("CMIO device is not open".to_string());
+        }
+        None
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "pid={}", self.pid)?;
+        writeln!(f, "modes={}", self.modes.join(","))?;
+        writeln!(f, "cmio_open={}", self.cmio_open)?;
+        writeln!(f, "connections={}", self.connections)?;
+        writeln!(f, "yields={}", self.yields)?;
+        writeln!(f, "tx_bytes={}", self.tx_bytes)?;
+        writeln!(f, "rx_bytes={}", self.rx_bytes)?;
+        writeln!(f, "errors={}", self.errors)?;
+        writeln!(f, "tx_rate={}", self.tx_rate)?;
+        writeln!(f, "rx_rate={}", self.rx_rate)?;
+        writeln!(f, "started={}", self.started)?;
+        writeln!(f, "updated={}", self.updated)
+    }
+}
+
+impl FromStr for Status {
+    type Err = StatusError;
+    
+    /// Parse the key=value lines of a status file, ignoring keys it doesn't know
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut status = Status::default();
+        for line in s.lines().filter(|line| !line.is_empty()) {
+            let invalid = || StatusError::Invalid(line.to_string());
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            let number = || value.parse::<u64>().map_err(|_| invalid());
+            match key {
+                "pid" => status.pid = value.parse().map_err(|_| invalid())?,
+                "modes" => status.modes = value.split(',').filter(|mode| !mode.is_empty()).map(str::to_string).collect(),
+                "cmio_open" => status.cmio_open = value.parse().map_err(|_| invalid())?,
+                "connections" => status.connections = value.parse().map_err(|_| invalid())?,
+                "yields" => status.yields = number()?,
+                "tx_bytes" => status.tx_bytes = number()?,
+                "rx_bytes" => status.rx_bytes = number()?,
+                "errors" => status.errors = number()?,
+                "tx_rate" => status.tx_rate = number()?,
+                "rx_rate" => status.rx_rate = number()?,
+                "started" => status.started = number()?,
+                "updated" => status.updated = number()?,
+                _ => {}
+            }
+        }
+        Ok(status)
+    }
+}
+
+/// Seconds since the epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// Write the status of this process to a file every STATUS_INTERVAL from a thread of its
+/// own, removing the file once a shutdown is requested
+/// 
+/// A file that can't be written is warned about once and retried, so a daemon without
+/// access to the directory still runs.
+pub fn spawn_writer(path: PathBuf, modes: &[&'static str]) {
+    let modes = modes.to_vec();
+    let started = unix_time();
+    thread::spawn(move || {
+        let mut previous = Status::current(&modes, started);
+        let mut warned = false;
+        while !shutdown::requested() {
+            let mut status = Status::current(&modes, started);
+            let seconds = STATUS_INTERVAL.as_secs().max(1);
+            status.tx_rate = (status.tx_bytes - previous.tx_bytes) / seconds;
+            status.rx_rate = (status.rx_bytes - previous.rx_bytes) / seconds;
+            match status.write(&path) {
+                Ok(()) => warned = false,
+                Err(e) if !warned => {
+                    warn!("Failed to write status file {}: {}", path.display(), e);
+                    warned = true;
+                }
+                Err(_) => {}
+            }
+            previous = status;
+            thread::sleep(STATUS_INTERVAL);
+        }
+        let _ = fs::remove_file(&path);
+    });
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_file() {
+        let status = Status {
+            pid: std::process::id(),
+            modes: vec!["network".to_string(), "unix".to_string()],
+            cmio_open: true,
+            connections: 3,
+            yields: 120,
+            tx_bytes: 4096,
+            rx_bytes: 1024,
+            errors: 1,
+            tx_rate: 512,
+            rx_rate: 128,
+            started: 1000,
+            updated: 1010,
+        };
+        let path = std::env::temp_dir().join(format!("tapcmio-status-{}", std::process::id()));
+        status.write(&path).unwrap();
+        assert_eq!(Status::read(&path).unwrap(), status);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(Status::read(&path), Err(StatusError::Read(..))));
+        assert!(matches!("pid".parse::<Status>(), Err(StatusError::Invalid(_))));
+
+        // Healthy while fresh and open, then stale, closed or gone
+        assert_eq!(status.problem(1012, Duration::from_secs(5)), None);
+        assert!(status.problem(1020, Duration::from_secs(5)).unwrap().contains("old"));
+        assert!(Status { cmio_open: false, ..status.clone() }.problem(1012, Duration::from_secs(5)).is_some());
+        assert!(Status { pid: i32::MAX as u32, ..status }.problem(1012, Duration::from_secs(5)).unwrap().contains("not running"));
+    }
+}
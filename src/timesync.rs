@@ -0,0 +1,249 @@
+use std::io;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+
+// Reason code the time requests and the host's answers are exchanged on
+pub const TIME_REASON: YieldReason = YieldReason::Other(0x4A);
+
+// How often the host clock is fetched unless another interval is given
+pub const DEFAULT_TIME_INTERVAL: Duration = Duration::from_secs(60);
+
+// Largest offset slewed rather than stepped, as a frozen clock would never catch up
+pub const SLEW_LIMIT: Duration = Duration::from_millis(128);
+
+// Request of the guest, with a sequence number (u32 BE)
+const TIME_REQUEST: u8 = 0x01;
+
+// Answer of the host, with the sequence number of the request, then the wall clock as
+// seconds (u64 BE) and nanoseconds (u32 BE) since the epoch
+const TIME_RESPONSE: u8 = 0x81;
+
+// Size of the host's answer
+const RESPONSE_SIZE: usize = 17;
+
+// What to do with the guest clock once the host's is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockAdjustment {
+    // Only log the offset
+    Monitor,
+    // Set the clock to the host's
+    Step,
+    // Speed the clock up or slow it down until it matches, stepping offsets beyond SLEW_LIMIT
+    Slew,
+}
+
+// Error returned for clock adjustments that can't be parsed
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Invalid clock adjustment: {0}, expected monitor, step or slew")]
+pub struct ParseClockAdjustmentError(String);
+
+impl FromStr for ClockAdjustment {
+    type Err = ParseClockAdjustmentError;
+    
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "monitor" => Ok(ClockAdjustment::Monitor),
+            "step" => Ok(ClockAdjustment::Step),
+            "slew" => Ok(ClockAdjustment::Slew),
+            _ => Err(ParseClockAdjustmentError(s.to_string())),
+        }
+    }
+}
+
+// Offset in nanoseconds from the guest clock to the host's, positive if the guest is behind
+fn offset_nanos(host: Duration, guest: Duration) -> i128 {
+    host.as_nanos() as i128 - guest.as_nanos() as i128
+}
+
+// Set the guest clock to a time since the epoch
+fn step_clock(time: Duration) -> io::Result<()> {
+    let time = libc::timespec { tv_sec: time.as_secs() as libc::time_t, tv_nsec: time.subsec_nanos() as libc::c_long };
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &time) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Have the kernel gradually correct the guest clock by an offset in nanoseconds
+fn slew_clock(offset: i128) -> io::Result<()> {
+    let micros = (offset / 1000) as i64;
+    let delta = libc::timeval { tv_sec: (micros / 1_000_000) as libc::time_t, tv_usec: (micros % 1_000_000) as libc::suseconds_t };
+    if unsafe { libc::adjtime(&delta, std::ptr::null_mut()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// A request waiting for the host's answer
+struct PendingRequest {
+    sequence: u32,
+    sent: Instant,
+}
+
+// Structure fetching the host wall clock every interval, as a subsystem of the
+// multiplexer on TIME_REASON, for machines whose own clock is frozen or wrong
+//
+// The guest sends TIME_REQUEST with a sequence number, and the host answers with
+// TIME_RESPONSE carrying it and its clock. Half the round trip is added to the host's
+// time to estimate it at the moment of the answer. The offset is logged and, depending on
+// the adjustment, the guest clock stepped or slewed, which needs CAP_SYS_TIME.
+pub struct TimeSync {
+    adjustment: ClockAdjustment,
+    interval: Duration,
+    sequence: u32,
+    pending: Option<PendingRequest>,
+    last_request: Option<Instant>,
+    offset: Option<i128>,
+    // Set once adjusting the clock failed, so the failure is only warned about once
+    warned: bool,
+}
+
+impl TimeSync {
+    /// Fetch the host clock every interval, adjusting the guest clock as given
+    pub fn new(adjustment: ClockAdjustment, interval: Duration) -> Self {
+        Self {
+            adjustment,
+            interval,
+            sequence: 0,
+            pending: None,
+            last_request: None,
+            offset: None,
+            warned: false,
+        }
+    }
+    
+    /// Offset in nanoseconds of the guest clock from the host's at the last answer,
+    /// positive if the guest was behind
+    pub fn offset(&self) -> Option<i128> {
+        self.offset
+    }
+    
+    /// Handle the host's answer to a request
+    /// 
+    /// A time too late to add the half round trip to is ignored like an answer to
+    /// another request.
+    fn handle_response(&mut self, data: &[u8]) -> Result<(), CmioError> {
+        if data.len() < RESPONSE_SIZE || data[0] != TIME_RESPONSE {
+            return Err(CmioError::ProtocolError(format!("invalid time message of {} bytes", data.len())));
+        }
+        let sequence = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+        let seconds = u64::from_be_bytes(data[5..13].try_into().unwrap());
+        let nanos = u32::from_be_bytes([data[13], data[14], data[15], data[16]]);
+        if nanos >= 1_000_000_000 {
+            return Err(CmioError::ProtocolError(format!("host time with {} nanoseconds", nanos)));
+        }
+        let Some(pending) = self.pending.take_if(|pending| pending.sequence == sequence) else {
+            debug!("Ignoring the host time for request {}, which isn't outstanding", sequence);
+            return Ok(());
+        };
+        let Some(host) = Duration::new(seconds, nanos).checked_add(pending.sent.elapsed() / 2) else {
+            warn!("Ignoring the host time of {} seconds, which is out of range", seconds);
+            return Ok(());
+        };
+        let guest = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let offset = offset_nanos(host, guest);
+        self.offset = Some(offset);
+        debug!(offset_ns = offset as i64, round_trip_us = pending.sent.elapsed().as_micros() as u64, "Fetched the host clock");
+        self.adjust(offset, host);
+        Ok(())
+    }
+    
+    /// Adjust the guest clock for an offset to the host time
+    fn adjust(&mut self, offset: i128, host: Duration) {
+        let result = match self.adjustment {
+            ClockAdjustment::Monitor => return,
+            ClockAdjustment::Slew if offset.unsigned_abs() <= SLEW_LIMIT.as_nanos() => slew_clock(offset),
+            ClockAdjustment::Step | ClockAdjustment::Slew => {
+                info!("Stepping the clock by {:.3} seconds to the host's", offset as f64 / 1e9);
+                step_clock(host)
+            }
+        };
+        if let Err(e) = result {
+            if !self.warned {
+                warn!("Failed to adjust the clock, which needs CAP_SYS_TIME: {}", e);
+                self.warned = true;
+            }
+        }
+    }
+}
+
+impl Subsystem for TimeSync {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, _max_len: usize) -> Result<(), CmioError> {
+        // Ask again once the interval has passed, even if the last answer never came
+        if self.last_request.is_some_and(|sent| sent.elapsed() < self.interval) {
+            return Ok(());
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        buffer.push(TIME_REQUEST);
+        buffer.extend_from_slice(&self.sequence.to_be_bytes());
+        let now = Instant::now();
+        self.pending = Some(PendingRequest { sequence: self.sequence, sent: now });
+        self.last_request = Some(now);
+        Ok(())
+    }
+    
+    /// Handle the host's answer, warning about and dropping a malformed one rather than
+    /// stopping the loop
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.handle_response(data) {
+            warn!("Dropping a time message: {}", e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    fn response(sequence: u32, time: Duration) -> Vec<u8> {
+        let mut data = vec![TIME_RESPONSE];
+        data.extend_from_slice(&sequence.to_be_bytes());
+        data.extend_from_slice(&time.as_secs().to_be_bytes());
+        data.extend_from_slice(&time.subsec_nanos().to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_time_sync() {
+        assert_eq!("slew".parse(), Ok(ClockAdjustment::Slew));
+        assert!("ntp".parse::<ClockAdjustment>().is_err());
+
+        let mut sync = TimeSync::new(ClockAdjustment::Monitor, Duration::from_secs(60));
+        let mut buffer = Vec::new();
+        sync.poll_tx(&mut buffer, 4096).unwrap();
+        assert_eq!(buffer, [TIME_REQUEST, 0, 0, 0, 1]);
+
+        // Answers to other requests are ignored, the right one gives the offset
+        let ahead = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(10);
+        sync.handle_rx(&response(7, ahead), TIME_REASON.code()).unwrap();
+        assert_eq!(sync.offset(), None);
+        sync.handle_rx(&response(1, ahead), TIME_REASON.code()).unwrap();
+        let offset = sync.offset().unwrap();
+        assert!((offset - 10_000_000_000).abs() < 1_000_000_000, "offset {}", offset);
+
+        // Malformed answers are dropped without disturbing the loop or the offset
+        assert!(sync.handle_rx(&[TIME_RESPONSE, 0], TIME_REASON.code()).is_ok());
+        assert!(sync.handle_response(&[TIME_RESPONSE, 0]).is_err());
+        sync.pending = Some(PendingRequest { sequence: 2, sent: Instant::now() });
+        let mut invalid_nanos = response(2, Duration::ZERO);
+        invalid_nanos[13..17].copy_from_slice(&1_000_000_000u32.to_be_bytes());
+        assert!(sync.handle_response(&invalid_nanos).is_err());
+        assert!(sync.handle_rx(&invalid_nanos, TIME_REASON.code()).is_ok());
+        assert!(sync.handle_rx(&response(2, Duration::new(u64::MAX, 999_999_999)), TIME_REASON.code()).is_ok());
+        assert_eq!(sync.offset(), Some(offset));
+
+        // Nothing more is asked before the interval
+        let mut buffer = Vec::new();
+        sync.poll_tx(&mut buffer, 4096).unwrap();
+        assert!(buffer.is_empty());
+    }
+}
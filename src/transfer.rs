@@ -0,0 +1,423 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, warn};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+
+// Reason code the transfer requests and responses are exchanged with the host on
+pub const TRANSFER_REASON: YieldReason = YieldReason::Other(0x47);
+
+// Request types, the same in the response answering them
+const TRANSFER_GET: u8 = 0x01;
+const TRANSFER_PUT: u8 = 0x02;
+const TRANSFER_STAT: u8 = 0x03;
+const TRANSFER_LIST: u8 = 0x04;
+
+// Flag in a response marking the last chunk of a file or listing
+const TRANSFER_FLAG_END: u8 = 0x01;
+
+// Size of a request without its path and data: 1 (type) + 4 (request ID) + 8 (offset)
+// + 4 (length) + 2 (path length)
+const REQUEST_HEADER_SIZE: usize = 19;
+
+// Size of a response without its data: 1 (type) + 4 (request ID) + 1 (status) + 4 (errno)
+// + 1 (flags) + 4 (data length)
+const RESPONSE_HEADER_SIZE: usize = 15;
+
+// Size of a stat response's data: 1 (kind) + 4 (mode) + 8 (size) + 8 (modification time)
+const STAT_SIZE: usize = 21;
+
+// Size of a listing entry without its name: 1 (kind) + 8 (size) + 2 (name length)
+const ENTRY_HEADER_SIZE: usize = 11;
+
+// Kind of a path in stat responses and listings
+const KIND_FILE: u8 = 0x00;
+const KIND_DIRECTORY: u8 = 0x01;
+const KIND_SYMLINK: u8 = 0x02;
+const KIND_OTHER: u8 = 0x03;
+
+// Status carried in every response, followed by the raw errno (i32, network byte order)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TransferStatus {
+    Success = 0x00,
+    NotFound = 0x01,
+    PermissionDenied = 0x02,
+    IsDirectory = 0x03,
+    NotDirectory = 0x04,
+    InvalidPath = 0x05, // Absolute, escaping the root or not UTF-8
+    InvalidMessage = 0x06,
+    Other = 0xFF,
+}
+
+impl TransferStatus {
+    fn from_error(error: &io::Error) -> Self {
+        match error.raw_os_error() {
+            Some(libc::ENOENT) => TransferStatus::NotFound,
+            Some(libc::EACCES) | Some(libc::EPERM) => TransferStatus::PermissionDenied,
+            Some(libc::EISDIR) => TransferStatus::IsDirectory,
+            Some(libc::ENOTDIR) => TransferStatus::NotDirectory,
+            _ => TransferStatus::Other,
+        }
+    }
+}
+
+// A request of the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Request {
+    kind: u8,
+    id: u32,
+    // Byte offset into the file for get and put, index of the first entry for list
+    offset: u64,
+    // Bytes to read for get, at most what fits a response
+    length: u32,
+    path: Vec<u8>,
+    // Bytes to write for put
+    data: Vec<u8>,
+}
+
+impl Request {
+    /// Parse the request at the start of data, returning it with its size
+    fn decode(data: &[u8]) -> Result<(Self, usize), CmioError> {
+        let header = data.get(..REQUEST_HEADER_SIZE)
+            .ok_or_else(|| CmioError::ProtocolError(format!("transfer request of {} bytes, shorter than its header", data.len())))?;
+        let kind = header[0];
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let offset = u64::from_be_bytes(header[5..13].try_into().unwrap());
+        let length = u32::from_be_bytes([header[13], header[14], header[15], header[16]]);
+        let path_length = u16::from_be_bytes([header[17], header[18]]) as usize;
+        let data_length = if kind == TRANSFER_PUT { length as usize } else { 0 };
+        let size = REQUEST_HEADER_SIZE + path_length + data_length;
+        if data.len() < size {
+            return Err(CmioError::ProtocolError(format!("transfer request {} of {} bytes cut short at {}", id, size, data.len())));
+        }
+        let path = data[REQUEST_HEADER_SIZE..REQUEST_HEADER_SIZE + path_length].to_vec();
+        let data = data[REQUEST_HEADER_SIZE + path_length..size].to_vec();
+        Ok((Self { kind, id, offset, length, path, data }, size))
+    }
+    
+    /// Serialize the request as the host sends it
+    #[cfg(all(test, not(target_arch = "riscv64")))]
+    fn encode(&self) -> Vec<u8> {
+        let mut request = vec![self.kind];
+        request.extend_from_slice(&self.id.to_be_bytes());
+        request.extend_from_slice(&self.offset.to_be_bytes());
+        request.extend_from_slice(&self.length.to_be_bytes());
+        request.extend_from_slice(&(self.path.len() as u16).to_be_bytes());
+        request.extend_from_slice(&self.path);
+        request.extend_from_slice(&self.data);
+        request
+    }
+}
+
+// Serialize a response to a request
+fn encode_response(kind: u8, id: u32, status: TransferStatus, errno: i32, flags: u8, data: &[u8]) -> Vec<u8> {
+    let mut response = Vec::with_capacity(RESPONSE_HEADER_SIZE + data.len());
+    response.push(kind);
+    response.extend_from_slice(&id.to_be_bytes());
+    response.push(status as u8);
+    response.extend_from_slice(&errno.to_be_bytes());
+    response.push(flags);
+    response.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    response.extend_from_slice(data);
+    response
+}
+
+// Kind of a path from its metadata, without following a symlink
+fn kind(metadata: &fs::Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        KIND_SYMLINK
+    } else if file_type.is_dir() {
+        KIND_DIRECTORY
+    } else if file_type.is_file() {
+        KIND_FILE
+    } else {
+        KIND_OTHER
+    }
+}
+
+// Structure serving the host's file transfer requests, as a subsystem of the multiplexer
+// on TRANSFER_REASON
+//
+// The host batches requests back to back, each a type, request ID, offset, length and
+// path, followed by the data for put. Responses are queued and sent back to back on the
+// following yields, with the request's type and ID, a status, errno, flags and data.
+// Files larger than a response are moved in chunks: the host gets or puts at increasing
+// offsets, and a get response carries TRANSFER_FLAG_END once it reaches the end of the
+// file. A put at offset 0 creates or truncates the file. A listing is likewise continued
+// from the index of the next entry until TRANSFER_FLAG_END.
+//
+// Paths are relative to the root the transfer was created with, and may not climb out of
+// it with `..` or through symlinks pointing outside it.
+pub struct FileTransfer {
+    root: PathBuf,
+    responses: VecDeque<Vec<u8>>,
+    max_message_size: usize,
+}
+
+impl FileTransfer {
+    /// Serve the files under root, sizing chunks to fit the CMIO buffer
+    pub fn new(root: impl AsRef<Path>, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        let root = root.as_ref();
+        let root = root.canonicalize()
+            .map_err(|e| CmioError::io(format!("open transfer root {}", root.display()), e))?;
+        Ok(Self {
+            root,
+            responses: VecDeque::new(),
+            max_message_size: cmio_max_buffer_size,
+        })
+    }
+    
+    /// The location of a request's path under the root, None if it isn't a relative
+    /// UTF-8 path staying inside it
+    /// 
+    /// Symlinks among the parent directories are resolved and must stay under the root,
+    /// as must the last component's target when it is a symlink to follow. A dangling one
+    /// is refused, since a put would create its target wherever it points. A missing
+    /// parent directory leaves the path as is, for the request to fail with NotFound.
+    fn resolve(&self, path: &[u8], follow: bool) -> Option<PathBuf> {
+        let path = Path::new(std::str::from_utf8(path).ok()?);
+        let mut resolved = self.root.clone();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::CurDir => {}
+                _ => return None,
+            }
+        }
+        let Some(name) = resolved.file_name().filter(|_| resolved != self.root) else {
+            return Some(resolved);
+        };
+        
+        let parent = match resolved.parent()?.canonicalize() {
+            Ok(parent) => parent,
+            Err(_) => return Some(resolved),
+        };
+        let resolved = parent.join(name);
+        if !parent.starts_with(&self.root) {
+            return None;
+        }
+        let is_symlink = fs::symlink_metadata(&resolved).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        if follow && is_symlink && !resolved.canonicalize().is_ok_and(|target| target.starts_with(&self.root)) {
+            return None;
+        }
+        Some(resolved)
+    }
+    
+    /// Answer a request, returning the response
+    fn handle(&self, request: &Request) -> Vec<u8> {
+        // Only a stat describes a symlink itself rather than where it points
+        let Some(path) = self.resolve(&request.path, request.kind != TRANSFER_STAT) else {
+            return encode_response(request.kind, request.id, TransferStatus::InvalidPath, libc::EINVAL, 0, &[]);
+        };
+        let result = match request.kind {
+            TRANSFER_GET => self.get(&path, request.offset, request.length),
+            TRANSFER_PUT => Self::put(&path, request.offset, &request.data),
+            TRANSFER_STAT => Self::stat(&path),
+            TRANSFER_LIST => self.list(&path, request.offset),
+            kind => {
+                warn!("Unknown transfer request type {:#04x}", kind);
+                return encode_response(kind, request.id, TransferStatus::InvalidMessage, libc::EINVAL, 0, &[]);
+            }
+        };
+        match result {
+            Ok((flags, data)) => encode_response(request.kind, request.id, TransferStatus::Success, 0, flags, &data),
+            Err(e) => {
+                debug!("Transfer request {} for {} failed: {}", request.id, path.display(), e);
+                encode_response(request.kind, request.id, TransferStatus::from_error(&e), e.raw_os_error().unwrap_or(0), 0, &[])
+            }
+        }
+    }
+    
+    /// Read a chunk of a file, as much as asked for and fits a response
+    fn get(&self, path: &Path, offset: u64, length: u32) -> io::Result<(u8, Vec<u8>)> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let length = (length as usize).min(self.max_message_size.saturating_sub(RESPONSE_HEADER_SIZE));
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(length);
+        file.take(length as u64).read_to_end(&mut data)?;
+        let flags = if offset + data.len() as u64 >= size { TRANSFER_FLAG_END } else { 0 };
+        Ok((flags, data))
+    }
+    
+    /// Write a chunk of a file, creating or truncating it at offset 0
+    fn put(path: &Path, offset: u64, data: &[u8]) -> io::Result<(u8, Vec<u8>)> {
+        let mut file = OpenOptions::new().write(true).create(offset == 0).truncate(offset == 0).open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        Ok((0, Vec::new()))
+    }
+    
+    /// Describe a path without following a symlink
+    fn stat(path: &Path) -> io::Result<(u8, Vec<u8>)> {
+        let metadata = fs::symlink_metadata(path)?;
+        let mut data = Vec::with_capacity(STAT_SIZE);
+        data.push(kind(&metadata));
+        data.extend_from_slice(&metadata.permissions().mode().to_be_bytes());
+        data.extend_from_slice(&metadata.len().to_be_bytes());
+        data.extend_from_slice(&metadata.mtime().to_be_bytes());
+        Ok((0, data))
+    }
+    
+    /// List a directory's entries in name order from the given index, as many as fit a
+    /// response
+    fn list(&self, path: &Path, first: u64) -> io::Result<(u8, Vec<u8>)> {
+        let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        let limit = self.max_message_size.saturating_sub(RESPONSE_HEADER_SIZE);
+        let mut data = Vec::new();
+        for entry in entries.iter().skip(first as usize) {
+            let name = entry.file_name();
+            let name = name.as_encoded_bytes();
+            if data.len() + ENTRY_HEADER_SIZE + name.len() > limit {
+                return Ok((0, data));
+            }
+            let metadata = entry.metadata()?;
+            data.push(kind(&metadata));
+            data.extend_from_slice(&metadata.len().to_be_bytes());
+            data.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            data.extend_from_slice(name);
+        }
+        Ok((TRANSFER_FLAG_END, data))
+    }
+}
+
+impl Subsystem for FileTransfer {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        while let Some(response) = self.responses.front() {
+            if buffer.len() + response.len() > max_len {
+                break;
+            }
+            buffer.extend_from_slice(response);
+            self.responses.pop_front();
+        }
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let (request, size) = match Request::decode(&data[offset..]) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    // Answered if the type and ID came through, the rest of the batch
+                    // can't be framed past it either way
+                    warn!("Dropping {} bytes of transfer requests: {}", data.len() - offset, e);
+                    if let Some(header) = data.get(offset..offset + 5) {
+                        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+                        self.responses.push_back(encode_response(header[0], id, TransferStatus::InvalidMessage, libc::EINVAL, 0, &[]));
+                    }
+                    break;
+                }
+            };
+            let response = self.handle(&request);
+            self.responses.push_back(response);
+            offset += size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    fn request(kind: u8, id: u32, offset: u64, length: u32, path: &str, data: &[u8]) -> Vec<u8> {
+        Request { kind, id, offset, length, path: path.as_bytes().to_vec(), data: data.to_vec() }.encode()
+    }
+
+    // Send a batch of requests and split what comes back into (id, status, flags, data)
+    fn exchange(transfer: &mut FileTransfer, batch: &[u8]) -> Vec<(u32, u8, u8, Vec<u8>)> {
+        transfer.handle_rx(batch, TRANSFER_REASON.code()).unwrap();
+        let mut buffer = Vec::new();
+        transfer.poll_tx(&mut buffer, 4096).unwrap();
+        let mut responses = Vec::new();
+        let mut rest = buffer.as_slice();
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes([rest[11], rest[12], rest[13], rest[14]]) as usize;
+            let id = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]);
+            responses.push((id, rest[5], rest[10], rest[RESPONSE_HEADER_SIZE..RESPONSE_HEADER_SIZE + length].to_vec()));
+            rest = &rest[RESPONSE_HEADER_SIZE + length..];
+        }
+        responses
+    }
+
+    #[test]
+    fn test_file_transfer() {
+        let root = std::env::temp_dir().join(format!("tapcmio-transfer-{}", std::process::id()));
+        fs::create_dir_all(root.join("results")).unwrap();
+        let mut transfer = FileTransfer::new(&root, 64).unwrap();
+
+        // A file put in two chunks is read back in chunks the size of a response
+        let contents: Vec<u8> = (0..100).collect();
+        let mut batch = request(TRANSFER_PUT, 1, 0, 60, "input.bin", &contents[..60]);
+        batch.extend(request(TRANSFER_PUT, 2, 60, 40, "./input.bin", &contents[60..]));
+        assert_eq!(exchange(&mut transfer, &batch), vec![(1, 0, 0, vec![]), (2, 0, 0, vec![])]);
+        let mut read = Vec::new();
+        loop {
+            let responses = exchange(&mut transfer, &request(TRANSFER_GET, 3, read.len() as u64, 1024, "input.bin", &[]));
+            assert_eq!(responses[0].3.len(), (100 - read.len()).min(64 - RESPONSE_HEADER_SIZE));
+            read.extend_from_slice(&responses[0].3);
+            if responses[0].2 & TRANSFER_FLAG_END != 0 {
+                break;
+            }
+        }
+        assert_eq!(read, contents);
+
+        // Stat and listings describe files and directories, continued from an index
+        let stat = &exchange(&mut transfer, &request(TRANSFER_STAT, 4, 0, 0, "input.bin", &[]))[0].3;
+        assert_eq!((stat.len(), stat[0], &stat[5..13]), (STAT_SIZE, KIND_FILE, &100u64.to_be_bytes()[..]));
+        let listing = &exchange(&mut transfer, &request(TRANSFER_LIST, 5, 0, 0, "", &[]))[0];
+        assert_eq!((listing.2, listing.3[0], &listing.3[11..20]), (TRANSFER_FLAG_END, KIND_FILE, &b"input.bin"[..]));
+        let listing = &exchange(&mut transfer, &request(TRANSFER_LIST, 6, 1, 0, "", &[]))[0];
+        assert_eq!((listing.3[0], &listing.3[11..]), (KIND_DIRECTORY, &b"results"[..]));
+
+        // Missing files and paths leaving the root fail
+        assert_eq!(exchange(&mut transfer, &request(TRANSFER_GET, 7, 0, 10, "missing", &[]))[0].1, TransferStatus::NotFound as u8);
+        assert_eq!(exchange(&mut transfer, &request(TRANSFER_GET, 8, 0, 10, "../etc/passwd", &[]))[0].1, TransferStatus::InvalidPath as u8);
+        assert_eq!(exchange(&mut transfer, &request(TRANSFER_STAT, 9, 0, 0, "/etc", &[]))[0].1, TransferStatus::InvalidPath as u8);
+        
+        // A request cut short is answered as invalid, and the rest of its batch dropped
+        assert_eq!(exchange(&mut transfer, &batch[..30]), vec![(1, TransferStatus::InvalidMessage as u8, 0, vec![])]);
+        assert!(exchange(&mut transfer, &batch[..4]).is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_symlinks_outside_root() {
+        let base = std::env::temp_dir().join(format!("tapcmio-transfer-links-{}", std::process::id()));
+        let (root, outside) = (base.join("root"), base.join("outside"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), b"secret").unwrap();
+        fs::write(root.join("inside"), b"inside").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("out")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), root.join("secret")).unwrap();
+        std::os::unix::fs::symlink(outside.join("created"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink("inside", root.join("alias")).unwrap();
+        let mut transfer = FileTransfer::new(&root, 64).unwrap();
+        let status = |transfer: &mut FileTransfer, kind: u8, path: &str, data: &[u8]| exchange(transfer, &request(kind, 1, 0, if data.is_empty() { 10 } else { data.len() as u32 }, path, data))[0].1;
+
+        // Neither directories nor files behind links pointing outside can be reached
+        assert_eq!(status(&mut transfer, TRANSFER_GET, "out/secret", &[]), TransferStatus::InvalidPath as u8);
+        assert_eq!(status(&mut transfer, TRANSFER_LIST, "out", &[]), TransferStatus::InvalidPath as u8);
+        assert_eq!(status(&mut transfer, TRANSFER_GET, "secret", &[]), TransferStatus::InvalidPath as u8);
+        assert_eq!(status(&mut transfer, TRANSFER_PUT, "secret", b"changed"), TransferStatus::InvalidPath as u8);
+        assert_eq!(status(&mut transfer, TRANSFER_PUT, "dangling", b"created"), TransferStatus::InvalidPath as u8);
+        assert_eq!(fs::read(outside.join("secret")).unwrap(), b"secret");
+        assert!(!outside.join("created").exists());
+
+        // Links staying inside are followed, and a stat describes the link itself
+        assert_eq!(exchange(&mut transfer, &request(TRANSFER_GET, 2, 0, 10, "alias", &[]))[0].3, b"inside");
+        assert_eq!(exchange(&mut transfer, &request(TRANSFER_STAT, 3, 0, 0, "out", &[]))[0].3[0], KIND_SYMLINK);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -1,42 +1,359 @@
 use std::io::{self, Read, Write};
-use std::os::unix::net::UnixStream;
+use std::ffi::OsStr;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{SocketAddr as UnixSocketAddr, UnixDatagram, UnixListener, UnixStream};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
-use std::net::TcpStream;
-use crate::cmio::{Cmio, CmioError};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs};
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+use nix::sys::socket::{setsockopt, sockopt};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::time::{clock_gettime, ClockId};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use rustls::pki_types::ServerName;
+use tracing::{info, warn};
+use crate::cmio::{Cmio, CmioError, CmioHandle};
+use crate::http_proxy::HttpProxy;
+use crate::idle::{IdleState, IdleStrategy, IdleWait};
+use crate::multiplexer::Subsystem;
+use crate::protocol::{YieldCommand, YieldDevice, YieldReason};
+use crate::shutdown;
+use crate::socks5::{Socks5Proxy, Socks5Target};
+use crate::status;
+#[cfg(feature = "bincode-codec")]
+use bincode::Options;
 
-// HTIF yield constants
-const HTIF_DEVICE_YIELD: u8 = 0x02;
-const HTIF_YIELD_CMD_MANUAL: u8 = 0x01;
-const UNIX_SOCKET_CMD: u16 = 0x43;
+// Flag set by the host in the response reason when the RX batch ends in a partial
+// message that continues in the next yield
+pub(crate) const RX_FLAG_CONTINUED: u16 = 0x8000;
+
+// Upper bound on a batch stitched together from consecutive yields
+const MAX_REASSEMBLY_SIZE: usize = 16 * 1024 * 1024;
 
 // Message types
-const MSG_TYPE_UNIX_CONNECT: u8 = 0x01;
-const MSG_TYPE_UNIX_SEND: u8 = 0x02;
-const MSG_TYPE_UNIX_RECEIVE: u8 = 0x03;
-const MSG_TYPE_UNIX_CLOSE: u8 = 0x04;
-const MSG_TYPE_TCP_CONNECT: u8 = 0x05;
-const MSG_TYPE_TCP_SEND: u8 = 0x06;
-const MSG_TYPE_TCP_RECEIVE: u8 = 0x07;
-const MSG_TYPE_TCP_CLOSE: u8 = 0x08;
+pub(crate) const MSG_TYPE_UNIX_CONNECT: u8 = 0x01;
+pub(crate) const MSG_TYPE_UNIX_SEND: u8 = 0x02;
+pub(crate) const MSG_TYPE_UNIX_RECEIVE: u8 = 0x03;
+pub(crate) const MSG_TYPE_UNIX_CLOSE: u8 = 0x04;
+pub(crate) const MSG_TYPE_TCP_CONNECT: u8 = 0x05;
+pub(crate) const MSG_TYPE_TCP_SEND: u8 = 0x06;
+pub(crate) const MSG_TYPE_TCP_RECEIVE: u8 = 0x07;
+pub(crate) const MSG_TYPE_TCP_CLOSE: u8 = 0x08;
+const MSG_TYPE_UNIX_LISTEN: u8 = 0x09;
+const MSG_TYPE_UNIX_ACCEPT: u8 = 0x0A;
+pub(crate) const MSG_TYPE_TCP_CONNECT_HOST: u8 = 0x0B;
+const MSG_TYPE_TLS_CONNECT: u8 = 0x0C;
+const MSG_TYPE_SET_OPTION: u8 = 0x0D;
+const MSG_TYPE_SHUTDOWN: u8 = 0x0E;
+const MSG_TYPE_RECEIVE_ALL: u8 = 0x0F;
+const MSG_TYPE_LIST_CONNECTIONS: u8 = 0x10;
+const MSG_TYPE_STATS: u8 = 0x11;
+const MSG_TYPE_UNIX_DGRAM_BIND: u8 = 0x12;
+const MSG_TYPE_UNIX_DGRAM_SEND_TO: u8 = 0x13;
+const MSG_TYPE_UNIX_DGRAM_RECEIVE: u8 = 0x14;
+const MSG_TYPE_RESOLVE: u8 = 0x15;
+const MSG_TYPE_SEND_CHUNK: u8 = 0x16;
+const MSG_TYPE_SEND_END: u8 = 0x17;
+const MSG_TYPE_HELLO: u8 = 0x18;
+const MSG_TYPE_PING: u8 = 0x19;
+// Register (port 0: unregister) a guest-local address the host may open streams to
+const MSG_TYPE_FORWARD_REGISTER: u8 = 0x1A;
+// Open a TCP stream to a registered forward, using the TCP send, receive and close messages
+const MSG_TYPE_FORWARD_OPEN: u8 = 0x1B;
+
+// Codecs selectable with MSG_TYPE_HELLO, each followed by its version in the request data
+const CODEC_NATIVE: u8 = 0x00;
+#[cfg(feature = "bincode-codec")]
+const CODEC_BINCODE: u8 = 0x01;
+const NATIVE_CODEC_VERSION: u8 = 1;
+#[cfg(feature = "bincode-codec")]
+const BINCODE_CODEC_VERSION: u8 = 1;
+
+// Size of the offset and total length heading MSG_TYPE_SEND_CHUNK data
+const CHUNK_HEADER_SIZE: usize = 8;
+
+// Request data for MSG_TYPE_STATS asking for every socket instead of the given socket ID
+const STATS_SCOPE_ALL: u8 = 0x01;
+
+// Connection kinds reported by MSG_TYPE_LIST_CONNECTIONS
+const CONNECTION_KIND_UNIX: u8 = 0x00;
+const CONNECTION_KIND_UNIX_LISTENER: u8 = 0x01;
+const CONNECTION_KIND_TCP: u8 = 0x02;
+const CONNECTION_KIND_TLS: u8 = 0x03;
+const CONNECTION_KIND_UNIX_DGRAM: u8 = 0x04;
+
+// Connection states reported by MSG_TYPE_LIST_CONNECTIONS
+const CONNECTION_STATE_CONNECTED: u8 = 0x00;
+const CONNECTION_STATE_LISTENING: u8 = 0x01;
+// The peer closed the stream or it failed; the socket ID stays registered until closed
+const CONNECTION_STATE_ENDED: u8 = 0x02;
+
+// Directions for MSG_TYPE_SHUTDOWN (same values as SHUT_RD/SHUT_WR/SHUT_RDWR)
+const SHUTDOWN_READ: u8 = 0x00;
+const SHUTDOWN_WRITE: u8 = 0x01;
+const SHUTDOWN_BOTH: u8 = 0x02;
+
+// Socket options configurable with MSG_TYPE_SET_OPTION
+const SOCKET_OPTION_TCP_NODELAY: u8 = 0x01;
+const SOCKET_OPTION_KEEPALIVE: u8 = 0x02;
+const SOCKET_OPTION_RECV_BUFFER_SIZE: u8 = 0x03;
+const SOCKET_OPTION_SEND_BUFFER_SIZE: u8 = 0x04;
+const SOCKET_OPTION_CONNECT_TIMEOUT: u8 = 0x05;
+const SOCKET_OPTION_READ_TIMEOUT: u8 = 0x06;
+const SOCKET_OPTION_IDLE_TIMEOUT: u8 = 0x07;
+
+// Flag in the message type byte marking a chunk that is continued by the next message
+// of the same type and socket ID
+const MSG_FLAG_MORE: u8 = 0x80;
+
+// Status carried in the first byte of every response, followed by the raw errno
+// (i32, network byte order) and, for receive and accept responses, the payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SocketStatus {
+    Success = 0x00,
+    NotFound = 0x01, // Unknown socket ID
+    InvalidArgument = 0x02,
+    ConnectionRefused = 0x03,
+    ConnectionReset = 0x04,
+    TimedOut = 0x05,
+    Eof = 0x06,
+    WouldBlock = 0x07, // No data or connection available yet
+    Unreachable = 0x08,
+    AddressInUse = 0x09,
+    PermissionDenied = 0x0A,
+    PathNotFound = 0x0B,
+    ProtocolError = 0x0C,
+    InvalidMessage = 0x0D,
+    TooManyConnections = 0x0E, // Connection limit reached or out of file descriptors
+    NameNotResolved = 0x0F, // Hostname lookup failed
+    Queued = 0x10, // Send accepted, with the number of bytes still waiting in the write queue as payload
+    SocketIdInUse = 0x11, // The requested socket ID is already registered
+    Other = 0xFF,
+}
+
+impl SocketStatus {
+    /// The status of a response's first byte, Other for codes this build doesn't know
+    #[cfg(all(feature = "host", not(target_arch = "riscv64")))]
+    pub(crate) fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => SocketStatus::Success,
+            0x01 => SocketStatus::NotFound,
+            0x02 => SocketStatus::InvalidArgument,
+            0x03 => SocketStatus::ConnectionRefused,
+            0x04 => SocketStatus::ConnectionReset,
+            0x05 => SocketStatus::TimedOut,
+            0x06 => SocketStatus::Eof,
+            0x07 => SocketStatus::WouldBlock,
+            0x08 => SocketStatus::Unreachable,
+            0x09 => SocketStatus::AddressInUse,
+            0x0A => SocketStatus::PermissionDenied,
+            0x0B => SocketStatus::PathNotFound,
+            0x0C => SocketStatus::ProtocolError,
+            0x0D => SocketStatus::InvalidMessage,
+            0x0E => SocketStatus::TooManyConnections,
+            0x0F => SocketStatus::NameNotResolved,
+            0x10 => SocketStatus::Queued,
+            0x11 => SocketStatus::SocketIdInUse,
+            _ => SocketStatus::Other,
+        }
+    }
+    
+    fn from_errno(errno: i32) -> Self {
+        match errno {
+            0 => SocketStatus::Success,
+            libc::ECONNREFUSED => SocketStatus::ConnectionRefused,
+            libc::ECONNRESET | libc::ECONNABORTED | libc::EPIPE => SocketStatus::ConnectionReset,
+            libc::ETIMEDOUT => SocketStatus::TimedOut,
+            libc::EAGAIN => SocketStatus::WouldBlock,
+            libc::EHOSTUNREACH | libc::ENETUNREACH | libc::EADDRNOTAVAIL => SocketStatus::Unreachable,
+            libc::EADDRINUSE => SocketStatus::AddressInUse,
+            libc::EACCES | libc::EPERM => SocketStatus::PermissionDenied,
+            libc::ENOENT => SocketStatus::PathNotFound,
+            libc::EINVAL => SocketStatus::InvalidArgument,
+            libc::EPROTO => SocketStatus::ProtocolError,
+            libc::EMFILE | libc::ENFILE => SocketStatus::TooManyConnections,
+            libc::EEXIST => SocketStatus::SocketIdInUse,
+            _ => SocketStatus::Other,
+        }
+    }
+}
+
+// Response data for a status without payload
+pub fn status_data(status: SocketStatus, errno: i32) -> Vec<u8> {
+    let mut data = vec![status as u8];
+    data.extend_from_slice(&errno.to_be_bytes());
+    data
+}
+
+// Response data for a status followed by a payload
+pub fn status_payload(status: SocketStatus, payload: &[u8]) -> Vec<u8> {
+    let mut data = status_data(status, 0);
+    data.extend_from_slice(payload);
+    data
+}
+
+// Error for a message cut short while reading the named field at the offset
+fn truncated(field: &str, offset: usize) -> CmioError {
+    CmioError::ProtocolError(format!("message truncated at offset {} reading the {}", offset, field))
+}
+
+// Error for a request whose data is shorter than its type requires
+fn short_data(message: &SocketMessage, needed: usize) -> CmioError {
+    CmioError::ProtocolError(format!(
+        "message type {:#04x} for socket {} carries {} bytes of data, expected at least {}",
+        message.msg_type, message.socket_id, message.data.len(), needed,
+    ))
+}
+
+// Printable form of a socket path for error messages
+fn display_path(path: &[u8]) -> String {
+    match path.first() {
+        Some(0) => format!("@{}", String::from_utf8_lossy(&path[1..])),
+        _ => String::from_utf8_lossy(path).into_owned(),
+    }
+}
+
+// Number of bytes to read for a receive request
+//
+// The request data optionally carries the maximum read size (u32 BE). Without it, or when
+// it is zero, MAX_READ_SIZE is used. The size is capped so the response fits in `limit`.
+fn requested_read_size(data: &[u8], limit: usize) -> usize {
+    let requested = match data.get(..4) {
+        Some(bytes) => u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize,
+        None => 0,
+    };
+    let requested = if requested == 0 { MAX_READ_SIZE } else { requested };
+    requested.min(limit.saturating_sub(DATA_HEADER_SIZE + STATUS_SIZE)).max(1)
+}
+
+// Address families used in address-bearing messages
+const ADDR_FAMILY_IPV4: u8 = 0x04;
+const ADDR_FAMILY_IPV6: u8 = 0x06;
+
+// Readiness token kinds, stored in the low bits of the mio token next to the socket ID
+const TOKEN_KIND_UNIX: usize = 0;
+const TOKEN_KIND_TCP: usize = 1;
+const TOKEN_KIND_UNIX_DGRAM: usize = 2;
+const TOKEN_KIND_UNIX_LISTENER: usize = 3;
+const TOKEN_KIND_BITS: usize = 2;
+
+// Size of a data-bearing message without its data: 1 (type) + 4 (socket_id) + 4 (data length)
+const DATA_HEADER_SIZE: usize = 9;
+
+// Default maximum number of open connections and listeners
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+// Maximum number of bytes read from a single socket per readiness pass, and the default
+// for receive requests that don't ask for a size
+const MAX_READ_SIZE: usize = 4096;
+
+// Time a TLS handshake may take when no connect timeout is configured for the socket
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Maximum size of a datagram forwarded from a readable datagram socket
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+// Maximum number of bytes waiting in a connection's write queue before sends are refused
+const MAX_WRITE_QUEUE_SIZE: usize = 4 * 1024 * 1024;
+
+// Size of the status and errno at the start of every response's data
+pub(crate) const STATUS_SIZE: usize = 5;
 
 // Maximum path length for Unix domain socket
 const MAX_PATH_LENGTH: usize = 108;
 
+// Wire encoding of socket messages, negotiated with MSG_TYPE_HELLO
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    // The hand-rolled network byte order format described in the README
+    Native,
+    // Serde-generated bincode encoding of SocketMessage (fixed-size little-endian integers)
+    #[cfg(feature = "bincode-codec")]
+    Bincode,
+}
+
+impl Codec {
+    // The codec for a MSG_TYPE_HELLO request, if it is built in and the version matches
+    fn negotiate(codec: u8, version: u8) -> Option<Self> {
+        match (codec, version) {
+            (CODEC_NATIVE, NATIVE_CODEC_VERSION) => Some(Codec::Native),
+            #[cfg(feature = "bincode-codec")]
+            (CODEC_BINCODE, BINCODE_CODEC_VERSION) => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+}
+
 // Structure for socket messages
 #[derive(Debug, Clone)]
-struct SocketMessage {
-    msg_type: u8,
-    socket_id: u32,
-    path: String,
-    ip_addr: [u8; 4],
-    port: u16,
-    data: Vec<u8>,
+#[cfg_attr(feature = "bincode-codec", derive(serde::Serialize, serde::Deserialize))]
+pub struct SocketMessage {
+    pub msg_type: u8,
+    pub socket_id: u32,
+    // Unix socket path, or hostname for TCP connect-by-hostname and TLS connect messages
+    pub path: Vec<u8>,
+    pub ip_addr: IpAddr,
+    pub port: u16,
+    pub data: Vec<u8>,
+    // More data for this message follows in a continuation message
+    pub(crate) more: bool,
+}
+
+// Append an address family byte followed by the 4 or 16 byte address
+fn write_ip_addr(buffer: &mut Vec<u8>, ip_addr: &IpAddr) {
+    match ip_addr {
+        IpAddr::V4(ip) => {
+            buffer.push(ADDR_FAMILY_IPV4);
+            buffer.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            buffer.push(ADDR_FAMILY_IPV6);
+            buffer.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+// Read an address family byte followed by the 4 or 16 byte address, advancing the offset
+fn read_ip_addr(data: &[u8], offset: &mut usize) -> Result<IpAddr, CmioError> {
+    if data.len() < *offset + 1 {
+        return Err(truncated("address family", *offset));
+    }
+    
+    let family = data[*offset];
+    *offset += 1;
+    
+    match family {
+        ADDR_FAMILY_IPV4 => {
+            if data.len() < *offset + 4 {
+                return Err(truncated("IPv4 address", *offset));
+            }
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&data[*offset..*offset + 4]);
+            *offset += 4;
+            Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+        },
+        ADDR_FAMILY_IPV6 => {
+            if data.len() < *offset + 16 {
+                return Err(truncated("IPv6 address", *offset));
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&data[*offset..*offset + 16]);
+            *offset += 16;
+            Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        _ => Err(CmioError::ProtocolError(format!("unknown address family {}", family))),
+    }
 }
 
 impl SocketMessage {
-    fn new(msg_type: u8, socket_id: u32, path: String, ip_addr: [u8; 4], port: u16, data: Vec<u8>) -> Self {
+    pub fn new(msg_type: u8, socket_id: u32, path: Vec<u8>, ip_addr: IpAddr, port: u16, data: Vec<u8>) -> Self {
         Self {
             msg_type,
             socket_id,
@@ -44,391 +361,2531 @@ impl SocketMessage {
             ip_addr,
             port,
             data,
+            more: false,
         }
     }
-
-    fn serialize(&self) -> Vec<u8> {
+    
+    // Whether the message can be chunked; connect-style messages carry addressing and are never large
+    fn is_chunkable(&self) -> bool {
+        !matches!(
+            self.msg_type,
+            MSG_TYPE_UNIX_CONNECT | MSG_TYPE_UNIX_LISTEN | MSG_TYPE_TCP_CONNECT | MSG_TYPE_TCP_CONNECT_HOST | MSG_TYPE_TLS_CONNECT
+                | MSG_TYPE_FORWARD_REGISTER | MSG_TYPE_UNIX_DGRAM_BIND | MSG_TYPE_UNIX_DGRAM_SEND_TO | MSG_TYPE_RESOLVE
+        )
+    }
+    
+    // Split off the first `len` bytes of data into a chunk flagged as continued
+    fn split_chunk(mut self, len: usize) -> (Self, Self) {
+        let rest = self.data.split_off(len);
+        let chunk = Self {
+            more: true,
+            ..self.clone()
+        };
+        self.data = rest;
+        (chunk, self)
+    }
+    
+    /// Serialize the message in the native codec
+    /// 
+    /// Fails with InvalidArgument if the path or hostname doesn't fit its one-byte length.
+    pub(crate) fn serialize(&self) -> Result<Vec<u8>, CmioError> {
         let mut buffer = Vec::new();
         
-        // Add message type
-        buffer.push(self.msg_type);
+        // Add message type, with the continuation flag if more data follows
+        buffer.push(if self.more { self.msg_type | MSG_FLAG_MORE } else { self.msg_type });
         
         // Add socket ID (4 bytes, network byte order)
         buffer.extend_from_slice(&self.socket_id.to_be_bytes());
         
-        // Only include connection info for connect messages, and only the relevant info
+        // Only include connection info for connect-style messages, and only the relevant info
         match self.msg_type {
-            MSG_TYPE_UNIX_CONNECT => {
+            MSG_TYPE_UNIX_CONNECT | MSG_TYPE_UNIX_LISTEN | MSG_TYPE_UNIX_DGRAM_BIND | MSG_TYPE_UNIX_DGRAM_SEND_TO | MSG_TYPE_RESOLVE => {
                 // Add path length (as u8)
-                buffer.push(self.path.len() as u8);
+                buffer.push(self.name_length()?);
                 // Add path
-                buffer.extend_from_slice(self.path.as_bytes());
+                buffer.extend_from_slice(&self.path);
             },
-            MSG_TYPE_TCP_CONNECT => {
-                // Add IP address (4 bytes)
-                buffer.extend_from_slice(&self.ip_addr);
+            MSG_TYPE_TCP_CONNECT | MSG_TYPE_FORWARD_REGISTER => {
+                // Add address family and IP address (4 or 16 bytes)
+                write_ip_addr(&mut buffer, &self.ip_addr);
                 // Add port (2 bytes, network byte order)
                 buffer.extend_from_slice(&self.port.to_be_bytes());
             },
-            _ => {
-                // For non-connect messages, add data length and data
-                let data_len = self.data.len() as u32;
-                buffer.extend_from_slice(&data_len.to_be_bytes());
-                buffer.extend_from_slice(&self.data);
-            }
+            MSG_TYPE_TCP_CONNECT_HOST | MSG_TYPE_TLS_CONNECT => {
+                // Add hostname length (as u8)
+                buffer.push(self.name_length()?);
+                // Add hostname
+                buffer.extend_from_slice(&self.path);
+                // Add port (2 bytes, network byte order)
+                buffer.extend_from_slice(&self.port.to_be_bytes());
+                // Add address family and resolved IP address (unspecified in requests)
+                write_ip_addr(&mut buffer, &self.ip_addr);
+            },
+            _ => {}
         }
         
-        buffer
+        // Every message ends with data length and data (the status in connect responses)
+        let data_len = self.data.len() as u32;
+        buffer.extend_from_slice(&data_len.to_be_bytes());
+        buffer.extend_from_slice(&self.data);
+        
+        Ok(buffer)
     }
-
-    fn deserialize(data: &[u8]) -> Result<Self, CmioError> {
+    
+    // Length of the path or hostname, which goes on the wire as a single byte
+    fn name_length(&self) -> Result<u8, CmioError> {
+        u8::try_from(self.path.len()).map_err(|_| CmioError::InvalidArgument(format!(
+            "name of {} bytes for socket {} is longer than {} bytes", self.path.len(), self.socket_id, u8::MAX)))
+    }
+    
+    /// Deserialize the message at the start of `data`
+    /// 
+    /// Returns the message together with the number of bytes it occupied, so that
+    /// callers can walk a batch of concatenated messages. Every length is checked against
+    /// the input, so malformed input fails with ProtocolError rather than panicking.
+    pub fn deserialize(data: &[u8]) -> Result<(Self, usize), CmioError> {
         if data.len() < 5 { // 1 (type) + 4 (socket_id)
-            return Err(CmioError::SetupError(-1)); // Invalid message format
+            return Err(truncated("message header", 0));
         }
         
-        let msg_type = data[0];
+        let msg_type = data[0] & !MSG_FLAG_MORE;
+        let more = data[0] & MSG_FLAG_MORE != 0;
         
         // Read socket ID (4 bytes, network byte order)
         let socket_id_bytes = [data[1], data[2], data[3], data[4]];
         let socket_id = u32::from_be_bytes(socket_id_bytes);
         
         let mut offset = 5;
-        let mut path = String::new();
-        let mut ip_addr = [0u8; 4];
+        let mut path = Vec::new();
+        let mut ip_addr = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
         let mut port = 0u16;
-        let mut message_data = Vec::new();
         
-        // Only read connection info for connect messages, and only the relevant info
+        // Only read connection info for connect-style messages, and only the relevant info
         match msg_type {
-            MSG_TYPE_UNIX_CONNECT => {
+            MSG_TYPE_UNIX_CONNECT | MSG_TYPE_UNIX_LISTEN | MSG_TYPE_UNIX_DGRAM_BIND | MSG_TYPE_UNIX_DGRAM_SEND_TO | MSG_TYPE_RESOLVE => {
                 if data.len() < offset + 1 {
-                    return Err(CmioError::SetupError(-1)); // Invalid message format
+                    return Err(truncated("path length", offset));
                 }
                 
                 let path_len = data[offset] as usize;
                 offset += 1;
                 
                 if data.len() < offset + path_len {
-                    return Err(CmioError::SetupError(-1)); // Invalid message format
+                    return Err(truncated("path", offset));
                 }
                 
-                let path_bytes = &data[offset..offset + path_len];
-                path = String::from_utf8(path_bytes.to_vec())
-                    .map_err(|_| CmioError::SetupError(-1))?;
+                // Raw bytes, a leading NUL selects the abstract namespace
+                path = data[offset..offset + path_len].to_vec();
                 offset += path_len;
             },
-            MSG_TYPE_TCP_CONNECT => {
-                if data.len() < offset + 6 { // 4 (ip) + 2 (port)
-                    return Err(CmioError::SetupError(-1)); // Invalid message format
-                }
+            MSG_TYPE_TCP_CONNECT | MSG_TYPE_FORWARD_REGISTER => {
+                // Read address family and IP address (4 or 16 bytes)
+                ip_addr = read_ip_addr(data, &mut offset)?;
                 
-                // Read IP address (4 bytes)
-                ip_addr = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
-                offset += 4;
+                if data.len() < offset + 2 {
+                    return Err(truncated("port", offset));
+                }
                 
                 // Read port (2 bytes, network byte order)
                 let port_bytes = [data[offset], data[offset + 1]];
                 port = u16::from_be_bytes(port_bytes);
                 offset += 2;
             },
-            _ => {
-                if data.len() < offset + 4 {
-                    return Err(CmioError::SetupError(-1)); // Invalid message format
+            MSG_TYPE_TCP_CONNECT_HOST | MSG_TYPE_TLS_CONNECT => {
+                if data.len() < offset + 1 {
+                    return Err(truncated("hostname length", offset));
                 }
                 
-                // Read data length (u32, network byte order)
-                let data_len_bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
-                let data_len = u32::from_be_bytes(data_len_bytes) as usize;
-                offset += 4;
+                let host_len = data[offset] as usize;
+                offset += 1;
                 
-                if data.len() < offset + data_len {
-                    return Err(CmioError::SetupError(-1)); // Invalid message format
+                if data.len() < offset + host_len + 2 { // hostname + 2 (port)
+                    return Err(truncated("hostname", offset));
                 }
                 
-                message_data = data[offset..offset + data_len].to_vec();
-            }
+                // Hostnames must be valid UTF-8
+                let host_bytes = &data[offset..offset + host_len];
+                std::str::from_utf8(host_bytes)
+                    .map_err(|_| CmioError::ProtocolError("hostname is not valid UTF-8".to_string()))?;
+                path = host_bytes.to_vec();
+                offset += host_len;
+                
+                // Read port (2 bytes, network byte order)
+                let port_bytes = [data[offset], data[offset + 1]];
+                port = u16::from_be_bytes(port_bytes);
+                offset += 2;
+                
+                // Read address family and resolved IP address
+                ip_addr = read_ip_addr(data, &mut offset)?;
+            },
+            _ => {}
         }
         
-        Ok(Self {
+        if data.len() < offset + 4 {
+            return Err(truncated("data length", offset));
+        }
+        
+        // Read data length (u32, network byte order)
+        let data_len_bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+        let data_len = u32::from_be_bytes(data_len_bytes) as usize;
+        offset += 4;
+        
+        if data.len() - offset < data_len {
+            return Err(truncated("data", offset));
+        }
+        
+        let message_data = data[offset..offset + data_len].to_vec();
+        offset += data_len;
+        
+        Ok((Self {
             msg_type,
             socket_id,
             path,
             ip_addr,
             port,
             data: message_data,
-        })
+            more,
+        }, offset))
+    }
+    
+    // Serialize the message with the given codec
+    fn encode(&self, codec: Codec) -> Result<Vec<u8>, CmioError> {
+        match codec {
+            Codec::Native => self.serialize(),
+            #[cfg(feature = "bincode-codec")]
+            Codec::Bincode => bincode_options().serialize(self)
+                .map_err(|e| CmioError::ProtocolError(format!("bincode encoding failed: {}", e))),
+        }
+    }
+    
+    /// Deserialize the message at the start of `data` with the given codec
+    /// 
+    /// Like deserialize, returns the message and the number of bytes it occupied.
+    fn decode(data: &[u8], codec: Codec) -> Result<(Self, usize), CmioError> {
+        match codec {
+            Codec::Native => Self::deserialize(data),
+            #[cfg(feature = "bincode-codec")]
+            Codec::Bincode => {
+                let mut reader = io::Cursor::new(data);
+                let message: Self = bincode_options().deserialize_from(&mut reader)
+                    .map_err(|e| CmioError::ProtocolError(format!("invalid bincode message: {}", e)))?;
+                
+                // Apply the checks the native format gets from its layout
+                if message.msg_type & MSG_FLAG_MORE != 0 {
+                    return Err(CmioError::ProtocolError(format!("message type {:#04x} overlaps the continuation flag", message.msg_type)));
+                }
+                if matches!(message.msg_type, MSG_TYPE_TCP_CONNECT_HOST | MSG_TYPE_TLS_CONNECT) {
+                    std::str::from_utf8(&message.path)
+                        .map_err(|_| CmioError::ProtocolError("hostname is not valid UTF-8".to_string()))?;
+                }
+                Ok((message, reader.position() as usize))
+            }
+        }
     }
 }
 
-// Structure to manage socket connections
-pub struct SocketManager {
-    cmio: Arc<Mutex<Cmio>>,
-    unix_connections: Arc<Mutex<HashMap<u32, (String, UnixStream)>>>,
-    tcp_connections: Arc<Mutex<HashMap<u32, (String, TcpStream)>>>,
-    cmio_max_buffer_size: usize,
+// Bincode configuration of the bincode codec, bounding allocations by the largest batch
+#[cfg(feature = "bincode-codec")]
+fn bincode_options() -> impl bincode::Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_REASSEMBLY_SIZE as u64)
+        .allow_trailing_bytes()
 }
 
-impl SocketManager {
-    pub fn new(cmio: Cmio, cmio_max_buffer_size: usize) -> Self {
-        Self {
-            cmio: Arc::new(Mutex::new(cmio)),
-            unix_connections: Arc::new(Mutex::new(HashMap::new())),
-            tcp_connections: Arc::new(Mutex::new(HashMap::new())),
-            cmio_max_buffer_size,
+// Connect to a TCP address, bounded by the connect timeout if one is configured
+fn connect_addr(addr: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+        None => TcpStream::connect(addr),
+    }
+}
+
+// The hostname carried in the path of connect-by-hostname messages
+fn host_name(path: &[u8]) -> Result<&str, CmioError> {
+    std::str::from_utf8(path).map_err(|_| CmioError::InvalidArgument(format!("hostname {} is not valid UTF-8", display_path(path))))
+}
+
+// Write as much of `data` as the socket accepts without blocking, returning the number of bytes written
+fn write_nonblocking<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        match writer.write(&data[written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
     }
+    Ok(written)
+}
+
+// Response data for a send, reporting any bytes left in the write queue
+fn send_status_data(pending: usize) -> Vec<u8> {
+    if pending == 0 {
+        status_data(SocketStatus::Success, 0)
+    } else {
+        status_payload(SocketStatus::Queued, &(pending as u32).to_be_bytes())
+    }
+}
+
+// Progress of a transfer streamed with MSG_TYPE_SEND_CHUNK
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ChunkedTransfer {
+    total: u32,
+    // Bytes accepted so far, which is the offset the next chunk must start at
+    received: u32,
+}
+
+// Check a chunk against the transfer in progress, starting one if there is none
+//
+// Returns the updated transfer (not yet counting the chunk) and how many leading bytes of
+// the chunk were already accepted, so retransmitted chunks are not written twice. Chunks
+// beyond the total length, with a different total, or leaving a gap are rejected.
+fn accept_chunk(transfer: Option<ChunkedTransfer>, offset: u32, total: u32, len: usize) -> Option<(ChunkedTransfer, usize)> {
+    if offset as u64 + len as u64 > total as u64 {
+        return None;
+    }
     
-    pub fn run_loop(&self) -> Result<(), CmioError> {
-        loop {
-            // Check for incoming messages
-            let (rx_data, _reason) = {
-                let mut cmio = self.cmio.lock().unwrap();
-                cmio.yield_with_buffer(
-                    HTIF_DEVICE_YIELD,
-                    HTIF_YIELD_CMD_MANUAL,
-                    UNIX_SOCKET_CMD,
-                    &[],
-                )?
-            };
-            
-            if !rx_data.is_empty() {
-                // Process the received data
-                self.process_received_data(&rx_data)?;
-            } else {
-                // No data to receive, yield to the scheduler
-                let mut cmio = self.cmio.lock().unwrap();
-                cmio.yield_with_buffer(
-                    HTIF_DEVICE_YIELD,
-                    HTIF_YIELD_CMD_MANUAL,
-                    UNIX_SOCKET_CMD,
-                    &[],
-                )?;
+    let transfer = match transfer {
+        Some(transfer) if transfer.total == total => transfer,
+        Some(_) => return None,
+        None if offset == 0 => ChunkedTransfer { total, received: 0 },
+        None => return None,
+    };
+    if offset > transfer.received {
+        return None;
+    }
+    
+    let skip = ((transfer.received - offset) as usize).min(len);
+    Some((transfer, skip))
+}
+
+// Response data acknowledging a chunked transfer up to the given offset
+fn chunk_ack_data(status: SocketStatus, errno: i32, received: u32) -> Vec<u8> {
+    let mut data = status_data(status, errno);
+    data.extend_from_slice(&received.to_be_bytes());
+    data
+}
+
+// Guest monotonic clock in nanoseconds, for MSG_TYPE_PING
+fn monotonic_nanos() -> u64 {
+    clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .map(|time| time.num_nanoseconds() as u64)
+        .unwrap_or(0)
+}
+
+// Resolve a hostname to all of its addresses, in resolver order without duplicates
+fn resolve_host(host: &str) -> io::Result<Vec<IpAddr>> {
+    let mut addrs = Vec::new();
+    for addr in (host, 0).to_socket_addrs()? {
+        if !addrs.contains(&addr.ip()) {
+            addrs.push(addr.ip());
+        }
+    }
+    Ok(addrs)
+}
+
+// Resolve a hostname via the guest resolver and connect to the first reachable address
+fn connect_host(host: &str, port: u16, timeout: Option<Duration>) -> Result<(IpAddr, TcpStream), CmioError> {
+    let addrs = (host, port).to_socket_addrs()
+        .map_err(|e| CmioError::io(format!("resolve {}", host), e))?;
+    
+    // Try each address until one connects
+    let mut last_error = None;
+    for addr in addrs {
+        match connect_addr(addr, timeout) {
+            Ok(stream) => return Ok((addr.ip(), stream)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    
+    let error = last_error.filter(|e| e.raw_os_error().is_some())
+        .unwrap_or_else(|| io::Error::from_raw_os_error(libc::EHOSTUNREACH));
+    Err(CmioError::io(format!("connect to {}:{}", host, port), error))
+}
+
+// Apply a socket option to a connected socket
+//
+// Returns InvalidArgument if the option does not apply to this kind of socket.
+fn apply_socket_option(fd: RawFd, option: u8, value: u32, is_tcp: bool) -> Result<SocketStatus, CmioError> {
+    let result = match option {
+        SOCKET_OPTION_TCP_NODELAY if is_tcp => setsockopt(fd, sockopt::TcpNoDelay, &(value != 0)),
+        SOCKET_OPTION_KEEPALIVE if is_tcp => setsockopt(fd, sockopt::KeepAlive, &(value != 0)),
+        SOCKET_OPTION_RECV_BUFFER_SIZE => setsockopt(fd, sockopt::RcvBuf, &(value as usize)),
+        SOCKET_OPTION_SEND_BUFFER_SIZE => setsockopt(fd, sockopt::SndBuf, &(value as usize)),
+        SOCKET_OPTION_READ_TIMEOUT => setsockopt(fd, sockopt::ReceiveTimeout, &TimeVal::milliseconds(value as i64)),
+        _ => return Ok(SocketStatus::InvalidArgument), // Error: Option not supported
+    };
+    
+    result.map_err(|e| CmioError::io(format!("set socket option {}", option), io::Error::from(e)))?;
+    Ok(SocketStatus::Success)
+}
+
+// Build the TLS client configuration trusting the bundled web PKI roots
+pub(crate) fn tls_client_config() -> Arc<ClientConfig> {
+    let root_store = RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring provider supports the default protocol versions")
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+// Complete the TLS handshake on a connected stream, giving up once the peer stays silent
+// for the timeout, so a peer that never answers can't stall the socket manager
+fn tls_handshake(connection: &mut ClientConnection, stream: &mut TcpStream, host: &str, timeout: Duration) -> Result<(), CmioError> {
+    stream.set_read_timeout(Some(timeout))
+        .and_then(|()| stream.set_write_timeout(Some(timeout)))
+        .map_err(|e| CmioError::io(format!("bound the TLS handshake with {}", host), e))?;
+    while connection.is_handshaking() {
+        connection.complete_io(stream).map_err(|e| match e.kind() {
+            // A timed out read or write reports EAGAIN, which would read as "try again"
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => CmioError::io(
+                format!("complete the TLS handshake with {} within {:?}", host, timeout),
+                io::Error::from_raw_os_error(libc::ETIMEDOUT),
+            ),
+            _ if e.raw_os_error().is_some() => CmioError::io(format!("complete the TLS handshake with {}", host), e),
+            _ => CmioError::TlsError(host.to_string(), e.to_string()),
+        })?;
+    }
+    stream.set_read_timeout(None)
+        .and_then(|()| stream.set_write_timeout(None))
+        .map_err(|e| CmioError::io(format!("clear the TLS handshake timeout with {}", host), e))
+}
+
+// A proxied TCP connection, optionally wrapped in TLS
+pub(crate) enum TcpConnection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for TcpConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TcpConnection::Plain(stream) => stream.read(buf),
+            TcpConnection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl TcpConnection {
+    // The underlying TCP socket
+    pub(crate) fn tcp_stream(&self) -> &TcpStream {
+        match self {
+            TcpConnection::Plain(stream) => stream,
+            TcpConnection::Tls(stream) => &stream.sock,
+        }
+    }
+    
+    // Whether encrypted data is buffered that still has to be written to the socket
+    fn has_pending_output(&self) -> bool {
+        match self {
+            TcpConnection::Plain(_) => false,
+            TcpConnection::Tls(stream) => stream.conn.wants_write(),
+        }
+    }
+    
+    // Whether decrypted data is buffered that a readiness poll on the socket would not report
+    fn has_buffered_data(&mut self) -> bool {
+        match self {
+            TcpConnection::Plain(_) => false,
+            TcpConnection::Tls(stream) => stream.conn.process_new_packets()
+                .map(|state| state.plaintext_bytes_to_read() > 0)
+                .unwrap_or(false),
+        }
+    }
+    
+    // Half or fully close the connection, sending close_notify first when writes are shut down on TLS
+    fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        if let (TcpConnection::Tls(stream), Shutdown::Write | Shutdown::Both) = (&mut *self, how) {
+            stream.conn.send_close_notify();
+            stream.conn.write_tls(&mut stream.sock)?;
+        }
+        self.tcp_stream().shutdown(how)
+    }
+}
+
+impl Write for TcpConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TcpConnection::Plain(stream) => stream.write(buf),
+            TcpConnection::Tls(stream) => stream.write(buf),
+        }
+    }
+    
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TcpConnection::Plain(stream) => stream.flush(),
+            TcpConnection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Drop for TcpConnection {
+    fn drop(&mut self) {
+        // Best-effort close_notify so the peer sees a clean TLS shutdown
+        if let TcpConnection::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.conn.write_tls(&mut stream.sock);
+        }
+    }
+}
+
+// Take as many queued messages as fit in one CMIO transmission
+//
+// A data-bearing message that doesn't fit is split: the part that fits is sent flagged
+// with MSG_FLAG_MORE and the remainder stays at the front of the queue for the next
+// transmission, so arbitrarily large payloads flow across multiple yields.
+fn take_batch(outgoing: &mut VecDeque<SocketMessage>, max_size: usize, codec: Codec) -> Vec<u8> {
+    let mut batch = Vec::new();
+    
+    while let Some(message) = outgoing.pop_front() {
+        let serialized = match message.encode(codec) {
+            Ok(serialized) => serialized,
+            Err(e) => {
+                warn!("Dropping a message for socket {}: {}", message.socket_id, e);
+                continue;
+            }
+        };
+        let space = max_size.saturating_sub(batch.len());
+        
+        if serialized.len() <= space {
+            batch.extend_from_slice(&serialized);
+            continue;
+        }
+        
+        // Send what fits now and keep the remainder queued
+        let header_size = serialized.len() - message.data.len();
+        if message.is_chunkable() && space > header_size {
+            let (chunk, rest) = message.split_chunk(space - header_size);
+            // The chunk has the header of the message, which encoded
+            if let Ok(chunk) = chunk.encode(codec) {
+                batch.extend_from_slice(&chunk);
             }
+            outgoing.push_front(rest);
+        } else {
+            outgoing.push_front(message);
         }
+        break;
     }
     
-    fn process_received_data(&self, data: &[u8]) -> Result<(), CmioError> {
-        let mut offset = 0;
-        let mut responses = Vec::new();
+    batch
+}
+
+// Pack receive messages into the payload of a RECEIVE_ALL response
+//
+// Each entry is the receive message type (which tells Unix and TCP sockets apart), the
+// socket ID (u32 BE), the data length (u32 BE) and the data, itself starting with the
+// status and errno of that socket's read.
+fn encode_receive_all(messages: &[SocketMessage]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    for message in messages {
+        payload.push(message.msg_type);
+        payload.extend_from_slice(&message.socket_id.to_be_bytes());
+        payload.extend_from_slice(&(message.data.len() as u32).to_be_bytes());
+        payload.extend_from_slice(&message.data);
+    }
+    payload
+}
+
+// Stitch an RX batch onto the data carried over from previous yields
+//
+// Returns the complete batch once a yield arrives without the continuation flag, or
+// None while more data is expected.
+fn reassemble(pending: &mut Vec<u8>, data: &[u8], continued: bool) -> Option<Vec<u8>> {
+    if pending.is_empty() && !continued {
+        return Some(data.to_vec());
+    }
+    
+    pending.extend_from_slice(data);
+    if continued {
+        None
+    } else {
+        Some(std::mem::take(pending))
+    }
+}
+
+// Build a Unix socket address from its wire encoding
+//
+// A leading NUL byte selects the Linux abstract namespace, with the remaining bytes as
+// the name. Anything else is a filesystem path, taken as raw bytes.
+fn unix_socket_addr(path: &[u8]) -> io::Result<UnixSocketAddr> {
+    match path.split_first() {
+        Some((0, name)) => UnixSocketAddr::from_abstract_name(name),
+        _ => UnixSocketAddr::from_pathname(OsStr::from_bytes(path)),
+    }
+}
+
+// Wire encoding of a Unix socket address, empty for unnamed sockets
+fn unix_addr_bytes(addr: &UnixSocketAddr) -> Vec<u8> {
+    if let Some(name) = addr.as_abstract_name() {
+        let mut path = vec![0];
+        path.extend_from_slice(name);
+        path
+    } else if let Some(pathname) = addr.as_pathname() {
+        pathname.as_os_str().as_bytes().to_vec()
+    } else {
+        Vec::new()
+    }
+}
+
+// Remove the socket file behind a bound address; abstract addresses leave no file behind
+fn remove_socket_file(path: &[u8]) {
+    if !path.is_empty() && path[0] != 0 {
+        let _ = std::fs::remove_file(Path::new(OsStr::from_bytes(path)));
+    }
+}
+
+// Payload of a datagram receive: sender address length (u8), sender address (empty for
+// unbound senders) and the datagram
+fn encode_datagram(sender: &[u8], datagram: &[u8]) -> Vec<u8> {
+    let sender = &sender[..sender.len().min(u8::MAX as usize)];
+    
+    let mut payload = Vec::with_capacity(1 + sender.len() + datagram.len());
+    payload.push(sender.len() as u8);
+    payload.extend_from_slice(sender);
+    payload.extend_from_slice(datagram);
+    payload
+}
+
+// Receive one datagram of up to `max_size` bytes, encoded with its sender
+fn recv_datagram(socket: &UnixDatagram, max_size: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; max_size];
+    let (n, sender) = socket.recv_from(&mut buffer)?;
+    Ok(encode_datagram(&unix_addr_bytes(&sender), &buffer[..n]))
+}
+
+// Append a LIST_CONNECTIONS entry
+//
+// Each entry is the socket ID (u32 BE), kind, state, name length (u8) and name (Unix path
+// or the hostname the connection was opened with), followed by the peer address family,
+// address and port. Unix sockets report the unspecified IPv4 address and port 0.
+fn write_connection_entry(buffer: &mut Vec<u8>, socket_id: u32, kind: u8, state: u8, name: &[u8], peer: Option<SocketAddr>) {
+    let peer = peer.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let name = &name[..name.len().min(u8::MAX as usize)];
+    
+    buffer.extend_from_slice(&socket_id.to_be_bytes());
+    buffer.push(kind);
+    buffer.push(state);
+    buffer.push(name.len() as u8);
+    buffer.extend_from_slice(name);
+    write_ip_addr(buffer, &peer.ip());
+    buffer.extend_from_slice(&peer.port().to_be_bytes());
+}
+
+// Traffic counters for a single connection
+#[derive(Debug, Clone)]
+struct SocketStats {
+    bytes_sent: u64,
+    bytes_received: u64,
+    errors: u32,
+    last_activity: SystemTime,
+}
+
+impl SocketStats {
+    fn new() -> Self {
+        Self {
+            bytes_sent: 0,
+            bytes_received: 0,
+            errors: 0,
+            last_activity: SystemTime::now(),
+        }
+    }
+    
+    fn sent(&mut self, bytes: usize) {
+        self.bytes_sent += bytes as u64;
+        self.last_activity = SystemTime::now();
+    }
+    
+    fn received(&mut self, bytes: usize) {
+        self.bytes_received += bytes as u64;
+        self.last_activity = SystemTime::now();
+    }
+    
+    fn error(&mut self) {
+        self.errors = self.errors.saturating_add(1);
+        self.last_activity = SystemTime::now();
+    }
+    
+    /// Append a STATS entry
+    /// 
+    /// The entry is the socket ID (u32 BE), connection kind, bytes sent and received
+    /// (u64 BE each), error count (u32 BE) and the last activity as milliseconds since
+    /// the Unix epoch (u64 BE).
+    fn write_entry(&self, buffer: &mut Vec<u8>, socket_id: u32, kind: u8) {
+        let last_activity = self.last_activity.duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
         
-        // Process each message in the batch
-        while offset < data.len() {
-            // Try to deserialize a message
-            match SocketMessage::deserialize(&data[offset..]) {
-                Ok(message) => {
-                    // Process the message based on its type
-                    let response = match message.msg_type {
-                        MSG_TYPE_UNIX_CONNECT => self.handle_unix_connect(message.clone()),
-                        MSG_TYPE_UNIX_SEND => self.handle_unix_send(message.clone()),
-                        MSG_TYPE_UNIX_RECEIVE => self.handle_unix_receive(message.clone()),
-                        MSG_TYPE_UNIX_CLOSE => self.handle_unix_close(message.clone()),
-                        MSG_TYPE_TCP_CONNECT => self.handle_tcp_connect(message.clone()),
-                        MSG_TYPE_TCP_SEND => self.handle_tcp_send(message.clone()),
-                        MSG_TYPE_TCP_RECEIVE => self.handle_tcp_receive(message.clone()),
-                        MSG_TYPE_TCP_CLOSE => self.handle_tcp_close(message.clone()),
-                        _ => Err(CmioError::SetupError(-1)), // Unknown message type
-                    }?;
-                    
-                    // Add the response to our batch
-                    responses.extend_from_slice(&response);
-                    
-                    // Calculate the size of the processed message
-                    let msg_size = 1 + 4 + 4 + message.data.len();
-                    offset += msg_size;
+        buffer.extend_from_slice(&socket_id.to_be_bytes());
+        buffer.push(kind);
+        buffer.extend_from_slice(&self.bytes_sent.to_be_bytes());
+        buffer.extend_from_slice(&self.bytes_received.to_be_bytes());
+        buffer.extend_from_slice(&self.errors.to_be_bytes());
+        buffer.extend_from_slice(&last_activity.to_be_bytes());
+    }
+}
+
+// Build the mio token identifying a watched socket
+fn socket_token(kind: usize, socket_id: u32) -> Token {
+    Token(((socket_id as usize) << TOKEN_KIND_BITS) | kind)
+}
+
+// Upstream proxy for outbound TCP connections
+#[derive(Debug, Clone)]
+pub enum UpstreamProxy {
+    Socks5(Socks5Proxy),
+    HttpConnect(HttpProxy),
+}
+
+impl UpstreamProxy {
+    // Open a tunnel to the target through the proxy
+    fn connect(&self, target: Socks5Target, timeout: Option<Duration>) -> io::Result<TcpStream> {
+        match self {
+            UpstreamProxy::Socks5(proxy) => proxy.connect(&target, timeout),
+            UpstreamProxy::HttpConnect(proxy) => {
+                let authority = match target {
+                    Socks5Target::Addr(addr) => addr.to_string(),
+                    Socks5Target::Host(host, port) => format!("{}:{}", host, port),
+                };
+                proxy.connect(&authority, timeout)
+            },
+        }
+    }
+}
+
+// Sockets of one kind by socket ID, each with the address or hostname it was opened with
+type SocketRegistry<T> = Arc<Mutex<HashMap<u32, T>>>;
+
+/// Handler for one message type, registered with SocketManager::register_handler
+/// 
+/// The handler returns the response to queue for the host. An error is reported to the
+/// host as a response of the same type carrying the status derived from its errno, with
+/// errno -1 meaning an invalid message. Any closure taking the manager and the message
+/// is a handler.
+pub trait MessageHandler {
+    fn handle(&self, manager: &SocketManager, message: SocketMessage) -> Result<SocketMessage, CmioError>;
+}
+
+impl<F> MessageHandler for F
+where
+    F: Fn(&SocketManager, SocketMessage) -> Result<SocketMessage, CmioError>,
+{
+    fn handle(&self, manager: &SocketManager, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self(manager, message)
+    }
+}
+
+// Handlers of the built-in message types
+fn builtin_handlers() -> HashMap<u8, Box<dyn MessageHandler>> {
+    let handlers: [(u8, Box<dyn MessageHandler>); 27] = [
+        (MSG_TYPE_UNIX_CONNECT, Box::new(SocketManager::handle_unix_connect)),
+        (MSG_TYPE_UNIX_SEND, Box::new(SocketManager::handle_unix_send)),
+        (MSG_TYPE_UNIX_RECEIVE, Box::new(SocketManager::handle_unix_receive)),
+        (MSG_TYPE_UNIX_CLOSE, Box::new(SocketManager::handle_unix_close)),
+        (MSG_TYPE_TCP_CONNECT, Box::new(SocketManager::handle_tcp_connect)),
+        (MSG_TYPE_TCP_SEND, Box::new(SocketManager::handle_tcp_send)),
+        (MSG_TYPE_TCP_RECEIVE, Box::new(SocketManager::handle_tcp_receive)),
+        (MSG_TYPE_TCP_CLOSE, Box::new(SocketManager::handle_tcp_close)),
+        (MSG_TYPE_UNIX_LISTEN, Box::new(SocketManager::handle_unix_listen)),
+        (MSG_TYPE_UNIX_ACCEPT, Box::new(SocketManager::handle_unix_accept)),
+        (MSG_TYPE_TCP_CONNECT_HOST, Box::new(SocketManager::handle_tcp_connect_host)),
+        (MSG_TYPE_TLS_CONNECT, Box::new(SocketManager::handle_tls_connect)),
+        (MSG_TYPE_SET_OPTION, Box::new(SocketManager::handle_set_option)),
+        (MSG_TYPE_SHUTDOWN, Box::new(SocketManager::handle_shutdown)),
+        (MSG_TYPE_RECEIVE_ALL, Box::new(SocketManager::handle_receive_all)),
+        (MSG_TYPE_LIST_CONNECTIONS, Box::new(SocketManager::handle_list_connections)),
+        (MSG_TYPE_STATS, Box::new(SocketManager::handle_stats)),
+        (MSG_TYPE_UNIX_DGRAM_BIND, Box::new(SocketManager::handle_unix_dgram_bind)),
+        (MSG_TYPE_UNIX_DGRAM_SEND_TO, Box::new(SocketManager::handle_unix_dgram_send_to)),
+        (MSG_TYPE_UNIX_DGRAM_RECEIVE, Box::new(SocketManager::handle_unix_dgram_receive)),
+        (MSG_TYPE_RESOLVE, Box::new(SocketManager::handle_resolve)),
+        (MSG_TYPE_SEND_CHUNK, Box::new(SocketManager::handle_send_chunk)),
+        (MSG_TYPE_SEND_END, Box::new(SocketManager::handle_send_end)),
+        (MSG_TYPE_HELLO, Box::new(SocketManager::handle_hello)),
+        (MSG_TYPE_PING, Box::new(SocketManager::handle_ping)),
+        (MSG_TYPE_FORWARD_REGISTER, Box::new(SocketManager::handle_forward_register)),
+        (MSG_TYPE_FORWARD_OPEN, Box::new(SocketManager::handle_forward_open)),
+    ];
+    handlers.into_iter().collect()
+}
+
+// Structure to manage socket connections
+pub struct SocketManager {
+    // Handle yielded through by run_loop, absent when a multiplexer does the yields
+    cmio: Option<CmioHandle>,
+    unix_connections: SocketRegistry<(Vec<u8>, UnixStream)>,
+    unix_listeners: SocketRegistry<(Vec<u8>, UnixListener)>,
+    tcp_connections: SocketRegistry<(String, TcpConnection)>,
+    unix_datagrams: SocketRegistry<(Vec<u8>, UnixDatagram)>,
+    tls_config: Arc<ClientConfig>,
+    connect_timeouts: Arc<Mutex<HashMap<u32, Duration>>>,
+    // Guest-local addresses registered by the host with MSG_TYPE_FORWARD_REGISTER, by forward ID
+    forwards: Mutex<HashMap<u32, SocketAddr>>,
+    poll: Poll,
+    outgoing: Mutex<VecDeque<SocketMessage>>,
+    // Tokens of connections whose stream ended, kept until the connection is replaced or closed
+    ended: Mutex<HashSet<Token>>,
+    // Traffic counters of open connections, keyed like their readiness tokens
+    stats: Mutex<HashMap<Token, SocketStats>>,
+    // Idle timeouts of open connections, after which they are closed by the manager
+    idle_timeouts: Mutex<HashMap<Token, Duration>>,
+    // Data accepted by sends that the socket could not take yet, flushed when it becomes writable
+    write_queues: Mutex<HashMap<Token, Vec<u8>>>,
+    // Chunked transfers in progress, until MSG_TYPE_SEND_END
+    transfers: Mutex<HashMap<Token, ChunkedTransfer>>,
+    reassembly: Mutex<Vec<u8>>,
+    // Next candidate for socket IDs assigned by the manager
+    next_socket_id: Mutex<u32>,
+    // Wire encoding of messages in both directions, switched with MSG_TYPE_HELLO
+    codec: Mutex<Codec>,
+    max_connections: usize,
+    // How the loop waits when a yield neither sent nor received anything
+    idle: Mutex<IdleState>,
+    // Upstream proxy for outbound TCP connections, if the guest has no direct egress
+    upstream_proxy: Option<UpstreamProxy>,
+    // Handlers by message type, the built-in ones plus any registered by the user
+    handlers: HashMap<u8, Box<dyn MessageHandler>>,
+    cmio_max_buffer_size: usize,
+}
+
+impl SocketManager {
+    pub fn new(cmio: Cmio, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        Self::with_handle(cmio.into_handle()?, cmio_max_buffer_size)
+    }
+    
+    /// Create a socket manager yielding through a handle, which other users of the
+    /// device such as a network interface may share
+    pub fn with_handle(cmio: CmioHandle, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        Self::build(Some(cmio), cmio_max_buffer_size)
+    }
+    
+    /// Create a socket manager without a CMIO device, to be registered with a multiplexer
+    /// sharing one with other subsystems
+    /// 
+    /// Batches are sized for buffers of cmio_max_buffer_size bytes. Its run_loop fails,
+    /// as the multiplexer does the yields.
+    pub fn for_multiplexer(cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        Self::build(None, cmio_max_buffer_size)
+    }
+    
+    fn build(cmio: Option<CmioHandle>, cmio_max_buffer_size: usize) -> Result<Self, CmioError> {
+        let poll = Poll::new()
+            .map_err(|e| CmioError::io("create the socket poll", e))?;
+        
+        Ok(Self {
+            cmio,
+            unix_connections: Arc::new(Mutex::new(HashMap::new())),
+            unix_listeners: Arc::new(Mutex::new(HashMap::new())),
+            tcp_connections: Arc::new(Mutex::new(HashMap::new())),
+            unix_datagrams: Arc::new(Mutex::new(HashMap::new())),
+            tls_config: tls_client_config(),
+            connect_timeouts: Arc::new(Mutex::new(HashMap::new())),
+            forwards: Mutex::new(HashMap::new()),
+            poll,
+            outgoing: Mutex::new(VecDeque::new()),
+            ended: Mutex::new(HashSet::new()),
+            stats: Mutex::new(HashMap::new()),
+            idle_timeouts: Mutex::new(HashMap::new()),
+            write_queues: Mutex::new(HashMap::new()),
+            transfers: Mutex::new(HashMap::new()),
+            reassembly: Mutex::new(Vec::new()),
+            next_socket_id: Mutex::new(1),
+            codec: Mutex::new(Codec::Native),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle: Mutex::new(IdleState::new(IdleStrategy::Immediate)),
+            upstream_proxy: None,
+            handlers: builtin_handlers(),
+            cmio_max_buffer_size,
+        })
+    }
+    
+    /// Set the maximum number of open connections and listeners
+    /// 
+    /// Connect, listen and accept requests beyond the limit fail with the
+    /// TooManyConnections status instead of exhausting file descriptors in the guest.
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.max_connections = max_connections;
+    }
+    
+    /// Set how the loop waits after a yield that neither sent nor received anything
+    /// 
+    /// The default yields again immediately. Sleeping or backing off saves machine cycles
+    /// at the cost of latency, and polling returns early once a socket becomes readable.
+    pub fn set_idle_strategy(&mut self, strategy: IdleStrategy) {
+        self.idle = Mutex::new(IdleState::new(strategy));
+    }
+    
+    /// Route outbound TCP and TLS connections through a SOCKS5 or HTTP CONNECT proxy
+    /// 
+    /// Hostnames are then resolved by the proxy, and connect-by-hostname responses report
+    /// the unspecified address since the chosen address is not known to the guest.
+    pub fn set_upstream_proxy(&mut self, proxy: Option<UpstreamProxy>) {
+        self.upstream_proxy = proxy;
+    }
+    
+    /// Handle messages of the given type with a custom handler
+    /// 
+    /// This lets other subsystems share the socket manager's CMIO channel. Messages of an
+    /// unregistered type use the plain format (type, socket ID, data length and data).
+    /// Registering a built-in type replaces its handler; the previous handler is returned.
+    /// 
+    /// # Panics
+    /// 
+    /// Panics if the type has the high bit set, which is reserved for the continuation flag.
+    pub fn register_handler<H: MessageHandler + 'static>(&mut self, msg_type: u8, handler: H) -> Option<Box<dyn MessageHandler>> {
+        assert!(msg_type & MSG_FLAG_MORE == 0, "message type 0x{:02x} has the continuation flag set", msg_type);
+        self.handlers.insert(msg_type, Box::new(handler))
+    }
+    
+    /// Run the socket manager loop
+    /// 
+    /// Every iteration performs a single CMIO exchange:
+    /// 1. Close idle connections, notifying the host, and once the queue has drained,
+    ///    queue the data of readable sockets
+    /// 2. Send as much of the queue as fits in the CMIO buffer, chunking large messages
+    /// 3. Handle the requests received in return, queueing responses for the next yield;
+    ///    a batch flagged with RX_FLAG_CONTINUED waits for the rest of it
+    /// 
+    /// Once a signal requests a shutdown, the loop sends what is still queued, closes all
+    /// sockets and returns after the goodbye message.
+    pub fn run_loop(&self) -> Result<(), CmioError> {
+        let cmio = self.device()?;
+        
+        // Responses are received into the same buffer every time to avoid an allocation
+        // per yield
+        let mut rx_data = Vec::new();
+        loop {
+            if shutdown::requested() {
+                return self.shut_down();
+            }
+            
+            // Step 1: Expire idle connections, proactively forward data from readable
+            // sockets once the queue has drained, and take the next batch
+            let batch = self.next_batch(self.cmio_max_buffer_size)?;
+            
+            // Step 2: Exchange the next batch with the host
+            let reason = cmio.yield_with_retry_into(
+                YieldDevice::Yield,
+                YieldCommand::Manual,
+                YieldReason::UnixSocket,
+                &batch,
+                &mut rx_data,
+            )?;
+            
+            // Step 3: Process the received requests once any partial batch is complete
+            self.receive(&rx_data, reason);
+            
+            // Step 4: Wait as the idle strategy says if nothing went either way
+            if batch.is_empty() && rx_data.is_empty() {
+                self.wait_idle()?;
+            } else {
+                self.idle.lock().unwrap().reset();
+            }
+        }
+    }
+    
+    /// Handle through which run_loop yields, failing for a manager created for a
+    /// multiplexer
+    fn device(&self) -> Result<&CmioHandle, CmioError> {
+        self.cmio.as_ref()
+            .ok_or_else(|| CmioError::InvalidArgument("the socket manager was created for a multiplexer, which runs the loop".to_string()))
+    }
+    
+    /// Expire idle connections, queue data from readable sockets once the queue has
+    /// drained, and take as much of the queue as fits in max_size bytes
+    fn next_batch(&self, max_size: usize) -> Result<Vec<u8>, CmioError> {
+        self.close_idle_connections();
+        status::set_connections(self.stats.lock().unwrap().len());
+        if self.outgoing.lock().unwrap().is_empty() {
+            self.collect_readable_data()?;
+        }
+        let codec = *self.codec.lock().unwrap();
+        Ok(take_batch(&mut self.outgoing.lock().unwrap(), max_size, codec))
+    }
+    
+    /// Process a batch of requests from the host, given with the response reason
+    /// 
+    /// A batch flagged with RX_FLAG_CONTINUED is held back until an unflagged one
+    /// completes it.
+    fn receive(&self, rx_data: &[u8], reason: u16) {
+        let continued = reason & RX_FLAG_CONTINUED != 0;
+        let complete = {
+            let mut reassembly = self.reassembly.lock().unwrap();
+            if reassembly.len() + rx_data.len() > MAX_REASSEMBLY_SIZE {
+                // The host never finished the batch, drop it rather than grow without bound
+                warn!("Dropping {} bytes of unfinished socket batch", reassembly.len());
+                reassembly.clear();
+            }
+            reassemble(&mut reassembly, rx_data, continued)
+        };
+        if let Some(data) = complete {
+            self.process_received_data(&data);
+        }
+    }
+    
+    /// Wait before the next yield as the idle strategy says
+    fn wait_idle(&self) -> Result<(), CmioError> {
+        let wait = self.idle.lock().unwrap().next_wait();
+        match wait {
+            IdleWait::None => Ok(()),
+            IdleWait::Sleep(duration) => {
+                std::thread::sleep(duration);
+                Ok(())
+            }
+            IdleWait::Poll(timeout) => {
+                // Readiness is level-triggered, so the events are seen again by the next pass
+                let mut events = Events::with_capacity(1);
+                match self.poll.poll(&mut events, Some(timeout)) {
+                    Ok(_) => Ok(()),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+                    Err(e) => Err(CmioError::io("wait for socket activity", e)),
+                }
+            }
+        }
+    }
+    
+    /// Stop after a shutdown request
+    /// 
+    /// Messages still queued for the host are sent first, dropping any requests that come
+    /// back, then every socket is closed, removing socket files of listeners, and the host
+    /// is sent the goodbye.
+    fn shut_down(&self) -> Result<(), CmioError> {
+        let cmio = self.device()?;
+        let codec = *self.codec.lock().unwrap();
+        loop {
+            let batch = take_batch(&mut self.outgoing.lock().unwrap(), self.cmio_max_buffer_size, codec);
+            if batch.is_empty() {
+                break;
+            }
+            let (rx_data, _reason) = cmio.yield_with_retry(
+                YieldDevice::Yield,
+                YieldCommand::Manual,
+                YieldReason::UnixSocket,
+                &batch,
+            )?;
+            if !rx_data.is_empty() {
+                warn!("Dropping {} bytes of socket requests received while shutting down", rx_data.len());
+            }
+        }
+        
+        self.close_all_connections();
+        info!("Closed all sockets, shutting down");
+        shutdown::say_goodbye_through(cmio, shutdown::GOODBYE_SOCKETS_CLOSED)
+    }
+    
+    /// Close every connection, listener and datagram socket
+    fn close_all_connections(&self) {
+        let mut unix_ids: Vec<u32> = self.unix_connections.lock().unwrap().keys().copied().collect();
+        unix_ids.extend(self.unix_listeners.lock().unwrap().keys());
+        unix_ids.extend(self.unix_datagrams.lock().unwrap().keys());
+        let tcp_ids: Vec<u32> = self.tcp_connections.lock().unwrap().keys().copied().collect();
+        
+        // Closing only fails for unknown sockets, which have nothing left to clean up
+        for socket_id in unix_ids {
+            let _ = self.handle_unix_close(SocketMessage::new(MSG_TYPE_UNIX_CLOSE, socket_id, Vec::new(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, Vec::new()));
+        }
+        for socket_id in tcp_ids {
+            let _ = self.handle_tcp_close(SocketMessage::new(MSG_TYPE_TCP_CLOSE, socket_id, Vec::new(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, Vec::new()));
+        }
+    }
+    
+    /// Read from every readable connection and queue the data as receive messages
+    fn collect_readable_data(&self) -> Result<(), CmioError> {
+        let messages = self.read_readable_data()?;
+        self.outgoing.lock().unwrap().extend(messages);
+        Ok(())
+    }
+    
+    /// Read from every readable connection, returning the data as receive messages
+    /// 
+    /// Write queues of writable connections are flushed first, a failed flush being
+    /// reported as a send message with the error status. Each socket is read once per
+    /// pass, the rest waiting for the next. End of stream or a read error is reported once
+    /// as a receive message with its status, and the connection is no longer watched.
+    fn read_readable_data(&self) -> Result<Vec<SocketMessage>, CmioError> {
+        let mut events = Events::with_capacity(1024);
+        self.poll.poll(&mut events, Some(Duration::from_millis(0)))
+            .map_err(|e| CmioError::io("poll readable sockets", e))?;
+        
+        let mut ready: Vec<Token> = events.iter()
+            .filter(|event| event.readiness().is_readable())
+            .map(|event| event.token())
+            .collect();
+        let mut messages = Vec::new();
+        
+        // Flush write queues of sockets that can take more data
+        for event in events.iter().filter(|event| event.readiness().is_writable()) {
+            if let Some(message) = self.flush_write_queue(event.token()) {
+                messages.push(message);
+            }
+        }
+        
+        // TLS connections may hold decrypted data that the socket no longer reports
+        {
+            let mut connections = self.tcp_connections.lock().unwrap();
+            for (socket_id, (_, connection)) in connections.iter_mut() {
+                let token = socket_token(TOKEN_KIND_TCP, *socket_id);
+                if connection.has_buffered_data() && !ready.contains(&token) {
+                    ready.push(token);
+                }
+            }
+        }
+        
+        for token in ready {
+            let kind = token.0 & ((1 << TOKEN_KIND_BITS) - 1);
+            let socket_id = (token.0 >> TOKEN_KIND_BITS) as u32;
+            
+            // A readable listener has a connection to accept and announce to the host
+            if kind == TOKEN_KIND_UNIX_LISTENER {
+                messages.extend(self.accept_pending(socket_id));
+                continue;
+            }
+            let mut buffer = vec![0u8; MAX_READ_SIZE];
+            
+            let (msg_type, fd, result) = if kind == TOKEN_KIND_UNIX {
+                let mut connections = self.unix_connections.lock().unwrap();
+                match connections.get_mut(&socket_id) {
+                    Some((_, stream)) => (MSG_TYPE_UNIX_RECEIVE, stream.as_raw_fd(), stream.read(&mut buffer)),
+                    None => continue,
+                }
+            } else if kind == TOKEN_KIND_UNIX_DGRAM {
+                // Datagrams are forwarded whole with their sender, so the payload is never empty
+                let datagrams = self.unix_datagrams.lock().unwrap();
+                match datagrams.get(&socket_id) {
+                    Some((_, socket)) => {
+                        let result = recv_datagram(socket, MAX_DATAGRAM_SIZE).map(|payload| {
+                            buffer = payload;
+                            buffer.len()
+                        });
+                        (MSG_TYPE_UNIX_DGRAM_RECEIVE, socket.as_raw_fd(), result)
+                    },
+                    None => continue,
+                }
+            } else {
+                let mut connections = self.tcp_connections.lock().unwrap();
+                match connections.get_mut(&socket_id) {
+                    Some((_, connection)) => (MSG_TYPE_TCP_RECEIVE, connection.tcp_stream().as_raw_fd(), connection.read(&mut buffer)),
+                    None => continue,
+                }
+            };
+            
+            let data = match result {
+                Ok(n) if n > 0 => {
+                    self.update_stats(token, |stats| stats.received(n));
+                    status_payload(SocketStatus::Success, &buffer[..n])
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Ok(_) => {
+                    // End of stream, report it once and stop watching
+                    self.unwatch(fd);
+                    self.ended.lock().unwrap().insert(token);
+                    status_data(SocketStatus::Eof, 0)
+                },
+                Err(e) => {
+                    // Read error, report it once and stop watching
+                    self.unwatch(fd);
+                    self.ended.lock().unwrap().insert(token);
+                    self.update_stats(token, |stats| stats.error());
+                    let errno = e.raw_os_error().unwrap_or(-1);
+                    status_data(SocketStatus::from_errno(errno), errno)
+                }
+            };
+            
+            messages.push(SocketMessage::new(
+                msg_type,
+                socket_id,
+                Vec::new(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                data,
+            ));
+        }
+        
+        Ok(messages)
+    }
+    
+    /// Accept a connection pending on a listener under a socket ID of its own, returning
+    /// the unsolicited accept message announcing it to the host
+    /// 
+    /// The message is the listener's accept response, carrying the new socket ID. Nothing is
+    /// accepted once the connection limit is reached; the connection waits in the backlog
+    /// until the host closes another one or accepts it explicitly.
+    fn accept_pending(&self, listener_id: u32) -> Option<SocketMessage> {
+        if self.check_connection_limit().is_err() {
+            return None;
+        }
+        let (path, stream) = {
+            let listeners = self.unix_listeners.lock().unwrap();
+            let (path, listener) = listeners.get(&listener_id)?;
+            match listener.accept() {
+                Ok((stream, _addr)) => (path.clone(), stream),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) => {
+                    warn!("Failed to accept on socket {}: {}", listener_id, e);
+                    return None;
+                }
+            }
+        };
+        let socket_id = self.claim_socket_id(0).ok()?;
+        if let Err(e) = self.add_unix_connection(socket_id, path.clone(), stream) {
+            warn!("Failed to register the connection accepted on socket {}: {}", listener_id, e);
+            return None;
+        }
+        Some(SocketMessage::new(
+            MSG_TYPE_UNIX_ACCEPT,
+            listener_id,
+            path,
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            status_payload(SocketStatus::Success, &socket_id.to_be_bytes()),
+        ))
+    }
+    
+    /// Write queued data to a writable connection
+    /// 
+    /// Once the queue and any buffered TLS output are drained, the connection is only watched
+    /// for readability again. Returns a send message with the error status if writing fails,
+    /// in which case the queued data is dropped.
+    fn flush_write_queue(&self, token: Token) -> Option<SocketMessage> {
+        let kind = token.0 & ((1 << TOKEN_KIND_BITS) - 1);
+        let socket_id = (token.0 >> TOKEN_KIND_BITS) as u32;
+        
+        let (msg_type, fd, result, pending_output) = if kind == TOKEN_KIND_UNIX {
+            let mut connections = self.unix_connections.lock().unwrap();
+            let (_, stream) = connections.get_mut(&socket_id)?;
+            let mut queues = self.write_queues.lock().unwrap();
+            let queue = queues.entry(token).or_default();
+            let result = write_nonblocking(stream, queue).map(|n| { queue.drain(..n); });
+            (MSG_TYPE_UNIX_SEND, stream.as_raw_fd(), result, false)
+        } else if kind == TOKEN_KIND_TCP {
+            let mut connections = self.tcp_connections.lock().unwrap();
+            let (_, connection) = connections.get_mut(&socket_id)?;
+            let mut queues = self.write_queues.lock().unwrap();
+            let queue = queues.entry(token).or_default();
+            let mut result = write_nonblocking(connection, queue).map(|n| { queue.drain(..n); });
+            
+            // Push out encrypted data rustls is still holding
+            if result.is_ok() && connection.has_pending_output() {
+                if let Err(e) = connection.flush() {
+                    if e.kind() != io::ErrorKind::WouldBlock {
+                        result = Err(e);
+                    }
+                }
+            }
+            (MSG_TYPE_TCP_SEND, connection.tcp_stream().as_raw_fd(), result, connection.has_pending_output())
+        } else {
+            return None;
+        };
+        
+        let mut queues = self.write_queues.lock().unwrap();
+        match result {
+            Ok(()) => {
+                if queues.get(&token).map(|queue| queue.is_empty()).unwrap_or(true) && !pending_output {
+                    queues.remove(&token);
+                    drop(queues);
+                    self.set_write_interest(token, fd, false);
+                }
+                None
+            },
+            Err(e) => {
+                queues.remove(&token);
+                drop(queues);
+                self.set_write_interest(token, fd, false);
+                self.update_stats(token, |stats| stats.error());
+                let errno = e.raw_os_error().unwrap_or(-1);
+                Some(SocketMessage::new(
+                    msg_type,
+                    socket_id,
+                    Vec::new(),
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    0,
+                    status_data(SocketStatus::from_errno(errno), errno),
+                ))
+            }
+        }
+    }
+    
+    /// Write data to a connection, queueing whatever the socket can't take right away
+    /// 
+    /// Data is appended to the queue if one already exists so ordering is preserved.
+    /// Returns the number of bytes left in the queue, or EAGAIN when the queue would grow
+    /// beyond MAX_WRITE_QUEUE_SIZE.
+    fn send_or_queue<W: Write>(&self, token: Token, fd: RawFd, writer: &mut W, data: &[u8]) -> io::Result<usize> {
+        let mut queues = self.write_queues.lock().unwrap();
+        let queue = queues.entry(token).or_default();
+        
+        if queue.is_empty() {
+            let written = write_nonblocking(writer, data)?;
+            queue.extend_from_slice(&data[written..]);
+        } else if queue.len() + data.len() > MAX_WRITE_QUEUE_SIZE {
+            return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+        } else {
+            queue.extend_from_slice(data);
+        }
+        
+        let pending = queue.len();
+        if pending == 0 {
+            queues.remove(&token);
+        }
+        drop(queues);
+        
+        if pending > 0 {
+            self.set_write_interest(token, fd, true);
+        }
+        Ok(pending)
+    }
+    
+    // Watch a connection for writability in addition to readability (unless its stream ended)
+    fn set_write_interest(&self, token: Token, fd: RawFd, writable: bool) {
+        let mut interest = if self.ended.lock().unwrap().contains(&token) { Ready::empty() } else { Ready::readable() };
+        if writable {
+            interest |= Ready::writable();
+        }
+        
+        if interest.is_empty() {
+            self.unwatch(fd);
+        } else if self.poll.reregister(&EventedFd(&fd), token, interest, PollOpt::level()).is_err() {
+            let _ = self.poll.register(&EventedFd(&fd), token, interest, PollOpt::level());
+        }
+    }
+    
+    // Watch a socket for readability so its data is forwarded without a receive request
+    fn watch(&self, kind: usize, socket_id: u32, fd: RawFd) -> Result<(), CmioError> {
+        self.poll.register(&EventedFd(&fd), socket_token(kind, socket_id), Ready::readable(), PollOpt::level())
+            .map_err(|e| CmioError::io(format!("watch socket {}", socket_id), e))
+    }
+    
+    // Stop watching a socket, ignoring sockets that are not watched
+    fn unwatch(&self, fd: RawFd) {
+        let _ = self.poll.deregister(&EventedFd(&fd));
+    }
+    
+    // Register a Unix stream under a socket ID and watch it for readability
+    fn add_unix_connection(&self, socket_id: u32, path: Vec<u8>, stream: UnixStream) -> Result<(), CmioError> {
+        // Non-blocking like TCP connections, so a slow peer can't stall the loop
+        stream.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make socket {} non-blocking", socket_id), e))?;
+        self.watch(TOKEN_KIND_UNIX, socket_id, stream.as_raw_fd())?;
+        self.ended.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, socket_id));
+        self.stats.lock().unwrap().insert(socket_token(TOKEN_KIND_UNIX, socket_id), SocketStats::new());
+        self.idle_timeouts.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, socket_id));
+        self.write_queues.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, socket_id));
+        self.transfers.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, socket_id));
+        let mut connections = self.unix_connections.lock().unwrap();
+        if let Some((_, previous)) = connections.insert(socket_id, (path, stream)) {
+            self.unwatch(previous.as_raw_fd());
+        }
+        Ok(())
+    }
+    
+    // Register a Unix datagram socket under a socket ID and watch it for readability
+    fn add_unix_datagram(&self, socket_id: u32, path: Vec<u8>, socket: UnixDatagram) -> Result<(), CmioError> {
+        self.watch(TOKEN_KIND_UNIX_DGRAM, socket_id, socket.as_raw_fd())?;
+        self.stats.lock().unwrap().insert(socket_token(TOKEN_KIND_UNIX_DGRAM, socket_id), SocketStats::new());
+        self.idle_timeouts.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX_DGRAM, socket_id));
+        let mut datagrams = self.unix_datagrams.lock().unwrap();
+        if let Some((_, previous)) = datagrams.insert(socket_id, (path, socket)) {
+            self.unwatch(previous.as_raw_fd());
+        }
+        Ok(())
+    }
+    
+    // Register a TCP connection under a socket ID and watch it for readability
+    fn add_tcp_connection(&self, socket_id: u32, name: String, connection: TcpConnection) -> Result<(), CmioError> {
+        self.watch(TOKEN_KIND_TCP, socket_id, connection.tcp_stream().as_raw_fd())?;
+        self.ended.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, socket_id));
+        self.stats.lock().unwrap().insert(socket_token(TOKEN_KIND_TCP, socket_id), SocketStats::new());
+        self.idle_timeouts.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, socket_id));
+        self.write_queues.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, socket_id));
+        self.transfers.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, socket_id));
+        let mut connections = self.tcp_connections.lock().unwrap();
+        if let Some((_, previous)) = connections.insert(socket_id, (name, connection)) {
+            self.unwatch(previous.tcp_stream().as_raw_fd());
+        }
+        Ok(())
+    }
+    
+    /// Close every connection idle for longer than its idle timeout
+    /// 
+    /// Each closed connection is reported with an unsolicited close message carrying the
+    /// TimedOut status, so the host can tell it apart from the response to its own close.
+    fn close_idle_connections(&self) {
+        let expired: Vec<Token> = {
+            let idle_timeouts = self.idle_timeouts.lock().unwrap();
+            if idle_timeouts.is_empty() {
+                return;
+            }
+            let stats = self.stats.lock().unwrap();
+            idle_timeouts.iter()
+                .filter(|(token, timeout)| {
+                    stats.get(token)
+                        .map(|stats| stats.last_activity.elapsed().unwrap_or_default() >= **timeout)
+                        .unwrap_or(false)
+                })
+                .map(|(token, _)| *token)
+                .collect()
+        };
+        
+        for token in expired {
+            let kind = token.0 & ((1 << TOKEN_KIND_BITS) - 1);
+            let socket_id = (token.0 >> TOKEN_KIND_BITS) as u32;
+            let message = SocketMessage::new(
+                if kind == TOKEN_KIND_TCP { MSG_TYPE_TCP_CLOSE } else { MSG_TYPE_UNIX_CLOSE },
+                socket_id,
+                Vec::new(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                status_data(SocketStatus::TimedOut, 0),
+            );
+            
+            // Closing only fails for unknown sockets, which have nothing left to clean up
+            let _ = if kind == TOKEN_KIND_TCP {
+                self.handle_tcp_close(message.clone())
+            } else {
+                self.handle_unix_close(message.clone())
+            };
+            
+            self.outgoing.lock().unwrap().push_back(message);
+        }
+    }
+    
+    // Refuse new connections once the configured limit is reached
+    fn check_connection_limit(&self) -> Result<(), CmioError> {
+        let open = self.unix_connections.lock().unwrap().len()
+            + self.unix_listeners.lock().unwrap().len()
+            + self.tcp_connections.lock().unwrap().len()
+            + self.unix_datagrams.lock().unwrap().len();
+        if open >= self.max_connections {
+            return Err(CmioError::TooManyConnections(self.max_connections));
+        }
+        Ok(())
+    }
+    
+    /// Reserve the socket ID for a new connection, listener or datagram socket
+    /// 
+    /// Socket ID 0 asks the manager to pick an ID that no socket of any kind uses.
+    /// A nonzero ID already in use fails with EEXIST instead of replacing that socket.
+    fn claim_socket_id(&self, requested: u32) -> Result<u32, CmioError> {
+        let in_use = |socket_id: u32| {
+            self.unix_connections.lock().unwrap().contains_key(&socket_id)
+                || self.unix_listeners.lock().unwrap().contains_key(&socket_id)
+                || self.tcp_connections.lock().unwrap().contains_key(&socket_id)
+                || self.unix_datagrams.lock().unwrap().contains_key(&socket_id)
+        };
+        
+        if requested != 0 {
+            return if in_use(requested) { Err(CmioError::SocketIdInUse(requested)) } else { Ok(requested) };
+        }
+        
+        // The connection limit keeps this from running out of IDs
+        let mut next = self.next_socket_id.lock().unwrap();
+        loop {
+            let candidate = *next;
+            *next = next.checked_add(1).unwrap_or(1);
+            if candidate != 0 && !in_use(candidate) {
+                return Ok(candidate);
+            }
+        }
+    }
+    
+    // Update the traffic counters of a connection, if it is still registered
+    fn update_stats<F: FnOnce(&mut SocketStats)>(&self, token: Token, update: F) {
+        if let Some(stats) = self.stats.lock().unwrap().get_mut(&token) {
+            update(stats);
+        }
+    }
+    
+    /// Dispatch every message of a complete batch to its handler, queueing the responses
+    /// 
+    /// Failures are reported to the host with a status and errno rather than stopping the
+    /// loop. A message that can't be decoded is answered with the InvalidMessage status,
+    /// under whatever type and socket ID its header holds, and the rest of the batch is
+    /// dropped since the next message can't be found without its length.
+    fn process_received_data(&self, data: &[u8]) {
+        let mut offset = 0;
+        
+        // Process each message in the batch
+        while offset < data.len() {
+            // Try to deserialize a message
+            let codec = *self.codec.lock().unwrap();
+            match SocketMessage::decode(&data[offset..], codec) {
+                Ok((message, consumed)) => {
+                    // Dispatch the message to the handler registered for its type
+                    let response = match self.handlers.get(&message.msg_type) {
+                        Some(handler) => handler.handle(self, message.clone()),
+                        None => Err(CmioError::ProtocolError(format!("unknown message type {:#04x}", message.msg_type))),
+                    };
+                    
+                    // Report failures to the host instead of aborting the loop
+                    let response = response.unwrap_or_else(|e| {
+                        let (status, errno) = match e.errno() {
+                            Some(errno) => (SocketStatus::from_errno(errno), errno),
+                            None => (SocketStatus::InvalidMessage, -1),
+                        };
+                        SocketMessage::new(
+                            message.msg_type,
+                            message.socket_id,
+                            message.path.clone(),
+                            message.ip_addr,
+                            message.port,
+                            status_data(status, errno),
+                        )
+                    });
+                    
+                    // Queue the response for the next CMIO transmission
+                    self.outgoing.lock().unwrap().push_back(response);
+                    
+                    // Move to the next message in the batch
+                    offset += consumed;
+                },
+                Err(e) => {
+                    warn!("Dropping {} bytes of socket batch after an invalid message: {}", data.len() - offset, e);
+                    let header = &data[offset..];
+                    let socket_id = header.get(1..5)
+                        .map_or(0, |id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]));
+                    self.outgoing.lock().unwrap().push_back(SocketMessage::new(
+                        header[0],
+                        socket_id,
+                        Vec::new(),
+                        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                        0,
+                        status_data(SocketStatus::InvalidMessage, e.errno().unwrap_or(-1)),
+                    ));
+                    return;
+                }
+            }
+        }
+    }
+    
+    fn handle_unix_connect(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self.check_connection_limit()?;
+        let socket_id = self.claim_socket_id(message.socket_id)?;
+        
+        // Connect to the Unix domain socket
+        let stream = unix_socket_addr(&message.path)
+            .and_then(|addr| UnixStream::connect_addr(&addr))
+            .map_err(|e| CmioError::io(format!("connect socket {} to {}", socket_id, display_path(&message.path)), e))?;
+        
+        // Add the connection to our map and watch it for readability
+        self.add_unix_connection(socket_id, message.path.clone(), stream)?;
+        
+        // Return success response
+        Ok(SocketMessage::new(
+            MSG_TYPE_UNIX_CONNECT,
+            socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(SocketStatus::Success, 0),
+        ))
+    }
+    
+    fn handle_unix_send(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        // Find the connection
+        let mut connections = self.unix_connections.lock().unwrap();
+        let connection = connections.get_mut(&message.socket_id);
+        
+        match connection {
+            Some((_, stream)) => {
+                // Write data to the socket
+                let token = socket_token(TOKEN_KIND_UNIX, message.socket_id);
+                let fd = stream.as_raw_fd();
+                let pending = self.send_or_queue(token, fd, stream, &message.data)
+                    .map_err(|e| {
+                        self.update_stats(token, |stats| stats.error());
+                        CmioError::io(format!("send to socket {}", message.socket_id), e)
+                    })?;
+                self.update_stats(token, |stats| stats.sent(message.data.len()));
+                
+                // Report whether the data was fully written or partly queued
+                Ok(SocketMessage::new(
+                    MSG_TYPE_UNIX_SEND,
+                    message.socket_id,
+                    message.path,
+                    message.ip_addr,
+                    message.port,
+                    send_status_data(pending),
+                ))
+            },
+            None => {
+                // Connection not found
+                Ok(SocketMessage::new(
+                    MSG_TYPE_UNIX_SEND,
+                    message.socket_id,
+                    message.path,
+                    message.ip_addr,
+                    message.port,
+                    status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+                ))
+            }
+        }
+    }
+    
+    fn handle_unix_receive(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        // Find the connection
+        let mut connections = self.unix_connections.lock().unwrap();
+        let connection = connections.get_mut(&message.socket_id);
+        
+        match connection {
+            Some((_, stream)) => {
+                // Read up to the requested size from the socket
+                let mut buffer = vec![0u8; requested_read_size(&message.data, self.cmio_max_buffer_size)];
+                match stream.read(&mut buffer) {
+                    Ok(n) => {
+                        self.update_stats(socket_token(TOKEN_KIND_UNIX, message.socket_id), |stats| stats.received(n));
+                        
+                        // Return the received data
+                        Ok(SocketMessage::new(
+                            MSG_TYPE_UNIX_RECEIVE,
+                            message.socket_id,
+                            message.path,
+                            message.ip_addr,
+                            message.port,
+                            status_payload(if n > 0 { SocketStatus::Success } else { SocketStatus::Eof }, &buffer[..n]),
+                        ))
+                    },
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::WouldBlock {
+                            // No data available
+                            Ok(SocketMessage::new(
+                                MSG_TYPE_UNIX_RECEIVE,
+                                message.socket_id,
+                                message.path,
+                                message.ip_addr,
+                                message.port,
+                                status_data(SocketStatus::WouldBlock, 0), // No data available
+                            ))
+                        } else {
+                            // Error reading from socket
+                            self.update_stats(socket_token(TOKEN_KIND_UNIX, message.socket_id), |stats| stats.error());
+                            Err(CmioError::io(format!("receive from socket {}", message.socket_id), e))
+                        }
+                    }
+                }
+            },
+            None => {
+                // Connection not found
+                Ok(SocketMessage::new(
+                    MSG_TYPE_UNIX_RECEIVE,
+                    message.socket_id,
+                    message.path,
+                    message.ip_addr,
+                    message.port,
+                    status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+                ))
+            }
+        }
+    }
+    
+    fn handle_unix_close(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self.ended.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, message.socket_id));
+        self.stats.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, message.socket_id));
+        self.idle_timeouts.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, message.socket_id));
+        self.write_queues.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, message.socket_id));
+        self.transfers.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX, message.socket_id));
+        self.stats.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX_DGRAM, message.socket_id));
+        self.idle_timeouts.lock().unwrap().remove(&socket_token(TOKEN_KIND_UNIX_DGRAM, message.socket_id));
+        
+        // Find and remove the connection
+        let mut connections = self.unix_connections.lock().unwrap();
+        let mut removed = connections.remove(&message.socket_id)
+            .map(|(_, stream)| self.unwatch(stream.as_raw_fd()));
+        
+        // The socket ID may also refer to a listener, in which case the socket file is cleaned up
+        if removed.is_none() {
+            let mut listeners = self.unix_listeners.lock().unwrap();
+            if let Some((path, listener)) = listeners.remove(&message.socket_id) {
+                self.unwatch(listener.as_raw_fd());
+                drop(listener);
+                remove_socket_file(&path);
+                removed = Some(());
+            }
+        }
+        
+        // Or to a datagram socket, whose socket file is cleaned up if it was bound to a path
+        if removed.is_none() {
+            let mut datagrams = self.unix_datagrams.lock().unwrap();
+            if let Some((path, socket)) = datagrams.remove(&message.socket_id) {
+                self.unwatch(socket.as_raw_fd());
+                drop(socket);
+                remove_socket_file(&path);
+                removed = Some(());
+            }
+        }
+        
+        match removed {
+            Some(_) => {
+                // Return success response
+                Ok(SocketMessage::new(
+                    MSG_TYPE_UNIX_CLOSE,
+                    message.socket_id,
+                    message.path,
+                    message.ip_addr,
+                    message.port,
+                    status_data(SocketStatus::Success, 0),
+                ))
+            },
+            None => {
+                // Connection not found
+                Ok(SocketMessage::new(
+                    MSG_TYPE_UNIX_CLOSE,
+                    message.socket_id,
+                    message.path,
+                    message.ip_addr,
+                    message.port,
+                    status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+                ))
+            }
+        }
+    }
+    
+    /// Create a Unix datagram socket
+    /// 
+    /// The socket is bound to the message path, or left unbound when the path is empty
+    /// (it can then only send). Received datagrams are forwarded like stream data.
+    fn handle_unix_dgram_bind(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self.check_connection_limit()?;
+        let socket_id = self.claim_socket_id(message.socket_id)?;
+        
+        let socket = if message.path.is_empty() {
+            UnixDatagram::unbound()
+        } else {
+            unix_socket_addr(&message.path).and_then(|addr| UnixDatagram::bind_addr(&addr))
+        }
+        .map_err(|e| CmioError::io(format!("bind datagram socket {} to {}", socket_id, display_path(&message.path)), e))?;
+        
+        // Set non-blocking mode so receives can be polled
+        socket.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make socket {} non-blocking", socket_id), e))?;
+        
+        // Add the socket to our map and watch it for readability
+        self.add_unix_datagram(socket_id, message.path.clone(), socket)?;
+        
+        // Return success response
+        Ok(SocketMessage::new(
+            MSG_TYPE_UNIX_DGRAM_BIND,
+            socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(SocketStatus::Success, 0),
+        ))
+    }
+    
+    /// Send the message data as one datagram to the message path
+    fn handle_unix_dgram_send_to(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let datagrams = self.unix_datagrams.lock().unwrap();
+        
+        let status = match datagrams.get(&message.socket_id) {
+            Some((_, socket)) => {
+                let token = socket_token(TOKEN_KIND_UNIX_DGRAM, message.socket_id);
+                unix_socket_addr(&message.path)
+                    .and_then(|addr| socket.send_to_addr(&message.data, &addr))
+                    .map_err(|e| {
+                        self.update_stats(token, |stats| stats.error());
+                        CmioError::io(format!("send a datagram from socket {} to {}", message.socket_id, display_path(&message.path)), e)
+                    })?;
+                self.update_stats(token, |stats| stats.sent(message.data.len()));
+                SocketStatus::Success
+            },
+            None => SocketStatus::NotFound, // Error: Connection not found
+        };
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_UNIX_DGRAM_SEND_TO,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(status, 0),
+        ))
+    }
+    
+    /// Receive one datagram
+    /// 
+    /// Like stream receives, the request data may carry the maximum size; longer datagrams
+    /// are truncated. The payload is the sender path length (u8), the sender path and the
+    /// datagram.
+    fn handle_unix_dgram_receive(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let datagrams = self.unix_datagrams.lock().unwrap();
+        let token = socket_token(TOKEN_KIND_UNIX_DGRAM, message.socket_id);
+        
+        let data = match datagrams.get(&message.socket_id) {
+            Some((_, socket)) => match recv_datagram(socket, requested_read_size(&message.data, self.cmio_max_buffer_size)) {
+                Ok(payload) => {
+                    self.update_stats(token, |stats| stats.received(payload.len() - 1 - payload[0] as usize));
+                    status_payload(SocketStatus::Success, &payload)
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => status_data(SocketStatus::WouldBlock, 0), // No data available
+                Err(e) => {
+                    // Error reading from socket
+                    self.update_stats(token, |stats| stats.error());
+                    return Err(CmioError::io(format!("receive a datagram on socket {}", message.socket_id), e));
+                }
+            },
+            None => status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+        };
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_UNIX_DGRAM_RECEIVE,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            data,
+        ))
+    }
+    
+    fn handle_unix_listen(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self.check_connection_limit()?;
+        let socket_id = self.claim_socket_id(message.socket_id)?;
+        
+        // Bind and listen on the Unix domain socket path
+        let listener = unix_socket_addr(&message.path)
+            .and_then(|addr| UnixListener::bind_addr(&addr))
+            .map_err(|e| CmioError::io(format!("listen on {} as socket {}", display_path(&message.path), socket_id), e))?;
+        
+        // Set non-blocking mode so accept can be polled, and watch for pending connections
+        // to announce them to the host
+        let watched = listener.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make socket {} non-blocking", socket_id), e))
+            .and_then(|()| self.watch(TOKEN_KIND_UNIX_LISTENER, socket_id, listener.as_raw_fd()));
+        if let Err(e) = watched {
+            remove_socket_file(&message.path);
+            return Err(e);
+        }
+        
+        // Add the listener to our map
+        {
+            let mut listeners = self.unix_listeners.lock().unwrap();
+            listeners.insert(socket_id, (message.path.clone(), listener));
+        }
+        
+        // Return success response
+        Ok(SocketMessage::new(
+            MSG_TYPE_UNIX_LISTEN,
+            socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(SocketStatus::Success, 0),
+        ))
+    }
+    
+    /// Accept a pending connection on a Unix domain socket listener
+    /// 
+    /// The request data carries the socket ID (u32, network byte order) under which
+    /// the accepted connection should be registered. The response data echoes that
+    /// socket ID when a connection was accepted, or is empty when none is pending.
+    /// Connections are usually accepted as they arrive and announced with an unsolicited
+    /// accept message, so this only finds those left over the connection limit.
+    fn handle_unix_accept(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        if message.data.len() < 4 {
+            return Err(short_data(&message, 4));
+        }
+        
+        let new_socket_id = u32::from_be_bytes([message.data[0], message.data[1], message.data[2], message.data[3]]);
+        self.check_connection_limit()?;
+        let new_socket_id = self.claim_socket_id(new_socket_id)?;
+        
+        // Find the listener
+        let listeners = self.unix_listeners.lock().unwrap();
+        let listener = listeners.get(&message.socket_id);
+        
+        match listener {
+            Some((path, listener)) => {
+                match listener.accept() {
+                    Ok((stream, _addr)) => {
+                        // Add the accepted connection to our map and watch it for readability
+                        self.add_unix_connection(new_socket_id, path.clone(), stream)?;
+                        
+                        // Notify the host of the new connection
+                        Ok(SocketMessage::new(
+                            MSG_TYPE_UNIX_ACCEPT,
+                            message.socket_id,
+                            message.path,
+                            message.ip_addr,
+                            message.port,
+                            status_payload(SocketStatus::Success, &new_socket_id.to_be_bytes()),
+                        ))
+                    },
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::WouldBlock {
+                            // No pending connection
+                            Ok(SocketMessage::new(
+                                MSG_TYPE_UNIX_ACCEPT,
+                                message.socket_id,
+                                message.path,
+                                message.ip_addr,
+                                message.port,
+                                status_data(SocketStatus::WouldBlock, 0), // No data available
+                            ))
+                        } else {
+                            // Error accepting the connection
+                            Err(CmioError::io(format!("accept on socket {}", message.socket_id), e))
+                        }
+                    }
+                }
+            },
+            None => {
+                // Listener not found
+                Ok(SocketMessage::new(
+                    MSG_TYPE_UNIX_ACCEPT,
+                    message.socket_id,
+                    message.path,
+                    message.ip_addr,
+                    message.port,
+                    status_data(SocketStatus::NotFound, 0), // Error: Listener not found
+                ))
+            }
+        }
+    }
+    
+    fn handle_tcp_connect(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self.check_connection_limit()?;
+        let socket_id = self.claim_socket_id(message.socket_id)?;
+        
+        // Connect to the TCP socket
+        let addr = match message.ip_addr {
+            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, message.port)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, message.port, 0, 0)),
+        };
+        let stream = self.open_tcp_addr(addr, self.claim_connect_timeout(message.socket_id, socket_id))?;
+        
+        // Set non-blocking mode
+        stream.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make socket {} non-blocking", socket_id), e))?;
+        
+        // Add the connection to our map and watch it for readability
+        self.add_tcp_connection(socket_id, String::new(), TcpConnection::Plain(stream))?;
+        
+        // Return success response
+        Ok(SocketMessage::new(
+            MSG_TYPE_TCP_CONNECT,
+            socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(SocketStatus::Success, 0),
+        ))
+    }
+    
+    /// Register a forward into a guest service, or unregister it
+    /// 
+    /// The socket ID is the host-chosen forward ID and the address is the guest-local
+    /// address streams opened on the forward connect to, with port 0 unregistering it.
+    /// Hosts send these right after the hello message; a registered ID fails with
+    /// EEXIST, and unregistering an unknown one has the NotFound status.
+    fn handle_forward_register(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        if message.socket_id == 0 {
+            return Err(CmioError::InvalidArgument("forward ID 0".to_string()));
+        }
+        
+        let mut forwards = self.forwards.lock().unwrap();
+        let status = if message.port == 0 {
+            match forwards.remove(&message.socket_id) {
+                Some(addr) => {
+                    info!("Unregistered forward {} to {}", message.socket_id, addr);
+                    SocketStatus::Success
+                },
+                None => SocketStatus::NotFound,
+            }
+        } else {
+            let addr = SocketAddr::new(message.ip_addr, message.port);
+            match forwards.entry(message.socket_id) {
+                Entry::Occupied(_) => return Err(CmioError::SocketIdInUse(message.socket_id)),
+                Entry::Vacant(entry) => entry.insert(addr),
+            };
+            info!("Registered forward {} to {}", message.socket_id, addr);
+            SocketStatus::Success
+        };
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_FORWARD_REGISTER,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(status, 0),
+        ))
+    }
+    
+    /// Open a stream on a registered forward, connecting it to the guest-local address
+    /// 
+    /// The data is the forward ID (4 bytes, network byte order) and the socket ID that
+    /// of the new stream, 0 letting the manager pick one. Forwards reach guest services
+    /// directly, never through the upstream proxy. The stream is then a TCP connection
+    /// like any other, and an unknown forward has the NotFound status.
+    fn handle_forward_open(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        if message.data.len() < 4 {
+            return Err(short_data(&message, 4));
+        }
+        let forward_id = u32::from_be_bytes([message.data[0], message.data[1], message.data[2], message.data[3]]);
+        
+        let addr = match self.forwards.lock().unwrap().get(&forward_id) {
+            Some(addr) => *addr,
+            None => {
+                return Ok(SocketMessage::new(
+                    MSG_TYPE_FORWARD_OPEN,
+                    message.socket_id,
+                    message.path,
+                    message.ip_addr,
+                    message.port,
+                    status_data(SocketStatus::NotFound, 0), // Error: Forward not registered
+                ));
+            }
+        };
+        
+        self.check_connection_limit()?;
+        let socket_id = self.claim_socket_id(message.socket_id)?;
+        let stream = connect_addr(addr, self.claim_connect_timeout(message.socket_id, socket_id))
+            .map_err(|e| CmioError::io(format!("connect forward {} to {}", forward_id, addr), e))?;
+        stream.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make socket {} non-blocking", socket_id), e))?;
+        self.add_tcp_connection(socket_id, String::new(), TcpConnection::Plain(stream))?;
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_FORWARD_OPEN,
+            socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(SocketStatus::Success, 0),
+        ))
+    }
+    
+    /// Connect to a TCP socket by hostname
+    /// 
+    /// The hostname is resolved via the guest resolver and each IPv4 or IPv6 address
+    /// is tried in turn. The response carries the address that was connected to.
+    fn handle_tcp_connect_host(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self.check_connection_limit()?;
+        let socket_id = self.claim_socket_id(message.socket_id)?;
+        
+        // Resolve the hostname and connect
+        let host = host_name(&message.path)?;
+        let (ip_addr, stream) = self.open_tcp_host(host, message.port, self.claim_connect_timeout(message.socket_id, socket_id))?;
+        
+        // Set non-blocking mode
+        stream.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make socket {} non-blocking", socket_id), e))?;
+        
+        // Add the connection to our map and watch it for readability
+        self.add_tcp_connection(socket_id, host.to_string(), TcpConnection::Plain(stream))?;
+        
+        // Return success response with the chosen address
+        Ok(SocketMessage::new(
+            MSG_TYPE_TCP_CONNECT_HOST,
+            socket_id,
+            message.path,
+            ip_addr,
+            message.port,
+            status_data(SocketStatus::Success, 0),
+        ))
+    }
+    
+    /// Connect to a TLS endpoint by hostname
+    /// 
+    /// The hostname is resolved like a connect-by-hostname request and is also used as
+    /// the SNI name and for certificate verification. The handshake completes before the
+    /// response is sent, after which TCP send/receive/close operate on the plaintext. It
+    /// fails with the timed out status once the peer stays silent for the connect timeout,
+    /// or DEFAULT_TLS_HANDSHAKE_TIMEOUT without one.
+    fn handle_tls_connect(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        self.check_connection_limit()?;
+        let socket_id = self.claim_socket_id(message.socket_id)?;
+        
+        let host = host_name(&message.path)?;
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|_| CmioError::InvalidArgument(format!("{} is not a valid TLS server name", host)))?;
+        
+        // Resolve the hostname and connect
+        let timeout = self.claim_connect_timeout(message.socket_id, socket_id);
+        let (ip_addr, mut stream) = self.open_tcp_host(host, message.port, timeout)?;
+        
+        // Perform the TLS handshake in blocking mode, bounded like the connect
+        let mut connection = ClientConnection::new(self.tls_config.clone(), server_name)
+            .map_err(|e| CmioError::TlsError(host.to_string(), e.to_string()))?;
+        tls_handshake(&mut connection, &mut stream, host, timeout.unwrap_or(DEFAULT_TLS_HANDSHAKE_TIMEOUT))?;
+        
+        // Set non-blocking mode
+        stream.set_nonblocking(true)
+            .map_err(|e| CmioError::io(format!("make socket {} non-blocking", socket_id), e))?;
+        
+        // Add the connection to our map and watch it for readability
+        let tls_stream = StreamOwned::new(connection, stream);
+        self.add_tcp_connection(socket_id, host.to_string(), TcpConnection::Tls(Box::new(tls_stream)))?;
+        
+        // Return success response with the chosen address
+        Ok(SocketMessage::new(
+            MSG_TYPE_TLS_CONNECT,
+            socket_id,
+            message.path,
+            ip_addr,
+            message.port,
+            status_data(SocketStatus::Success, 0),
+        ))
+    }
+    
+    // Open a TCP stream to an address, through the upstream proxy if one is configured
+    fn open_tcp_addr(&self, addr: SocketAddr, timeout: Option<Duration>) -> Result<TcpStream, CmioError> {
+        let result = match &self.upstream_proxy {
+            Some(proxy) => proxy.connect(Socks5Target::Addr(addr), timeout),
+            None => connect_addr(addr, timeout),
+        };
+        result.map_err(|e| CmioError::io(format!("connect to {}", addr), e))
+    }
+    
+    // Open a TCP stream to a hostname, through the upstream proxy if one is configured
+    fn open_tcp_host(&self, host: &str, port: u16, timeout: Option<Duration>) -> Result<(IpAddr, TcpStream), CmioError> {
+        match &self.upstream_proxy {
+            Some(proxy) => {
+                let stream = proxy.connect(Socks5Target::Host(host.to_string(), port), timeout)
+                    .map_err(|e| CmioError::io(format!("connect to {}:{} through the proxy", host, port), e))?;
+                Ok((IpAddr::V4(Ipv4Addr::UNSPECIFIED), stream))
+            },
+            None => connect_host(host, port, timeout),
+        }
+    }
+    
+    /// Resolve the hostname in the message path with the guest resolver
+    /// 
+    /// The socket ID is only echoed back. The response carries the Success status, the
+    /// number of addresses (u16 BE) and each address as family and IPv4 or IPv6 address,
+    /// or the NameNotResolved status when the lookup fails.
+    fn handle_resolve(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let host = host_name(&message.path)?;
+        
+        let data = match resolve_host(host) {
+            Ok(addrs) => {
+                let mut payload = (addrs.len().min(u16::MAX as usize) as u16).to_be_bytes().to_vec();
+                for addr in addrs.iter().take(u16::MAX as usize) {
+                    write_ip_addr(&mut payload, addr);
+                }
+                status_payload(SocketStatus::Success, &payload)
+            },
+            Err(e) => status_data(SocketStatus::NameNotResolved, e.raw_os_error().unwrap_or(0)),
+        };
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_RESOLVE,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            data,
+        ))
+    }
+    
+    // Token of the Unix or TCP connection registered under a socket ID, TCP taking precedence
+    fn stream_token(&self, socket_id: u32) -> Option<Token> {
+        if self.tcp_connections.lock().unwrap().contains_key(&socket_id) {
+            Some(socket_token(TOKEN_KIND_TCP, socket_id))
+        } else if self.unix_connections.lock().unwrap().contains_key(&socket_id) {
+            Some(socket_token(TOKEN_KIND_UNIX, socket_id))
+        } else {
+            None
+        }
+    }
+    
+    /// Stream one chunk of a large payload to a Unix or TCP connection
+    /// 
+    /// The data starts with the chunk's offset and the transfer's total length (u32 each,
+    /// network byte order), followed by the chunk payload. Chunks must arrive in order;
+    /// retransmitted bytes are acknowledged again without being written twice. The response
+    /// acknowledges the offset the next chunk must start at, with the Success status, the
+    /// WouldBlock status when the write queue is full (retry the chunk later), or the
+    /// InvalidArgument status when the chunk doesn't continue the transfer.
+    fn handle_send_chunk(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        if message.data.len() < CHUNK_HEADER_SIZE {
+            return Err(short_data(&message, CHUNK_HEADER_SIZE));
+        }
+        let offset = u32::from_be_bytes([message.data[0], message.data[1], message.data[2], message.data[3]]);
+        let total = u32::from_be_bytes([message.data[4], message.data[5], message.data[6], message.data[7]]);
+        let payload = &message.data[CHUNK_HEADER_SIZE..];
+        
+        let respond = |data| Ok(SocketMessage::new(
+            MSG_TYPE_SEND_CHUNK,
+            message.socket_id,
+            message.path.clone(),
+            message.ip_addr,
+            message.port,
+            data,
+        ));
+        
+        let token = match self.stream_token(message.socket_id) {
+            Some(token) => token,
+            None => return respond(status_data(SocketStatus::NotFound, 0)), // Error: Connection not found
+        };
+        
+        let current = self.transfers.lock().unwrap().get(&token).copied();
+        let (mut transfer, skip) = match accept_chunk(current, offset, total, payload.len()) {
+            Some(accepted) => accepted,
+            None => {
+                let received = current.map(|transfer| transfer.received).unwrap_or(0);
+                return respond(chunk_ack_data(SocketStatus::InvalidArgument, libc::EINVAL, received));
+            }
+        };
+        
+        // Write the part of the chunk that wasn't accepted before
+        let new_data = &payload[skip..];
+        if !new_data.is_empty() {
+            let result = if token == socket_token(TOKEN_KIND_TCP, message.socket_id) {
+                let mut connections = self.tcp_connections.lock().unwrap();
+                let (_, stream) = connections.get_mut(&message.socket_id).ok_or(CmioError::UnknownSocket(message.socket_id))?;
+                let fd = stream.tcp_stream().as_raw_fd();
+                let result = self.send_or_queue(token, fd, stream, new_data);
+                if stream.has_pending_output() {
+                    self.set_write_interest(token, fd, true);
+                }
+                result
+            } else {
+                let mut connections = self.unix_connections.lock().unwrap();
+                let (_, stream) = connections.get_mut(&message.socket_id).ok_or(CmioError::UnknownSocket(message.socket_id))?;
+                let fd = stream.as_raw_fd();
+                self.send_or_queue(token, fd, stream, new_data)
+            };
+            
+            match result {
+                Ok(_) => self.update_stats(token, |stats| stats.sent(new_data.len())),
+                Err(ref e) if e.raw_os_error() == Some(libc::EAGAIN) => {
+                    // Write queue full, the host retries the chunk later
+                    return respond(chunk_ack_data(SocketStatus::WouldBlock, libc::EAGAIN, transfer.received));
                 },
                 Err(e) => {
-                    // Error deserializing message, stop processing
-                    return Err(e);
+                    self.update_stats(token, |stats| stats.error());
+                    return Err(CmioError::io(format!("send a chunk to socket {}", message.socket_id), e));
                 }
             }
+            transfer.received += new_data.len() as u32;
         }
         
-        // Send all responses in a single CMIO transmission
-        if !responses.is_empty() {
-            let mut cmio = self.cmio.lock().unwrap();
-            cmio.yield_with_buffer(
-                HTIF_DEVICE_YIELD,
-                HTIF_YIELD_CMD_MANUAL,
-                UNIX_SOCKET_CMD,
-                &responses,
-            )?;
-        }
-        
-        Ok(())
+        self.transfers.lock().unwrap().insert(token, transfer);
+        respond(chunk_ack_data(SocketStatus::Success, 0, transfer.received))
     }
     
-    fn handle_unix_connect(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
-        // Connect to the Unix domain socket
-        let stream = UnixStream::connect(Path::new(&message.path))
-            .map_err(|e| CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))?;
+    /// Answer a liveness check from the host
+    /// 
+    /// The response carries the Success status, the guest's monotonic clock in nanoseconds
+    /// (u64, network byte order) and the request data echoed back, so the host can match
+    /// replies to requests and measure the round trip through the guest loop.
+    fn handle_ping(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let mut payload = monotonic_nanos().to_be_bytes().to_vec();
+        payload.extend_from_slice(&message.data);
         
-        // Add the connection to our map
-        {
-            let mut connections = self.unix_connections.lock().unwrap();
-            connections.insert(message.socket_id, (message.path.clone(), stream));
+        Ok(SocketMessage::new(
+            MSG_TYPE_PING,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_payload(SocketStatus::Success, &payload),
+        ))
+    }
+    
+    /// Negotiate the wire encoding of messages
+    /// 
+    /// The data is the codec (native or bincode) and its version. If the codec is built in
+    /// and the version matches, the response and all later messages in both directions,
+    /// including the rest of the host's current batch, use it. Otherwise the response has
+    /// the InvalidArgument status and the current codec stays in use.
+    fn handle_hello(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        if message.data.len() < 2 {
+            return Err(short_data(&message, 2));
         }
         
-        // Return success response
+        let status = match Codec::negotiate(message.data[0], message.data[1]) {
+            Some(codec) => {
+                *self.codec.lock().unwrap() = codec;
+                SocketStatus::Success
+            },
+            None => SocketStatus::InvalidArgument, // Error: Unsupported codec or version
+        };
+        
         Ok(SocketMessage::new(
-            MSG_TYPE_UNIX_CONNECT,
+            MSG_TYPE_HELLO,
             message.socket_id,
             message.path,
             message.ip_addr,
             message.port,
-            vec![0], // Success
-        ).serialize())
+            status_data(status, 0),
+        ))
     }
     
-    fn handle_unix_send(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
-        // Find the connection
-        let mut connections = self.unix_connections.lock().unwrap();
-        let connection = connections.get_mut(&message.socket_id);
-        
-        match connection {
-            Some((_, stream)) => {
-                // Write data to the socket
-                stream.write_all(&message.data)
-                    .map_err(|e| CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))?;
-                
-                // Return success response
-                Ok(SocketMessage::new(
-                    MSG_TYPE_UNIX_SEND,
-                    message.socket_id,
-                    message.path,
-                    message.ip_addr,
-                    message.port,
-                    vec![0], // Success
-                ).serialize())
+    /// Finish a chunked transfer
+    /// 
+    /// Responds with the Success status if every byte up to the total length was accepted,
+    /// or the InvalidArgument status otherwise. Either way the transfer ends and the response
+    /// carries the number of bytes accepted, so a new transfer can start at offset 0.
+    fn handle_send_end(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let data = match self.stream_token(message.socket_id) {
+            Some(token) => match self.transfers.lock().unwrap().remove(&token) {
+                Some(transfer) if transfer.received == transfer.total => {
+                    chunk_ack_data(SocketStatus::Success, 0, transfer.received)
+                },
+                Some(transfer) => chunk_ack_data(SocketStatus::InvalidArgument, libc::EINVAL, transfer.received),
+                None => chunk_ack_data(SocketStatus::InvalidArgument, libc::EINVAL, 0), // Error: No transfer in progress
             },
-            None => {
-                // Connection not found
-                Ok(SocketMessage::new(
-                    MSG_TYPE_UNIX_SEND,
-                    message.socket_id,
-                    message.path,
-                    message.ip_addr,
-                    message.port,
-                    vec![1], // Error: Connection not found
-                ).serialize())
-            }
+            None => status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+        };
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_SEND_END,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            data,
+        ))
+    }
+    
+    // The connect timeout of a connect that asked for a socket ID and was given one, if
+    // any: a timeout set on socket ID 0 applies to the next connect asking for an ID to be
+    // assigned and moves to the assigned ID, so the next such connect doesn't share it,
+    // while an assigned ID without one drops whatever an earlier failed connect left
+    fn claim_connect_timeout(&self, requested: u32, assigned: u32) -> Option<Duration> {
+        let mut timeouts = self.connect_timeouts.lock().unwrap();
+        if requested != assigned {
+            match timeouts.remove(&requested) {
+                Some(timeout) => timeouts.insert(assigned, timeout),
+                None => timeouts.remove(&assigned),
+            };
         }
+        timeouts.get(&assigned).copied()
     }
     
-    fn handle_unix_receive(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
-        // Find the connection
-        let mut connections = self.unix_connections.lock().unwrap();
-        let connection = connections.get_mut(&message.socket_id);
+    /// Configure a socket option
+    /// 
+    /// The request data carries the option (1 byte) followed by its value (u32, network
+    /// byte order). Boolean options treat any non-zero value as enabled, buffer sizes
+    /// are in bytes and timeouts in milliseconds with zero meaning no timeout. The
+    /// connect timeout is recorded for the socket ID and applies to the next connect; set on
+    /// socket ID 0, it applies to the next connect asking for an ID to be assigned.
+    /// The idle timeout applies to the open connection and is cleared when it closes.
+    fn handle_set_option(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        if message.data.len() < 5 {
+            return Err(short_data(&message, 5));
+        }
         
-        match connection {
-            Some((_, stream)) => {
-                // Read data from the socket
-                let mut buffer = vec![0u8; 4096]; // Read up to 4KB
-                match stream.read(&mut buffer) {
-                    Ok(n) => {
-                        // Return the received data
-                        Ok(SocketMessage::new(
-                            MSG_TYPE_UNIX_RECEIVE,
-                            message.socket_id,
-                            message.path,
-                            message.ip_addr,
-                            message.port,
-                            buffer[..n].to_vec(),
-                        ).serialize())
-                    },
-                    Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
-                            // No data available
-                            Ok(SocketMessage::new(
-                                MSG_TYPE_UNIX_RECEIVE,
-                                message.socket_id,
-                                message.path,
-                                message.ip_addr,
-                                message.port,
-                                vec![], // Empty data
-                            ).serialize())
-                        } else {
-                            // Error reading from socket
-                            Err(CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))
-                        }
+        let option = message.data[0];
+        let value = u32::from_be_bytes([message.data[1], message.data[2], message.data[3], message.data[4]]);
+        
+        let status = if option == SOCKET_OPTION_CONNECT_TIMEOUT {
+            let mut timeouts = self.connect_timeouts.lock().unwrap();
+            if value == 0 {
+                timeouts.remove(&message.socket_id);
+            } else {
+                timeouts.insert(message.socket_id, Duration::from_millis(value as u64));
+            }
+            SocketStatus::Success
+        } else if option == SOCKET_OPTION_IDLE_TIMEOUT {
+            let token = if self.tcp_connections.lock().unwrap().contains_key(&message.socket_id) {
+                Some(socket_token(TOKEN_KIND_TCP, message.socket_id))
+            } else if self.unix_connections.lock().unwrap().contains_key(&message.socket_id) {
+                Some(socket_token(TOKEN_KIND_UNIX, message.socket_id))
+            } else if self.unix_datagrams.lock().unwrap().contains_key(&message.socket_id) {
+                Some(socket_token(TOKEN_KIND_UNIX_DGRAM, message.socket_id))
+            } else {
+                None
+            };
+            match token {
+                Some(token) => {
+                    let mut timeouts = self.idle_timeouts.lock().unwrap();
+                    if value == 0 {
+                        timeouts.remove(&token);
+                    } else {
+                        timeouts.insert(token, Duration::from_millis(value as u64));
                     }
-                }
-            },
-            None => {
-                // Connection not found
-                Ok(SocketMessage::new(
-                    MSG_TYPE_UNIX_RECEIVE,
-                    message.socket_id,
-                    message.path,
-                    message.ip_addr,
-                    message.port,
-                    vec![1], // Error: Connection not found
-                ).serialize())
+                    SocketStatus::Success
+                },
+                None => SocketStatus::NotFound, // Error: Connection not found
             }
-        }
+        } else if let Some((_, connection)) = self.tcp_connections.lock().unwrap().get(&message.socket_id) {
+            apply_socket_option(connection.tcp_stream().as_raw_fd(), option, value, true)?
+        } else if let Some((_, stream)) = self.unix_connections.lock().unwrap().get(&message.socket_id) {
+            apply_socket_option(stream.as_raw_fd(), option, value, false)?
+        } else if let Some((_, socket)) = self.unix_datagrams.lock().unwrap().get(&message.socket_id) {
+            apply_socket_option(socket.as_raw_fd(), option, value, false)?
+        } else {
+            SocketStatus::NotFound // Error: Connection not found
+        };
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_SET_OPTION,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(status, 0),
+        ))
     }
     
-    fn handle_unix_close(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
-        // Find and remove the connection
-        let mut connections = self.unix_connections.lock().unwrap();
-        let removed = connections.remove(&message.socket_id);
+    /// Shut down the read half, write half, or both halves of a connection
+    /// 
+    /// The request data carries the direction (1 byte). The connection stays registered
+    /// until it is closed, so the other half can still be used after a half-close.
+    fn handle_shutdown(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        if message.data.is_empty() {
+            return Err(short_data(&message, 1));
+        }
         
-        match removed {
-            Some(_) => {
-                // Return success response
-                Ok(SocketMessage::new(
-                    MSG_TYPE_UNIX_CLOSE,
-                    message.socket_id,
-                    message.path,
-                    message.ip_addr,
-                    message.port,
-                    vec![0], // Success
-                ).serialize())
+        let how = match message.data[0] {
+            SHUTDOWN_READ => Some(Shutdown::Read),
+            SHUTDOWN_WRITE => Some(Shutdown::Write),
+            SHUTDOWN_BOTH => Some(Shutdown::Both),
+            _ => None,
+        };
+        
+        let status = match how {
+            Some(how) => {
+                if let Some((_, connection)) = self.tcp_connections.lock().unwrap().get_mut(&message.socket_id) {
+                    connection.shutdown(how)
+                        .map_err(|e| CmioError::io(format!("shut down socket {}", message.socket_id), e))?;
+                    SocketStatus::Success
+                } else if let Some((_, stream)) = self.unix_connections.lock().unwrap().get(&message.socket_id) {
+                    stream.shutdown(how)
+                        .map_err(|e| CmioError::io(format!("shut down socket {}", message.socket_id), e))?;
+                    SocketStatus::Success
+                } else {
+                    SocketStatus::NotFound // Error: Connection not found
+                }
             },
-            None => {
-                // Connection not found
-                Ok(SocketMessage::new(
-                    MSG_TYPE_UNIX_CLOSE,
-                    message.socket_id,
-                    message.path,
-                    message.ip_addr,
-                    message.port,
-                    vec![1], // Error: Connection not found
-                ).serialize())
+            None => SocketStatus::InvalidArgument, // Error: Invalid direction
+        };
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_SHUTDOWN,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_data(status, 0),
+        ))
+    }
+    
+    /// Drain every readable registered socket in one round trip
+    /// 
+    /// The socket ID of the request is ignored. The response carries the Success status
+    /// followed by one entry per socket that had data, EOF or an error, as packed by
+    /// encode_receive_all. Sockets with nothing to read are left out.
+    fn handle_receive_all(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let messages = self.read_readable_data()?;
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_RECEIVE_ALL,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_payload(SocketStatus::Success, &encode_receive_all(&messages)),
+        ))
+    }
+    
+    /// List every open connection and listener
+    /// 
+    /// The socket ID of the request is ignored. The response carries the Success status,
+    /// the number of entries (u32 BE) and one entry per socket ID, in ascending order, as
+    /// written by write_connection_entry.
+    fn handle_list_connections(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let ended = self.ended.lock().unwrap();
+        let state = |kind: usize, socket_id: u32| {
+            if ended.contains(&socket_token(kind, socket_id)) {
+                CONNECTION_STATE_ENDED
+            } else {
+                CONNECTION_STATE_CONNECTED
             }
+        };
+        
+        let mut entries = Vec::new();
+        for (socket_id, (path, _)) in self.unix_connections.lock().unwrap().iter() {
+            entries.push((*socket_id, CONNECTION_KIND_UNIX, state(TOKEN_KIND_UNIX, *socket_id), path.clone(), None));
+        }
+        for (socket_id, (path, _)) in self.unix_listeners.lock().unwrap().iter() {
+            entries.push((*socket_id, CONNECTION_KIND_UNIX_LISTENER, CONNECTION_STATE_LISTENING, path.clone(), None));
+        }
+        for (socket_id, (path, _)) in self.unix_datagrams.lock().unwrap().iter() {
+            entries.push((*socket_id, CONNECTION_KIND_UNIX_DGRAM, CONNECTION_STATE_CONNECTED, path.clone(), None));
+        }
+        for (socket_id, (name, connection)) in self.tcp_connections.lock().unwrap().iter() {
+            let kind = match connection {
+                TcpConnection::Plain(_) => CONNECTION_KIND_TCP,
+                TcpConnection::Tls(_) => CONNECTION_KIND_TLS,
+            };
+            let peer = connection.tcp_stream().peer_addr().ok();
+            entries.push((*socket_id, kind, state(TOKEN_KIND_TCP, *socket_id), name.as_bytes().to_vec(), peer));
+        }
+        entries.sort_by_key(|entry| entry.0);
+        
+        let mut payload = (entries.len() as u32).to_be_bytes().to_vec();
+        for (socket_id, kind, state, name, peer) in entries {
+            write_connection_entry(&mut payload, socket_id, kind, state, &name, peer);
         }
+        
+        Ok(SocketMessage::new(
+            MSG_TYPE_LIST_CONNECTIONS,
+            message.socket_id,
+            message.path,
+            message.ip_addr,
+            message.port,
+            status_payload(SocketStatus::Success, &payload),
+        ))
     }
     
-    fn handle_tcp_connect(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
-        // Connect to the TCP socket
-        let addr = format!("{}.{}.{}.{}:{}", 
-            message.ip_addr[0], message.ip_addr[1], 
-            message.ip_addr[2], message.ip_addr[3], 
-            message.port);
-        let stream = TcpStream::connect(&addr)
-            .map_err(|e| CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))?;
+    /// Query the traffic counters of one or all connections
+    /// 
+    /// With empty data, reports the Unix and TCP connections registered under the socket ID
+    /// of the request, or NotFound when there are none. With STATS_SCOPE_ALL as data,
+    /// reports every connection. The response carries the Success status, the number of
+    /// entries (u32 BE) and the entries in ascending socket ID order, as written by
+    /// SocketStats::write_entry.
+    fn handle_stats(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        let all = match message.data.first() {
+            None => false,
+            Some(&STATS_SCOPE_ALL) => true,
+            Some(&scope) => return Err(CmioError::ProtocolError(format!("unknown stats scope {}", scope))),
+        };
         
-        // Set non-blocking mode
-        stream.set_nonblocking(true)
-            .map_err(|e| CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))?;
+        let stats = self.stats.lock().unwrap();
+        let mut entries: Vec<(u32, u8, &SocketStats)> = stats.iter()
+            .map(|(token, stats)| {
+                let kind = token.0 & ((1 << TOKEN_KIND_BITS) - 1);
+                let socket_id = (token.0 >> TOKEN_KIND_BITS) as u32;
+                let kind = match kind {
+                    TOKEN_KIND_UNIX => CONNECTION_KIND_UNIX,
+                    TOKEN_KIND_UNIX_DGRAM => CONNECTION_KIND_UNIX_DGRAM,
+                    _ => CONNECTION_KIND_TCP,
+                };
+                (socket_id, kind, stats)
+            })
+            .filter(|(socket_id, _, _)| all || *socket_id == message.socket_id)
+            .collect();
+        entries.sort_by_key(|(socket_id, kind, _)| (*socket_id, *kind));
         
-        // Add the connection to our map
-        {
-            let mut connections = self.tcp_connections.lock().unwrap();
-            connections.insert(message.socket_id, (message.path.clone(), stream));
-        }
+        let data = if entries.is_empty() && !all {
+            status_data(SocketStatus::NotFound, 0) // Error: Connection not found
+        } else {
+            let mut payload = (entries.len() as u32).to_be_bytes().to_vec();
+            for (socket_id, kind, stats) in entries {
+                stats.write_entry(&mut payload, socket_id, kind);
+            }
+            status_payload(SocketStatus::Success, &payload)
+        };
         
-        // Return success response
         Ok(SocketMessage::new(
-            MSG_TYPE_TCP_CONNECT,
+            MSG_TYPE_STATS,
             message.socket_id,
             message.path,
             message.ip_addr,
             message.port,
-            vec![0], // Success
-        ).serialize())
+            data,
+        ))
     }
     
-    fn handle_tcp_send(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
+    fn handle_tcp_send(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
         // Find the connection
         let mut connections = self.tcp_connections.lock().unwrap();
         let connection = connections.get_mut(&message.socket_id);
@@ -436,18 +2893,29 @@ impl SocketManager {
         match connection {
             Some((_, stream)) => {
                 // Write data to the socket
-                stream.write_all(&message.data)
-                    .map_err(|e| CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))?;
+                let token = socket_token(TOKEN_KIND_TCP, message.socket_id);
+                let fd = stream.tcp_stream().as_raw_fd();
+                let pending = self.send_or_queue(token, fd, stream, &message.data)
+                    .map_err(|e| {
+                        self.update_stats(token, |stats| stats.error());
+                        CmioError::io(format!("send to socket {}", message.socket_id), e)
+                    })?;
+                self.update_stats(token, |stats| stats.sent(message.data.len()));
                 
-                // Return success response
+                // TLS records rustls couldn't write yet are flushed on writability too
+                if stream.has_pending_output() {
+                    self.set_write_interest(token, fd, true);
+                }
+                
+                // Report whether the data was fully written or partly queued
                 Ok(SocketMessage::new(
                     MSG_TYPE_TCP_SEND,
                     message.socket_id,
                     message.path,
                     message.ip_addr,
                     message.port,
-                    vec![0], // Success
-                ).serialize())
+                    send_status_data(pending),
+                ))
             },
             None => {
                 // Connection not found
@@ -457,23 +2925,25 @@ impl SocketManager {
                     message.path,
                     message.ip_addr,
                     message.port,
-                    vec![1], // Error: Connection not found
-                ).serialize())
+                    status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+                ))
             }
         }
     }
     
-    fn handle_tcp_receive(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
+    fn handle_tcp_receive(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
         // Find the connection
         let mut connections = self.tcp_connections.lock().unwrap();
         let connection = connections.get_mut(&message.socket_id);
         
         match connection {
             Some((_, stream)) => {
-                // Read data from the socket
-                let mut buffer = vec![0u8; 4096]; // Read up to 4KB
+                // Read up to the requested size from the socket
+                let mut buffer = vec![0u8; requested_read_size(&message.data, self.cmio_max_buffer_size)];
                 match stream.read(&mut buffer) {
                     Ok(n) => {
+                        self.update_stats(socket_token(TOKEN_KIND_TCP, message.socket_id), |stats| stats.received(n));
+                        
                         // Return the received data
                         Ok(SocketMessage::new(
                             MSG_TYPE_TCP_RECEIVE,
@@ -481,8 +2951,8 @@ impl SocketManager {
                             message.path,
                             message.ip_addr,
                             message.port,
-                            buffer[..n].to_vec(),
-                        ).serialize())
+                            status_payload(if n > 0 { SocketStatus::Success } else { SocketStatus::Eof }, &buffer[..n]),
+                        ))
                     },
                     Err(e) => {
                         if e.kind() == io::ErrorKind::WouldBlock {
@@ -493,11 +2963,12 @@ impl SocketManager {
                                 message.path,
                                 message.ip_addr,
                                 message.port,
-                                vec![], // Empty data
-                            ).serialize())
+                                status_data(SocketStatus::WouldBlock, 0), // No data available
+                            ))
                         } else {
                             // Error reading from socket
-                            Err(CmioError::SetupError(e.raw_os_error().unwrap_or(-1)))
+                            self.update_stats(socket_token(TOKEN_KIND_TCP, message.socket_id), |stats| stats.error());
+                            Err(CmioError::io(format!("receive from socket {}", message.socket_id), e))
                         }
                     }
                 }
@@ -510,16 +2981,25 @@ impl SocketManager {
                     message.path,
                     message.ip_addr,
                     message.port,
-                    vec![1], // Error: Connection not found
-                ).serialize())
+                    status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+                ))
             }
         }
     }
     
-    fn handle_tcp_close(&self, message: SocketMessage) -> Result<Vec<u8>, CmioError> {
+    fn handle_tcp_close(&self, message: SocketMessage) -> Result<SocketMessage, CmioError> {
+        // Forget any connect timeout configured for this socket ID
+        self.connect_timeouts.lock().unwrap().remove(&message.socket_id);
+        self.ended.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, message.socket_id));
+        self.stats.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, message.socket_id));
+        self.idle_timeouts.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, message.socket_id));
+        self.write_queues.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, message.socket_id));
+        self.transfers.lock().unwrap().remove(&socket_token(TOKEN_KIND_TCP, message.socket_id));
+        
         // Find and remove the connection
         let mut connections = self.tcp_connections.lock().unwrap();
-        let removed = connections.remove(&message.socket_id);
+        let removed = connections.remove(&message.socket_id)
+            .map(|(_, connection)| self.unwatch(connection.tcp_stream().as_raw_fd()));
         
         match removed {
             Some(_) => {
@@ -530,8 +3010,8 @@ impl SocketManager {
                     message.path,
                     message.ip_addr,
                     message.port,
-                    vec![0], // Success
-                ).serialize())
+                    status_data(SocketStatus::Success, 0),
+                ))
             },
             None => {
                 // Connection not found
@@ -541,59 +3021,672 @@ impl SocketManager {
                     message.path,
                     message.ip_addr,
                     message.port,
-                    vec![1], // Error: Connection not found
-                ).serialize())
+                    status_data(SocketStatus::NotFound, 0), // Error: Connection not found
+                ))
             }
         }
     }
-}
+}
+
+impl Subsystem for SocketManager {
+    /// Hand out the next batch of messages for the host, as run_loop would send
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        let batch = self.next_batch(max_len.min(self.cmio_max_buffer_size))?;
+        buffer.extend_from_slice(&batch);
+        Ok(())
+    }
+    
+    /// Process the requests of a batch from the host, queueing their responses
+    fn handle_rx(&mut self, data: &[u8], reason: u16) -> Result<(), CmioError> {
+        self.receive(data, reason);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unix_connect_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_UNIX_CONNECT,
+            0x12345678,
+            b"/tmp/test.sock".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED), // IP not used for Unix connects
+            0,            // Port not used for Unix connects
+            vec![],      // No data for connect messages
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_UNIX_CONNECT);
+        assert_eq!(deserialized.socket_id, 0x12345678);
+        assert_eq!(deserialized.path, b"/tmp/test.sock");
+        assert_eq!(deserialized.ip_addr, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(deserialized.port, 0);
+        assert_eq!(deserialized.data, vec![]);
+    }
+
+    #[test]
+    fn test_unix_listen_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_UNIX_LISTEN,
+            0x0badf00d,
+            b"/tmp/listen.sock".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED), // IP not used for Unix listens
+            0,            // Port not used for Unix listens
+            vec![],      // No data for listen messages
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_UNIX_LISTEN);
+        assert_eq!(deserialized.socket_id, 0x0badf00d);
+        assert_eq!(deserialized.path, b"/tmp/listen.sock");
+        assert_eq!(deserialized.data, vec![]);
+    }
+
+    #[test]
+    fn test_unix_accept_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_UNIX_ACCEPT,
+            0x0badf00d,
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            0x00000042u32.to_be_bytes().to_vec(), // Socket ID for the accepted connection
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_UNIX_ACCEPT);
+        assert_eq!(deserialized.socket_id, 0x0badf00d);
+        assert_eq!(deserialized.data, vec![0, 0, 0, 0x42]);
+    }
+
+    #[test]
+    fn test_tcp_connect_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_TCP_CONNECT,
+            0x87654321,
+            b"".to_vec(), // Path not used for TCP connects
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            443,
+            vec![], // No data for connect messages
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_TCP_CONNECT);
+        assert_eq!(deserialized.socket_id, 0x87654321);
+        assert_eq!(deserialized.path, b"");
+        assert_eq!(deserialized.ip_addr, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(deserialized.port, 443);
+        assert_eq!(deserialized.data, vec![]);
+    }
+
+    #[test]
+    fn test_tcp_connect_ipv6_message() {
+        let ip_addr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let message = SocketMessage::new(
+            MSG_TYPE_TCP_CONNECT,
+            0x87654321,
+            b"".to_vec(), // Path not used for TCP connects
+            ip_addr,
+            443,
+            vec![], // No data for connect messages
+        );
+
+        let serialized = message.serialize().unwrap();
+        // 1 (type) + 4 (socket_id) + 1 (family) + 16 (ip) + 2 (port) + 4 (data length)
+        assert_eq!(serialized.len(), 28);
+        assert_eq!(serialized[5], ADDR_FAMILY_IPV6);
+
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_TCP_CONNECT);
+        assert_eq!(deserialized.ip_addr, ip_addr);
+        assert_eq!(deserialized.port, 443);
+
+        // Unknown address family is rejected
+        let mut invalid_family = serialized.clone();
+        invalid_family[5] = 0x05;
+        assert!(SocketMessage::deserialize(&invalid_family).is_err());
+    }
+
+    #[test]
+    fn test_tcp_connect_host_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_TCP_CONNECT_HOST,
+            0x11223344,
+            b"example.com".to_vec(),
+            IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)), // Resolved address, only meaningful in responses
+            8080,
+            vec![], // No data for connect messages
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_TCP_CONNECT_HOST);
+        assert_eq!(deserialized.socket_id, 0x11223344);
+        assert_eq!(deserialized.path, b"example.com");
+        assert_eq!(deserialized.ip_addr, IpAddr::V4(Ipv4Addr::new(93, 184, 216, 34)));
+        assert_eq!(deserialized.port, 8080);
+        assert_eq!(deserialized.data, vec![]);
+
+        // Truncated hostname connect is rejected
+        assert!(SocketMessage::deserialize(&serialized[..serialized.len() - 1]).is_err());
+
+        // A hostname too long for its length byte isn't serialized, and is dropped from a batch
+        let mut oversized = message.clone();
+        oversized.path = vec![b'a'; 256];
+        assert!(matches!(oversized.serialize(), Err(CmioError::InvalidArgument(_))));
+        let mut outgoing = VecDeque::from([oversized, message]);
+        assert_eq!(take_batch(&mut outgoing, 4096, Codec::Native), serialized);
+    }
 
-#[cfg(all(test, not(target_arch = "riscv64")))]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_tls_connect_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_TLS_CONNECT,
+            0x55667788,
+            b"example.com".to_vec(), // Hostname is also the SNI name
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            443,
+            vec![], // No data for connect messages
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_TLS_CONNECT);
+        assert_eq!(deserialized.socket_id, 0x55667788);
+        assert_eq!(deserialized.path, b"example.com");
+        assert_eq!(deserialized.port, 443);
+    }
 
     #[test]
-    fn test_unix_connect_message() {
+    fn test_set_option_message() {
+        let mut data = vec![SOCKET_OPTION_READ_TIMEOUT];
+        data.extend_from_slice(&1500u32.to_be_bytes());
+        let message = SocketMessage::new(
+            MSG_TYPE_SET_OPTION,
+            0x01020304,
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            data,
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_SET_OPTION);
+        assert_eq!(deserialized.socket_id, 0x01020304);
+        assert_eq!(deserialized.data, vec![SOCKET_OPTION_READ_TIMEOUT, 0, 0, 0x05, 0xdc]);
+    }
+
+    #[test]
+    fn test_apply_socket_option() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let fd = stream.as_raw_fd();
+
+        assert_eq!(apply_socket_option(fd, SOCKET_OPTION_SEND_BUFFER_SIZE, 65536, false).unwrap(), SocketStatus::Success);
+        assert_eq!(apply_socket_option(fd, SOCKET_OPTION_READ_TIMEOUT, 2000, false).unwrap(), SocketStatus::Success);
+        assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_secs(2)));
+
+        // TCP-only options are not supported on Unix sockets
+        assert_eq!(apply_socket_option(fd, SOCKET_OPTION_TCP_NODELAY, 1, false).unwrap(), SocketStatus::InvalidArgument);
+        assert_eq!(apply_socket_option(fd, 0xFF, 0, false).unwrap(), SocketStatus::InvalidArgument);
+    }
+
+    #[test]
+    fn test_shutdown_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_SHUTDOWN,
+            0x0a0b0c0d,
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            vec![SHUTDOWN_WRITE],
+        );
+
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+
+        assert_eq!(deserialized.msg_type, MSG_TYPE_SHUTDOWN);
+        assert_eq!(deserialized.socket_id, 0x0a0b0c0d);
+        assert_eq!(deserialized.data, vec![SHUTDOWN_WRITE]);
+    }
+
+    #[test]
+    fn test_connect_response_status() {
         let message = SocketMessage::new(
             MSG_TYPE_UNIX_CONNECT,
             0x12345678,
-            "/tmp/test.sock".to_string(),
-            [0, 0, 0, 0], // IP not used for Unix connects
-            0,            // Port not used for Unix connects
-            vec![],      // No data for connect messages
+            b"/tmp/test.sock".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            status_data(SocketStatus::from_errno(libc::ECONNREFUSED), libc::ECONNREFUSED),
         );
 
-        let serialized = message.serialize();
-        let deserialized = SocketMessage::deserialize(&serialized).unwrap();
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
 
-        assert_eq!(deserialized.msg_type, MSG_TYPE_UNIX_CONNECT);
-        assert_eq!(deserialized.socket_id, 0x12345678);
-        assert_eq!(deserialized.path, "/tmp/test.sock");
-        assert_eq!(deserialized.ip_addr, [0, 0, 0, 0]);
-        assert_eq!(deserialized.port, 0);
-        assert_eq!(deserialized.data, vec![]);
+        assert_eq!(deserialized.path, b"/tmp/test.sock");
+        assert_eq!(deserialized.data[0], SocketStatus::ConnectionRefused as u8);
+        assert_eq!(i32::from_be_bytes([deserialized.data[1], deserialized.data[2], deserialized.data[3], deserialized.data[4]]), libc::ECONNREFUSED);
     }
 
     #[test]
-    fn test_tcp_connect_message() {
+    fn test_status_from_errno() {
+        assert_eq!(SocketStatus::from_errno(0), SocketStatus::Success);
+        assert_eq!(SocketStatus::from_errno(libc::ECONNRESET), SocketStatus::ConnectionReset);
+        assert_eq!(SocketStatus::from_errno(libc::EPIPE), SocketStatus::ConnectionReset);
+        assert_eq!(SocketStatus::from_errno(libc::ETIMEDOUT), SocketStatus::TimedOut);
+        assert_eq!(SocketStatus::from_errno(libc::ENOENT), SocketStatus::PathNotFound);
+        assert_eq!(SocketStatus::from_errno(libc::EMFILE), SocketStatus::TooManyConnections);
+        assert_eq!(SocketStatus::from_errno(libc::EIO), SocketStatus::Other);
+
+        assert_eq!(status_payload(SocketStatus::Success, &[7, 8]), vec![0, 0, 0, 0, 0, 7, 8]);
+    }
+
+    #[test]
+    fn test_mixed_batch() {
+        let messages = vec![
+            SocketMessage::new(
+                MSG_TYPE_UNIX_CONNECT,
+                1,
+                b"/tmp/test.sock".to_vec(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                vec![],
+            ),
+            SocketMessage::new(
+                MSG_TYPE_TCP_CONNECT,
+                2,
+                b"".to_vec(),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+                8080,
+                vec![],
+            ),
+            SocketMessage::new(
+                MSG_TYPE_UNIX_SEND,
+                1,
+                b"".to_vec(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                b"hello".to_vec(),
+            ),
+            SocketMessage::new(
+                MSG_TYPE_TCP_CONNECT_HOST,
+                3,
+                b"example.com".to_vec(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                443,
+                vec![],
+            ),
+            SocketMessage::new(
+                MSG_TYPE_TCP_RECEIVE,
+                2,
+                b"".to_vec(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                vec![],
+            ),
+        ];
+
+        let mut batch = Vec::new();
+        for message in &messages {
+            batch.extend_from_slice(&message.serialize().unwrap());
+        }
+
+        // Walk the batch using the consumed lengths
+        let mut offset = 0;
+        let mut walked = Vec::new();
+        while offset < batch.len() {
+            let (message, consumed) = SocketMessage::deserialize(&batch[offset..]).unwrap();
+            walked.push(message);
+            offset += consumed;
+        }
+
+        assert_eq!(offset, batch.len());
+        assert_eq!(walked.len(), messages.len());
+        for (walked, expected) in walked.iter().zip(&messages) {
+            assert_eq!(walked.msg_type, expected.msg_type);
+            assert_eq!(walked.socket_id, expected.socket_id);
+            assert_eq!(walked.path, expected.path);
+            assert_eq!(walked.ip_addr, expected.ip_addr);
+            assert_eq!(walked.port, expected.port);
+            assert_eq!(walked.data, expected.data);
+        }
+    }
+
+    #[test]
+    fn test_requested_read_size() {
+        // No size requested, or zero, falls back to the default
+        assert_eq!(requested_read_size(&[], 65536), MAX_READ_SIZE);
+        assert_eq!(requested_read_size(&0u32.to_be_bytes(), 65536), MAX_READ_SIZE);
+
+        // Small control reads and large bulk reads
+        assert_eq!(requested_read_size(&16u32.to_be_bytes(), 65536), 16);
+        assert_eq!(requested_read_size(&32768u32.to_be_bytes(), 65536), 32768);
+
+        // Bounded by what fits in the CMIO buffer
+        assert_eq!(
+            requested_read_size(&u32::MAX.to_be_bytes(), 65536),
+            65536 - DATA_HEADER_SIZE - STATUS_SIZE
+        );
+        assert_eq!(requested_read_size(&[], 1024), 1024 - DATA_HEADER_SIZE - STATUS_SIZE);
+    }
+
+    #[test]
+    fn test_unix_dgram_send_to_message() {
         let message = SocketMessage::new(
-            MSG_TYPE_TCP_CONNECT,
-            0x87654321,
-            "".to_string(), // Path not used for TCP connects
-            [10, 0, 0, 1],
-            443,
-            vec![], // No data for connect messages
+            MSG_TYPE_UNIX_DGRAM_SEND_TO,
+            9,
+            b"/run/systemd/journal/socket".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            b"MESSAGE=hello".to_vec(),
         );
 
-        let serialized = message.serialize();
-        let deserialized = SocketMessage::deserialize(&serialized).unwrap();
+        let serialized = message.serialize().unwrap();
+        assert_eq!(serialized.len(), 1 + 4 + 1 + 27 + 4 + 13);
+        assert!(!message.is_chunkable());
 
-        assert_eq!(deserialized.msg_type, MSG_TYPE_TCP_CONNECT);
-        assert_eq!(deserialized.socket_id, 0x87654321);
-        assert_eq!(deserialized.path, "");
-        assert_eq!(deserialized.ip_addr, [10, 0, 0, 1]);
-        assert_eq!(deserialized.port, 443);
-        assert_eq!(deserialized.data, vec![]);
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+        assert_eq!(deserialized.msg_type, MSG_TYPE_UNIX_DGRAM_SEND_TO);
+        assert_eq!(deserialized.path, b"/run/systemd/journal/socket");
+        assert_eq!(deserialized.data, b"MESSAGE=hello");
+    }
+
+    #[test]
+    fn test_abstract_unix_address() {
+        // Abstract names are raw bytes after a leading NUL, and need not be UTF-8
+        let mut path = format!("\0tapcmio-abstract-{}", std::process::id()).into_bytes();
+        path.push(0xff);
+
+        let message = SocketMessage::new(
+            MSG_TYPE_UNIX_LISTEN,
+            1,
+            path.clone(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            vec![],
+        );
+        let (deserialized, _) = SocketMessage::deserialize(&message.serialize().unwrap()).unwrap();
+        assert_eq!(deserialized.path, path);
+
+        let addr = unix_socket_addr(&path).unwrap();
+        assert_eq!(addr.as_abstract_name(), Some(&path[1..]));
+        assert_eq!(unix_addr_bytes(&addr), path);
+
+        let listener = UnixListener::bind_addr(&addr).unwrap();
+        let _stream = UnixStream::connect_addr(&unix_socket_addr(&path).unwrap()).unwrap();
+        assert!(listener.accept().is_ok());
+
+        // Without a leading NUL the bytes are a filesystem path
+        let addr = unix_socket_addr(b"/tmp/test.sock").unwrap();
+        assert_eq!(addr.as_pathname(), Some(Path::new("/tmp/test.sock")));
+        assert_eq!(unix_addr_bytes(&addr), b"/tmp/test.sock");
+    }
+
+    #[test]
+    fn test_resolve_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_RESOLVE,
+            3,
+            b"localhost".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            vec![],
+        );
+
+        let serialized = message.serialize().unwrap();
+        assert_eq!(serialized.len(), 1 + 4 + 1 + 9 + 4);
+
+        let (deserialized, _) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized.msg_type, MSG_TYPE_RESOLVE);
+        assert_eq!(deserialized.path, b"localhost");
+
+        let addrs = resolve_host("127.0.0.1").unwrap();
+        assert_eq!(addrs, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    }
+
+    #[test]
+    fn test_recv_datagram() {
+        let path = std::env::temp_dir().join(format!("tapcmio-dgram-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let sender = UnixDatagram::unbound().unwrap();
+
+        sender.send_to(b"ping", &path).unwrap();
+        let payload = recv_datagram(&receiver, 64).unwrap();
+        assert_eq!(payload, vec![0, b'p', b'i', b'n', b'g']);
+
+        // Datagrams longer than the maximum size are truncated
+        sender.send_to(b"truncated", &path).unwrap();
+        assert_eq!(recv_datagram(&receiver, 5).unwrap(), vec![0, b't', b'r', b'u', b'n', b'c']);
+
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(encode_datagram(b"/a", b"x"), vec![2, b'/', b'a', b'x']);
+    }
+
+    #[test]
+    fn test_write_connection_entry() {
+        let mut buffer = Vec::new();
+        write_connection_entry(&mut buffer, 7, CONNECTION_KIND_UNIX, CONNECTION_STATE_ENDED, b"/tmp/s", None);
+
+        assert_eq!(&buffer[0..4], &7u32.to_be_bytes());
+        assert_eq!(buffer[4], CONNECTION_KIND_UNIX);
+        assert_eq!(buffer[5], CONNECTION_STATE_ENDED);
+        assert_eq!(buffer[6], 6);
+        assert_eq!(&buffer[7..13], b"/tmp/s");
+        assert_eq!(buffer[13], ADDR_FAMILY_IPV4);
+        assert_eq!(&buffer[14..18], &[0, 0, 0, 0]);
+        assert_eq!(&buffer[18..20], &0u16.to_be_bytes());
+        assert_eq!(buffer.len(), 20);
+
+        let peer: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let mut buffer = Vec::new();
+        write_connection_entry(&mut buffer, 8, CONNECTION_KIND_TLS, CONNECTION_STATE_CONNECTED, b"example.com", Some(peer));
+
+        assert_eq!(buffer[4], CONNECTION_KIND_TLS);
+        assert_eq!(buffer[6], 11);
+        let mut offset = 7 + 11;
+        assert_eq!(read_ip_addr(&buffer, &mut offset).unwrap(), peer.ip());
+        assert_eq!(&buffer[offset..offset + 2], &443u16.to_be_bytes());
+    }
+
+    #[test]
+    fn test_socket_stats_entry() {
+        let mut stats = SocketStats::new();
+        stats.sent(100);
+        stats.sent(20);
+        stats.received(7);
+        stats.error();
+        stats.last_activity = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+        let mut buffer = Vec::new();
+        stats.write_entry(&mut buffer, 42, CONNECTION_KIND_TCP);
+
+        assert_eq!(buffer.len(), 4 + 1 + 8 + 8 + 4 + 8);
+        assert_eq!(&buffer[0..4], &42u32.to_be_bytes());
+        assert_eq!(buffer[4], CONNECTION_KIND_TCP);
+        assert_eq!(&buffer[5..13], &120u64.to_be_bytes());
+        assert_eq!(&buffer[13..21], &7u64.to_be_bytes());
+        assert_eq!(&buffer[21..25], &1u32.to_be_bytes());
+        assert_eq!(&buffer[25..33], &1_700_000_000_123u64.to_be_bytes());
+    }
+
+    #[test]
+    fn test_encode_receive_all() {
+        let messages = vec![
+            SocketMessage::new(
+                MSG_TYPE_UNIX_RECEIVE,
+                1,
+                b"".to_vec(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                status_payload(SocketStatus::Success, b"abc"),
+            ),
+            SocketMessage::new(
+                MSG_TYPE_TCP_RECEIVE,
+                2,
+                b"".to_vec(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                status_data(SocketStatus::Eof, 0),
+            ),
+        ];
+
+        let payload = encode_receive_all(&messages);
+        assert_eq!(payload.len(), 2 * 9 + 8 + 5);
+
+        // First entry: Unix socket 1 with three bytes of data
+        assert_eq!(payload[0], MSG_TYPE_UNIX_RECEIVE);
+        assert_eq!(&payload[1..5], &1u32.to_be_bytes());
+        assert_eq!(&payload[5..9], &8u32.to_be_bytes());
+        assert_eq!(payload[9], SocketStatus::Success as u8);
+        assert_eq!(&payload[14..17], b"abc");
+
+        // Second entry: TCP socket 2 at end of stream
+        assert_eq!(payload[17], MSG_TYPE_TCP_RECEIVE);
+        assert_eq!(&payload[18..22], &2u32.to_be_bytes());
+        assert_eq!(&payload[22..26], &5u32.to_be_bytes());
+        assert_eq!(payload[26], SocketStatus::Eof as u8);
+
+        assert!(encode_receive_all(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_reassemble_split_message() {
+        let message = SocketMessage::new(
+            MSG_TYPE_TCP_SEND,
+            0x12345678,
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            (0..64).collect(),
+        );
+        let serialized = message.serialize().unwrap();
+
+        // The message straddles three yields
+        let mut pending = Vec::new();
+        assert_eq!(reassemble(&mut pending, &serialized[..10], true), None);
+        assert_eq!(reassemble(&mut pending, &serialized[10..40], true), None);
+        let complete = reassemble(&mut pending, &serialized[40..], false).unwrap();
+
+        assert_eq!(complete, serialized);
+        assert!(pending.is_empty());
+
+        let (deserialized, consumed) = SocketMessage::deserialize(&complete).unwrap();
+        assert_eq!(consumed, serialized.len());
+        assert_eq!(deserialized.data, message.data);
+
+        // Unflagged batches pass straight through
+        assert_eq!(reassemble(&mut pending, &serialized, false).unwrap(), serialized);
+    }
+
+    #[test]
+    fn test_more_flag() {
+        let mut message = SocketMessage::new(
+            MSG_TYPE_TCP_RECEIVE,
+            0x12345678,
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            vec![1, 2, 3],
+        );
+        message.more = true;
+
+        let serialized = message.serialize().unwrap();
+        assert_eq!(serialized[0], MSG_TYPE_TCP_RECEIVE | MSG_FLAG_MORE);
+
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+        assert_eq!(deserialized.msg_type, MSG_TYPE_TCP_RECEIVE);
+        assert!(deserialized.more);
+        assert_eq!(deserialized.data, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_take_batch_chunks_large_messages() {
+        let payload: Vec<u8> = (0..100).collect();
+        let mut outgoing = VecDeque::new();
+        outgoing.push_back(SocketMessage::new(
+            MSG_TYPE_TCP_RECEIVE,
+            0x12345678,
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            payload.clone(),
+        ));
+
+        // Each transmission fits 32 bytes of data after the 9 byte header
+        let mut reassembled = Vec::new();
+        let mut chunks = 0;
+        loop {
+            let batch = take_batch(&mut outgoing, DATA_HEADER_SIZE + 32, Codec::Native);
+            assert!(batch.len() <= DATA_HEADER_SIZE + 32);
+
+            let (chunk, _) = SocketMessage::deserialize(&batch).unwrap();
+            assert_eq!(chunk.msg_type, MSG_TYPE_TCP_RECEIVE);
+            assert_eq!(chunk.socket_id, 0x12345678);
+            reassembled.extend_from_slice(&chunk.data);
+            chunks += 1;
+
+            if !chunk.more {
+                break;
+            }
+        }
+
+        assert_eq!(chunks, 4);
+        assert_eq!(reassembled, payload);
+        assert!(outgoing.is_empty());
+    }
+
+    #[test]
+    fn test_take_batch_keeps_whole_messages_together() {
+        let mut outgoing = VecDeque::new();
+        for socket_id in 0..3 {
+            outgoing.push_back(SocketMessage::new(
+                MSG_TYPE_UNIX_SEND,
+                socket_id,
+                b"".to_vec(),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                0,
+                status_data(SocketStatus::Success, 0),
+            ));
+        }
+
+        // Two 14 byte messages fit, the third waits for the next transmission
+        let batch = take_batch(&mut outgoing, 30, Codec::Native);
+        assert_eq!(batch.len(), 28);
+        assert_eq!(outgoing.len(), 1);
+
+        let batch = take_batch(&mut outgoing, 30, Codec::Native);
+        assert_eq!(batch.len(), 14);
+        assert!(outgoing.is_empty());
     }
 
     #[test]
@@ -601,19 +3694,20 @@ mod tests {
         let message = SocketMessage::new(
             MSG_TYPE_UNIX_SEND,
             0xdeadbeef,
-            "".to_string(), // Path not included in non-connect messages
-            [0, 0, 0, 0],   // IP not included in non-connect messages
+            b"".to_vec(), // Path not included in non-connect messages
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED), // IP not included in non-connect messages
             0,              // Port not included in non-connect messages
             vec![9, 10, 11, 12],
         );
 
-        let serialized = message.serialize();
-        let deserialized = SocketMessage::deserialize(&serialized).unwrap();
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
 
         assert_eq!(deserialized.msg_type, MSG_TYPE_UNIX_SEND);
         assert_eq!(deserialized.socket_id, 0xdeadbeef);
-        assert_eq!(deserialized.path, "");
-        assert_eq!(deserialized.ip_addr, [0, 0, 0, 0]);
+        assert_eq!(deserialized.path, b"");
+        assert_eq!(deserialized.ip_addr, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
         assert_eq!(deserialized.port, 0);
         assert_eq!(deserialized.data, vec![9, 10, 11, 12]);
     }
@@ -623,19 +3717,20 @@ mod tests {
         let message = SocketMessage::new(
             MSG_TYPE_TCP_RECEIVE,
             0xcafebabe,
-            "".to_string(),
-            [0, 0, 0, 0],
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             0,
             vec![13, 14, 15, 16],
         );
 
-        let serialized = message.serialize();
-        let deserialized = SocketMessage::deserialize(&serialized).unwrap();
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
 
         assert_eq!(deserialized.msg_type, MSG_TYPE_TCP_RECEIVE);
         assert_eq!(deserialized.socket_id, 0xcafebabe);
-        assert_eq!(deserialized.path, "");
-        assert_eq!(deserialized.ip_addr, [0, 0, 0, 0]);
+        assert_eq!(deserialized.path, b"");
+        assert_eq!(deserialized.ip_addr, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
         assert_eq!(deserialized.port, 0);
         assert_eq!(deserialized.data, vec![13, 14, 15, 16]);
     }
@@ -645,14 +3740,15 @@ mod tests {
         let message = SocketMessage::new(
             MSG_TYPE_UNIX_SEND, // Changed from CONNECT to SEND since connects don't have data
             0x12345678,
-            "".to_string(),
-            [0, 0, 0, 0],
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             0,
             vec![], // Empty data for non-connect message
         );
 
-        let serialized = message.serialize();
-        let deserialized = SocketMessage::deserialize(&serialized).unwrap();
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
 
         assert_eq!(deserialized.data, vec![]);
     }
@@ -663,14 +3759,15 @@ mod tests {
         let message = SocketMessage::new(
             MSG_TYPE_TCP_SEND,
             0x12345678,
-            "".to_string(),
-            [0, 0, 0, 0],
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             0,
             large_data.clone(),
         );
 
-        let serialized = message.serialize();
-        let deserialized = SocketMessage::deserialize(&serialized).unwrap();
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
 
         assert_eq!(deserialized.data, large_data);
     }
@@ -700,16 +3797,356 @@ mod tests {
 
         // Test with invalid message type
         let message = SocketMessage::new(
-            0xFF, // Invalid message type
+            0x7F, // Invalid message type (the high bit is the continuation flag)
             0x12345678,
-            "".to_string(),
-            [0, 0, 0, 0],
+            b"".to_vec(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             0,
             vec![1, 2, 3],
         );
 
-        let serialized = message.serialize();
-        let deserialized = SocketMessage::deserialize(&serialized).unwrap();
-        assert_eq!(deserialized.msg_type, 0xFF);
+        let serialized = message.serialize().unwrap();
+        let (deserialized, consumed) = SocketMessage::deserialize(&serialized).unwrap();
+        assert_eq!(consumed, serialized.len());
+        assert_eq!(deserialized.msg_type, 0x7F);
+        assert!(!deserialized.more);
+    }
+
+    // Writer accepting a limited number of bytes before it would block
+    struct ThrottledWriter {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Write for ThrottledWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.len() >= self.capacity {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.capacity - self.written.len()).min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_nonblocking() {
+        let mut writer = ThrottledWriter { written: Vec::new(), capacity: 7 };
+
+        // Stops at the first WouldBlock and reports how much was taken
+        assert_eq!(write_nonblocking(&mut writer, b"hello world").unwrap(), 7);
+        assert_eq!(writer.written, b"hello w");
+        assert_eq!(write_nonblocking(&mut writer, b"orld").unwrap(), 0);
+
+        writer.capacity = 100;
+        assert_eq!(write_nonblocking(&mut writer, b"orld").unwrap(), 4);
+        assert_eq!(writer.written, b"hello world");
+
+        // Send responses carry the number of queued bytes only when some are left
+        assert_eq!(send_status_data(0), status_data(SocketStatus::Success, 0));
+        let queued = send_status_data(300);
+        assert_eq!(queued[0], SocketStatus::Queued as u8);
+        assert_eq!(&queued[STATUS_SIZE..], &300u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_accept_chunk() {
+        // A transfer starts at offset 0
+        assert_eq!(accept_chunk(None, 4, 10, 4), None);
+        let (transfer, skip) = accept_chunk(None, 0, 10, 4).unwrap();
+        assert_eq!(transfer, ChunkedTransfer { total: 10, received: 0 });
+        assert_eq!(skip, 0);
+
+        // The next chunk continues where the last one ended
+        let transfer = ChunkedTransfer { total: 10, received: 4 };
+        assert_eq!(accept_chunk(Some(transfer), 4, 10, 4), Some((transfer, 0)));
+
+        // Retransmitted bytes are skipped, fully or partly
+        assert_eq!(accept_chunk(Some(transfer), 0, 10, 4), Some((transfer, 4)));
+        assert_eq!(accept_chunk(Some(transfer), 2, 10, 6), Some((transfer, 2)));
+
+        // Gaps, overruns and a changed total are rejected
+        assert_eq!(accept_chunk(Some(transfer), 6, 10, 2), None);
+        assert_eq!(accept_chunk(Some(transfer), 4, 10, 7), None);
+        assert_eq!(accept_chunk(Some(transfer), 4, 12, 4), None);
+
+        let ack = chunk_ack_data(SocketStatus::Success, 0, 8);
+        assert_eq!(&ack[STATUS_SIZE..], &8u32.to_be_bytes());
+    }
+
+    #[test]
+    fn test_builtin_handlers() {
+        let handlers = builtin_handlers();
+
+        // Every built-in type has a handler, and none uses the continuation flag
+        for msg_type in MSG_TYPE_UNIX_CONNECT..=MSG_TYPE_SEND_END {
+            assert!(handlers.contains_key(&msg_type), "no handler for 0x{:02x}", msg_type);
+        }
+        assert!(handlers.keys().all(|msg_type| msg_type & MSG_FLAG_MORE == 0));
+    }
+
+    #[test]
+    fn test_codec_negotiation() {
+        assert_eq!(Codec::negotiate(CODEC_NATIVE, NATIVE_CODEC_VERSION), Some(Codec::Native));
+        assert_eq!(Codec::negotiate(CODEC_NATIVE, NATIVE_CODEC_VERSION + 1), None);
+        assert_eq!(Codec::negotiate(0x7F, 1), None);
+    }
+
+    #[cfg(feature = "bincode-codec")]
+    #[test]
+    fn test_bincode_codec() {
+        assert_eq!(Codec::negotiate(CODEC_BINCODE, BINCODE_CODEC_VERSION), Some(Codec::Bincode));
+
+        let message = SocketMessage::new(
+            MSG_TYPE_TLS_CONNECT,
+            0x12345678,
+            b"example.com".to_vec(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            443,
+            vec![1, 2, 3],
+        );
+
+        // Messages are self-delimiting, so batches can be walked as with the native codec
+        let mut batch = message.encode(Codec::Bincode).unwrap();
+        batch.extend_from_slice(&message.encode(Codec::Bincode).unwrap());
+        let (decoded, consumed) = SocketMessage::decode(&batch, Codec::Bincode).unwrap();
+        assert_eq!(consumed, batch.len() / 2);
+        assert_eq!(decoded.msg_type, MSG_TYPE_TLS_CONNECT);
+        assert_eq!(decoded.socket_id, 0x12345678);
+        assert_eq!(decoded.path, b"example.com");
+        assert_eq!(decoded.ip_addr, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(decoded.port, 443);
+        assert_eq!(decoded.data, vec![1, 2, 3]);
+
+        // Truncated messages and hostnames that aren't UTF-8 are rejected
+        assert!(SocketMessage::decode(&batch[..consumed - 1], Codec::Bincode).is_err());
+        let mut invalid = message.clone();
+        invalid.path = vec![0xff];
+        assert!(SocketMessage::decode(&invalid.encode(Codec::Bincode).unwrap(), Codec::Bincode).is_err());
+
+        // Large data is split to fit the buffer like with the native codec
+        let mut outgoing = VecDeque::new();
+        outgoing.push_back(SocketMessage::new(
+            MSG_TYPE_TCP_RECEIVE,
+            1,
+            Vec::new(),
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            0,
+            vec![7; 100],
+        ));
+        let batch = take_batch(&mut outgoing, 80, Codec::Bincode);
+        assert_eq!(batch.len(), 80);
+        let (chunk, _) = SocketMessage::decode(&batch, Codec::Bincode).unwrap();
+        assert!(chunk.more);
+        assert_eq!(chunk.data.len() + outgoing[0].data.len(), 100);
+    }
+
+    #[test]
+    fn test_monotonic_nanos() {
+        let first = monotonic_nanos();
+        let second = monotonic_nanos();
+        assert!(first > 0);
+        assert!(second >= first);
+    }
+
+    #[test]
+    fn test_multiplexer_subsystem() {
+        let mut manager = SocketManager::for_multiplexer(4096).unwrap();
+        assert!(matches!(manager.run_loop(), Err(CmioError::InvalidArgument(_))));
+
+        // Requests handed over by the multiplexer are answered on its next poll
+        let request = SocketMessage::new(MSG_TYPE_RESOLVE, 3, b"127.0.0.1".to_vec(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, vec![]);
+        manager.handle_rx(&request.serialize().unwrap(), YieldReason::UnixSocket.code()).unwrap();
+        let mut buffer = Vec::new();
+        manager.poll_tx(&mut buffer, 4096).unwrap();
+        let (response, _) = SocketMessage::deserialize(&buffer).unwrap();
+        assert_eq!((response.msg_type, response.socket_id), (MSG_TYPE_RESOLVE, 3));
+
+        buffer.clear();
+        manager.poll_tx(&mut buffer, 4096).unwrap();
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_idle_connection_closed() {
+        let manager = SocketManager::for_multiplexer(4096).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connect = SocketMessage::new(MSG_TYPE_TCP_CONNECT, 4, vec![], IpAddr::V4(Ipv4Addr::LOCALHOST), port, vec![]);
+        assert_eq!(manager.handle_tcp_connect(connect).unwrap().data[0], SocketStatus::Success as u8);
+        let _peer = listener.accept().unwrap();
+        let mut data = vec![SOCKET_OPTION_IDLE_TIMEOUT];
+        data.extend_from_slice(&1u32.to_be_bytes());
+        manager.handle_set_option(SocketMessage::new(MSG_TYPE_SET_OPTION, 4, vec![], IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, data)).unwrap();
+
+        // Once idle for longer than the timeout, the next batch announces the close
+        std::thread::sleep(Duration::from_millis(20));
+        let batch = manager.next_batch(4096).unwrap();
+        let (close, consumed) = SocketMessage::deserialize(&batch).unwrap();
+        assert_eq!(consumed, batch.len());
+        assert_eq!((close.msg_type, close.socket_id), (MSG_TYPE_TCP_CLOSE, 4));
+        assert_eq!(close.data[0], SocketStatus::TimedOut as u8);
+        assert!(!manager.tcp_connections.lock().unwrap().contains_key(&4));
+        assert!(manager.next_batch(4096).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let mut manager = SocketManager::for_multiplexer(4096).unwrap();
+        manager.set_max_connections(1);
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connect = |socket_id: u32| SocketMessage::new(MSG_TYPE_TCP_CONNECT, socket_id, vec![], IpAddr::V4(Ipv4Addr::LOCALHOST), port, vec![]);
+
+        // The connect over the limit is answered with its status, the first one unharmed
+        let mut batch = connect(1).serialize().unwrap();
+        batch.extend_from_slice(&connect(2).serialize().unwrap());
+        manager.receive(&batch, YieldReason::UnixSocket.code());
+        let outgoing: Vec<SocketMessage> = manager.outgoing.lock().unwrap().drain(..).collect();
+        assert_eq!(outgoing[0].data[0], SocketStatus::Success as u8);
+        assert_eq!(outgoing[1].socket_id, 2);
+        assert_eq!(outgoing[1].data[0], SocketStatus::TooManyConnections as u8);
+        assert_eq!(&outgoing[1].data[1..STATUS_SIZE], &libc::EMFILE.to_be_bytes());
+        assert_eq!(manager.tcp_connections.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_assigned_socket_ids() {
+        let manager = SocketManager::for_multiplexer(4096).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connect = |socket_id: u32| SocketMessage::new(MSG_TYPE_TCP_CONNECT, socket_id, vec![], IpAddr::V4(Ipv4Addr::LOCALHOST), port, vec![]);
+
+        // Socket ID 0 is given a fresh ID each time, which the response carries
+        let first = manager.handle_tcp_connect(connect(0)).unwrap();
+        let second = manager.handle_tcp_connect(connect(0)).unwrap();
+        assert_eq!(first.data[0], SocketStatus::Success as u8);
+        assert_ne!(first.socket_id, 0);
+        assert_ne!(second.socket_id, 0);
+        assert_ne!(first.socket_id, second.socket_id);
+
+        // A duplicate explicit ID is refused and the connection under it kept
+        let peer = listener.accept().unwrap().0.peer_addr().unwrap();
+        listener.accept().unwrap();
+        assert!(matches!(manager.handle_tcp_connect(connect(first.socket_id)), Err(CmioError::SocketIdInUse(id)) if id == first.socket_id));
+        let connections = manager.tcp_connections.lock().unwrap();
+        let (_, connection) = &connections[&first.socket_id];
+        assert_eq!(connection.tcp_stream().local_addr().unwrap(), peer);
+    }
+
+    #[test]
+    fn test_assigned_connect_timeout() {
+        let manager = SocketManager::for_multiplexer(4096).unwrap();
+        let set_timeout = |socket_id: u32, millis: u32| {
+            let mut data = vec![SOCKET_OPTION_CONNECT_TIMEOUT];
+            data.extend_from_slice(&millis.to_be_bytes());
+            manager.handle_set_option(SocketMessage::new(MSG_TYPE_SET_OPTION, socket_id, vec![], IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, data)).unwrap();
+        };
+
+        // Each connect asking for an ID takes the timeout set on ID 0 before it
+        set_timeout(0, 100);
+        assert_eq!(manager.claim_connect_timeout(0, 11), Some(Duration::from_millis(100)));
+        set_timeout(0, 200);
+        assert_eq!(manager.claim_connect_timeout(0, 12), Some(Duration::from_millis(200)));
+        assert_eq!(manager.claim_connect_timeout(0, 13), None);
+        assert_eq!(manager.claim_connect_timeout(11, 11), Some(Duration::from_millis(100)));
+        assert_eq!(manager.claim_connect_timeout(12, 12), Some(Duration::from_millis(200)));
+
+        // An ID assigned again forgets the timeout an earlier connect left under it
+        assert_eq!(manager.claim_connect_timeout(0, 11), None);
+    }
+
+    #[test]
+    fn test_invalid_message_response() {
+        let manager = SocketManager::for_multiplexer(4096).unwrap();
+
+        // A message cut short ends the batch with an InvalidMessage response, and the
+        // messages before it are still answered
+        let resolve = SocketMessage::new(MSG_TYPE_RESOLVE, 3, b"127.0.0.1".to_vec(), IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, vec![]);
+        let mut batch = resolve.serialize().unwrap();
+        batch.extend_from_slice(&[MSG_TYPE_UNIX_CONNECT, 0, 0, 0, 9, 5]);
+        batch.extend_from_slice(&resolve.serialize().unwrap());
+        manager.receive(&batch, YieldReason::UnixSocket.code());
+
+        let outgoing: Vec<SocketMessage> = manager.outgoing.lock().unwrap().drain(..).collect();
+        assert_eq!(outgoing.len(), 2);
+        assert_eq!((outgoing[0].msg_type, outgoing[0].socket_id), (MSG_TYPE_RESOLVE, 3));
+        assert_eq!((outgoing[1].msg_type, outgoing[1].socket_id), (MSG_TYPE_UNIX_CONNECT, 9));
+        assert_eq!(outgoing[1].data[0], SocketStatus::InvalidMessage as u8);
+    }
+
+    #[test]
+    fn test_tls_handshake_timeout() {
+        // A peer that accepts the connection but never answers the client hello
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let _peer = listener.accept().unwrap();
+        let server_name = ServerName::try_from("example.com").unwrap();
+        let mut connection = ClientConnection::new(tls_client_config(), server_name).unwrap();
+
+        let started = std::time::Instant::now();
+        let error = tls_handshake(&mut connection, &mut stream, "example.com", Duration::from_millis(50)).unwrap_err();
+        assert_eq!(error.errno(), Some(libc::ETIMEDOUT));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_unix_accept_notification() {
+        let manager = SocketManager::for_multiplexer(4096).unwrap();
+        let path = std::env::temp_dir().join(format!("tapcmio-accept-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path_bytes = path.as_os_str().as_bytes().to_vec();
+        let listen = SocketMessage::new(MSG_TYPE_UNIX_LISTEN, 5, path_bytes, IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, vec![]);
+        assert_eq!(manager.handle_unix_listen(listen).unwrap().data[0], SocketStatus::Success as u8);
+        assert!(manager.read_readable_data().unwrap().is_empty());
+
+        // A connection to the path is accepted and announced without an accept request
+        let _client = UnixStream::connect(&path).unwrap();
+        let messages = manager.read_readable_data().unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!((messages[0].msg_type, messages[0].socket_id), (MSG_TYPE_UNIX_ACCEPT, 5));
+        assert_eq!(messages[0].data[0], SocketStatus::Success as u8);
+        let socket_id = u32::from_be_bytes(messages[0].data[STATUS_SIZE..STATUS_SIZE + 4].try_into().unwrap());
+        assert_ne!(socket_id, 5);
+        assert!(manager.unix_connections.lock().unwrap().contains_key(&socket_id));
+        assert!(manager.read_readable_data().unwrap().is_empty());
+
+        // Closing the listener removes its socket file
+        let close = SocketMessage::new(MSG_TYPE_UNIX_CLOSE, 5, vec![], IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, vec![]);
+        manager.handle_unix_close(close).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_forward_register_and_open() {
+        let manager = SocketManager::for_multiplexer(4096).unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let status = |response: &SocketMessage| response.data[0];
+
+        // The registration survives a round trip with its address
+        let register = SocketMessage::new(MSG_TYPE_FORWARD_REGISTER, 7, vec![], localhost, port, vec![]);
+        let (decoded, _) = SocketMessage::deserialize(&register.serialize().unwrap()).unwrap();
+        assert_eq!((decoded.ip_addr, decoded.port), (localhost, port));
+        assert_eq!(status(&manager.handle_forward_register(decoded).unwrap()), SocketStatus::Success as u8);
+        assert!(matches!(manager.handle_forward_register(register), Err(CmioError::SocketIdInUse(7))));
+
+        // Opening a stream connects it to the guest service as a TCP connection
+        let open = |forward_id: u32| SocketMessage::new(MSG_TYPE_FORWARD_OPEN, 0, vec![], localhost, 0, forward_id.to_be_bytes().to_vec());
+        let response = manager.handle_forward_open(open(7)).unwrap();
+        assert_eq!(status(&response), SocketStatus::Success as u8);
+        assert!(manager.tcp_connections.lock().unwrap().contains_key(&response.socket_id));
+        listener.accept().unwrap();
+        assert_eq!(status(&manager.handle_forward_open(open(8)).unwrap()), SocketStatus::NotFound as u8);
+
+        // Port 0 unregisters it
+        let unregister = SocketMessage::new(MSG_TYPE_FORWARD_REGISTER, 7, vec![], localhost, 0, vec![]);
+        assert_eq!(status(&manager.handle_forward_register(unregister.clone()).unwrap()), SocketStatus::Success as u8);
+        assert_eq!(status(&manager.handle_forward_register(unregister).unwrap()), SocketStatus::NotFound as u8);
     }
 } 
\ No newline at end of file
@@ -0,0 +1,459 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConnection, StreamOwned};
+use tracing::{debug, info, warn};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::http::{HeaderName, HeaderValue};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Message, WebSocket};
+use crate::cmio::CmioError;
+use crate::multiplexer::Subsystem;
+use crate::protocol::YieldReason;
+use crate::unix_tcp_socket::{tls_client_config, TcpConnection};
+
+// Reason code WebSocket messages are exchanged with the host on
+pub const WEBSOCKET_REASON: YieldReason = YieldReason::Other(0x4D);
+
+// Request of the host to open a WebSocket: the URL, then headers for the handshake
+const WEBSOCKET_OPEN: u8 = 0x01;
+
+// Frame of the host to send on a WebSocket: the kind, then the data
+const WEBSOCKET_SEND: u8 = 0x02;
+
+// Request of the host to close a WebSocket, optionally with a code (u16 BE) and reason
+const WEBSOCKET_CLOSE: u8 = 0x03;
+
+// Answer of the guest to an open request: a status byte, then the error if it failed
+const WEBSOCKET_OPENED: u8 = 0x81;
+
+// Frame received on a WebSocket: the kind, then the data
+const WEBSOCKET_RECEIVE: u8 = 0x82;
+
+// Notice of the guest that a WebSocket is closed, with the code (u16 BE) and reason
+const WEBSOCKET_CLOSED: u8 = 0x83;
+
+// Kinds of frames carried by send and receive messages
+const KIND_TEXT: u8 = 0x00;
+const KIND_BINARY: u8 = 0x01;
+
+// Set on the kind of a message whose frame continues in the next one, for frames that
+// don't fit in one yield
+const FLAG_MORE: u8 = 0x80;
+
+// Status of an open answer
+const STATUS_OPENED: u8 = 0;
+const STATUS_FAILED: u8 = 1;
+
+// Size of the type, socket ID and length preceding each payload
+const HEADER_SIZE: usize = 9;
+
+// Close code reported when a WebSocket ends without a close frame (RFC 6455 abnormal closure)
+const CLOSE_ABNORMAL: u16 = 1006;
+
+// How long connecting to a WebSocket server may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Append a message: the type, socket ID (u32 BE), payload length (u32 BE), then the payload
+fn put_message(messages: &mut VecDeque<Vec<u8>>, kind: u8, id: u32, payload: &[u8]) {
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.push(kind);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(payload);
+    messages.push_back(message);
+}
+
+// Reader of the fields of an open request
+struct FieldReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> FieldReader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], CmioError> {
+        let bytes = self.data.get(self.offset..self.offset + length)
+            .ok_or_else(|| CmioError::ProtocolError(format!("WebSocket open request truncated at offset {}", self.offset)))?;
+        self.offset += length;
+        Ok(bytes)
+    }
+    
+    fn u16(&mut self) -> Result<u16, CmioError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    
+    fn short(&mut self) -> Result<String, CmioError> {
+        let length = self.u16()? as usize;
+        Ok(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+}
+
+// Read the URL and headers of an open request
+fn open_request(payload: &[u8]) -> Result<(String, Vec<(String, String)>), CmioError> {
+    let mut reader = FieldReader { data: payload, offset: 0 };
+    let url = reader.short()?;
+    let headers = (0..reader.u16()?).map(|_| Ok((reader.short()?, reader.short()?))).collect::<Result<Vec<_>, CmioError>>()?;
+    Ok((url, headers))
+}
+
+// Connect to a WebSocket server and complete the handshake, over TLS for wss URLs
+fn connect(url: &str, headers: &[(String, String)]) -> Result<WebSocket<TcpConnection>, String> {
+    let mut request = url.into_client_request().map_err(|e| e.to_string())?;
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("invalid header {}: {}", name, e))?;
+        let value = HeaderValue::from_str(value).map_err(|e| format!("invalid value of header {}: {}", name, e))?;
+        request.headers_mut().append(name, value);
+    }
+    let uri = request.uri();
+    let secure = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => return Err(format!("{} is not a ws or wss URL", url)),
+    };
+    let host = uri.host().ok_or("URL has no host")?.trim_matches(['[', ']']).to_string();
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+    
+    // Try each address of the host in turn
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host));
+    let mut stream = None;
+    for addr in (host.as_str(), port).to_socket_addrs().map_err(|e| format!("resolve {}: {}", host, e))? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let mut stream = stream.ok_or_else(|| format!("connect to {}:{}: {}", host, port, last_error))?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT)).map_err(|e| e.to_string())?;
+    
+    let connection = if secure {
+        let server_name = ServerName::try_from(host.clone()).map_err(|_| format!("{} is not a valid TLS server name", host))?;
+        let mut connection = ClientConnection::new(tls_client_config(), server_name).map_err(|e| e.to_string())?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream).map_err(|e| format!("TLS handshake with {}: {}", host, e))?;
+        }
+        TcpConnection::Tls(Box::new(StreamOwned::new(connection, stream)))
+    } else {
+        TcpConnection::Plain(stream)
+    };
+    
+    // Handshake in blocking mode, then leave frames to be read as they come
+    let (websocket, _) = tungstenite::client(request, connection).map_err(|e| e.to_string())?;
+    let socket = websocket.get_ref().tcp_stream();
+    socket.set_read_timeout(None).and_then(|_| socket.set_nonblocking(true)).map_err(|e| e.to_string())?;
+    Ok(websocket)
+}
+
+// An open WebSocket
+struct Tunnel {
+    websocket: WebSocket<TcpConnection>,
+    // Data of a frame from the host that continues in later send messages
+    partial: Vec<u8>,
+    // Close frame received from the server, reported once the connection is over
+    close: Option<(u16, String)>,
+}
+
+// Structure tunnelling WebSockets opened by the host, as a subsystem of the multiplexer on
+// WEBSOCKET_REASON, so bidirectional push protocols can be proxied over CMIO
+//
+// Each message is a type, a socket ID chosen by the host (u32 BE) and the length of the
+// payload (u32 BE), followed by the payload. WEBSOCKET_OPEN carries the URL (u16 BE length
+// and bytes), then the count of headers (u16 BE) and the name and value of each the same
+// way; the guest connects, with TLS for wss URLs, and answers with WEBSOCKET_OPENED.
+// WEBSOCKET_SEND and WEBSOCKET_RECEIVE carry a kind, text or binary, followed by the data,
+// split over messages with FLAG_MORE when a frame doesn't fit in one yield.
+// WEBSOCKET_CLOSE starts the closing handshake and WEBSOCKET_CLOSED reports its end, or
+// the connection being lost, with the code and reason of the server.
+pub struct WebSocketTunnel {
+    tunnels: HashMap<u32, Tunnel>,
+    messages: VecDeque<Vec<u8>>,
+    max_message_size: usize,
+}
+
+impl WebSocketTunnel {
+    /// Tunnel WebSockets, with messages to the host of up to max_message_size bytes
+    pub fn new(max_message_size: usize) -> Self {
+        Self {
+            tunnels: HashMap::new(),
+            messages: VecDeque::new(),
+            max_message_size,
+        }
+    }
+    
+    /// Number of open WebSockets
+    pub fn open_count(&self) -> usize {
+        self.tunnels.len()
+    }
+    
+    /// Open a WebSocket as the host asked, answering whether it worked
+    fn open(&mut self, id: u32, payload: &[u8]) {
+        let (url, headers) = match open_request(payload) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Failed to open WebSocket {}: {}", id, e);
+                let mut answer = vec![STATUS_FAILED];
+                answer.extend_from_slice(e.to_string().as_bytes());
+                put_message(&mut self.messages, WEBSOCKET_OPENED, id, &answer);
+                return;
+            }
+        };
+        let result = if self.tunnels.contains_key(&id) {
+            Err(format!("socket {} is already open", id))
+        } else {
+            connect(&url, &headers)
+        };
+        match result {
+            Ok(websocket) => {
+                info!("Opened WebSocket {} to {}", id, url);
+                self.tunnels.insert(id, Tunnel { websocket, partial: Vec::new(), close: None });
+                put_message(&mut self.messages, WEBSOCKET_OPENED, id, &[STATUS_OPENED]);
+            }
+            Err(e) => {
+                warn!("Failed to open WebSocket {} to {}: {}", id, url, e);
+                let mut answer = vec![STATUS_FAILED];
+                answer.extend_from_slice(e.as_bytes());
+                put_message(&mut self.messages, WEBSOCKET_OPENED, id, &answer);
+            }
+        }
+    }
+    
+    /// Send a frame from the host, once all of its data has come
+    fn send(&mut self, id: u32, payload: &[u8]) {
+        let Some((&kind, data)) = payload.split_first() else {
+            warn!("Dropping empty send on WebSocket {}", id);
+            return;
+        };
+        let Some(tunnel) = self.tunnels.get_mut(&id) else {
+            debug!("Dropping frame for WebSocket {}, which isn't open", id);
+            return;
+        };
+        tunnel.partial.extend_from_slice(data);
+        if kind & FLAG_MORE != 0 {
+            return;
+        }
+        let data = std::mem::take(&mut tunnel.partial);
+        let message = match kind {
+            KIND_TEXT => Message::Text(String::from_utf8_lossy(&data).into_owned()),
+            KIND_BINARY => Message::Binary(data),
+            _ => {
+                warn!("Dropping frame of invalid kind {:#04x} on WebSocket {}", kind, id);
+                return;
+            }
+        };
+        match tunnel.websocket.send(message) {
+            Ok(()) => {}
+            // Left buffered, to be flushed as the socket drains
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => warn!("Failed to send on WebSocket {}: {}", id, e),
+        }
+    }
+    
+    /// Start closing a WebSocket, with the code and reason the host gave
+    fn close(&mut self, id: u32, payload: &[u8]) {
+        let Some(tunnel) = self.tunnels.get_mut(&id) else {
+            debug!("Ignoring close of WebSocket {}, which isn't open", id);
+            return;
+        };
+        let frame = (payload.len() >= 2).then(|| CloseFrame {
+            code: CloseCode::from(u16::from_be_bytes([payload[0], payload[1]])),
+            reason: String::from_utf8_lossy(&payload[2..]).into_owned().into(),
+        });
+        match tunnel.websocket.close(frame) {
+            Ok(()) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => debug!("Failed to close WebSocket {}: {}", id, e),
+        }
+    }
+    
+    /// Queue a received frame for the host, split to fit in yields
+    fn receive(&mut self, id: u32, kind: u8, data: &[u8]) {
+        let chunk_size = self.max_message_size.saturating_sub(HEADER_SIZE + 1).max(1);
+        let mut chunks = data.chunks(chunk_size).peekable();
+        if chunks.peek().is_none() {
+            put_message(&mut self.messages, WEBSOCKET_RECEIVE, id, &[kind]);
+        }
+        while let Some(chunk) = chunks.next() {
+            let mut payload = vec![if chunks.peek().is_some() { kind | FLAG_MORE } else { kind }];
+            payload.extend_from_slice(chunk);
+            put_message(&mut self.messages, WEBSOCKET_RECEIVE, id, &payload);
+        }
+    }
+    
+    /// Read the frames that arrived on the open WebSockets, dropping those that are over
+    fn read_frames(&mut self) {
+        let mut received = Vec::new();
+        let mut closed = Vec::new();
+        for (&id, tunnel) in self.tunnels.iter_mut() {
+            loop {
+                match tunnel.websocket.read() {
+                    Ok(Message::Text(text)) => received.push((id, KIND_TEXT, text.into_bytes())),
+                    Ok(Message::Binary(data)) => received.push((id, KIND_BINARY, data)),
+                    Ok(Message::Close(frame)) => {
+                        tunnel.close = Some(frame.map_or((CLOSE_ABNORMAL, String::new()), |frame| (frame.code.into(), frame.reason.into_owned())));
+                    }
+                    // Pings are answered by tungstenite itself
+                    Ok(_) => {}
+                    Err(tungstenite::Error::Io(e)) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                        closed.push(id);
+                        break;
+                    }
+                    Err(e) => {
+                        debug!("WebSocket {} failed: {}", id, e);
+                        closed.push(id);
+                        break;
+                    }
+                }
+            }
+        }
+        for (id, kind, data) in received {
+            self.receive(id, kind, &data);
+        }
+        for id in closed {
+            let Some(tunnel) = self.tunnels.remove(&id) else {
+                continue;
+            };
+            let (code, reason) = tunnel.close.unwrap_or((CLOSE_ABNORMAL, String::new()));
+            info!("WebSocket {} closed with {}", id, code);
+            let mut payload = code.to_be_bytes().to_vec();
+            payload.extend_from_slice(reason.as_bytes());
+            put_message(&mut self.messages, WEBSOCKET_CLOSED, id, &payload);
+        }
+    }
+}
+
+impl Subsystem for WebSocketTunnel {
+    fn poll_tx(&mut self, buffer: &mut Vec<u8>, max_len: usize) -> Result<(), CmioError> {
+        // Write out frames left buffered by a full socket
+        for tunnel in self.tunnels.values_mut() {
+            let _ = tunnel.websocket.flush();
+        }
+        // Only read more once the host has taken what is queued
+        if self.messages.is_empty() {
+            self.read_frames();
+        }
+        while let Some(message) = self.messages.front() {
+            if buffer.len() + message.len() > max_len {
+                break;
+            }
+            buffer.extend_from_slice(message);
+            self.messages.pop_front();
+        }
+        Ok(())
+    }
+    
+    fn handle_rx(&mut self, data: &[u8], _reason: u16) -> Result<(), CmioError> {
+        let mut offset = 0;
+        while offset < data.len() {
+            // The rest of a batch can't be framed past a truncated message
+            let Some(header) = data.get(offset..offset + HEADER_SIZE) else {
+                warn!("Dropping {} bytes of a truncated WebSocket message at offset {}", data.len() - offset, offset);
+                break;
+            };
+            let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+            let length = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+            let Some(payload) = data.get(offset + HEADER_SIZE..offset + HEADER_SIZE + length) else {
+                warn!("Dropping WebSocket {} message with a payload truncated at offset {}", id, offset);
+                break;
+            };
+            match header[0] {
+                WEBSOCKET_OPEN => self.open(id, payload),
+                WEBSOCKET_SEND => self.send(id, payload),
+                WEBSOCKET_CLOSE => self.close(id, payload),
+                kind => warn!("Ignoring WebSocket {} message of invalid type {:#04x}", id, kind),
+            }
+            offset += HEADER_SIZE + length;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(target_arch = "riscv64")))]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn message(kind: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut messages = VecDeque::new();
+        put_message(&mut messages, kind, id, payload);
+        messages.pop_front().unwrap()
+    }
+
+    // Poll until the guest has something for the host
+    fn poll(tunnel: &mut WebSocketTunnel) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        for _ in 0..200 {
+            tunnel.poll_tx(&mut buffer, 4096).unwrap();
+            if !buffer.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        buffer
+    }
+
+    #[test]
+    fn test_websocket_tunnel() {
+        // A server echoing one frame, then closing
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut websocket = tungstenite::accept(stream).unwrap();
+            let frame = websocket.read().unwrap();
+            websocket.send(frame).unwrap();
+            websocket.close(Some(CloseFrame { code: CloseCode::Normal, reason: "bye".into() })).unwrap();
+            while websocket.read().is_ok() {}
+        });
+
+        let mut tunnel = WebSocketTunnel::new(4096);
+        let mut open = Vec::new();
+        let url = format!("ws://127.0.0.1:{}/echo", port);
+        open.extend_from_slice(&(url.len() as u16).to_be_bytes());
+        open.extend_from_slice(url.as_bytes());
+        open.extend_from_slice(&[0, 1, 0, 6]);
+        open.extend_from_slice(b"X-Test");
+        open.extend_from_slice(&[0, 3]);
+        open.extend_from_slice(b"yes");
+        tunnel.handle_rx(&message(WEBSOCKET_OPEN, 7, &open), WEBSOCKET_REASON.code()).unwrap();
+        assert_eq!(tunnel.open_count(), 1);
+        assert_eq!(poll(&mut tunnel), message(WEBSOCKET_OPENED, 7, &[STATUS_OPENED]));
+
+        // A frame split over two messages goes out whole and comes back
+        let mut sends = message(WEBSOCKET_SEND, 7, &[KIND_TEXT | FLAG_MORE, b'h', b'e']);
+        sends.extend(message(WEBSOCKET_SEND, 7, b"\x00llo"));
+        tunnel.handle_rx(&sends, WEBSOCKET_REASON.code()).unwrap();
+        let mut received = poll(&mut tunnel);
+        if received.len() == message(WEBSOCKET_RECEIVE, 7, b"\x00hello").len() {
+            received.extend(poll(&mut tunnel));
+        }
+        let mut expected = message(WEBSOCKET_RECEIVE, 7, b"\x00hello");
+        expected.extend(message(WEBSOCKET_CLOSED, 7, b"\x03\xe8bye"));
+        assert_eq!(received, expected);
+        assert_eq!(tunnel.open_count(), 0);
+        server.join().unwrap();
+
+        // Failures to connect are answered too
+        tunnel.handle_rx(&message(WEBSOCKET_OPEN, 8, b"\x00\x0bhttp://x/ws\x00\x00"), WEBSOCKET_REASON.code()).unwrap();
+        assert_eq!(poll(&mut tunnel)[HEADER_SIZE], STATUS_FAILED);
+        
+        // Malformed messages are dropped, and a malformed open is answered as failed
+        let mut malformed = vec![0x7f, 0, 0, 0, 1, 0, 0, 0, 0];
+        malformed.extend(message(WEBSOCKET_SEND, 8, &[]));
+        malformed.extend(message(WEBSOCKET_OPEN, 9, b"\x00\x0bws://"));
+        malformed.extend_from_slice(&[WEBSOCKET_SEND, 0, 0]);
+        tunnel.handle_rx(&malformed, WEBSOCKET_REASON.code()).unwrap();
+        let answer = poll(&mut tunnel);
+        let mut failed = vec![STATUS_FAILED];
+        failed.extend_from_slice(b"Protocol error: WebSocket open request truncated at offset 2");
+        assert_eq!(answer, message(WEBSOCKET_OPENED, 9, &failed));
+        assert_eq!(tunnel.open_count(), 0);
+    }
+}
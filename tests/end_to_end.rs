@@ -0,0 +1,260 @@
+// End-to-end scenarios running the guest's subsystems behind an emulated CMIO device on a
+// thread of their own, with the host's side of each protocol answering their yields
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, TcpListener};
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tapcmio::emu::{EmuCmio, EmuHost};
+use tapcmio::host::{HostBatches, SocketClient, SocketOp, SocketResponse, Transport};
+use tapcmio::multiplexer::{Multiplexer, Subsystem};
+use tapcmio::network::{NetworkInterface, TapConfig};
+use tapcmio::unix_tcp_socket::{SocketManager, SocketStatus};
+use tapcmio::{CmioError, YieldReason};
+
+// Size of both buffers of the emulated device, small enough for messages to be chunked
+const BUFFER_SIZE: usize = 4096;
+
+// Yields answered before a scenario is given up on
+const MAX_YIELDS: usize = 10_000;
+
+// Run a guest subsystem on a thread of its own, multiplexed on an emulated device, and
+// return the host's end of the device, or why the subsystem couldn't be built
+//
+// The guest's loop ends once the host's end is dropped.
+fn spawn_guest<F>(reason: YieldReason, build: F) -> Result<(EmuHost, JoinHandle<()>), String>
+where
+    F: FnOnce(usize) -> Result<Box<dyn Subsystem>, CmioError> + Send + 'static,
+{
+    let (cmio, host) = EmuCmio::pair(BUFFER_SIZE, BUFFER_SIZE).unwrap();
+    let (ready, built) = mpsc::channel();
+    let guest = thread::spawn(move || {
+        let subsystem = match build(BUFFER_SIZE) {
+            Ok(subsystem) => subsystem,
+            Err(e) => {
+                ready.send(Err(e.to_string())).unwrap();
+                return;
+            }
+        };
+        ready.send(Ok(())).unwrap();
+        let mut multiplexer = Multiplexer::emulated(cmio);
+        multiplexer.register(reason, subsystem).unwrap();
+        while multiplexer.run_once().is_ok() {}
+    });
+    match built.recv().unwrap() {
+        Ok(()) => Ok((host, guest)),
+        Err(e) => {
+            guest.join().unwrap();
+            Err(e)
+        }
+    }
+}
+
+// Host's end of the socket manager's device, answering its yields with the client's
+// requests
+struct SocketHost {
+    link: EmuHost,
+    client: SocketClient,
+    responses: VecDeque<SocketResponse>,
+}
+
+impl SocketHost {
+    fn spawn() -> (Self, JoinHandle<()>) {
+        let (link, guest) = spawn_guest(YieldReason::UnixSocket, |size| Ok(Box::new(SocketManager::for_multiplexer(size)?))).unwrap();
+        (Self { link, client: SocketClient::new(), responses: VecDeque::new() }, guest)
+    }
+
+    // Answer yields until the response to the oldest request of the operation came back,
+    // skipping the manager's own messages, such as a stream having ended
+    fn response(&mut self, op: SocketOp) -> SocketResponse {
+        for _ in 0..MAX_YIELDS {
+            while let Some(response) = self.responses.pop_front() {
+                if response.op == op {
+                    return response;
+                }
+            }
+            let request = self.link.recv().unwrap();
+            if request.reason == YieldReason::UnixSocket.code() {
+                self.responses.extend(self.client.parse_batch(&request.data).unwrap());
+            }
+            let (reason, batch) = self.client.take_batch(BUFFER_SIZE);
+            self.link.respond(reason, &batch).unwrap();
+        }
+        panic!("no {:?} response after {} yields", op, MAX_YIELDS);
+    }
+
+    // Check that the oldest requests of the operations succeeded
+    fn expect_success(&mut self, ops: &[SocketOp]) {
+        for &op in ops {
+            let response = self.response(op);
+            assert_eq!(response.status, SocketStatus::Success, "{:?} failed with errno {}", op, response.errno);
+        }
+    }
+
+    // Read from a connection until len bytes arrived, returning them
+    fn receive(&mut self, transport: Transport, socket_id: u32, len: usize) -> Vec<u8> {
+        let mut received = Vec::new();
+        while received.len() < len {
+            self.client.receive(transport, socket_id, 0);
+            let response = self.response(SocketOp::Receive(transport));
+            match response.status {
+                SocketStatus::Success => received.extend_from_slice(&response.payload),
+                SocketStatus::WouldBlock => thread::sleep(Duration::from_millis(5)),
+                status => panic!("receive failed with {:?}", status),
+            }
+        }
+        received
+    }
+}
+
+#[test]
+fn test_tcp_echo() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let echo = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = stream.read(&mut buffer).unwrap();
+        stream.write_all(&buffer[..n]).unwrap();
+    });
+
+    let (mut host, guest) = SocketHost::spawn();
+    host.client.connect_tcp(1, addr);
+    host.client.send(Transport::Tcp, 1, b"hello over cmio");
+    host.expect_success(&[SocketOp::Connect(Transport::Tcp), SocketOp::Send(Transport::Tcp)]);
+    assert_eq!(host.receive(Transport::Tcp, 1, 15), b"hello over cmio");
+
+    host.client.close(Transport::Tcp, 1);
+    host.expect_success(&[SocketOp::Close(Transport::Tcp)]);
+    echo.join().unwrap();
+    drop(host);
+    guest.join().unwrap();
+}
+
+#[test]
+fn test_unix_round_trip() {
+    let path = std::env::temp_dir().join(format!("tapcmio-e2e-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    let echo = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = vec![0u8; 6000];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(&request).unwrap();
+    });
+
+    // A send larger than the buffer is cut across responses
+    let payload: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+    let (mut host, guest) = SocketHost::spawn();
+    host.client.connect_unix(2, path.as_os_str().as_encoded_bytes()).unwrap();
+    host.client.send(Transport::Unix, 2, &payload);
+    host.expect_success(&[SocketOp::Connect(Transport::Unix), SocketOp::Send(Transport::Unix)]);
+    assert_eq!(host.receive(Transport::Unix, 2, payload.len()), payload);
+
+    host.client.close(Transport::Unix, 2);
+    host.expect_success(&[SocketOp::Close(Transport::Unix)]);
+    echo.join().unwrap();
+    drop(host);
+    guest.join().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+// Addresses of the TAP ping scenario
+const HOST_MAC: [u8; 6] = [0x02, 0, 0, 0, 0xe2, 0xe2];
+const HOST_IP: Ipv4Addr = Ipv4Addr::new(10, 213, 0, 2);
+const GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 213, 0, 1);
+
+// Internet checksum of the data
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2).map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32).sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// Ethernet frame as read from a TAP interface, behind its packet info
+fn ethernet(destination: [u8; 6], ethertype: u16, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0, 0];
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(&destination);
+    frame.extend_from_slice(&HOST_MAC);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn arp_request() -> Vec<u8> {
+    let mut arp = vec![0, 1, 0x08, 0x00, 6, 4, 0, 1];
+    arp.extend_from_slice(&HOST_MAC);
+    arp.extend_from_slice(&HOST_IP.octets());
+    arp.extend_from_slice(&[0; 6]);
+    arp.extend_from_slice(&GUEST_IP.octets());
+    ethernet([0xff; 6], 0x0806, &arp)
+}
+
+fn echo_request(guest_mac: [u8; 6]) -> Vec<u8> {
+    let mut icmp = vec![8, 0, 0, 0, 0x12, 0x34, 0, 1];
+    icmp.extend_from_slice(b"tapcmio ping");
+    let icmp_checksum = checksum(&icmp);
+    icmp[2..4].copy_from_slice(&icmp_checksum.to_be_bytes());
+
+    let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 1, 0, 0];
+    ip[2..4].copy_from_slice(&((20 + icmp.len()) as u16).to_be_bytes());
+    ip.extend_from_slice(&HOST_IP.octets());
+    ip.extend_from_slice(&GUEST_IP.octets());
+    let ip_checksum = checksum(&ip);
+    ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+    ip.extend_from_slice(&icmp);
+    ethernet(guest_mac, 0x0800, &ip)
+}
+
+// Needs CAP_NET_ADMIN to create the guest's TAP interface, and passes without it
+#[test]
+fn test_tap_ping() {
+    let spawned = spawn_guest(YieldReason::TapRxTx, |size| {
+        let config = TapConfig {
+            name: "tapcmio-e2e0".to_string(),
+            addresses: vec![(GUEST_IP.into(), 24)],
+            ..TapConfig::default()
+        };
+        Ok(Box::new(NetworkInterface::for_multiplexer(&config, size)?))
+    });
+    let (mut host, guest) = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            eprintln!("Skipping the TAP ping: {}", e);
+            return;
+        }
+    };
+
+    // Ask for the guest's address, which also teaches it the host's, then ping it
+    let mut batches = HostBatches::new(BUFFER_SIZE);
+    batches.queue(&[arp_request()]);
+    let mut replied = false;
+    for _ in 0..MAX_YIELDS {
+        let request = host.recv().unwrap();
+        if request.reason == YieldReason::TapRxTx.code() {
+            for frame in batches.receive(&request.data).iter().filter(|frame| frame.len() >= 4 + 42) {
+                let ethernet = &frame[4..];
+                match u16::from_be_bytes([ethernet[12], ethernet[13]]) {
+                    0x0806 if ethernet[21] == 2 => batches.queue(&[echo_request(ethernet[22..28].try_into().unwrap())]),
+                    0x0800 if ethernet[23] == 1 && ethernet[34] == 0 => replied = true,
+                    _ => {}
+                }
+            }
+        }
+        let batch = batches.next_batch();
+        host.respond(YieldReason::TapRxTx.code(), &batch).unwrap();
+        if replied {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert!(replied, "no echo reply from the guest");
+    drop(host);
+    guest.join().unwrap();
+}